@@ -0,0 +1,158 @@
+//! Decompress raw deflate streams with a bounded amount of memory
+//!
+//! Only the 32 KiB deflate window is kept in RAM. The output is streamed into a [Write], for example the writer of a new file.
+use miniz_oxide::inflate::{
+    core::{decompress, DecompressorOxide},
+    TINFLStatus,
+};
+use std::io::Write;
+use thiserror::Error;
+
+/// Size of the deflate window. The decompressor uses a buffer of this size as its output
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// Errors that can occur while decompressing
+#[derive(Error, Debug)]
+pub enum InflateError {
+    /// The input is not a valid deflate stream
+    #[error("The input is not a valid deflate stream")]
+    InvalidData,
+    /// The decompressed data does not have the expected length
+    #[error(
+        "The decompressed data has the wrong length (Expected {expected}; Got at least {got})"
+    )]
+    WrongLength {
+        /// The expected length
+        expected: u32,
+        /// The number of bytes that were decompressed before the mismatch was detected
+        got: u32,
+    },
+    /// Error while writing the decompressed data
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+}
+
+/// Decompress the raw deflate stream `input` into `output`
+///
+/// Fails without writing more than `length` bytes if the stream decompresses to something else than `length` bytes. Returns the blake3 hash of the decompressed data.
+pub fn inflate<W: Write>(
+    mut input: &[u8],
+    length: u32,
+    output: &mut W,
+) -> Result<[u8; 32], InflateError> {
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    let mut window = vec![0u8; WINDOW_SIZE];
+    let mut position = 0;
+    let mut written: u32 = 0;
+    let mut hasher = blake3::Hasher::new();
+    loop {
+        // Without TINFL_FLAG_HAS_MORE_INPUT the input is the entire stream and without TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF the window wraps around
+        let (status, consumed, produced) =
+            decompress(&mut decompressor, input, &mut window, position, 0);
+        input = &input[consumed..];
+        let decompressed = &window[position..position + produced];
+        written = written.saturating_add(produced as u32);
+        if written > length {
+            return Err(InflateError::WrongLength {
+                expected: length,
+                got: written,
+            });
+        }
+        output.write_all(decompressed)?;
+        hasher.update(decompressed);
+        position = (position + produced) % WINDOW_SIZE;
+
+        match status {
+            TINFLStatus::Done => break,
+            TINFLStatus::HasMoreOutput => continue,
+            _ => return Err(InflateError::InvalidData),
+        }
+    }
+    if written != length {
+        return Err(InflateError::WrongLength {
+            expected: length,
+            got: written,
+        });
+    }
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec;
+
+    /// Data that is several windows long and repeats itself across window boundaries
+    fn test_data() -> Vec<u8> {
+        let mut state: u32 = 1;
+        let pattern: Vec<u8> = (0..20_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 24) as u8
+            })
+            .collect();
+        pattern.repeat(7)
+    }
+
+    #[test]
+    fn compressed_data_survives_a_round_trip() {
+        let data = test_data();
+        assert!(data.len() > 4 * WINDOW_SIZE);
+        let compressed = compress_to_vec(&data, 9);
+        assert!(compressed.len() < data.len() / 2);
+
+        let mut output = Vec::new();
+        let hash = inflate(&compressed, data.len() as u32, &mut output).unwrap();
+        assert!(output == data);
+        assert_eq!(hash, *blake3::hash(&data).as_bytes());
+    }
+
+    #[test]
+    fn empty_data_survives_a_round_trip() {
+        let compressed = compress_to_vec(&[], 9);
+        let mut output = Vec::new();
+        let hash = inflate(&compressed, 0, &mut output).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(hash, *blake3::hash(&[]).as_bytes());
+    }
+
+    #[test]
+    fn the_output_is_never_longer_than_the_expected_length() {
+        let data = test_data();
+        let compressed = compress_to_vec(&data, 9);
+        let mut output = Vec::new();
+        let result = inflate(&compressed, 1000, &mut output);
+        assert!(matches!(
+            result,
+            Err(InflateError::WrongLength { expected: 1000, .. })
+        ));
+        assert!(output.len() <= 1000);
+    }
+
+    #[test]
+    fn a_short_output_is_detected() {
+        let data = test_data();
+        let compressed = compress_to_vec(&data, 9);
+        let mut output = Vec::new();
+        let result = inflate(&compressed, data.len() as u32 + 1, &mut output);
+        assert!(matches!(
+            result,
+            Err(InflateError::WrongLength { got, .. }) if got == data.len() as u32
+        ));
+    }
+
+    #[test]
+    fn invalid_data_is_rejected() {
+        let data = test_data();
+        let compressed = compress_to_vec(&data, 9);
+        let mut output = Vec::new();
+        let result = inflate(
+            &compressed[..compressed.len() / 2],
+            data.len() as u32,
+            &mut output,
+        );
+        assert!(matches!(result, Err(InflateError::InvalidData)));
+        let result = inflate(&[0xff; 16], 16, &mut Vec::new());
+        assert!(matches!(result, Err(InflateError::InvalidData)));
+    }
+}
@@ -2,18 +2,21 @@
 use crate::{
     file_metadata::{FileMetadata, ReadMetadataError, WriteMetadataError},
     storage::{EraseStorageError, Storage, StorageError},
+    wear::Wear,
 };
 use std::{
     fmt::Debug,
-    io::{SeekFrom, Write},
+    io::{Read, SeekFrom, Write},
     ops::Deref,
     ptr::NonNull,
-    sync::RwLock,
+    sync::{Arc, RwLock},
 };
 use std::{io::Seek, marker::ConstParamTy};
 use thiserror::Error;
 use zerocopy::IntoBytes;
 
+pub use crate::file_metadata::MAX_PRIORITY;
+
 /// Represents an error that can occur while reading a file.
 #[derive(Error, Debug)]
 pub enum ReadFileError {
@@ -29,6 +32,9 @@ pub enum ReadFileError {
     /// The file is not marked as ready, possibly due to a power loss during writing.
     #[error("File is not marked as ready. Maybe you lost power during writing?")]
     FileNotReady,
+    /// The requested range goes past the end of the file content.
+    #[error("The requested range goes past the end of the file content")]
+    OutOfBounds,
 }
 
 /// Represents an error that can occur while writing a file.
@@ -82,7 +88,10 @@ pub enum UpgradeFileError {
     /// Cannot read a file that has been deleted.
     #[error("Cannot read a file that has been deleted.")]
     FileHasBeenDeleted,
-    /// Cannot read a file that is not ready yet.
+    /// Cannot read a file that is still being written. Try again after the writer committed it.
+    #[error("Cannot read a file that is still being written.")]
+    WriterActive,
+    /// Cannot read a file that was never committed.
     #[error("Cannot read a file that is not ready yet.")]
     NotReady,
     /// Cannot read a file that is marked for deletion.
@@ -98,12 +107,32 @@ pub enum DeleteFileContentError {
     EraseStorageError(#[from] EraseStorageError),
 }
 
+/// Represents an error that can occur while truncating a file.
+#[derive(Error, Debug)]
+pub enum TruncateFileError {
+    /// Files can only be made shorter.
+    #[error("A file can not be made longer by truncating it")]
+    LongerThanFile,
+    /// The length of a file can only be set once.
+    #[error("The file has already been truncated")]
+    AlreadyTruncated,
+    /// Error occurred in the storage layer.
+    #[error(transparent)]
+    StorageError(#[from] StorageError),
+    /// Error occurred while erasing the space after the new end.
+    #[error(transparent)]
+    EraseStorageError(#[from] EraseStorageError),
+}
+
 /// Represents an error that can occur while committing file content.
 #[derive(Error, Debug)]
 pub enum CommitFileContentError {
     /// Error occurred in the storage layer.
     #[error(transparent)]
     StorageError(#[from] StorageError),
+    /// Error occurred while truncating the file to the written length.
+    #[error(transparent)]
+    TruncateFileError(#[from] TruncateFileError),
 }
 
 /// Represents the transition state of file content.
@@ -126,6 +155,8 @@ struct InnerFile<T: Storage + 'static + Send + Sync> {
     writer_count: usize,
     /// Reference to the storage.
     storage: &'static T,
+    /// Erase counters of the storage.
+    wear: Arc<Wear<T>>,
     /// Reference to the address in storage.
     storage_address: u32,
     /// Offset from the base address; only used for writer.
@@ -189,6 +220,25 @@ pub struct File<T: Storage + 'static + Send + Sync, const STATE: FileState = { F
     content: &'static [u8],
     metadata: &'static FileMetadata,
     info: NonNull<RwLock<InnerFile<T>>>,
+    /// Position of the next [Read] of a reader. Every reference has its own, so clones can be read independently
+    read_offset: u32,
+}
+
+/// A copy of the documented metadata of a file, taken at one point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadataSnapshot {
+    /// Name of the file
+    pub name: String,
+    /// Length of the content in bytes
+    pub length: u32,
+    /// Hash of the content
+    pub hash: [u8; 32],
+    /// Age of the file. Older files are deleted first when space is needed
+    pub age: u8,
+    /// Priority of the file, between 0 and [MAX_PRIORITY]
+    pub priority: u8,
+    /// Important files are never deleted to make space for other files
+    pub important: bool,
 }
 
 unsafe impl<T: Storage + 'static + Send + Sync, const STATE: FileState> Send for File<T, STATE> {}
@@ -203,6 +253,7 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
         data: &'static [u8],
         metadata: &'static FileMetadata,
         storage: &'static T,
+        wear: &Arc<Wear<T>>,
         storage_address: u32,
         transition: impl FnOnce(FileContentTransition) + 'static + Send + Sync,
     ) -> Result<Self, ReadFileError> {
@@ -222,11 +273,13 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
                 weak_count: 0,
                 writer_count: 0,
                 storage,
+                wear: wear.clone(),
                 storage_address,
                 current_offset: 0,
                 transition: Box::new(transition),
                 has_been_deleted: false,
             }))),
+            read_offset: 0,
         };
 
         if metadata.marked_for_deletion() {
@@ -251,16 +304,26 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
     /// Read a file from storage.
     ///
     /// `address` is an address that can be used with storage.
-    pub fn from_storage(
+    pub(crate) fn from_storage(
         storage: &'static T,
+        wear: &Arc<Wear<T>>,
         address: u32,
     ) -> Result<Self, ReadFileFromStorageError> {
         let metadata = FileMetadata::from_storage(storage, address)?;
         let content = storage
-            .read(address + size_of::<FileMetadata>() as u32, metadata.length)
+            .read(
+                address + size_of::<FileMetadata>() as u32,
+                metadata.content_length(),
+            )
             .map_err(ReadFileError::from)?;
-        let file_content =
-            File::<T, { FileState::Reader }>::new(content, metadata, storage, address, |_| ())?;
+        let file_content = File::<T, { FileState::Reader }>::new(
+            content,
+            metadata,
+            storage,
+            wear,
+            address,
+            |_| (),
+        )?;
 
         Ok(file_content)
     }
@@ -278,11 +341,15 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Reader }> {
 
 impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
     /// Create a new file writer with the given memory area.
+    ///
+    /// The first `written` bytes of the content have already been written.
     fn new_writer(
         data: &'static [u8],
         metadata: &'static FileMetadata,
         storage: &'static T,
+        wear: &Arc<Wear<T>>,
         storage_address: u32,
+        written: u32,
         transition: impl FnOnce(FileContentTransition) + 'static + Send + Sync,
     ) -> Result<Self, WriteFileError> {
         if !metadata.valid_marker() {
@@ -301,7 +368,7 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
             // For now I allow this as this should never happen
         }
 
-        if !data.iter().all(|byte| *byte == 0xff) {
+        if !data[written as usize..].iter().all(|byte| *byte == 0xff) {
             return Err(WriteFileError::NotZeroed);
         }
 
@@ -313,17 +380,20 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
                 weak_count: 0,
                 writer_count: 1,
                 storage,
+                wear: wear.clone(),
                 storage_address,
-                current_offset: 0,
+                current_offset: written,
                 transition: Box::new(transition),
                 has_been_deleted: false,
             }))),
+            read_offset: 0,
         })
     }
 
     /// Create a new file and return a writer.
-    pub fn to_storage(
+    pub(crate) fn to_storage(
         storage: &'static T,
+        wear: &Arc<Wear<T>>,
         address: u32,
         length: u32,
         name: &str,
@@ -337,7 +407,45 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
             content,
             metadata,
             storage,
+            wear,
+            address,
+            0,
+            |_| (),
+        )?;
+
+        Ok(file_content)
+    }
+
+    /// Create a new file with all of its content and return a writer that only needs to be committed.
+    ///
+    /// The metadata and the content are written as one [batch](Storage::write_batch).
+    pub(crate) fn to_storage_with_content(
+        storage: &'static T,
+        wear: &Arc<Wear<T>>,
+        address: u32,
+        name: &str,
+        hash: &[u8; 32],
+        content: &[u8],
+    ) -> Result<Self, WriteFileToStorageError> {
+        let content_address = address + size_of::<FileMetadata>() as u32;
+        let previous_content = storage
+            .read(content_address, content.len() as u32)
+            .map_err(WriteFileError::from)?;
+        if !previous_content.iter().all(|byte| *byte == 0xff) {
+            return Err(WriteFileError::NotZeroed.into());
+        }
+        let metadata =
+            FileMetadata::new_to_storage_with_content(storage, address, name, hash, content)?;
+        let content = storage
+            .read(content_address, metadata.length)
+            .map_err(WriteFileError::from)?;
+        let file_content = File::<T, { FileState::Writer }>::new_writer(
+            content,
+            metadata,
+            storage,
+            wear,
             address,
+            metadata.length,
             |_| (),
         )?;
 
@@ -358,6 +466,8 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
                 self.metadata
                     .set_ready(info.storage, info.storage_address)?;
             }
+            // A power loss right after committing should not lose the file
+            info.storage.flush()?;
         }
         unsafe {
             Ok(std::mem::transmute::<
@@ -366,9 +476,153 @@ impl<T: Storage + 'static + Send + Sync> File<T, { FileState::Writer }> {
             >(self))
         }
     }
+
+    /// Write `buf` at `offset` from the start of the file.
+    ///
+    /// The parts of a file can be written in any order, but every byte can only be written once, as writing can only flip bits from 1 to 0. This is useful for uploads, where chunks can arrive out of order.
+    ///
+    /// Fails if `buf` does not fit into the file at `offset`.
+    pub fn write_at(&mut self, offset: u32, buf: &[u8]) -> std::io::Result<()> {
+        let fits = (offset as usize)
+            .checked_add(buf.len())
+            .is_some_and(|end| end <= self.content.len());
+        if !fits {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The data does not fit into the file at the given offset",
+            ));
+        }
+        self.seek(SeekFrom::Start(offset as u64))?;
+        self.write_all(buf)
+    }
+
+    /// Shrink the file to `length` bytes.
+    ///
+    /// Use this if less data was written than space was reserved. The blocks after the new end are erased and can be used by other files once the file is committed. As the length can not be overwritten, a file can only be shortened once.
+    pub fn truncate(&mut self, length: u32) -> Result<(), TruncateFileError> {
+        let reserved_length = self.content.len() as u32;
+        if length > reserved_length {
+            return Err(TruncateFileError::LongerThanFile);
+        }
+        if length == reserved_length {
+            return Ok(());
+        }
+        if self.metadata.truncated() {
+            return Err(TruncateFileError::AlreadyTruncated);
+        }
+
+        let info = unsafe { self.info.as_ref().read().unwrap() };
+        let metadata_length = size_of::<FileMetadata>() as u32;
+        let new_end = (metadata_length + length).div_ceil(T::BLOCK_SIZE) * T::BLOCK_SIZE;
+        let reserved_end =
+            (metadata_length + reserved_length).div_ceil(T::BLOCK_SIZE) * T::BLOCK_SIZE;
+        let released = &self.content[(new_end - metadata_length).min(reserved_length) as usize..];
+        if new_end < reserved_end && released.iter().any(|byte| *byte != 0xff) {
+            info.wear
+                .erase(info.storage_address + new_end, reserved_end - new_end)?;
+        }
+        unsafe {
+            self.metadata
+                .set_truncated_length(info.storage, info.storage_address, length)?;
+        }
+        drop(info);
+
+        self.content = &self.content[..length as usize];
+        Ok(())
+    }
+}
+
+/// A writer for a file whose length is not known upfront
+///
+/// Obtained with [Filesystem::get_streaming_writer](crate::Filesystem::get_streaming_writer), which reserves the largest free space. Data can only be appended. Committing truncates the file to the data that was actually written, which releases the rest of the reserved space.
+pub struct StreamingWriter<T: Storage + 'static + Send + Sync> {
+    file: File<T, { FileState::Writer }>,
+    written: u32,
+}
+
+impl<T: Storage + 'static + Send + Sync> StreamingWriter<T> {
+    pub(crate) fn new(file: File<T, { FileState::Writer }>) -> Self {
+        Self { file, written: 0 }
+    }
+
+    /// Number of bytes written so far.
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Maximum number of bytes that fit into the reserved space.
+    pub fn capacity(&self) -> u32 {
+        self.file.content.len() as u32
+    }
+
+    /// Truncate the file to the written length, commit it and convert it to a reader.
+    pub fn commit(mut self) -> Result<File<T, { FileState::Reader }>, CommitFileContentError> {
+        self.file.truncate(self.written)?;
+        self.file.commit()
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> Write for StreamingWriter<T> {
+    /// Append `buf` to the file.
+    ///
+    /// Fails without writing anything if `buf` does not fit into the reserved space.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() as u64 > (self.capacity() - self.written) as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "The data does not fit into the space reserved for the streaming writer",
+            ));
+        }
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u32;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
 }
 
 impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE> {
+    /// Get the name, length, hash, age, priority and importance of the file in one call
+    pub fn metadata(&self) -> FileMetadataSnapshot {
+        FileMetadataSnapshot {
+            name: self.metadata.name_str().to_owned(),
+            length: self.metadata.content_length(),
+            hash: self.metadata.hash,
+            age: self.metadata.age(),
+            priority: self.metadata.priority(),
+            important: self.metadata.important(),
+        }
+    }
+
+    /// Copy a part of the content into `buffer`
+    ///
+    /// Copies `buffer.len()` bytes starting `offset` bytes into the content. Only that range is read from the storage and no reader is created, so this also works on a weak reference. Use it to check the start of a large file, like the magic of a WASM module, before opening all of it.
+    pub fn read_range(&self, offset: u32, buffer: &mut [u8]) -> Result<(), ReadFileError> {
+        // Holding the lock keeps the file from being deleted while it is read
+        let info = unsafe { self.info.as_ref().read().unwrap() };
+        if info.has_been_deleted || !self.metadata.valid_marker() || self.metadata.deleted() {
+            return Err(ReadFileError::FileWasDeleted);
+        }
+        if !self.metadata.ready() {
+            return Err(ReadFileError::FileNotReady);
+        }
+        let end = offset
+            .checked_add(buffer.len() as u32)
+            .ok_or(ReadFileError::OutOfBounds)?;
+        if end > self.metadata.content_length() {
+            return Err(ReadFileError::OutOfBounds);
+        }
+        let data = info.storage.read_range(
+            info.storage_address,
+            size_of::<FileMetadata>() as u32 + offset,
+            buffer.len() as u32,
+        )?;
+        buffer.copy_from_slice(data);
+        Ok(())
+    }
+
     /// Creates a new weak pointer to this data.
     pub fn downgrade(&self) -> File<T, { FileState::Weak }> {
         unsafe {
@@ -378,6 +632,7 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
             content: self.content,
             metadata: self.metadata,
             info: self.info,
+            read_offset: 0,
         }
     }
 
@@ -391,7 +646,7 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
     ///
     /// Upgrading a writer will always fail. Use commit instead.
     ///
-    /// Upgrading will always fail while there is a writer alive.
+    /// Upgrading will always fail while there is a writer alive. That error is transient, unlike the others.
     pub fn upgrade(&self) -> Result<File<T, { FileState::Reader }>, UpgradeFileError> {
         if STATE == FileState::Writer {
             return Err(UpgradeFileError::CannotUpgradeWriter);
@@ -400,6 +655,13 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
         if info.has_been_deleted {
             return Err(UpgradeFileError::FileHasBeenDeleted);
         }
+        // The metadata is memory-mapped, so this checks the live storage. It may have been erased without going through this file
+        if !self.metadata.valid_marker() || self.metadata.deleted() {
+            return Err(UpgradeFileError::FileHasBeenDeleted);
+        }
+        if info.writer_count > 0 {
+            return Err(UpgradeFileError::WriterActive);
+        }
         if !self.metadata.ready() {
             return Err(UpgradeFileError::NotReady);
         }
@@ -408,10 +670,13 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
         }
 
         info.reader_count += 1;
+        // Weak references created from a writer still cover the space that was reserved before truncating
+        let content_length = (self.metadata.content_length() as usize).min(self.content.len());
         Ok(File::<T, { FileState::Reader }> {
-            content: self.content,
+            content: &self.content[..content_length],
             metadata: self.metadata,
             info: self.info,
+            read_offset: 0,
         })
     }
 
@@ -479,6 +744,25 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
         return Ok(());
     }
 
+    /// Raise the priority of the file.
+    ///
+    /// Files with a higher priority are deleted later when space is needed. The priority can only be raised, lower priorities are ignored. [MAX_PRIORITY] is the same as [File::set_important].
+    pub fn set_priority(&self, priority: u8) -> Result<(), WriteMetadataError> {
+        let info = unsafe { self.info.as_ref().read().unwrap() };
+
+        unsafe {
+            self.metadata
+                .set_priority(info.storage, info.storage_address, priority)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the priority of the file, between 0 and [MAX_PRIORITY].
+    pub fn priority(&self) -> u8 {
+        self.metadata.priority()
+    }
+
     /// Increase the age of the file.
     pub fn increase_age(&self) -> Result<(), WriteMetadataError> {
         let info = unsafe { self.info.as_ref().read().unwrap() };
@@ -498,17 +782,17 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
     ///
     /// If there are no strong references left, the file will be deleted right away.
     pub(crate) fn mark_for_deletion(&self) -> Result<(), DeleteFileContentError> {
-        let info = unsafe { self.info.as_ref().read().unwrap() };
+        let mut info = unsafe { self.info.as_ref().write().unwrap() };
 
         // TODO: Move this block in the !info.has_been_deleted guard
         unsafe {
             self.metadata
                 .set_marked_for_deletion(info.storage, info.storage_address)
-                .unwrap();
+                .map_err(EraseStorageError::from)?;
         };
+        // Deleting while still holding the lock, so a reference that is dropped concurrently can not delete the file a second time
         if !info.has_been_deleted && info.writer_count == 0 && info.reader_count == 0 {
-            drop(info);
-            unsafe { self.internal_delete()? };
+            unsafe { self.delete_locked(&mut info)? };
         }
         Ok(())
     }
@@ -529,6 +813,16 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
     /// Any access to this file afterwards is not safe.
     unsafe fn internal_delete(&self) -> Result<(), DeleteFileContentError> {
         let mut info = unsafe { self.info.as_ref().write().unwrap() };
+        unsafe { self.delete_locked(&mut info) }
+    }
+
+    /// Delete the file while the caller holds the lock on its info.
+    ///
+    /// Does nothing if the file has already been deleted. The storage may already contain another file by then, so erasing it again would destroy that file.
+    unsafe fn delete_locked(&self, info: &mut InnerFile<T>) -> Result<(), DeleteFileContentError> {
+        if info.has_been_deleted {
+            return Ok(());
+        }
 
         let previous_transition: &mut Box<
             dyn FnOnce(FileContentTransition) + 'static + Send + Sync,
@@ -543,11 +837,16 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> File<T, STATE>
             .map_err(EraseStorageError::from)?;
         info.has_been_deleted = true;
 
-        let full_file_length = self.metadata.length + size_of::<FileMetadata>() as u32;
+        // The space after the end of a truncated file may already belong to another file
+        let full_file_length = self.metadata.content_length() + size_of::<FileMetadata>() as u32;
         let length = full_file_length.div_ceil(T::BLOCK_SIZE) * T::BLOCK_SIZE;
 
-        // TODO: Make sure the block with the metadata gets erased last
-        info.storage.erase(info.storage_address, length)?;
+        // Erase the block with the metadata last. If the power is lost in between, the metadata still marks the remaining blocks as a deleted file, so they are not mistaken for free space with stale content.
+        if length > T::BLOCK_SIZE {
+            info.wear
+                .erase(info.storage_address + T::BLOCK_SIZE, length - T::BLOCK_SIZE)?;
+        }
+        info.wear.erase(info.storage_address, T::BLOCK_SIZE)?;
         Ok(())
     }
 
@@ -607,6 +906,9 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> Debug for File<
 impl<T: Storage + 'static + Send + Sync> Deref for File<T, { FileState::Reader }> {
     type Target = [u8];
 
+    /// Get the content of the file.
+    ///
+    /// This does not take the lock, so it is cheap enough for hot loops. The content is never written after the file was committed and it is only erased after the last reader was dropped, so it stays valid as long as this reader exists.
     fn deref(&self) -> &Self::Target {
         self.content
     }
@@ -626,6 +928,7 @@ impl<T: Storage + 'static + Send + Sync> Clone for File<T, { FileState::Reader }
             content: self.content,
             metadata: self.metadata,
             info: self.info,
+            read_offset: self.read_offset,
         }
     }
 }
@@ -638,6 +941,7 @@ impl<T: Storage + 'static + Send + Sync> Clone for File<T, { FileState::Weak }>
             content: self.content,
             metadata: self.metadata,
             info: self.info,
+            read_offset: 0,
         }
     }
 }
@@ -660,17 +964,17 @@ impl<T: Storage + 'static + Send + Sync, const STATE: FileState> Drop for File<T
             return;
         }
 
-        let weak_count = info.weak_count;
-        let has_been_deleted = info.has_been_deleted;
-        drop(info);
-        if !has_been_deleted && self.metadata.marked_for_deletion() {
+        // The deletion has to happen before the lock is released. Otherwise the last weak reference could be dropped on another thread in between, delete the file as well and free the info.
+        if !info.has_been_deleted && self.metadata.marked_for_deletion() {
             unsafe {
                 // We cant really handle a failed deletion here
                 // TODO: maybe log it
-                let _ = self.internal_delete();
+                let _ = self.delete_locked(&mut info);
             };
         }
 
+        let weak_count = info.weak_count;
+        drop(info);
         if weak_count == 0 {
             unsafe {
                 drop(Box::from_non_null(self.info));
@@ -690,31 +994,52 @@ impl<T: Storage + 'static + Send + Sync> Seek for File<T, { FileState::Writer }>
                 .map_err(|e| std::io::Error::other(e.to_string()))?
                 .current_offset
         };
-        let new_offset = match pos {
-            SeekFrom::Start(offset) => offset.try_into().unwrap_or(u32::MAX).clamp(0, length),
-            SeekFrom::End(offset) => length
-                .saturating_add_signed(
-                    offset
-                        .clamp(isize::MIN as i64, isize::MAX as i64)
-                        .try_into()
-                        .unwrap(),
-                )
-                .clamp(0, length),
-            SeekFrom::Current(offset) => current_offset
-                .saturating_add_signed(
-                    offset
-                        .clamp(isize::MIN as i64, isize::MAX as i64)
-                        .try_into()
-                        .unwrap(),
-                )
-                .clamp(0, length),
-        };
-
-        *current_offset = new_offset;
+        *current_offset = seek_offset(pos, *current_offset, length);
         Ok(*current_offset as u64)
     }
 }
 
+/// Calculate the offset after seeking to `pos`, clamped to the length of the file
+fn seek_offset(pos: SeekFrom, current_offset: u32, length: u32) -> u32 {
+    match pos {
+        SeekFrom::Start(offset) => offset.try_into().unwrap_or(u32::MAX).clamp(0, length),
+        SeekFrom::End(offset) => length
+            .saturating_add_signed(
+                offset
+                    .clamp(isize::MIN as i64, isize::MAX as i64)
+                    .try_into()
+                    .unwrap(),
+            )
+            .clamp(0, length),
+        SeekFrom::Current(offset) => current_offset
+            .saturating_add_signed(
+                offset
+                    .clamp(isize::MIN as i64, isize::MAX as i64)
+                    .try_into()
+                    .unwrap(),
+            )
+            .clamp(0, length),
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> Seek for File<T, { FileState::Reader }> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.read_offset = seek_offset(pos, self.read_offset, self.content.len() as u32);
+        Ok(self.read_offset as u64)
+    }
+}
+
+impl<T: Storage + 'static + Send + Sync> Read for File<T, { FileState::Reader }> {
+    /// Read from the content at the current offset without taking the lock, like [Deref].
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = &self.content[self.read_offset as usize..];
+        let read_length = std::cmp::min(remaining.len(), buf.len());
+        buf[..read_length].copy_from_slice(&remaining[..read_length]);
+        self.read_offset += read_length as u32;
+        Ok(read_length)
+    }
+}
+
 impl<T: Storage + 'static + Send + Sync> Write for File<T, { FileState::Writer }> {
     /// The same as [std::io::Write::write] but you can only flip bits from 1 to 0.
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
@@ -780,7 +1105,14 @@ mod tests {
 
     fn call_new() -> File<SimulatedStorage, { FileState::Reader }> {
         let (storage, content, metadata) = get_backing();
-        let content = File::<_, { FileState::Reader }>::new(content, metadata, storage, 0, |_| ());
+        let content = File::<_, { FileState::Reader }>::new(
+            content,
+            metadata,
+            storage,
+            &Wear::new(storage),
+            0,
+            |_| (),
+        );
         return content.unwrap();
     }
 
@@ -793,18 +1125,36 @@ mod tests {
     #[test]
     fn equality_works() {
         let (storage1, content1, metadata1) = get_backing();
-        let content1 =
-            File::<_, { FileState::Reader }>::new(content1, metadata1, storage1, 0, |_| ())
-                .unwrap();
+        let content1 = File::<_, { FileState::Reader }>::new(
+            content1,
+            metadata1,
+            storage1,
+            &Wear::new(storage1),
+            0,
+            |_| (),
+        )
+        .unwrap();
         let (storage2, content2, metadata2) = get_backing();
-        let content2 =
-            File::<_, { FileState::Reader }>::new(content2, metadata2, storage2, 0, |_| ())
-                .unwrap();
+        let content2 = File::<_, { FileState::Reader }>::new(
+            content2,
+            metadata2,
+            storage2,
+            &Wear::new(storage2),
+            0,
+            |_| (),
+        )
+        .unwrap();
         let (storage3, content3, metadata3) = get_backing();
         content3[1] = 17;
-        let content3 =
-            File::<_, { FileState::Reader }>::new(content3, metadata3, storage3, 0, |_| ())
-                .unwrap();
+        let content3 = File::<_, { FileState::Reader }>::new(
+            content3,
+            metadata3,
+            storage3,
+            &Wear::new(storage3),
+            0,
+            |_| (),
+        )
+        .unwrap();
         assert_eq!(content1, content2);
         assert_ne!(content2, content3);
     }
@@ -813,8 +1163,15 @@ mod tests {
     fn cloning_works() {
         let (storage, content, metadata) = get_backing();
         content[3] = 17;
-        let content =
-            File::<_, { FileState::Reader }>::new(content, metadata, storage, 0, |_| ()).unwrap();
+        let content = File::<_, { FileState::Reader }>::new(
+            content,
+            metadata,
+            storage,
+            &Wear::new(storage),
+            0,
+            |_| (),
+        )
+        .unwrap();
         let cloned_content = content.clone();
         assert_eq!(content, cloned_content);
     }
@@ -917,6 +1274,187 @@ mod tests {
         assert!(weak_content2.deleted() == true);
     }
 
+    #[test]
+    fn upgrading_fails_if_the_storage_was_erased_underneath() {
+        let (storage, content, metadata) = get_backing();
+        let content = File::<_, { FileState::Reader }>::new(
+            content,
+            metadata,
+            storage,
+            &Wear::new(storage),
+            0,
+            |_| (),
+        )
+        .unwrap();
+        let weak_content = content.downgrade();
+        drop(content);
+        storage.erase(0, SimulatedStorage::BLOCK_SIZE).unwrap();
+        let Err(UpgradeFileError::FileHasBeenDeleted) = weak_content.upgrade() else {
+            panic!("Should not be able to upgrade a file whose storage was erased");
+        };
+    }
+
+    #[test]
+    fn upgrading_while_the_file_is_deleted_never_returns_erased_content() {
+        let content = call_new();
+        let expected = content.to_vec();
+        let weak_content = content.downgrade();
+        drop(content);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let weak_content = weak_content.clone();
+                let expected = &expected;
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        if let Ok(reader) = weak_content.upgrade() {
+                            assert_eq!(reader.as_ref(), expected.as_slice());
+                        }
+                    }
+                });
+            }
+            weak_content.mark_for_deletion().unwrap();
+        });
+        let Err(UpgradeFileError::FileHasBeenDeleted) = weak_content.upgrade() else {
+            panic!("Should not be able to upgrade a deleted file");
+        };
+    }
+
+    #[test]
+    fn readers_keep_the_content_valid_while_other_threads_drop_references() {
+        let content = call_new_with_pattern();
+        let expected = content.to_vec();
+        let weak_content = content.downgrade();
+        // Marked files are deleted as soon as the last reference is dropped
+        content.mark_for_deletion().unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                let reader = content.clone();
+                let weak_content = weak_content.clone();
+                let expected = &expected;
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        let copy = reader.clone();
+                        assert_eq!(copy.as_ref(), expected.as_slice());
+                        drop(copy);
+                    }
+                    drop(weak_content);
+                    assert_eq!(reader.as_ref(), expected.as_slice());
+                });
+            }
+            drop(content);
+        });
+        let Err(UpgradeFileError::FileHasBeenDeleted) = weak_content.upgrade() else {
+            panic!("The file should be deleted after the last reader was dropped");
+        };
+        assert!(unsafe { weak_content.erased() });
+    }
+
+    fn call_new_with_pattern() -> File<SimulatedStorage, { FileState::Reader }> {
+        let (storage, content, metadata) = get_backing();
+        content
+            .iter_mut()
+            .enumerate()
+            .for_each(|(index, byte)| *byte = index as u8);
+        File::<_, { FileState::Reader }>::new(
+            content,
+            metadata,
+            storage,
+            &Wear::new(storage),
+            0,
+            |_| (),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn a_file_can_be_read_in_small_increments() {
+        let mut content = call_new_with_pattern();
+        let mut read = Vec::new();
+        let mut buffer = [0u8; 7];
+        loop {
+            let length = content.read(&mut buffer).unwrap();
+            if length == 0 {
+                break;
+            }
+            read.extend_from_slice(&buffer[..length]);
+        }
+        assert_eq!(read, content.as_ref());
+    }
+
+    #[test]
+    fn seeking_moves_the_read_offset() {
+        let mut content = call_new_with_pattern();
+        let mut buffer = [0u8; 3];
+
+        assert_eq!(content.seek(SeekFrom::Start(10)).unwrap(), 10);
+        content.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [10, 11, 12]);
+
+        assert_eq!(content.seek(SeekFrom::Current(-6)).unwrap(), 7);
+        content.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [7, 8, 9]);
+
+        assert_eq!(content.seek(SeekFrom::End(-2)).unwrap(), 98);
+        assert_eq!(content.read(&mut buffer).unwrap(), 2);
+        assert_eq!(buffer[..2], [98, 99]);
+
+        // Seeking past the end stops at the end
+        assert_eq!(content.seek(SeekFrom::Current(5)).unwrap(), 100);
+        assert_eq!(content.read(&mut buffer).unwrap(), 0);
+    }
+
+    #[test]
+    fn clones_are_read_independently() {
+        let mut content = call_new_with_pattern();
+        let mut buffer = [0u8; 4];
+        content.read_exact(&mut buffer).unwrap();
+        let mut clone = content.clone();
+        clone.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [4, 5, 6, 7]);
+        content.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn upgrading_fails_while_the_file_is_being_written() {
+        let storage = get_test_storage();
+        let writer = File::<_, { FileState::Writer }>::to_storage(
+            storage,
+            &Wear::new(storage),
+            0,
+            100,
+            "toast",
+            &[0; 32],
+        )
+        .unwrap();
+        let weak_content = writer.downgrade();
+        let Err(UpgradeFileError::WriterActive) = weak_content.upgrade() else {
+            panic!("Should not be able to upgrade while the writer is alive");
+        };
+        let content = writer.commit().unwrap();
+        weak_content.upgrade().unwrap();
+        drop(content);
+    }
+
+    #[test]
+    fn upgrading_a_file_that_was_never_committed_is_not_transient() {
+        let storage = get_test_storage();
+        let writer = File::<_, { FileState::Writer }>::to_storage(
+            storage,
+            &Wear::new(storage),
+            0,
+            100,
+            "toast",
+            &[0; 32],
+        )
+        .unwrap();
+        let weak_content = writer.downgrade();
+        drop(writer);
+        let Err(UpgradeFileError::NotReady) = weak_content.upgrade() else {
+            panic!("Should not be able to upgrade a file that was never committed");
+        };
+    }
+
     #[test]
     fn upgrading_fails_when_marked_for_deletion() {
         let content = call_new();
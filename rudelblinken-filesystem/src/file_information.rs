@@ -3,8 +3,9 @@ use crate::{
         DeleteFileContentError, File, FileState, ReadFileFromStorageError, WriteFileToStorageError,
     },
     storage::Storage,
+    wear::Wear,
 };
-use std::fmt::Formatter;
+use std::{fmt::Formatter, sync::Arc};
 
 /// Internal proxy for a file that tracks some metadata in memory
 pub(crate) struct FileInformation<T: Storage + 'static + Send + Sync> {
@@ -47,9 +48,10 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
     /// address is an address that can be used with storage
     pub fn from_storage(
         storage: &'static T,
+        wear: &Arc<Wear<T>>,
         address: u32,
     ) -> Result<FileInformation<T>, ReadFileFromStorageError> {
-        let file_content = File::<T, { FileState::Reader }>::from_storage(storage, address)?;
+        let file_content = File::<T, { FileState::Reader }>::from_storage(storage, wear, address)?;
 
         let information = FileInformation {
             address,
@@ -64,13 +66,15 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
     /// Create a new file and return a writer
     pub fn to_storage(
         storage: &'static T,
+        wear: &Arc<Wear<T>>,
         address: u32,
         length: u32,
         name: &str,
         hash: &[u8; 32],
     ) -> Result<(Self, File<T, { FileState::Writer }>), WriteFileToStorageError> {
-        let file_content =
-            File::<T, { FileState::Writer }>::to_storage(storage, address, length, name, hash)?;
+        let file_content = File::<T, { FileState::Writer }>::to_storage(
+            storage, wear, address, length, name, hash,
+        )?;
 
         let information = FileInformation {
             address,
@@ -81,6 +85,37 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
         Ok((information, file_content))
     }
 
+    /// Create a new file with all of its content and return a writer that only needs to be committed
+    pub fn to_storage_with_content(
+        storage: &'static T,
+        wear: &Arc<Wear<T>>,
+        address: u32,
+        name: &str,
+        hash: &[u8; 32],
+        content: &[u8],
+    ) -> Result<(Self, File<T, { FileState::Writer }>), WriteFileToStorageError> {
+        let file_content = File::<T, { FileState::Writer }>::to_storage_with_content(
+            storage, wear, address, name, hash, content,
+        )?;
+
+        let information = FileInformation {
+            address,
+            length: content.len() as u32,
+            name: name.into(),
+            content: file_content.downgrade(),
+        };
+        Ok((information, file_content))
+    }
+
+    /// Update the length after the file has been truncated
+    ///
+    /// Only committed files can be truncated, so this does nothing while the file is still being written.
+    pub fn refresh_length(&mut self) {
+        if let Ok(content) = self.content.upgrade() {
+            self.length = content.len() as u32;
+        }
+    }
+
     /// Transition to ready by reading content from storage
     pub fn mark_for_deletion(&self) -> Result<(), DeleteFileContentError> {
         self.content.mark_for_deletion()
@@ -101,11 +136,21 @@ impl<T: Storage + 'static + Send + Sync> FileInformation<T> {
         self.content.ready()
     }
 
+    /// Check if the file can be read, i.e. it is ready and neither deleted nor marked for deletion
+    pub fn readable(&self) -> bool {
+        self.valid() && !self.marked_for_deletion() && !self.deleted()
+    }
+
     /// Check if the file is important
     pub fn important(&self) -> bool {
         self.content.important()
     }
 
+    /// Get the priority of the file
+    pub fn priority(&self) -> u8 {
+        self.content.priority()
+    }
+
     /// Get the age of the file
     pub fn age(&self) -> u8 {
         self.content.age()
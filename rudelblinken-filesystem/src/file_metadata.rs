@@ -5,7 +5,7 @@
 //! # Overview
 //!
 //! The `FileMetadata` struct represents the metadata segment of a file that is memory-mapped
-//! into storage. It includes fields for flags, length, hash, name, and padding. The struct
+//! into storage. It includes fields for flags, length, hash, name, checksum and the truncated length. The struct
 //! provides methods for creating new metadata, reading existing metadata from storage, and
 //! setting various flags in the metadata.
 //!
@@ -28,6 +28,10 @@ use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout};
 pub enum ReadMetadataError {
     #[error("The read metadata does not have valid marker flags")]
     InvalidMarkers,
+    #[error(
+        "The checksum of the read metadata does not match, it was probably not written completely"
+    )]
+    InvalidChecksum,
     #[error("Failed to interpret the storage as metadata: {0}")]
     FailedToInterpretStorageAsMetadata(String),
     #[error(transparent)]
@@ -54,8 +58,20 @@ impl FileFlags {
     const DELETED: u16 =             0b0000000001000000;
     /// Important files wont be deleted automatically if space is needed
     const IMPORTANT: u16 =           0b0000000010000000;
+    /// Cleared for files with a priority of at least 1
+    const PRIORITY_1: u16 =          0b0000010000000000;
+    /// Cleared for files with a priority of at least 2
+    const PRIORITY_2: u16 =          0b0000100000000000;
+    /// Cleared if the content is shorter than `length`, see `truncated_length`
+    const TRUNCATED: u16 =           0b0000000100000000;
 }
 
+/// Checksum of metadata that was written before checksums were introduced
+const LEGACY_CHECKSUM: u32 = 0;
+
+/// Highest priority of a file. Files with this priority are important and never deleted automatically
+pub const MAX_PRIORITY: u8 = 3;
+
 /// Represents a the metadata segment of a file that is memory-mapped into storage.
 ///
 /// Read an existing metadata segment at an address with [from_storage] or place a new one with [new_from_storage]
@@ -73,8 +89,14 @@ pub struct FileMetadata {
     pub hash: [u8; 32],
     /// Name of the file, null terminated or 16 chars
     pub name: [u8; 16],
-    /// Reserved space to fill the metadata to 64 byte
-    _padding: [u8; 8],
+    /// CRC-32 of the length, hash and name
+    ///
+    /// It is written last, so it also detects metadata that was only partially written
+    checksum: u32,
+    /// Length in bytes after the file was truncated
+    ///
+    /// Only valid if the truncated flag is set. `length` still describes the space that was reserved when the file was created.
+    truncated_length: u32,
 }
 
 impl std::fmt::Debug for FileMetadata {
@@ -89,6 +111,7 @@ impl std::fmt::Debug for FileMetadata {
             .field("marked_for_deletion", &self.marked_for_deletion())
             .field("deleted", &self.deleted())
             .field("length", &self.length)
+            .field("content_length", &self.content_length())
             .field("hash", &hash_string)
             .field("name", &self.name_str())
             .field("important", &self.important())
@@ -105,11 +128,39 @@ impl FileMetadata {
             length,
             hash: *hash,
             name: [0; 16],
-            _padding: [0; 8],
+            checksum: 0,
+            truncated_length: u32::MAX,
         };
         metadata.set_name(name);
+        metadata.checksum = metadata.compute_checksum();
         metadata
     }
+
+    /// Calculate the checksum over the fields that do not change after the file was created
+    fn compute_checksum(&self) -> u32 {
+        let mut crc: u32 = 0xffffffff;
+        let bytes = self
+            .length
+            .as_bytes()
+            .iter()
+            .chain(self.hash.iter())
+            .chain(self.name.iter());
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (crc & 1).wrapping_neg();
+                crc = (crc >> 1) ^ (0xedb88320 & mask);
+            }
+        }
+        !crc
+    }
+
+    /// Check that the length, hash and name are intact
+    ///
+    /// Metadata written before checksums were introduced has a checksum of 0 and is always accepted.
+    pub fn valid_checksum(&self) -> bool {
+        self.checksum == LEGACY_CHECKSUM || self.checksum == self.compute_checksum()
+    }
     /// Assert that the marker flags have been set correctly for this file
     pub fn valid_marker(&self) -> bool {
         if self.flags & FileFlags::HIGH_MARKERS != FileFlags::HIGH_MARKERS {
@@ -201,6 +252,61 @@ impl FileMetadata {
         self.set_flags(storage, address, FileFlags::IMPORTANT)
     }
 
+    /// Raise the priority of the metadata in storage
+    ///
+    /// The priority can only be raised, as bits can only be cleared without erasing. Lower priorities are ignored. [MAX_PRIORITY] marks the file as important.
+    ///
+    /// Assumes that this metadata is located at `address`. Undefined behaviour if it is not or has since been deleted
+    pub unsafe fn set_priority<T: Storage>(
+        &self,
+        storage: &T,
+        address: u32,
+        priority: u8,
+    ) -> Result<(), StorageError> {
+        let flags = match priority {
+            0 => return Ok(()),
+            1 => FileFlags::PRIORITY_1,
+            2 => FileFlags::PRIORITY_1 | FileFlags::PRIORITY_2,
+            _ => FileFlags::IMPORTANT,
+        };
+        self.set_flags(storage, address, flags)
+    }
+
+    /// Set the length of the content to something shorter than the reserved length
+    ///
+    /// This can only be done once, as the length can not be overwritten without erasing.
+    ///
+    /// Assumes that this metadata is located at `address`. Undefined behaviour if it is not or has since been deleted
+    pub unsafe fn set_truncated_length<T: Storage>(
+        &self,
+        storage: &T,
+        address: u32,
+        length: u32,
+    ) -> Result<(), StorageError> {
+        let offset = std::mem::offset_of!(FileMetadata, truncated_length) as u32;
+        let flags: u16 = self.flags & !FileFlags::TRUNCATED;
+        // The flag is only set after the length, so a power loss in between leaves the file untruncated
+        storage.write_batch(&[
+            (address + offset, length.as_bytes()),
+            (address, flags.as_bytes()),
+        ])
+    }
+
+    /// Check if the file has been truncated
+    pub fn truncated(&self) -> bool {
+        self.flags & FileFlags::TRUNCATED == 0
+    }
+
+    /// Get the length of the content in bytes
+    ///
+    /// This is shorter than `length` if the file has been truncated.
+    pub fn content_length(&self) -> u32 {
+        if self.truncated() {
+            return self.truncated_length.min(self.length);
+        }
+        self.length
+    }
+
     /// Check if the file is ready to be read
     pub fn ready(&self) -> bool {
         self.flags & FileFlags::READY == 0
@@ -221,6 +327,20 @@ impl FileMetadata {
         self.flags & FileFlags::IMPORTANT == 0
     }
 
+    /// Get the priority of the file, between 0 and [MAX_PRIORITY]
+    pub fn priority(&self) -> u8 {
+        if self.important() {
+            return MAX_PRIORITY;
+        }
+        if self.flags & FileFlags::PRIORITY_2 == 0 {
+            return 2;
+        }
+        if self.flags & FileFlags::PRIORITY_1 == 0 {
+            return 1;
+        }
+        0
+    }
+
     /// Get the age of the metadata.
     pub fn age(&self) -> u8 {
         self.age.count_ones() as u8
@@ -241,6 +361,37 @@ impl FileMetadata {
             .map_err(|e| WriteMetadataError::FailedToInterpretStorageAsMetadata(e.to_string()))
     }
 
+    /// Create new metadata for `content` at the specified location and write the content after it
+    ///
+    /// The metadata and the content are written as one [batch](Storage::write_batch), so storages that support it leave a shorter window in which a power loss leaves only the metadata.
+    pub fn new_to_storage_with_content<T: Storage>(
+        storage: &T,
+        address: u32,
+        name: &str,
+        hash: &[u8; 32],
+        content: &[u8],
+    ) -> Result<&'static Self, WriteMetadataError> {
+        let new_metadata = Self::new(name, content.len() as u32, hash);
+        let as_bytes = new_metadata.as_bytes();
+        let content_address = address + size_of::<Self>() as u32;
+        let writes = [(address, as_bytes), (content_address, content)];
+        // An empty file may end at the end of the storage, where even an empty write would fail
+        let writes = if content.is_empty() {
+            &writes[..1]
+        } else {
+            &writes[..]
+        };
+        storage.write_batch(writes)?;
+        for (address, data) in writes {
+            if storage.read(*address, data.len() as u32)? != *data {
+                return Err(StorageError::ReadDataDoesNotMatchWrittenData.into());
+            }
+        }
+        let memory_mapped_metadata = storage.read(address, as_bytes.len() as u32)?;
+        FileMetadata::ref_from_bytes(memory_mapped_metadata)
+            .map_err(|e| WriteMetadataError::FailedToInterpretStorageAsMetadata(e.to_string()))
+    }
+
     /// Read exisiting metadata from the specified location
     ///
     /// Returns a reference to memory mapped flash storage
@@ -255,6 +406,9 @@ impl FileMetadata {
         if !metadata.valid_marker() {
             return Err(ReadMetadataError::InvalidMarkers);
         }
+        if !metadata.valid_checksum() {
+            return Err(ReadMetadataError::InvalidChecksum);
+        }
         Ok(metadata)
     }
 }
@@ -281,6 +435,35 @@ mod tests {
         assert!(metadata.valid_marker());
     }
 
+    #[test]
+    fn priority_can_only_be_raised() {
+        let storage = SimulatedStorage::new();
+        let metadata = FileMetadata::new_to_storage(&storage, 0, "toast", 300, &[0; 32]).unwrap();
+        assert_eq!(metadata.priority(), 0);
+        unsafe { metadata.set_priority(&storage, 0, 2) }.unwrap();
+        assert_eq!(metadata.priority(), 2);
+        unsafe { metadata.set_priority(&storage, 0, 1) }.unwrap();
+        assert_eq!(metadata.priority(), 2);
+        assert!(!metadata.important());
+        unsafe { metadata.set_priority(&storage, 0, MAX_PRIORITY) }.unwrap();
+        assert_eq!(metadata.priority(), MAX_PRIORITY);
+        assert!(metadata.important());
+        assert!(metadata.valid_marker());
+    }
+
+    #[test]
+    fn truncating_keeps_the_reserved_length() {
+        let storage = SimulatedStorage::new();
+        let metadata = FileMetadata::new_to_storage(&storage, 0, "toast", 300, &[0; 32]).unwrap();
+        assert_eq!(metadata.content_length(), 300);
+        unsafe { metadata.set_truncated_length(&storage, 0, 120) }.unwrap();
+        let read_metadata = FileMetadata::from_storage(&storage, 0).unwrap();
+        assert!(read_metadata.truncated());
+        assert_eq!(read_metadata.length, 300);
+        assert_eq!(read_metadata.content_length(), 120);
+        assert!(read_metadata.valid_marker());
+    }
+
     #[test]
     fn reading_metadata_works() {
         let mut storage = SimulatedStorage::new();
@@ -0,0 +1,309 @@
+//! A small persistent key-value store on top of the filesystem
+//!
+//! Every entry is stored as an important file, so entries are never evicted to make space for other files. File names are limited to 16 bytes, so the name is derived from a hash of the key. The file also contains the key, so a hash collision is detected instead of returning the value of a different key.
+//!
+//! Every key has two slots. A new value is always written to the slot that is not in use, and the old slot is only deleted after the new value is complete. If the power is lost in between, both slots exist and the one with the newer generation wins. A failed write never loses the old value.
+//!
+//! Layout of an entry: `[generation: u8][key length: u8][key][value]`
+//!
+//! The store is limited to [MAX_ENTRIES] entries and [MAX_TOTAL_SIZE] bytes, so it can not fill the whole storage.
+#![cfg_attr(
+    feature = "simulated",
+    doc = r##"
+```rust
+use rudelblinken_filesystem::kv_store;
+use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+use rudelblinken_filesystem::Filesystem;
+
+let storage = SimulatedStorage::new();
+let static_storage: &'static SimulatedStorage = unsafe { std::mem::transmute(&storage) };
+let mut filesystem = Filesystem::new(static_storage);
+
+kv_store::set(&mut filesystem, "color", &[255, 0, 0]).unwrap();
+assert_eq!(kv_store::get(&filesystem, "color"), Some(vec![255, 0, 0]));
+assert_eq!(kv_store::get(&filesystem, "brightness"), None);
+```
+"##
+)]
+use crate::{storage::Storage, Filesystem, FilesystemWriteError};
+use thiserror::Error;
+
+/// Prefix of all files that belong to the key-value store
+const FILE_PREFIX: &str = "kv-";
+/// Maximum number of entries. Every entry occupies at least one block of storage
+pub const MAX_ENTRIES: usize = 16;
+/// Maximum size of all entries together in bytes, including the keys
+pub const MAX_TOTAL_SIZE: u32 = 2048;
+
+/// Errors that can occur when setting a value
+#[derive(Error, Debug)]
+pub enum KvStoreError {
+    /// The key is longer than 255 bytes
+    #[error("The key is longer than 255 bytes")]
+    KeyTooLong,
+    /// Storing the entry would exceed [MAX_ENTRIES] or [MAX_TOTAL_SIZE]
+    #[error("The key-value store is full")]
+    StoreFull,
+    /// Error while writing the entry
+    #[error(transparent)]
+    FilesystemWriteError(#[from] FilesystemWriteError),
+}
+
+/// Name of the file for a slot of a key without the slot digit
+fn entry_name(key: &str) -> String {
+    let hash = blake3::hash(key.as_bytes());
+    let hex: String = hash.as_bytes()[0..6]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("{}{}", FILE_PREFIX, hex)
+}
+
+fn slot_name(key: &str, slot: usize) -> String {
+    format!("{}{}", entry_name(key), slot)
+}
+
+/// Read the generation and value of a slot
+fn read_slot<T: Storage + 'static + Send + Sync>(
+    filesystem: &Filesystem<T>,
+    key: &str,
+    slot: usize,
+) -> Option<(u8, Vec<u8>)> {
+    let file = filesystem.read_file(&slot_name(key, slot))?;
+    let content = file.upgrade().ok()?;
+    let (generation, rest) = content.as_ref().split_first()?;
+    let (key_length, rest) = rest.split_first()?;
+    let (stored_key, value) = rest.split_at_checked(*key_length as usize)?;
+    if stored_key != key.as_bytes() {
+        return None;
+    }
+    Some((*generation, value.to_vec()))
+}
+
+/// Find the slot that holds the current value, if the key exists
+fn current_slot<T: Storage + 'static + Send + Sync>(
+    filesystem: &Filesystem<T>,
+    key: &str,
+) -> Option<(usize, u8, Vec<u8>)> {
+    match (read_slot(filesystem, key, 0), read_slot(filesystem, key, 1)) {
+        (Some((first, _)), Some((second, value))) if second == first.wrapping_add(1) => {
+            Some((1, second, value))
+        }
+        (Some((first, value)), _) => Some((0, first, value)),
+        (None, Some((second, value))) => Some((1, second, value)),
+        (None, None) => None,
+    }
+}
+
+/// Get the value of a key
+pub fn get<T: Storage + 'static + Send + Sync>(
+    filesystem: &Filesystem<T>,
+    key: &str,
+) -> Option<Vec<u8>> {
+    current_slot(filesystem, key).map(|(_, _, value)| value)
+}
+
+/// Set the value of a key
+///
+/// The old value stays readable until the new value is completely written.
+pub fn set<T: Storage + 'static + Send + Sync>(
+    filesystem: &mut Filesystem<T>,
+    key: &str,
+    value: &[u8],
+) -> Result<(), KvStoreError> {
+    let key_length: u8 = key.len().try_into().map_err(|_| KvStoreError::KeyTooLong)?;
+
+    let name = entry_name(key);
+    // Both slots of this key are replaced, so they do not count against the limits
+    let (entries, total_size) = filesystem
+        .dump_directory()
+        .iter()
+        .filter(|entry| entry.name.starts_with(FILE_PREFIX) && !entry.name.starts_with(&name))
+        .fold((0, 0), |(entries, total_size), entry| {
+            (entries + 1, total_size + entry.length)
+        });
+    let entry_length = (2 + key.len() + value.len()) as u32;
+    if entries >= MAX_ENTRIES || total_size + entry_length > MAX_TOTAL_SIZE {
+        return Err(KvStoreError::StoreFull);
+    }
+
+    let (new_slot, generation) = match current_slot(filesystem, key) {
+        Some((slot, generation, _)) => (1 - slot, generation.wrapping_add(1)),
+        None => (0, 0),
+    };
+    let mut content = Vec::with_capacity(entry_length as usize);
+    content.push(generation);
+    content.push(key_length);
+    content.extend_from_slice(key.as_bytes());
+    content.extend_from_slice(value);
+    let hash: [u8; 32] = blake3::hash(&content).into();
+
+    // The unused slot may contain a stale value from an interrupted set
+    let new_name = slot_name(key, new_slot);
+    let _ = filesystem.delete_file(&new_name);
+    filesystem.write_important_file(&new_name, &content, &hash)?;
+    // The new value is complete and wins over the old one, so a failure here only leaves a stale copy that the next set removes
+    let _ = filesystem.delete_file(&slot_name(key, 1 - new_slot));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::faulty::FaultyStorage;
+    use crate::storage::simulated::{SimulatedStorage, SizedSimulatedStorage};
+
+    /// Large enough to hold [MAX_ENTRIES] entries
+    type LargeSimulatedStorage = SizedSimulatedStorage<{ 64 * 4096 }, 4096>;
+
+    #[test]
+    fn set_values_can_be_read() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        assert_eq!(get(&filesystem, "color"), None);
+        set(&mut filesystem, "color", &[1, 2, 3]).unwrap();
+        set(&mut filesystem, "empty", &[]).unwrap();
+        assert_eq!(get(&filesystem, "color"), Some(vec![1, 2, 3]));
+        assert_eq!(get(&filesystem, "empty"), Some(vec![]));
+    }
+
+    #[test]
+    fn setting_a_value_replaces_the_old_one() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for round in 0..300u32 {
+            set(&mut filesystem, "counter", &round.to_le_bytes()).unwrap();
+            assert_eq!(
+                get(&filesystem, "counter"),
+                Some(round.to_le_bytes().to_vec())
+            );
+        }
+        let files = filesystem
+            .dump_directory()
+            .iter()
+            .filter(|entry| entry.name.starts_with(FILE_PREFIX))
+            .count();
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn values_survive_a_remount() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        set(&mut filesystem, "color", &[1, 2, 3]).unwrap();
+        set(&mut filesystem, "color", &[4, 5, 6]).unwrap();
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(get(&filesystem, "color"), Some(vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn long_keys_are_rejected() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let key = "k".repeat(256);
+        assert!(matches!(
+            set(&mut filesystem, &key, &[1]),
+            Err(KvStoreError::KeyTooLong)
+        ));
+    }
+
+    #[test]
+    fn the_store_is_limited() {
+        let owned_storage = LargeSimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&LargeSimulatedStorage, &'static LargeSimulatedStorage>(
+                &owned_storage,
+            )
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for entry in 0..MAX_ENTRIES {
+            set(&mut filesystem, &format!("key{}", entry), &[1]).unwrap();
+        }
+        assert!(matches!(
+            set(&mut filesystem, "another", &[1]),
+            Err(KvStoreError::StoreFull)
+        ));
+        // Existing entries can still be replaced
+        set(&mut filesystem, "key0", &[2]).unwrap();
+        assert!(matches!(
+            set(&mut filesystem, "key0", &vec![0; MAX_TOTAL_SIZE as usize]),
+            Err(KvStoreError::StoreFull)
+        ));
+        assert_eq!(get(&filesystem, "key0"), Some(vec![2]));
+    }
+
+    #[test]
+    fn a_power_loss_while_setting_a_value_keeps_the_old_value() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_faulty_storage = FaultyStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &FaultyStorage<SimulatedStorage>,
+                &'static FaultyStorage<SimulatedStorage>,
+            >(&owned_faulty_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        set(&mut filesystem, "color", &[1, 2, 3]).unwrap();
+
+        storage.fail_after_writes(0);
+        set(&mut filesystem, "color", &[4, 5, 6]).unwrap_err();
+        assert!(storage.powered_off());
+        drop(filesystem);
+
+        storage.reboot();
+        let mut filesystem = Filesystem::new(storage);
+        assert_eq!(get(&filesystem, "color"), Some(vec![1, 2, 3]));
+        set(&mut filesystem, "color", &[4, 5, 6]).unwrap();
+        assert_eq!(get(&filesystem, "color"), Some(vec![4, 5, 6]));
+    }
+
+    #[test]
+    fn a_power_loss_before_the_old_value_is_deleted_keeps_the_new_value() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_faulty_storage = FaultyStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &FaultyStorage<SimulatedStorage>,
+                &'static FaultyStorage<SimulatedStorage>,
+            >(&owned_faulty_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        set(&mut filesystem, "color", &[1, 2, 3]).unwrap();
+
+        // The new value is written, but the old one is not erased
+        storage.fail_after_erases(0);
+        set(&mut filesystem, "color", &[4, 5, 6]).unwrap();
+        assert!(storage.powered_off());
+        drop(filesystem);
+
+        storage.reboot();
+        let mut filesystem = Filesystem::new(storage);
+        assert_eq!(get(&filesystem, "color"), Some(vec![4, 5, 6]));
+        set(&mut filesystem, "color", &[7, 8, 9]).unwrap();
+        assert_eq!(get(&filesystem, "color"), Some(vec![7, 8, 9]));
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(get(&filesystem, "color"), Some(vec![7, 8, 9]));
+    }
+}
@@ -22,6 +22,7 @@
 //!
 //! When you want to create a new file, but there is not enough space, the filesystem will automatically delete the oldest unimportant files to make space.
 //! Unimportant files are files that have not explicitly been marked as important.
+//! Files can also get a priority between 0 and 3. Files with a higher priority are only deleted after all files with a lower priority, and priority 3 is the same as important.
 //!
 //! The age of a file is determined by the number of ticks and reboots since it was created. It can be a number between 0 and 15. A file with age 16 has just been created, while a file with age 1 is the oldest file. Every reboot increases the age of all files by 1. You can manually call the tick method to age all files.
 //! Files with age 16 require 1 tick to go to 15. Files with age 15 require 2 ticks to go to 14. Files with age 14 require 3 ticks. The recommended tick rate is once per minute.
@@ -51,24 +52,33 @@ let mut filesystem = Filesystem::new(static_storage_ref);
 ```
 "##
 )]
-use file::{CommitFileContentError, File, FileState, WriteFileToStorageError};
+use file::{
+    CommitFileContentError, DeleteFileContentError, File, FileState, StreamingWriter,
+    WriteFileToStorageError,
+};
 use file_information::FileInformation;
 use file_metadata::FileMetadata;
 use std::{
     collections::{BTreeMap, VecDeque},
-    io::Write,
     ops::Bound::Included,
+    sync::Arc,
     u16,
 };
 use storage::{EraseStorageError, Storage};
 use thiserror::Error;
+use wear::Wear;
 
+#[cfg(feature = "compression")]
+#[cfg_attr(docsrs, doc(cfg(feature = "compression")))]
+pub mod compression;
 /// [file::File] provides a safe interface to read and write files.
 pub mod file;
 mod file_information;
 mod file_metadata;
+pub mod kv_store;
 /// Storage traits and implementations
 pub mod storage;
+mod wear;
 
 /// Errors that can occur when finding free space
 #[derive(Error, Debug, Clone)]
@@ -102,8 +112,19 @@ pub enum FilesystemWriteError {
     /// There already exists a file with that name. Delete it first
     #[error("There already exists a file with that name. Delete it first")]
     NameAlreadyTaken,
+    /// Error while deleting the old copy of a moved file
+    #[error(transparent)]
+    DeleteFileContentError(#[from] DeleteFileContentError),
+    /// The filesystem already contains [Storage::MAX_FILES] files. Delete some first
+    #[error("The filesystem already contains the maximum number of files. Delete some first")]
+    TooManyFiles,
 }
 
+/// Errors that can occur when defragmenting the filesystem
+///
+/// Defragmenting writes a copy of every file it moves, so these are the same errors as for writing a file.
+pub type FilesystemError = FilesystemWriteError;
+
 /// Errors that can occur when deleting a file
 #[derive(Error, Debug)]
 pub enum FilesystemDeleteError {
@@ -116,6 +137,12 @@ pub enum FilesystemDeleteError {
     /// The file does not exist
     #[error("The file does not exist")]
     FileNotFound,
+    /// Error while deleting the file content
+    #[error(transparent)]
+    DeleteFileContentError(#[from] DeleteFileContentError),
+    /// A file is still being read or written, so the storage can not be erased
+    #[error("A file is still being read or written")]
+    FileInUse,
 }
 
 ///  A struct representing the filesystem backed by a generic storage type `T`.
@@ -125,21 +152,112 @@ pub enum FilesystemDeleteError {
 /// * `T` - A type that implements the `Storage` trait and is `'static`, `Send`, and `Sync`.
 pub struct Filesystem<T: Storage + 'static + Send + Sync> {
     storage: &'static T,
+    /// Erase counters of the storage, shared with the files
+    wear: Arc<Wear<T>>,
     files: Vec<FileInformation<T>>,
 }
 
+/// Selects a file in [Filesystem::find]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileSelector<'a> {
+    /// The file with this name
+    Name(&'a str),
+    /// The file with this hash
+    Hash(&'a [u8; 32]),
+}
+
+impl FileSelector<'_> {
+    /// Check if the file is selected
+    fn matches<T: Storage + 'static + Send + Sync>(&self, file: &FileInformation<T>) -> bool {
+        match self {
+            FileSelector::Name(name) => file.name == *name,
+            FileSelector::Hash(hash) => file.compare_hash(hash),
+        }
+    }
+}
+
+/// Information about a file in the filesystem, as returned by [Filesystem::dump_directory]
+///
+/// Enable the `serde` feature to serialize it, e.g. to print the directory of a dumped flash image as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FileEntry {
+    /// Name of the file
+    pub name: String,
+    /// Length of the file content in bytes
+    pub length: u32,
+    /// First block of the file
+    pub start_block: u16,
+    /// Number of blocks occupied by the file, including its metadata
+    pub block_count: u16,
+    /// Age of the file between 0 and 16. See the crate documentation
+    pub age: u8,
+    /// Priority of the file between 0 and 3
+    pub priority: u8,
+    /// Whether the file is marked as important
+    pub important: bool,
+}
+
+/// Result of [Filesystem::integrity_check]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Number of files that can be read
+    pub valid_files: u32,
+    /// Number of files that are marked for deletion or deleted, but not erased yet
+    pub marked_for_deletion: u32,
+    /// Number of files that were never committed, e.g. because of a power loss during an upload
+    pub unfinished_files: u32,
+    /// Blocks that are not part of any file, but are not erased either
+    pub stray_blocks: Vec<u16>,
+    /// Names that are used by more than one readable file
+    pub duplicate_names: Vec<String>,
+    /// The block at which the scan for files starts. `None` if it was never stored
+    pub first_block: Option<u16>,
+    /// Whether the first block is the start of a readable file. Always true if there are no readable files
+    pub first_block_valid: bool,
+}
+
+impl IntegrityReport {
+    /// Whether the storage is in the state that a freshly mounted filesystem leaves behind
+    ///
+    /// Files that are marked for deletion are not counted as a defect, as they are erased once the last reader drops them.
+    pub fn is_clean(&self) -> bool {
+        self.unfinished_files == 0
+            && self.stray_blocks.is_empty()
+            && self.duplicate_names.is_empty()
+            && self.first_block_valid
+    }
+}
+
+/// Whether a file fits into the filesystem, as returned by [Filesystem::can_fit]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fit {
+    /// The file fits without deleting other files
+    Free,
+    /// The file only fits if these unimportant files are deleted
+    NeedsEviction {
+        /// Names of the files that would be deleted
+        victims: Vec<String>,
+    },
+    /// The file does not fit, even if all unimportant files were deleted, or there are already [Storage::MAX_FILES] files
+    Impossible,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Importance {
     Free,
-    Unimportant { age: u8 },
+    Unimportant { age: u8, priority: u8 },
     Important,
 }
 
 impl Importance {
+    /// Cost of deleting a range. A file with a higher priority always costs more than a file with a lower priority
+    ///
+    /// Every file costs at least 1, even a new one, so a range with free space is cheaper than the same range with a file in it.
     fn get_cost(&self) -> Option<u8> {
         return match self {
             Importance::Free => Some(0),
-            Importance::Unimportant { age } => Some(16 - age),
+            Importance::Unimportant { age, priority } => Some(17 - age + 17 * priority),
             Importance::Important => None,
         };
     }
@@ -148,7 +266,10 @@ impl Importance {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct Range {
     importance: Importance,
-    length: u16,
+    /// Length in blocks
+    ///
+    /// Ranges span up to twice the number of blocks because of the wraparound, so this needs to be wider than the persisted `u16` block numbers.
+    length: u32,
 }
 
 impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
@@ -185,6 +306,7 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         // Create a fs with an empty files table
         let mut filesystem = Self {
             storage,
+            wear: Wear::new(storage),
             files: Vec::new(),
         };
 
@@ -199,6 +321,7 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
             let current_block_number = (block_number + first_block as u32) % T::BLOCKS;
             let file_information = FileInformation::from_storage(
                 filesystem.storage,
+                &filesystem.wear,
                 current_block_number * T::BLOCK_SIZE,
             );
             let file_information = match file_information {
@@ -217,26 +340,88 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
                             current_block_number
                         );
                         filesystem
-                            .storage
+                            .wear
                             .erase(current_block_number * T::BLOCK_SIZE, T::BLOCK_SIZE)
                             .unwrap();
                     };
                     continue;
                 }
             };
-            block_number += ((file_information.length + 64) / T::BLOCK_SIZE) + 1;
+            block_number += (file_information.length + size_of::<FileMetadata>() as u32)
+                .div_ceil(T::BLOCK_SIZE);
             filesystem.files.push(file_information);
         }
+        if filesystem.files.len() > T::MAX_FILES as usize {
+            // Ignoring the other files would overwrite them, so no new files can be created until some are deleted
+            println!(
+                "Found {} files, which is more than the limit of {}",
+                filesystem.files.len(),
+                T::MAX_FILES
+            );
+        }
 
         unsafe { filesystem.selfcheck() };
 
         filesystem
     }
 
+    /// Scan the storage for defects without fixing them
+    ///
+    /// [Filesystem::new] erases stray blocks and removes duplicate files while mounting. This only reads the storage and the metadata, so it can be used for diagnostics on a running device.
+    pub fn integrity_check(&self) -> IntegrityReport {
+        let first_block = self.get_first_block().ok();
+        let mut report = IntegrityReport {
+            first_block,
+            ..Default::default()
+        };
+        let mut names: BTreeMap<&str, u32> = BTreeMap::new();
+        let mut valid_starts: Vec<u16> = Vec::new();
+
+        let mut block_number = 0;
+        while block_number < T::BLOCKS {
+            let current_block = (block_number + first_block.unwrap_or(0) as u32) % T::BLOCKS;
+            let address = current_block * T::BLOCK_SIZE;
+            let Ok(metadata) = FileMetadata::from_storage(self.storage, address) else {
+                block_number += 1;
+                let Ok(content) = self.storage.read(address, T::BLOCK_SIZE) else {
+                    continue;
+                };
+                if content.iter().any(|byte| *byte != 0xff) {
+                    report.stray_blocks.push(current_block as u16);
+                }
+                continue;
+            };
+            block_number += (metadata.content_length() + size_of::<FileMetadata>() as u32)
+                .div_ceil(T::BLOCK_SIZE);
+
+            if !metadata.ready() {
+                report.unfinished_files += 1;
+            } else if metadata.marked_for_deletion() || metadata.deleted() {
+                report.marked_for_deletion += 1;
+            } else {
+                report.valid_files += 1;
+                valid_starts.push(current_block as u16);
+                *names.entry(metadata.name_str()).or_default() += 1;
+            }
+        }
+
+        report.stray_blocks.sort();
+        report.duplicate_names = names
+            .into_iter()
+            .filter(|(_, count)| *count > 1)
+            .map(|(name, _)| name.to_owned())
+            .collect();
+        report.first_block_valid = valid_starts.is_empty()
+            || first_block.is_some_and(|first_block| valid_starts.contains(&first_block));
+        report
+    }
+
     /// Check the filesystem for errors and try to fix them
     ///
     /// Only safe, if none of the files have been read yet. This should only be called in new.
     unsafe fn selfcheck(&mut self) {
+        self.remove_duplicate_files();
+
         // Fix the first block number, if the first file is marked for deletion or deleted
         if let Some(first_file) = self.files.first() {
             if first_file.marked_for_deletion() || first_file.deleted() {
@@ -245,51 +430,192 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
             }
         }
 
-        // TODO: Find and remove files with a duplicate name
-
         // TODO: Cleanup
     }
 
-    /// Finds a file by name and returns a reference to it.
-    pub fn read_file(&self, name: &str) -> Option<File<T, { FileState::Weak }>> {
-        let file = self.files.iter().find(|file| {
-            file.name == name && !file.marked_for_deletion() && !file.deleted() && file.valid()
-        })?;
+    /// Delete all but the newest file of every name
+    ///
+    /// A crash between writing a new file and deleting the old one leaves two files with the same name. The files are ordered by their position after the first block, so a later file is newer. The age marker takes precedence, if it differs.
+    fn remove_duplicate_files(&mut self) {
+        let mut newest: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut duplicates: Vec<usize> = Vec::new();
+        for (index, file) in self.files.iter().enumerate() {
+            if !file.valid() || file.marked_for_deletion() || file.deleted() {
+                continue;
+            }
+            let Some(&previous) = newest.get(file.name.as_str()) else {
+                newest.insert(&file.name, index);
+                continue;
+            };
+            if file.age() >= self.files[previous].age() {
+                newest.insert(&file.name, index);
+                duplicates.push(previous);
+            } else {
+                duplicates.push(index);
+            }
+        }
+
+        for index in duplicates {
+            let file = &self.files[index];
+            println!("Deleting an older copy of {}", file.name);
+            if let Err(error) = file.mark_for_deletion() {
+                println!("Failed to delete an older copy of {}: {}", file.name, error);
+            }
+        }
+        self.cleanup_files();
+    }
+
+    /// Finds a readable file by name or hash and returns a reference to it.
+    pub fn find(&self, selector: FileSelector) -> Option<File<T, { FileState::Weak }>> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.readable() && selector.matches(file))?;
         Some(file.read())
     }
 
     /// Finds a file by name and returns a reference to it.
+    pub fn read_file(&self, name: &str) -> Option<File<T, { FileState::Weak }>> {
+        self.find(FileSelector::Name(name))
+    }
+
+    /// Finds a file by hash and returns a reference to it.
     pub fn read_file_by_hash(&self, hash: &[u8; 32]) -> Option<File<T, { FileState::Weak }>> {
-        let file = self.files.iter().find(|file| {
-            file.compare_hash(hash)
-                && !file.marked_for_deletion()
-                && !file.deleted()
-                && file.valid()
-        })?;
-        Some(file.read())
+        self.find(FileSelector::Hash(hash))
+    }
+
+    /// Check if a readable file matches the selector
+    ///
+    /// Only consults the in-memory file list and does not create a reference to the file.
+    pub fn contains(&self, selector: FileSelector) -> bool {
+        self.files
+            .iter()
+            .any(|file| file.readable() && selector.matches(file))
+    }
+
+    /// Check if a readable file with this name exists
+    pub fn exists(&self, name: &str) -> bool {
+        self.contains(FileSelector::Name(name))
+    }
+
+    /// Check if a readable file with this hash exists
+    pub fn exists_by_hash(&self, hash: &[u8; 32]) -> bool {
+        self.contains(FileSelector::Hash(hash))
+    }
+
+    /// List all readable files with their location in storage
+    ///
+    /// This only reads the metadata of the files. It does not modify the filesystem and does not keep any references to the files.
+    pub fn dump_directory(&self) -> Vec<FileEntry> {
+        self.files
+            .iter()
+            .filter(|file| file.readable())
+            .map(|file| FileEntry {
+                name: file.name.clone(),
+                length: file.length,
+                start_block: (file.address / T::BLOCK_SIZE) as u16,
+                block_count: (file.length + size_of::<FileMetadata>() as u32)
+                    .div_ceil(T::BLOCK_SIZE) as u16,
+                age: file.age(),
+                priority: file.priority(),
+                important: file.important(),
+            })
+            .collect()
+    }
+
+    /// Number of bytes in storage that are not occupied by any file
+    ///
+    /// Space used by unimportant files is not included, even though it can be reclaimed when a new file is written. File metadata counts as occupied space.
+    pub fn free_space(&self) -> u32 {
+        let used_blocks: u32 = self
+            .files
+            .iter()
+            .map(|file| (file.length + size_of::<FileMetadata>() as u32).div_ceil(T::BLOCK_SIZE))
+            .sum();
+        T::BLOCKS.saturating_sub(used_blocks) * T::BLOCK_SIZE
+    }
+
+    /// Check whether a file with `length` bytes of content could be written right now
+    ///
+    /// Unlike writing the file, this does not delete or move any files, so it can be used to ask before evicting something. The victims are the files that [Filesystem::write_file] would delete.
+    pub fn can_fit(&self, length: u32) -> Fit {
+        let Some(length_with_metadata) = length.checked_add(size_of::<FileMetadata>() as u32)
+        else {
+            return Fit::Impossible;
+        };
+        if matches!(self.find_unused_space(length_with_metadata), Ok(Some(_))) {
+            return match self.at_file_limit() {
+                true => Fit::Impossible,
+                false => Fit::Free,
+            };
+        }
+
+        let Ok(free_ranges) = self.analyze_free_space() else {
+            return Fit::Impossible;
+        };
+        let length_in_blocks = length_with_metadata.div_ceil(T::BLOCK_SIZE);
+        let Some(cheapest_range) = self.find_cheapest_range(&free_ranges, length_in_blocks) else {
+            return Fit::Impossible;
+        };
+        let victims = cheapest_range
+            .iter()
+            .filter(|(_, range)| range.importance != Importance::Free)
+            .filter_map(|(start, _)| {
+                let address = (start % T::BLOCKS) * T::BLOCK_SIZE;
+                self.files.iter().find(|file| file.address == address)
+            })
+            .map(|file| file.name.clone())
+            .collect();
+        Fit::NeedsEviction { victims }
+    }
+
+    /// Get the number of times every block was erased
+    ///
+    /// The counters are persisted in the storage metadata, so they include the erases of previous mounts. Erases before the counters were introduced are not included.
+    pub fn wear_stats(&self) -> Vec<u32> {
+        self.wear.erase_counts()
+    }
+
+    /// Write the erase counters that are only kept in RAM to the storage metadata
+    ///
+    /// The counters are written in batches to spare the metadata storage, so the latest erases are lost on a power loss unless this is called. They are also written once the filesystem and all of its files are dropped.
+    pub fn persist_wear_stats(&self) {
+        self.wear.flush();
+    }
+
+    /// How expensive it is to delete a file
+    fn importance(file: &FileInformation<T>) -> Importance {
+        if file.important() || !file.can_be_deleted() {
+            return Importance::Important;
+        }
+        Importance::Unimportant {
+            age: file.age(),
+            priority: file.priority(),
+        }
+    }
+
+    /// Whether there are already [Storage::MAX_FILES] files
+    fn at_file_limit(&self) -> bool {
+        self.files.iter().filter(|file| !file.deleted()).count() >= T::MAX_FILES as usize
     }
 
     /// Get information about the free space in the storage
-    fn analyze_free_space(&self) -> Result<BTreeMap<u16, Range>, FindFreeSpaceError> {
-        let mut free_ranges: BTreeMap<u16, Range> = Default::default();
+    fn analyze_free_space(&self) -> Result<BTreeMap<u32, Range>, FindFreeSpaceError> {
+        let mut free_ranges: BTreeMap<u32, Range> = Default::default();
         free_ranges.insert(
             0,
             Range {
                 importance: Importance::Free,
-                length: T::BLOCKS as u16 * 2,
+                length: T::BLOCKS * 2,
             },
         );
 
         for file in &self.files {
-            let file_importance = if file.important() || !file.can_be_deleted() {
-                Importance::Important
-            } else {
-                Importance::Unimportant { age: file.age() }
-            };
+            let file_importance = Self::importance(file);
 
-            let start_block = (file.address / T::BLOCK_SIZE) as u16;
+            let start_block = file.address / T::BLOCK_SIZE;
             let length_in_blocks =
-                (file.length + size_of::<FileMetadata>() as u32).div_ceil(T::BLOCK_SIZE) as u16;
+                (file.length + size_of::<FileMetadata>() as u32).div_ceil(T::BLOCK_SIZE);
             let end_block = start_block + length_in_blocks;
 
             let Some((
@@ -346,10 +672,10 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
             if Importance::Free == first_entry.1.importance {
                 panic!("In case of wraparound, the first entry should always be free");
             }
-            if first_entry.1.length < wraparound_length as u16 {
+            if first_entry.1.length < wraparound_length as u32 {
                 panic!("In case of wraparound, the first entry should always be large enough to accommodate the wraparound");
             }
-            free_ranges.insert(wraparound_length as u16, first_entry.1.clone());
+            free_ranges.insert(wraparound_length as u32, first_entry.1.clone());
             free_ranges.remove(&0);
         }
 
@@ -362,7 +688,7 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
                 *end_space.0,
                 Range {
                     importance: end_space.1.importance,
-                    length: end_space.1.length - T::BLOCKS as u16,
+                    length: end_space.1.length - T::BLOCKS,
                 },
             );
         }
@@ -371,46 +697,45 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
 
         // Duplicate all ranges to the back
         for range in free_ranges.clone().into_iter() {
-            free_ranges.insert(range.0 + T::BLOCKS as u16, range.1);
+            free_ranges.insert(range.0 + T::BLOCKS, range.1);
         }
 
         return Ok(free_ranges);
     }
 
-    /// Find a free space in storage of at least the given length.
+    /// Find a range of at least the given length that is not occupied by any file.
     ///
-    /// For now the space is guaranteed to start at a block boundary
-    fn find_free_space(&self, length: u32) -> Result<u32, FindFreeSpaceError> {
+    /// Returns `None` if the space is too fragmented or too full.
+    fn find_unused_space(&self, length: u32) -> Result<Option<u32>, FindFreeSpaceError> {
         let free_ranges = self.analyze_free_space()?;
+        let length_in_blocks = length.div_ceil(T::BLOCK_SIZE);
 
-        for range in free_ranges.iter() {
-            println!("Free range: {:?}", range);
-        }
-
-        let length_in_blocks = length.div_ceil(T::BLOCK_SIZE) as u16;
-
-        if let Some((free_range_start, free_range_length)) = free_ranges
+        Ok(free_ranges
             .iter()
-            .filter(|(&start, _)| start < T::BLOCKS as u16)
+            .filter(|(&start, _)| start < T::BLOCKS)
             .filter(|(_, range)| range.importance == Importance::Free)
             .filter(|(_, range)| range.length >= (length_in_blocks))
             .min_by(|(_, range_a), (_, range_b)| range_a.length.cmp(&range_b.length))
-            .map(|(a, b)| (*a as u32, b.length as u32))
-        {
-            // let longest_range_start = longest_range.0 % (T::BLOCKS);
-            println!(
-                "Found free space at {} with length {}",
-                free_range_start, free_range_length
-            );
-            return Ok(free_range_start * T::BLOCK_SIZE);
-        }
-        // println!("No unused free space found");
+            .map(|(start, _)| *start * T::BLOCK_SIZE))
+    }
 
-        let mut cheapest_range: VecDeque<(u16, Range)> = VecDeque::new();
-        let mut cheapest_range_cost: u16 = u16::MAX;
-        let mut current_range: VecDeque<(u16, Range)> = VecDeque::new();
-        let mut current_range_cost: u16 = 0;
-        let mut current_range_length: u16 = 0;
+    /// Find the consecutive ranges of at least `length_in_blocks` blocks that are cheapest to free
+    ///
+    /// Important files are never part of the result. Returns `None` if there is no such range.
+    fn find_cheapest_range(
+        &self,
+        free_ranges: &BTreeMap<u32, Range>,
+        length_in_blocks: u32,
+    ) -> Option<VecDeque<(u32, Range)>> {
+        // The ranges are duplicated to handle the wraparound, so a longer range would overlap itself
+        if length_in_blocks > T::BLOCKS {
+            return None;
+        }
+        let mut cheapest_range: VecDeque<(u32, Range)> = VecDeque::new();
+        let mut cheapest_range_cost: u32 = u32::MAX;
+        let mut current_range: VecDeque<(u32, Range)> = VecDeque::new();
+        let mut current_range_cost: u32 = 0;
+        let mut current_range_length: u32 = 0;
         for (check_start, check_range) in free_ranges.iter() {
             let Some(cost) = check_range.importance.get_cost() else {
                 // println!("Skipping important range {:?}", check_range);
@@ -423,7 +748,7 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
                 current_range_length = 0;
             }
             current_range.push_back((*check_start, *check_range));
-            current_range_cost += cost as u16;
+            current_range_cost += cost as u32;
             current_range_length += check_range.length;
             // println!(
             //     "Current range: {:?}, cost: {}, length: {}",
@@ -439,12 +764,12 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
                     }
                     let removed = current_range.pop_front().unwrap();
                     let removed_cost = removed.1.importance.get_cost().unwrap();
-                    current_range_cost -= removed_cost as u16;
+                    current_range_cost -= removed_cost as u32;
                     current_range_length -= removed.1.length;
                 }
             }
             if let Some(front) = current_range.front() {
-                if front.0 >= T::BLOCKS as u16 {
+                if front.0 >= T::BLOCKS {
                     break;
                 }
             }
@@ -456,16 +781,39 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
             }
         }
 
-        if cheapest_range_cost == u16::MAX {
-            return Err(FindFreeSpaceError::NotEnoughSpace);
+        if cheapest_range_cost == u32::MAX {
+            return None;
+        }
+        Some(cheapest_range)
+    }
+
+    /// Find a free space in storage of at least the given length.
+    ///
+    /// For now the space is guaranteed to start at a block boundary
+    fn find_free_space(&self, length: u32) -> Result<u32, FindFreeSpaceError> {
+        let free_ranges = self.analyze_free_space()?;
+
+        for range in free_ranges.iter() {
+            println!("Free range: {:?}", range);
+        }
+
+        if let Some(address) = self.find_unused_space(length)? {
+            println!("Found free space at {}", address / T::BLOCK_SIZE);
+            return Ok(address);
         }
+        let length_in_blocks = length.div_ceil(T::BLOCK_SIZE);
+        // println!("No unused free space found");
+
+        let Some(cheapest_range) = self.find_cheapest_range(&free_ranges, length_in_blocks) else {
+            return Err(FindFreeSpaceError::NotEnoughSpace);
+        };
 
         for range in cheapest_range.iter() {
             println!("Cheapest range: {:?}", range);
             let matched_file = self
                 .files
                 .iter()
-                .find(|f| f.address == range.0 as u32 * T::BLOCK_SIZE);
+                .find(|f| f.address == range.0 * T::BLOCK_SIZE);
 
             if let Some(file) = matched_file {
                 file.mark_for_deletion().unwrap();
@@ -477,7 +825,7 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         }
 
         let first = cheapest_range.front().unwrap();
-        let start = first.0 as u32 * T::BLOCK_SIZE;
+        let start = first.0 * T::BLOCK_SIZE;
         println!("Found unimportant space at {}", start);
         return Ok(start);
 
@@ -499,6 +847,32 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         // return Err(FindFreeSpaceError::NotEnoughSpace);
     }
 
+    /// Find the longest free space, without deleting any files.
+    ///
+    /// Returns the address of the space and the number of bytes that fit into it after the metadata.
+    fn find_largest_free_space(&self) -> Result<(u32, u32), FindFreeSpaceError> {
+        let free_ranges = self.analyze_free_space()?;
+
+        let Some((free_range_start, free_range_length)) = free_ranges
+            .iter()
+            .filter(|(&start, _)| start < T::BLOCKS)
+            .filter(|(_, range)| range.importance == Importance::Free)
+            .map(|(start, range)| (*start, range.length.min(T::BLOCKS)))
+            .max_by_key(|(_, length)| *length)
+        else {
+            return Err(FindFreeSpaceError::NoFreeSpace);
+        };
+
+        let free_bytes = free_range_length * T::BLOCK_SIZE;
+        if free_bytes <= size_of::<FileMetadata>() as u32 {
+            return Err(FindFreeSpaceError::NoFreeSpace);
+        }
+        Ok((
+            free_range_start * T::BLOCK_SIZE,
+            free_bytes - size_of::<FileMetadata>() as u32,
+        ))
+    }
+
     /// Write a file to storage.
     pub fn write_file(
         &mut self,
@@ -506,9 +880,24 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         content: &[u8],
         _hash: &[u8; 32],
     ) -> Result<(), FilesystemWriteError> {
-        let mut writer = self.get_file_writer(name, content.len() as u32, _hash)?;
+        let writer = self.create_file(name, content, _hash)?;
+        writer.commit()?;
+        Ok(())
+    }
 
-        writer.write_all(content)?;
+    /// Write a file to storage and mark it as important.
+    ///
+    /// The file is marked before it is committed, so there is no moment in which it could be evicted to make space for another file.
+    pub fn write_important_file(
+        &mut self,
+        name: &str,
+        content: &[u8],
+        hash: &[u8; 32],
+    ) -> Result<(), FilesystemWriteError> {
+        let writer = self.create_file(name, content, hash)?;
+        writer
+            .set_important()
+            .map_err(WriteFileToStorageError::from)?;
         writer.commit()?;
         Ok(())
     }
@@ -516,13 +905,92 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
     /// Get a writer that allows writing a file over time.
     ///
     /// The file can only be read after the content was finished
+    ///
+    /// If the file does not fit into any gap, unimportant files are evicted. Call [Filesystem::defragment] first to merge the gaps instead.
     pub fn get_file_writer(
         &mut self,
         name: &str,
         length: u32,
         hash: &[u8; 32],
     ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
+        let free_location = self.make_room_for_file(name, length)?;
+
+        let (file, writer) = FileInformation::to_storage(
+            self.storage,
+            &self.wear,
+            free_location,
+            length,
+            name,
+            hash,
+        )?;
+        self.files.push(file);
+        Ok(writer)
+    }
+
+    /// Create a file with all of its content and return a writer that only needs to be committed
+    ///
+    /// The metadata and the content are written as one [batch](Storage::write_batch).
+    fn create_file(
+        &mut self,
+        name: &str,
+        content: &[u8],
+        hash: &[u8; 32],
+    ) -> Result<File<T, { FileState::Writer }>, FilesystemWriteError> {
+        let free_location = self.make_room_for_file(name, content.len() as u32)?;
+
+        let (file, writer) = FileInformation::to_storage_with_content(
+            self.storage,
+            &self.wear,
+            free_location,
+            name,
+            hash,
+            content,
+        )?;
+        self.files.push(file);
+        Ok(writer)
+    }
+
+    /// Find the location for a new file, evicting files if necessary
+    fn make_room_for_file(&mut self, name: &str, length: u32) -> Result<u32, FilesystemWriteError> {
+        self.cleanup_files();
+        self.check_name_is_free(name)?;
+        let length_with_metadata = length + size_of::<FileMetadata>() as u32;
+        // Without unused space the file takes the place of the evicted files, so the number of files does not grow
+        if self.at_file_limit() && self.find_unused_space(length_with_metadata)?.is_some() {
+            return Err(FilesystemWriteError::TooManyFiles);
+        }
+        Ok(self.find_free_space(length_with_metadata)?)
+    }
+
+    /// Get a writer for a file whose length is not known upfront.
+    ///
+    /// This reserves the largest free space without deleting other files. The file is truncated to the written length on commit, which releases the rest of the space. Writing more than [StreamingWriter::capacity] fails.
+    pub fn get_streaming_writer(
+        &mut self,
+        name: &str,
+        hash: &[u8; 32],
+    ) -> Result<StreamingWriter<T>, FilesystemWriteError> {
         self.cleanup_files();
+        self.check_name_is_free(name)?;
+        if self.at_file_limit() {
+            return Err(FilesystemWriteError::TooManyFiles);
+        }
+        let (free_location, length) = self.find_largest_free_space()?;
+
+        let (file, writer) = FileInformation::to_storage(
+            self.storage,
+            &self.wear,
+            free_location,
+            length,
+            name,
+            hash,
+        )?;
+        self.files.push(file);
+        Ok(StreamingWriter::new(writer))
+    }
+
+    /// Fail if there is already a live file with this name
+    fn check_name_is_free(&self, name: &str) -> Result<(), FilesystemWriteError> {
         if self
             .files
             .iter()
@@ -530,29 +998,27 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         {
             return Err(FilesystemWriteError::NameAlreadyTaken);
         }
-        let free_location = self.find_free_space(length + size_of::<FileMetadata>() as u32)?;
-
-        let (file, writer) =
-            FileInformation::to_storage(self.storage, free_location, length, name, hash)?;
-        self.files.push(file);
-        Ok(writer)
+        Ok(())
     }
 
     /// Delete a file
     ///
     /// The file will only be deleted once there are no strong references to its content left. Strong references can be obtained by calling upgrade on the content of a file
     pub fn delete_file(&mut self, filename: &str) -> Result<(), FilesystemDeleteError> {
-        let Some((index, _)) = self
+        // Prefer the live file, there may also be older files with the same name that are only marked for deletion
+        let Some(index) = self
             .files
             .iter()
-            .enumerate()
-            .find(|(_, file)| file.name == filename)
+            .position(|file| {
+                file.name == filename && !file.marked_for_deletion() && !file.deleted()
+            })
+            .or_else(|| self.files.iter().position(|file| file.name == filename))
         else {
             return Err(FilesystemDeleteError::FileNotFound);
         };
         let file = &mut self.files[index];
         if !file.marked_for_deletion() {
-            file.mark_for_deletion().unwrap();
+            file.mark_for_deletion()?;
         }
 
         let file = &self.files[index];
@@ -572,6 +1038,122 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         Ok(())
     }
 
+    /// Delete all files and erase the entire storage
+    ///
+    /// Fails without changing anything if a file is still being read or written, as its content would be erased underneath. Weak references to the deleted files can not be upgraded anymore.
+    pub fn format(&mut self) -> Result<(), FilesystemDeleteError> {
+        if self.files.iter().any(|file| !file.can_be_deleted()) {
+            return Err(FilesystemDeleteError::FileInUse);
+        }
+        // Deleting the files first makes sure their weak references know about it
+        for file in &self.files {
+            if !file.deleted() {
+                file.mark_for_deletion()?;
+            }
+        }
+        self.files.clear();
+
+        // Blocks outside of files may still contain the remains of interrupted writes. Blocks that are already erased are skipped to save wear
+        for block in 0..T::BLOCKS {
+            let address = block * T::BLOCK_SIZE;
+            let erased = self
+                .storage
+                .read(address, T::BLOCK_SIZE)
+                .is_ok_and(|content| content.iter().all(|byte| *byte == 0xff));
+            if !erased {
+                self.wear.erase(address, T::BLOCK_SIZE)?;
+            }
+        }
+        self.set_first_block(0)?;
+        Ok(())
+    }
+
+    /// Move files towards the first block to merge the free space between them
+    ///
+    /// After many writes and deletes the free space is scattered in small gaps between the files, so a big file may not fit even if there is enough free space in total. Writing it would evict unimportant files instead. Defragmenting moves files into the gaps before them, so the free space ends up in one range after the last file.
+    ///
+    /// This is never done implicitly, as it rewrites most of the files. Call it when [Filesystem::can_fit] reports that a file needs evictions although [Filesystem::free_space] would suffice.
+    ///
+    /// A file is only moved into a gap that fits it entirely, so the old copy stays intact until the new one is committed. If the power is lost in between, the duplicate is removed on the next mount. Files that are being written, that are marked for deletion or that have strong references are not moved.
+    ///
+    /// Weak references to a moved file can not be upgraded anymore. Get a new one with [Filesystem::read_file].
+    pub fn defragment(&mut self) -> Result<(), FilesystemError> {
+        self.cleanup_files();
+        let first_block = self.get_first_block()? as u32;
+        let relative_block =
+            |address: u32| (address / T::BLOCK_SIZE + T::BLOCKS - first_block) % T::BLOCKS;
+        self.files.sort_by_key(|file| relative_block(file.address));
+
+        // Start of the free space after the previous file, relative to the first block
+        let mut cursor: u32 = 0;
+        for index in 0..self.files.len() {
+            let file = &self.files[index];
+            let start = relative_block(file.address);
+            let length_in_blocks =
+                (file.length + size_of::<FileMetadata>() as u32).div_ceil(T::BLOCK_SIZE);
+            let end = start + length_in_blocks;
+            let movable = file.valid()
+                && !file.marked_for_deletion()
+                && !file.deleted()
+                && file.can_be_deleted();
+            if !movable || start.saturating_sub(cursor) < length_in_blocks {
+                cursor = cursor.max(end);
+                continue;
+            }
+
+            let address = ((first_block + cursor) % T::BLOCKS) * T::BLOCK_SIZE;
+            cursor += length_in_blocks;
+            if !self.move_file(index, address)? {
+                // Someone got a strong reference in the meantime
+                cursor = cursor.max(end);
+            }
+        }
+        self.cleanup_files();
+
+        let new_first_block = self.find_new_first_block();
+        if new_first_block as u32 != first_block {
+            self.set_first_block(new_first_block)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a file to `address` and delete the old copy
+    ///
+    /// Returns false if the old copy could not be deleted right away, because there are strong references to it.
+    fn move_file(&mut self, index: usize, address: u32) -> Result<bool, FilesystemWriteError> {
+        let file = &self.files[index];
+        let Ok(content) = file.read().upgrade() else {
+            return Ok(false);
+        };
+        let (moved_file, writer) = FileInformation::to_storage_with_content(
+            self.storage,
+            &self.wear,
+            address,
+            &file.name,
+            content.hash(),
+            &content,
+        )?;
+        // Copy the metadata before committing, so a committed copy is always complete
+        writer
+            .set_priority(file.priority())
+            .map_err(WriteFileToStorageError::from)?;
+        for _ in file.age()..16 {
+            writer
+                .increase_age()
+                .map_err(WriteFileToStorageError::from)?;
+        }
+        writer.commit()?;
+        drop(content);
+
+        let old_file = std::mem::replace(&mut self.files[index], moved_file);
+        old_file.mark_for_deletion()?;
+        let deleted = old_file.deleted();
+        if !deleted {
+            self.files.push(old_file);
+        }
+        Ok(deleted)
+    }
+
     fn find_new_first_block(&self) -> u16 {
         let good_file = self
             .files
@@ -611,14 +1193,24 @@ impl<T: Storage + 'static + Send + Sync> Filesystem<T> {
         for index in remove_indices.into_iter().rev() {
             self.files.swap_remove(index);
         }
+        // Files from streaming writers only get their final length when they are committed
+        for file in &mut self.files {
+            file.refresh_length();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::simulated::SimulatedStorage;
+    use crate::storage::faulty::FaultyStorage;
+    use crate::storage::recording::{RecordingStorage, StorageOp};
+    use crate::storage::simulated::{
+        Esp32C3SimulatedStorage, SimulatedStorage, SizedSimulatedStorage,
+    };
+    use crate::storage::StorageError;
 
     use super::*;
+    use std::io::Write;
 
     #[test]
     fn writing_and_reading_a_simple_file_works() {
@@ -633,20 +1225,118 @@ mod tests {
     }
 
     #[test]
-    fn can_read_a_file_from_an_old_storage() {
+    fn files_can_be_found_by_name_and_hash() {
         let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
-        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
         let mut filesystem = Filesystem::new(storage);
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let filesystem = Filesystem::new(storage);
-        let result = filesystem.read_file("fancy").unwrap();
-        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[1u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("plain", &[4, 5, 6], &[2u8; 32])
+            .unwrap();
+
+        let by_name = filesystem.find(FileSelector::Name("plain")).unwrap();
+        assert_eq!(by_name.upgrade().unwrap().as_ref(), [4, 5, 6]);
+        let by_hash = filesystem.find(FileSelector::Hash(&[1u8; 32])).unwrap();
+        assert_eq!(by_hash.upgrade().unwrap().as_ref(), [1, 2, 3]);
+
+        assert!(filesystem.find(FileSelector::Name("missing")).is_none());
+        assert!(filesystem.find(FileSelector::Hash(&[3u8; 32])).is_none());
+        filesystem.delete_file("fancy").unwrap();
+        assert!(filesystem.find(FileSelector::Name("fancy")).is_none());
+        assert!(filesystem.find(FileSelector::Hash(&[1u8; 32])).is_none());
     }
 
     #[test]
-    fn can_read_a_file_by_hash() {
+    fn free_space_shrinks_with_every_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let total = SimulatedStorage::BLOCKS * SimulatedStorage::BLOCK_SIZE;
+        assert_eq!(filesystem.free_space(), total);
+
+        filesystem
+            .write_file("small", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        assert_eq!(
+            filesystem.free_space(),
+            total - SimulatedStorage::BLOCK_SIZE
+        );
+
+        let large = vec![0u8; 2 * SimulatedStorage::BLOCK_SIZE as usize];
+        filesystem.write_file("large", &large, &[1u8; 32]).unwrap();
+        assert_eq!(
+            filesystem.free_space(),
+            total - 4 * SimulatedStorage::BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn directory_dump_lists_written_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let small = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let large = vec![0u8; 2 * SimulatedStorage::BLOCK_SIZE as usize];
+        filesystem.write_file("small", &small, &[0u8; 32]).unwrap();
+        filesystem.write_file("large", &large, &[1u8; 32]).unwrap();
+        filesystem
+            .write_file("deleted", &small, &[2u8; 32])
+            .unwrap();
+        filesystem.delete_file("deleted").unwrap();
+        filesystem
+            .read_file("large")
+            .unwrap()
+            .set_important()
+            .unwrap();
+
+        let directory = filesystem.dump_directory();
+        assert_eq!(directory.len(), 2);
+        let small_entry = directory
+            .iter()
+            .find(|entry| entry.name == "small")
+            .unwrap();
+        assert_eq!(small_entry.length, 9);
+        assert_eq!(small_entry.block_count, 1);
+        assert!(!small_entry.important);
+        let large_entry = directory
+            .iter()
+            .find(|entry| entry.name == "large")
+            .unwrap();
+        assert_eq!(large_entry.length, 2 * SimulatedStorage::BLOCK_SIZE);
+        assert_eq!(large_entry.block_count, 3);
+        assert!(large_entry.important);
+        assert_ne!(small_entry.start_block, large_entry.start_block);
+        assert_eq!(small_entry.age, large_entry.age);
+
+        // Dumping does not keep the files alive
+        filesystem.delete_file("small").unwrap();
+        assert!(filesystem.read_file("small").is_none());
+        assert_eq!(filesystem.dump_directory().len(), 1);
+    }
+
+    #[test]
+    fn can_read_a_file_from_an_old_storage() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let file = vec![1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let filesystem = Filesystem::new(storage);
+        let result = filesystem.read_file("fancy").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn can_read_a_file_by_hash() {
         let owned_storage = SimulatedStorage::new();
         let storage =
             unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
@@ -674,6 +1364,121 @@ mod tests {
         assert_eq!(result.upgrade().unwrap().as_ref(), file);
     }
 
+    #[test]
+    fn exists_only_reports_readable_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[1u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("other", &[4, 5, 6], &[2u8; 32])
+            .unwrap();
+
+        assert!(filesystem.exists("fancy"));
+        assert!(filesystem.exists_by_hash(&[1u8; 32]));
+        assert!(!filesystem.exists("missing"));
+        assert!(!filesystem.exists_by_hash(&[3u8; 32]));
+
+        let reader = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        assert!(!filesystem.exists("fancy"));
+        assert!(!filesystem.exists_by_hash(&[1u8; 32]));
+        assert!(filesystem.exists("other"));
+        assert!(filesystem.exists_by_hash(&[2u8; 32]));
+        assert_eq!(reader.as_ref(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn files_can_be_placed_beyond_block_32767() {
+        type LargeStorage = SizedSimulatedStorage<{ 40000 * 128 }, 128>;
+        let owned_storage = LargeStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<&LargeStorage, &'static LargeStorage>(&owned_storage) };
+        let metadata_size = size_of::<FileMetadata>();
+        let content_with_blocks = |blocks: usize| -> Vec<u8> {
+            (0..(blocks * 128 - metadata_size))
+                .map(|index| (index % 251) as u8)
+                .collect()
+        };
+        let mut filesystem = Filesystem::new(storage);
+
+        let big = content_with_blocks(33000);
+        filesystem.write_file("big", &big, &[0u8; 32]).unwrap();
+        filesystem
+            .write_file("small", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let entries = filesystem.dump_directory();
+        let small_entry = entries.iter().find(|entry| entry.name == "small").unwrap();
+        assert_eq!(small_entry.start_block, 33000);
+
+        // The next file only fits if it wraps around the end of the storage
+        filesystem.delete_file("big").unwrap();
+        let huge = content_with_blocks(35000);
+        filesystem.write_file("huge", &huge, &[0u8; 32]).unwrap();
+
+        let filesystem = Filesystem::new(storage);
+        let reader = filesystem.read_file("huge").unwrap().upgrade().unwrap();
+        assert!(reader.as_ref() == huge.as_slice());
+        let reader = filesystem.read_file("small").unwrap().upgrade().unwrap();
+        assert_eq!(reader.as_ref(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn formatting_removes_all_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let file = vec![0u8; 3 * SimulatedStorage::BLOCK_SIZE as usize];
+        for name in ["first", "second", "third"] {
+            filesystem.write_file(name, &file, &[0u8; 32]).unwrap();
+        }
+        filesystem.delete_file("first").unwrap();
+        let weak = filesystem.read_file("second").unwrap();
+
+        filesystem.format().unwrap();
+        assert!(filesystem.dump_directory().is_empty());
+        assert!(weak.upgrade().is_err());
+        assert_eq!(filesystem.get_first_block().unwrap(), 0);
+
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.dump_directory().is_empty());
+        for block in 0..SimulatedStorage::BLOCKS {
+            let content = storage
+                .read(
+                    block * SimulatedStorage::BLOCK_SIZE,
+                    SimulatedStorage::BLOCK_SIZE,
+                )
+                .unwrap();
+            assert!(content.iter().all(|byte| *byte == 0xff));
+        }
+    }
+
+    #[test]
+    fn formatting_fails_while_a_file_is_read() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let reader = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+
+        let Err(FilesystemDeleteError::FileInUse) = filesystem.format() else {
+            panic!("Should not be able to format while a file is read");
+        };
+        assert_eq!(reader.as_ref(), [1, 2, 3]);
+        drop(reader);
+        filesystem.format().unwrap();
+    }
+
     #[test]
     fn unimportant_files_get_deleted() {
         let owned_storage = SimulatedStorage::new();
@@ -706,6 +1511,67 @@ mod tests {
             .unwrap_err();
     }
 
+    #[test]
+    fn only_files_written_as_important_survive_filling_the_storage() {
+        // A bit bigger than half the storage size
+        let file = vec![0u8; SimulatedStorage::SIZE as usize / 2 + 1 - size_of::<FileMetadata>()];
+
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem.write_file("normal", &file, &[0u8; 32]).unwrap();
+        filesystem.write_file("filler", &file, &[1u8; 32]).unwrap();
+        assert!(filesystem.read_file("normal").is_none());
+
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_important_file("important", &file, &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("filler", &file, &[1u8; 32])
+            .unwrap_err();
+        let result = filesystem.read_file("important").unwrap();
+        assert!(result.important());
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn files_are_evicted_in_order_of_priority() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        // Three of these fit into the storage
+        let file = vec![0u8; 5 * SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
+        for (name, priority) in [("low", 0), ("high", 2), ("medium", 1)] {
+            filesystem.write_file(name, &file, &[0u8; 32]).unwrap();
+            let result = filesystem.read_file(name).unwrap();
+            result.set_priority(priority).unwrap();
+            assert_eq!(result.priority(), priority);
+        }
+
+        for (index, evicted) in ["low", "medium", "high"].into_iter().enumerate() {
+            let name = format!("new{}", index);
+            filesystem.write_file(&name, &file, &[0u8; 32]).unwrap();
+            filesystem
+                .read_file(&name)
+                .unwrap()
+                .set_important()
+                .unwrap();
+            assert!(filesystem.read_file(evicted).is_none());
+        }
+        filesystem
+            .write_file("new3", &file, &[0u8; 32])
+            .unwrap_err();
+    }
+
     #[test]
     fn open_reader_protects_files_from_being_deleted() {
         let owned_storage = SimulatedStorage::new();
@@ -777,6 +1643,29 @@ mod tests {
         };
     }
 
+    #[test]
+    fn deleting_a_replaced_file_deletes_the_new_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let old_file = filesystem.read_file("fancy").unwrap();
+        let strong_ref = old_file.upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        filesystem
+            .write_file("fancy", &[4, 5, 6], &[0u8; 32])
+            .unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        let None = filesystem.read_file("fancy") else {
+            panic!("The new file should have been deleted");
+        };
+        assert_eq!(strong_ref.as_ref(), &[1, 2, 3]);
+    }
+
     #[test]
     fn no_new_references_can_be_created_to_a_file_marked_for_deletion() {
         let owned_storage = SimulatedStorage::new();
@@ -825,6 +1714,43 @@ mod tests {
         assert_eq!(result.upgrade().unwrap().as_ref(), file);
     }
 
+    #[test]
+    fn writing_chunks_in_reverse_order_works() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+
+        let file: Vec<u8> = (0..100).collect();
+        let mut writer = filesystem
+            .get_file_writer("chunked", file.len() as u32, &[0u8; 32])
+            .unwrap();
+        for (index, chunk) in file.chunks(16).enumerate().rev() {
+            writer.write_at(index as u32 * 16, chunk).unwrap();
+        }
+        writer.commit().unwrap();
+
+        let result = filesystem.read_file("chunked").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), file);
+    }
+
+    #[test]
+    fn writing_past_the_end_of_a_file_fails() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+
+        let mut writer = filesystem
+            .get_file_writer("chunked", 20, &[0u8; 32])
+            .unwrap();
+        writer.write_at(16, &[1, 2, 3, 4]).unwrap();
+        assert!(writer.write_at(16, &[1, 2, 3, 4, 5]).is_err());
+        assert!(writer.write_at(u32::MAX, &[1]).is_err());
+    }
+
     #[test]
     fn can_write_a_big_file() {
         let owned_storage = SimulatedStorage::new();
@@ -862,68 +1788,1100 @@ mod tests {
     }
 
     #[test]
-    fn deleting_a_file_does_not_make_space_for_a_new_file_if_there_are_still_strong_references_to_its_content(
-    ) {
+    fn erases_are_counted_and_spread_over_the_blocks() {
         let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
-        let fancy_file = filesystem.read_file("fancy").unwrap();
-        let strong_ref = fancy_file.upgrade().unwrap();
-        filesystem.delete_file("fancy").unwrap();
-        let Err(_) = filesystem.write_file("fancy2", &file, &[0u8; 32]) else {
-            panic!("Should fail because the file was not yet deleted");
+        assert_eq!(
+            filesystem.wear_stats(),
+            vec![0; SimulatedStorage::BLOCKS as usize]
+        );
+
+        // Keep all but one block occupied and always replace the oldest file
+        let kept_files = SimulatedStorage::BLOCKS - 1;
+        let rounds = 4;
+        for index in 0..kept_files {
+            filesystem
+                .write_file(&format!("file_{}", index), &[1, 2, 3], &[0u8; 32])
+                .unwrap();
+        }
+        for index in kept_files..kept_files * (rounds + 1) {
+            filesystem
+                .delete_file(&format!("file_{}", index - kept_files))
+                .unwrap();
+            filesystem
+                .write_file(&format!("file_{}", index), &[1, 2, 3], &[0u8; 32])
+                .unwrap();
+        }
+
+        let stats = filesystem.wear_stats();
+        assert_eq!(stats.iter().sum::<u32>(), kept_files * rounds);
+        assert!(stats.iter().filter(|count| **count > 0).count() >= kept_files as usize);
+        assert!(*stats.iter().max().unwrap() <= rounds + 1);
+
+        drop(filesystem);
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(filesystem.wear_stats(), stats);
+    }
+
+    #[test]
+    fn erase_counters_are_written_in_batches() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
         };
-        assert_eq!(strong_ref.as_ref(), file);
-        drop(strong_ref);
-        // Should work now, because we dropped the last strong reference
-        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+        let mut filesystem = Filesystem::new(storage);
+        for index in 0..3 {
+            let name = format!("file_{}", index);
+            filesystem
+                .write_file(&name, &[1, 2, 3], &[0u8; 32])
+                .unwrap();
+            filesystem.delete_file(&name).unwrap();
+        }
+        assert!(storage.read_metadata("wear_0").is_err());
+        assert_eq!(filesystem.wear_stats().iter().sum::<u32>(), 3);
+
+        filesystem.persist_wear_stats();
+        assert!(storage.read_metadata("wear_0").is_ok());
+        assert_eq!(filesystem.wear_stats().iter().sum::<u32>(), 3);
+
+        // Enough erases to fill a batch are written without persisting
+        for index in 0..wear::PERSIST_AFTER {
+            let name = format!("other_{}", index);
+            filesystem
+                .write_file(&name, &[1, 2, 3], &[0u8; 32])
+                .unwrap();
+            filesystem.delete_file(&name).unwrap();
+        }
+        let stats = filesystem.wear_stats();
+        std::mem::forget(filesystem);
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(
+            filesystem.wear_stats().iter().sum::<u32>(),
+            3 + wear::PERSIST_AFTER
+        );
+        assert_eq!(filesystem.wear_stats(), stats);
     }
 
     #[test]
-    fn writing_a_file_thats_too_big_fails() {
+    fn writing_a_file_batches_the_metadata_and_content_and_marks_it_ready_last() {
         let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_recording_storage = RecordingStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &RecordingStorage<SimulatedStorage>,
+                &'static RecordingStorage<SimulatedStorage>,
+            >(&owned_recording_storage)
+        };
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize + 1];
-        let Err(_) = filesystem.write_file("fancy", &file, &[0u8; 32]) else {
-            panic!("Should fail when there is not enough space");
+        storage.clear();
+
+        let content = vec![7u8; 2 * SimulatedStorage::BLOCK_SIZE as usize];
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
+
+        let operations: Vec<StorageOp> = storage
+            .operations()
+            .into_iter()
+            .filter(|operation| !matches!(operation, StorageOp::WriteMetadata { .. }))
+            .collect();
+        let [StorageOp::WriteBatch { writes }, ready, StorageOp::Flush] = operations.as_slice()
+        else {
+            panic!(
+                "Expected one batch, the ready flag and a flush, got {:?}",
+                operations
+            );
+        };
+
+        // The batch has the metadata followed by the content
+        let [(file_address, metadata_length), (content_address, content_length)] = writes[..]
+        else {
+            panic!("Expected the metadata and the content, got {:?}", writes);
         };
+        assert_eq!(metadata_length, size_of::<FileMetadata>() as u32);
+        assert_eq!(content_address, file_address + metadata_length);
+        assert_eq!(content_length, content.len() as u32);
+
+        // Then the ready flag in the metadata is set and flushed
+        assert!(matches!(
+            ready,
+            StorageOp::Write { address, .. } if *address == file_address
+        ));
     }
 
     #[test]
-    fn deleting_the_first_file_moves_the_marker() {
+    fn an_invalid_batch_writes_nothing() {
+        let storage = SimulatedStorage::new();
+        let result = storage.write_batch(&[(0, &[0u8; 4]), (SimulatedStorage::SIZE, &[0u8; 4])]);
+        assert!(matches!(result, Err(StorageError::AddressTooBig)));
+        assert_eq!(storage.read(0, 4).unwrap(), &[0xff; 4]);
+    }
+
+    #[test]
+    fn a_power_loss_during_a_batch_keeps_the_earlier_writes() {
         let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
-        let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::SIZE as usize
-            - size_of::<FileMetadata>()
-            - SimulatedStorage::BLOCK_SIZE as usize * 2];
-        filesystem.write_file("first", &file, &[0u8; 32]).unwrap();
-        assert_eq!(filesystem.get_first_block().unwrap(), 0);
-        let file2 = [0u8; SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("second", &file2, &[0u8; 32]).unwrap();
-        assert_eq!(filesystem.get_first_block().unwrap(), 0);
-        filesystem.delete_file("first").unwrap();
-        assert_ne!(filesystem.get_first_block().unwrap(), 0);
-        // drop(filesystem);
-        // let mut filesystem = Filesystem::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let faulty_storage = FaultyStorage::new(storage);
+        faulty_storage.fail_after_writes(1);
+
+        let result = faulty_storage.write_batch(&[(0, &[1u8; 4]), (4, &[2u8; 4]), (8, &[3u8; 4])]);
+        assert!(result.is_err());
+        assert!(faulty_storage.powered_off());
+        assert_eq!(storage.read(0, 4).unwrap(), &[1; 4]);
+        assert_eq!(storage.read(4, 8).unwrap(), &[0xff; 8]);
     }
 
     #[test]
-    fn can_not_create_two_files_with_the_same_name() {
+    fn a_power_loss_while_truncating_leaves_the_file_untruncated() {
         let owned_storage = SimulatedStorage::new();
-        let storage =
-            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_faulty_storage = FaultyStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &FaultyStorage<SimulatedStorage>,
+                &'static FaultyStorage<SimulatedStorage>,
+            >(&owned_faulty_storage)
+        };
         let mut filesystem = Filesystem::new(storage);
-        let file = [0u8; SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
-        filesystem.write_file("cool", &file, &[0u8; 32]).unwrap();
-        filesystem
-            .write_file("cool", &file, &[0u8; 32])
-            .unwrap_err();
+        let mut writer = filesystem
+            .get_file_writer("fancy", 1000, &[0u8; 32])
+            .unwrap();
+        writer.write_all(&[5u8; 10]).unwrap();
+
+        // Lose power after writing the truncated length but before setting the flag
+        storage.fail_after_writes(1);
+        assert!(writer.truncate(10).is_err());
+        // The metadata is memory mapped, so this is what is in storage
+        assert_eq!(writer.metadata().length, 1000);
+        drop(writer);
+
+        storage.reboot();
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("fancy").is_none());
+    }
+
+    #[test]
+    fn an_empty_filesystem_has_room_for_a_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(filesystem.can_fit(1000), Fit::Free);
+        assert_eq!(
+            filesystem.can_fit(SimulatedStorage::BLOCKS * SimulatedStorage::BLOCK_SIZE),
+            Fit::Impossible
+        );
+    }
+
+    #[test]
+    fn checking_the_fit_reports_the_victims_without_deleting_them() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for index in 0..SimulatedStorage::BLOCKS - 1 {
+            filesystem
+                .write_file(&format!("file_{}", index), &[1, 2, 3], &[0u8; 32])
+                .unwrap();
+        }
+
+        // Needs two blocks, but only one is free
+        let length = SimulatedStorage::BLOCK_SIZE;
+        let Fit::NeedsEviction { victims } = filesystem.can_fit(length) else {
+            panic!("Expected that a file needs to be evicted");
+        };
+        assert_eq!(victims.len(), 1);
+        assert!(filesystem.read_file(&victims[0]).is_some());
+        assert_eq!(
+            filesystem.can_fit(length),
+            Fit::NeedsEviction {
+                victims: victims.clone()
+            }
+        );
+
+        filesystem
+            .write_file("big", &vec![7u8; length as usize], &[1u8; 32])
+            .unwrap();
+        assert!(filesystem.read_file(&victims[0]).is_none());
+    }
+
+    #[test]
+    fn important_files_are_never_victims() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for index in 0..SimulatedStorage::BLOCKS - 1 {
+            filesystem
+                .write_important_file(&format!("file_{}", index), &[1, 2, 3], &[0u8; 32])
+                .unwrap();
+        }
+        assert_eq!(filesystem.can_fit(100), Fit::Free);
+        assert_eq!(
+            filesystem.can_fit(SimulatedStorage::BLOCK_SIZE),
+            Fit::Impossible
+        );
+    }
+
+    #[test]
+    fn a_fresh_filesystem_passes_the_integrity_check() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("other", &vec![7u8; 5000], &[1u8; 32])
+            .unwrap();
+
+        let report = filesystem.integrity_check();
+        assert_eq!(report.valid_files, 2);
+        assert_eq!(report.first_block, Some(0));
+        assert!(report.is_clean());
+    }
+
+    #[test]
+    fn the_integrity_check_reports_stray_blocks_without_erasing_them() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let stray_block = filesystem.dump_directory()[0].start_block + 5;
+        storage
+            .write(
+                stray_block as u32 * SimulatedStorage::BLOCK_SIZE + 100,
+                &[0u8; 4],
+            )
+            .unwrap();
+
+        let report = filesystem.integrity_check();
+        assert_eq!(report.stray_blocks, vec![stray_block]);
+        assert!(!report.is_clean());
+        assert_eq!(filesystem.integrity_check(), report);
+    }
+
+    #[test]
+    fn the_integrity_check_reports_files_marked_for_deletion() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let reader = filesystem.read_file("fancy").unwrap().upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+
+        let report = filesystem.integrity_check();
+        assert_eq!(report.valid_files, 0);
+        assert_eq!(report.marked_for_deletion, 1);
+        assert!(report.is_clean());
+        drop(reader);
+    }
+
+    #[test]
+    fn the_integrity_check_reports_duplicate_names() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+
+        // Copy the file to another block, like a crash between writing a new version and deleting the old one
+        let original = filesystem.dump_directory()[0].start_block as u32;
+        let copy = (original + 5) % SimulatedStorage::BLOCKS;
+        let block = storage
+            .read(
+                original * SimulatedStorage::BLOCK_SIZE,
+                SimulatedStorage::BLOCK_SIZE,
+            )
+            .unwrap()
+            .to_vec();
+        storage
+            .write(copy * SimulatedStorage::BLOCK_SIZE, &block)
+            .unwrap();
+
+        let report = filesystem.integrity_check();
+        assert_eq!(report.valid_files, 2);
+        assert_eq!(report.duplicate_names, vec!["fancy".to_string()]);
+        assert!(!report.is_clean());
+    }
+
+    #[test]
+    fn the_integrity_check_reports_a_first_block_without_a_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let first_block = filesystem.dump_directory()[0].start_block + 5;
+        storage
+            .write_metadata("first_block", &first_block.to_le_bytes())
+            .unwrap();
+
+        let report = filesystem.integrity_check();
+        assert_eq!(report.first_block, Some(first_block));
+        assert!(!report.first_block_valid);
+        assert_eq!(report.valid_files, 1);
+    }
+
+    #[test]
+    fn file_metadata_can_be_read_at_once() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("fancy", &[1, 2, 3, 4, 5], &[7u8; 32])
+            .unwrap();
+        let file = filesystem.read_file("fancy").unwrap();
+        file.set_important().unwrap();
+
+        let metadata = file.upgrade().unwrap().metadata();
+        assert_eq!(metadata.name, "fancy");
+        assert_eq!(metadata.length, 5);
+        assert_eq!(metadata.hash, *file.upgrade().unwrap().hash());
+        assert_eq!(metadata.age, file.age());
+        assert!(metadata.important);
+        assert_eq!(metadata, file.metadata());
+    }
+
+    #[test]
+    fn a_storage_full_of_important_files_rejects_new_files() {
+        let owned_storage = Esp32C3SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&Esp32C3SimulatedStorage, &'static Esp32C3SimulatedStorage>(
+                &owned_storage,
+            )
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for i in 0..Esp32C3SimulatedStorage::MAX_FILES {
+            filesystem
+                .write_important_file(&format!("file_{}", i), &[0; 32], &[0u8; 32])
+                .unwrap();
+        }
+
+        assert_eq!(filesystem.can_fit(32), Fit::Impossible);
+        let result = filesystem.write_file("one_too_many", &[0; 32], &[0u8; 32]);
+        assert!(matches!(
+            result,
+            Err(FilesystemWriteError::FindFreeSpaceError(
+                FindFreeSpaceError::NotEnoughSpace
+            ))
+        ));
+
+        filesystem.delete_file("file_0").unwrap();
+        filesystem
+            .write_file("one_too_many", &[0; 32], &[0u8; 32])
+            .unwrap();
+    }
+
+    #[test]
+    fn a_file_takes_the_place_of_an_evicted_one_when_every_block_holds_a_file() {
+        let owned_storage = Esp32C3SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&Esp32C3SimulatedStorage, &'static Esp32C3SimulatedStorage>(
+                &owned_storage,
+            )
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for i in 0..Esp32C3SimulatedStorage::MAX_FILES {
+            filesystem
+                .write_file(&format!("file_{}", i), &[0; 32], &[0u8; 32])
+                .unwrap();
+        }
+        filesystem
+            .read_file("file_1")
+            .unwrap()
+            .set_important()
+            .unwrap();
+
+        assert!(matches!(
+            filesystem.can_fit(32),
+            Fit::NeedsEviction { victims } if !victims.contains(&"file_1".to_string())
+        ));
+        filesystem
+            .write_file("one_more", &[0; 32], &[0u8; 32])
+            .unwrap();
+        assert!(filesystem.exists("one_more"));
+        assert!(filesystem.exists("file_1"));
+        assert_eq!(
+            filesystem.dump_directory().len(),
+            Esp32C3SimulatedStorage::MAX_FILES as usize
+        );
+    }
+
+    #[test]
+    fn every_file_of_a_full_storage_is_found_after_a_remount() {
+        let owned_storage = Esp32C3SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&Esp32C3SimulatedStorage, &'static Esp32C3SimulatedStorage>(
+                &owned_storage,
+            )
+        };
+        let mut filesystem = Filesystem::new(storage);
+        for i in 0..Esp32C3SimulatedStorage::BLOCKS {
+            filesystem
+                .write_important_file(&format!("file_{}", i), &[0; 32], &[0u8; 32])
+                .unwrap();
+        }
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        for i in 0..Esp32C3SimulatedStorage::BLOCKS {
+            assert!(filesystem.exists(&format!("file_{}", i)));
+        }
+    }
+
+    #[test]
+    fn files_wrap_around_the_end_of_a_production_sized_storage() {
+        let owned_storage = Esp32C3SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&Esp32C3SimulatedStorage, &'static Esp32C3SimulatedStorage>(
+                &owned_storage,
+            )
+        };
+        let mut filesystem = Filesystem::new(storage);
+
+        // Only two of these files fit, so the later ones have to wrap around and evict the older ones
+        let size = Esp32C3SimulatedStorage::SIZE as usize / 3 - size_of::<FileMetadata>();
+        for i in 0..10u8 {
+            let file = vec![i; size];
+            filesystem
+                .write_file(&format!("file_{}", i), &file, &[i; 32])
+                .unwrap();
+            let result = filesystem.read_file(&format!("file_{}", i)).unwrap();
+            assert_eq!(result.upgrade().unwrap().as_ref(), file);
+        }
+        assert!(filesystem.read_file("file_0").is_none());
+    }
+
+    #[test]
+    fn deleting_a_file_does_not_make_space_for_a_new_file_if_there_are_still_strong_references_to_its_content(
+    ) {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize - size_of::<FileMetadata>()];
+        filesystem.write_file("fancy", &file, &[0u8; 32]).unwrap();
+        let fancy_file = filesystem.read_file("fancy").unwrap();
+        let strong_ref = fancy_file.upgrade().unwrap();
+        filesystem.delete_file("fancy").unwrap();
+        let Err(_) = filesystem.write_file("fancy2", &file, &[0u8; 32]) else {
+            panic!("Should fail because the file was not yet deleted");
+        };
+        assert_eq!(strong_ref.as_ref(), file);
+        drop(strong_ref);
+        // Should work now, because we dropped the last strong reference
+        filesystem.write_file("fancy2", &file, &[0u8; 32]).unwrap();
+    }
+
+    #[test]
+    fn writing_a_file_thats_too_big_fails() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize + 1];
+        let Err(_) = filesystem.write_file("fancy", &file, &[0u8; 32]) else {
+            panic!("Should fail when there is not enough space");
+        };
+    }
+
+    #[test]
+    fn deleting_the_first_file_moves_the_marker() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::SIZE as usize
+            - size_of::<FileMetadata>()
+            - SimulatedStorage::BLOCK_SIZE as usize * 2];
+        filesystem.write_file("first", &file, &[0u8; 32]).unwrap();
+        assert_eq!(filesystem.get_first_block().unwrap(), 0);
+        let file2 = [0u8; SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
+        filesystem.write_file("second", &file2, &[0u8; 32]).unwrap();
+        assert_eq!(filesystem.get_first_block().unwrap(), 0);
+        filesystem.delete_file("first").unwrap();
+        assert_ne!(filesystem.get_first_block().unwrap(), 0);
+        // drop(filesystem);
+        // let mut filesystem = Filesystem::new(storage);
+    }
+
+    #[test]
+    fn only_the_newest_file_with_a_duplicate_name_survives_a_remount() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("cool", &[1, 2, 3], &[1u8; 32])
+            .unwrap();
+        // Hide the old file from the name check, like a crash before it was deleted
+        filesystem.files[0].name = "hidden".into();
+        filesystem
+            .write_file("cool", &[4, 5, 6], &[4u8; 32])
+            .unwrap();
+        drop(filesystem);
+
+        let filesystem = Filesystem::new(storage);
+        let copies = filesystem
+            .files
+            .iter()
+            .filter(|file| file.name == "cool" && !file.marked_for_deletion())
+            .count();
+        assert_eq!(copies, 1);
+        let result = filesystem.read_file("cool").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), [4, 5, 6]);
+        assert!(filesystem.read_file_by_hash(&[1u8; 32]).is_none());
+
+        let filesystem = Filesystem::new(storage);
+        let result = filesystem.read_file("cool").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), [4, 5, 6]);
+    }
+
+    #[test]
+    fn erases_of_files_that_outlive_the_filesystem_are_counted() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("program", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+        let file = filesystem.read_file("program").unwrap().upgrade().unwrap();
+        filesystem.delete_file("program").unwrap();
+        drop(filesystem);
+
+        // The file is only erased once its last reader is dropped
+        drop(file);
+        let filesystem = Filesystem::new(storage);
+        assert_eq!(filesystem.wear_stats().iter().sum::<u32>(), 1);
+    }
+
+    #[test]
+    fn a_write_interrupted_by_a_power_loss_leaves_no_partial_file() {
+        let content = vec![7u8; 3 * SimulatedStorage::BLOCK_SIZE as usize];
+        // Writing the metadata, the content and the ready flag
+        for writes in 0..4 {
+            let owned_storage = SimulatedStorage::new();
+            let storage = unsafe {
+                std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+            };
+            let owned_faulty_storage = FaultyStorage::new(storage);
+            let storage = unsafe {
+                std::mem::transmute::<
+                    &FaultyStorage<SimulatedStorage>,
+                    &'static FaultyStorage<SimulatedStorage>,
+                >(&owned_faulty_storage)
+            };
+            let mut filesystem = Filesystem::new(storage);
+            filesystem
+                .write_file("old", &[1, 2, 3], &[1u8; 32])
+                .unwrap();
+
+            storage.fail_after_writes(writes);
+            let result = filesystem.write_file("new", &content, &[2u8; 32]);
+            assert_eq!(result.is_err(), storage.powered_off());
+            drop(filesystem);
+
+            storage.reboot();
+            let filesystem = Filesystem::new(storage);
+            let old = filesystem.read_file("old").unwrap();
+            assert_eq!(old.upgrade().unwrap().as_ref(), [1, 2, 3]);
+            match filesystem.read_file("new") {
+                Some(new) => assert_eq!(new.upgrade().unwrap().as_ref(), content),
+                None => assert!(result.is_err()),
+            }
+        }
+    }
+
+    #[test]
+    fn a_corrupted_length_does_not_hide_the_following_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("first", &[1, 2, 3], &[1u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("second", &[4, 5, 6], &[4u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("third", &[7, 8, 9], &[7u8; 32])
+            .unwrap();
+        drop(filesystem);
+
+        // Corrupt the length of the first file, so it seems to span over the others
+        let length = storage.read(4, 4).unwrap();
+        let length = unsafe { std::slice::from_raw_parts_mut(length.as_ptr() as *mut u8, 4) };
+        length.copy_from_slice(&(8 * SimulatedStorage::BLOCK_SIZE).to_le_bytes());
+
+        let filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("first").is_none());
+        let result = filesystem.read_file("second").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), [4, 5, 6]);
+        let result = filesystem.read_file("third").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn committing_a_file_flushes_the_storage() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_faulty_storage = FaultyStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &FaultyStorage<SimulatedStorage>,
+                &'static FaultyStorage<SimulatedStorage>,
+            >(&owned_faulty_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let mut writer = filesystem.get_file_writer("fancy", 3, &[0u8; 32]).unwrap();
+        writer.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(storage.flushes(), 0);
+        writer.commit().unwrap();
+        assert_eq!(storage.flushes(), 1);
+    }
+
+    #[test]
+    fn a_delete_interrupted_by_a_power_loss_does_not_resurrect_the_file() {
+        let failures: [fn(&FaultyStorage<SimulatedStorage>); 2] = [
+            // While setting the deleted flag
+            |storage| storage.fail_after_writes(1),
+            // While erasing the content
+            |storage| storage.fail_after_erases(0),
+        ];
+        for fail in failures {
+            let owned_storage = SimulatedStorage::new();
+            let storage = unsafe {
+                std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+            };
+            let owned_faulty_storage = FaultyStorage::new(storage);
+            let storage = unsafe {
+                std::mem::transmute::<
+                    &FaultyStorage<SimulatedStorage>,
+                    &'static FaultyStorage<SimulatedStorage>,
+                >(&owned_faulty_storage)
+            };
+            let mut filesystem = Filesystem::new(storage);
+            filesystem
+                .write_file("old", &[1, 2, 3], &[1u8; 32])
+                .unwrap();
+
+            fail(storage);
+            filesystem.delete_file("old").unwrap_err();
+            assert!(storage.powered_off());
+            drop(filesystem);
+
+            storage.reboot();
+            let mut filesystem = Filesystem::new(storage);
+            assert!(filesystem.read_file("old").is_none());
+            filesystem
+                .write_file("old", &[4, 5, 6], &[4u8; 32])
+                .unwrap();
+            let old = filesystem.read_file("old").unwrap();
+            assert_eq!(old.upgrade().unwrap().as_ref(), [4, 5, 6]);
+        }
+    }
+
+    #[test]
+    fn deleting_a_file_erases_the_metadata_block_last() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_recording_storage = RecordingStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &RecordingStorage<SimulatedStorage>,
+                &'static RecordingStorage<SimulatedStorage>,
+            >(&owned_recording_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        storage.clear();
+
+        // The metadata pushes the file into a third block
+        let content = vec![7u8; 2 * SimulatedStorage::BLOCK_SIZE as usize];
+        filesystem
+            .write_file("fancy", &content, &[0u8; 32])
+            .unwrap();
+        let Some(StorageOp::Write {
+            address: file_address,
+            ..
+        }) = storage
+            .operations()
+            .into_iter()
+            .find(|operation| matches!(operation, StorageOp::Write { .. }))
+        else {
+            panic!("Expected the metadata to be written first");
+        };
+
+        storage.clear();
+        filesystem.delete_file("fancy").unwrap();
+        let erases: Vec<StorageOp> = storage
+            .operations()
+            .into_iter()
+            .filter(|operation| matches!(operation, StorageOp::Erase { .. }))
+            .collect();
+        assert_eq!(
+            erases,
+            vec![
+                StorageOp::Erase {
+                    address: file_address + SimulatedStorage::BLOCK_SIZE,
+                    length: 2 * SimulatedStorage::BLOCK_SIZE,
+                },
+                StorageOp::Erase {
+                    address: file_address,
+                    length: SimulatedStorage::BLOCK_SIZE,
+                },
+            ]
+        );
+        assert!(filesystem.read_file("fancy").is_none());
+    }
+
+    #[test]
+    fn a_power_loss_between_the_erases_of_a_delete_keeps_the_metadata() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let owned_faulty_storage = FaultyStorage::new(storage);
+        let storage = unsafe {
+            std::mem::transmute::<
+                &FaultyStorage<SimulatedStorage>,
+                &'static FaultyStorage<SimulatedStorage>,
+            >(&owned_faulty_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let content = vec![7u8; 2 * SimulatedStorage::BLOCK_SIZE as usize];
+        filesystem.write_file("old", &content, &[1u8; 32]).unwrap();
+
+        // The content is erased, but the power is lost before the metadata block is erased
+        storage.fail_after_erases(1);
+        filesystem.delete_file("old").unwrap_err();
+        assert!(storage.powered_off());
+        drop(filesystem);
+
+        let written_blocks = (0..SimulatedStorage::BLOCKS)
+            .filter(|block| {
+                storage
+                    .read(
+                        block * SimulatedStorage::BLOCK_SIZE,
+                        SimulatedStorage::BLOCK_SIZE,
+                    )
+                    .unwrap()
+                    .iter()
+                    .any(|byte| *byte != 0xff)
+            })
+            .count();
+        assert_eq!(written_blocks, 1);
+
+        storage.reboot();
+        let mut filesystem = Filesystem::new(storage);
+        assert!(filesystem.read_file("old").is_none());
+        filesystem
+            .write_file("old", &[4, 5, 6], &[4u8; 32])
+            .unwrap();
+        let old = filesystem.read_file("old").unwrap();
+        assert_eq!(old.upgrade().unwrap().as_ref(), [4, 5, 6]);
+    }
+
+    #[test]
+    fn defragmenting_makes_room_for_a_file_that_did_not_fit() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let block_content_length =
+            SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>();
+        for index in 0..12u8 {
+            let name = format!("file{}", index);
+            filesystem
+                .write_file(&name, &vec![index; block_content_length], &[index; 32])
+                .unwrap();
+            filesystem
+                .read_file(&name)
+                .unwrap()
+                .set_important()
+                .unwrap();
+        }
+        for index in (1..12).step_by(2) {
+            filesystem.delete_file(&format!("file{}", index)).unwrap();
+        }
+
+        // 10 blocks are free, but at most 5 of them in a row
+        let big_length = 8 * SimulatedStorage::BLOCK_SIZE;
+        let big_file = vec![42u8; big_length as usize - size_of::<FileMetadata>()];
+        assert_eq!(filesystem.find_unused_space(big_length).unwrap(), None);
+        assert_eq!(filesystem.can_fit(big_file.len() as u32), Fit::Impossible);
+        assert!(filesystem
+            .write_file("big", &big_file, &[42u8; 32])
+            .is_err());
+
+        filesystem.defragment().unwrap();
+        assert!(filesystem.find_unused_space(big_length).unwrap().is_some());
+        filesystem
+            .write_file("big", &big_file, &[42u8; 32])
+            .unwrap();
+
+        let filesystem = Filesystem::new(storage);
+        for index in (0..12u8).step_by(2) {
+            let file = filesystem.read_file(&format!("file{}", index)).unwrap();
+            assert!(file.important());
+            assert_eq!(
+                file.upgrade().unwrap().as_ref(),
+                vec![index; block_content_length]
+            );
+        }
+        let result = filesystem.read_file("big").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), big_file);
+    }
+
+    #[test]
+    fn defragmenting_avoids_evicting_files() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let block_content_length =
+            SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>();
+        for index in 0..12u8 {
+            filesystem
+                .write_file(
+                    &format!("file{}", index),
+                    &vec![index; block_content_length],
+                    &[index; 32],
+                )
+                .unwrap();
+        }
+        for index in (1..12).step_by(2) {
+            filesystem.delete_file(&format!("file{}", index)).unwrap();
+        }
+
+        // Fits without evictions only after the gaps between the unimportant files are merged
+        let big_file =
+            vec![42u8; 8 * SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
+        assert!(matches!(
+            filesystem.can_fit(big_file.len() as u32),
+            Fit::NeedsEviction { .. }
+        ));
+        filesystem.defragment().unwrap();
+        assert_eq!(filesystem.can_fit(big_file.len() as u32), Fit::Free);
+        filesystem
+            .write_file("big", &big_file, &[42u8; 32])
+            .unwrap();
+
+        let filesystem = Filesystem::new(storage);
+        for index in (0..12u8).step_by(2) {
+            let file = filesystem.read_file(&format!("file{}", index)).unwrap();
+            assert_eq!(
+                file.upgrade().unwrap().as_ref(),
+                vec![index; block_content_length]
+            );
+        }
+        let result = filesystem.read_file("big").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), big_file);
+    }
+
+    #[test]
+    fn defragmenting_does_not_move_files_with_strong_references() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("first", &[1, 2, 3], &[1u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("second", &[4, 5, 6], &[4u8; 32])
+            .unwrap();
+        filesystem
+            .write_file("third", &[7, 8, 9], &[7u8; 32])
+            .unwrap();
+        let address = filesystem
+            .files
+            .iter()
+            .find(|file| file.name == "third")
+            .unwrap()
+            .address;
+        filesystem.delete_file("second").unwrap();
+        let strong_ref = filesystem.read_file("third").unwrap().upgrade().unwrap();
+
+        filesystem.defragment().unwrap();
+        let third = filesystem
+            .files
+            .iter()
+            .find(|file| file.name == "third")
+            .unwrap();
+        assert_eq!(third.address, address);
+        assert_eq!(strong_ref.as_ref(), [7, 8, 9]);
+
+        drop(strong_ref);
+        filesystem.defragment().unwrap();
+        let third = filesystem
+            .files
+            .iter()
+            .find(|file| file.name == "third")
+            .unwrap();
+        assert!(third.address < address);
+        let result = filesystem.read_file("third").unwrap();
+        assert_eq!(result.upgrade().unwrap().as_ref(), [7, 8, 9]);
+    }
+
+    #[test]
+    fn can_not_create_two_files_with_the_same_name() {
+        let owned_storage = SimulatedStorage::new();
+        let storage =
+            unsafe { std::mem::transmute::<_, &'static SimulatedStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let file = [0u8; SimulatedStorage::BLOCK_SIZE as usize - size_of::<FileMetadata>()];
+        filesystem.write_file("cool", &file, &[0u8; 32]).unwrap();
+        filesystem
+            .write_file("cool", &file, &[0u8; 32])
+            .unwrap_err();
+    }
+
+    #[test]
+    fn a_streaming_writer_releases_the_unused_space_on_commit() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let mut writer = filesystem
+            .get_streaming_writer("stream", &[1u8; 32])
+            .unwrap();
+        writer.write_all(&[1, 2, 3]).unwrap();
+        writer.write_all(&[4, 5]).unwrap();
+        assert_eq!(writer.written(), 5);
+        let file = writer.commit().unwrap();
+        assert_eq!(file.as_ref(), [1, 2, 3, 4, 5]);
+        drop(file);
+
+        // Everything but the first block is free again
+        let rest = vec![
+            7u8;
+            (SimulatedStorage::BLOCKS - 1) as usize
+                * SimulatedStorage::BLOCK_SIZE as usize
+                - size_of::<FileMetadata>()
+        ];
+        filesystem.write_file("rest", &rest, &[7u8; 32]).unwrap();
+
+        let filesystem = Filesystem::new(storage);
+        let file = filesystem.read_file("stream").unwrap();
+        assert_eq!(file.upgrade().unwrap().as_ref(), [1, 2, 3, 4, 5]);
+        let file = filesystem.read_file("rest").unwrap();
+        assert_eq!(file.upgrade().unwrap().as_ref(), rest);
+    }
+
+    #[test]
+    fn writing_past_the_capacity_of_a_streaming_writer_fails() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let mut writer = filesystem
+            .get_streaming_writer("stream", &[1u8; 32])
+            .unwrap();
+        let capacity = writer.capacity();
+        writer.write_all(&vec![1u8; capacity as usize - 1]).unwrap();
+        let error = writer.write_all(&[2, 3]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::StorageFull);
+        writer.write_all(&[2]).unwrap();
+        let file = writer.commit().unwrap();
+        assert_eq!(file.len(), capacity as usize);
+    }
+
+    #[test]
+    fn a_range_can_be_read_without_opening_the_file() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        let content: Vec<u8> = b"\0asm"
+            .iter()
+            .copied()
+            .chain((4..5000u32).map(|index| index as u8))
+            .collect();
+        filesystem
+            .write_file("program", &content, &[0u8; 32])
+            .unwrap();
+        let file = filesystem.read_file("program").unwrap();
+
+        let mut magic = [0u8; 4];
+        file.read_range(0, &mut magic).unwrap();
+        assert_eq!(&magic, b"\0asm");
+        let mut middle = [0u8; 5];
+        file.read_range(10, &mut middle).unwrap();
+        assert_eq!(middle, content[10..15]);
+        file.read_range(content.len() as u32, &mut []).unwrap();
+        assert!(matches!(
+            file.read_range(content.len() as u32 - 2, &mut middle),
+            Err(crate::file::ReadFileError::OutOfBounds)
+        ));
+        assert!(matches!(
+            file.read_range(u32::MAX, &mut magic),
+            Err(crate::file::ReadFileError::OutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn a_range_of_a_deleted_file_can_not_be_read() {
+        let owned_storage = SimulatedStorage::new();
+        let storage = unsafe {
+            std::mem::transmute::<&SimulatedStorage, &'static SimulatedStorage>(&owned_storage)
+        };
+        let mut filesystem = Filesystem::new(storage);
+        filesystem
+            .write_file("program", b"\0asm", &[0u8; 32])
+            .unwrap();
+        let file = filesystem.read_file("program").unwrap();
+        filesystem.delete_file("program").unwrap();
+
+        assert!(matches!(
+            file.read_range(0, &mut [0u8; 4]),
+            Err(crate::file::ReadFileError::FileWasDeleted)
+        ));
     }
 }
@@ -8,6 +8,18 @@ use thiserror::Error;
 #[cfg_attr(docsrs, doc(cfg(feature = "simulated")))]
 pub mod simulated;
 
+#[cfg(any(test, feature = "simulated"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "simulated")))]
+pub mod faulty;
+
+#[cfg(any(test, feature = "simulated"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "simulated")))]
+pub mod recording;
+
+#[cfg(any(test, feature = "simulated"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "simulated")))]
+pub mod disk;
+
 #[cfg(feature = "esp")]
 #[cfg_attr(docsrs, doc(cfg(feature = "esp")))]
 pub mod esp;
@@ -63,6 +75,12 @@ pub trait Storage {
     const BLOCK_SIZE: u32;
     /// Total number of blocks
     const BLOCKS: u32;
+    /// Maximum number of files that can be created on the storage
+    ///
+    /// The filesystem keeps some information about every file in RAM. Every file takes at least one block, so there can never be more than [Storage::BLOCKS] files and the memory usage is already bounded by the size of the storage. That is why the default does not limit anything on its own. Storages on devices with little RAM can lower it; creating files then fails with [TooManyFiles](crate::FilesystemWriteError::TooManyFiles) once the limit is reached.
+    ///
+    /// Files that are already on the storage are always loaded, even if there are more than this, because ignoring them would overwrite them.
+    const MAX_FILES: u32 = Self::BLOCKS;
 
     /// Read at a specific location.
     ///
@@ -70,12 +88,37 @@ pub trait Storage {
     ///
     /// This function is expected to return a slice that points into memory mapped storage. This means that the data is not copied and the data is directly read from the storage. This way no copy operations are needed to read data from the storage.
     fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError>;
+    /// Read `length` bytes starting `offset` bytes after `address`
+    ///
+    /// Use this to look at a part of a larger region without mapping all of it. The range wraps around at the end of the storage like [Storage::read].
+    fn read_range(
+        &self,
+        address: u32,
+        offset: u32,
+        length: u32,
+    ) -> Result<&'static [u8], StorageError> {
+        let size = Self::BLOCK_SIZE as u64 * Self::BLOCKS as u64;
+        if address as u64 >= size {
+            return Err(StorageError::AddressTooBig);
+        }
+        let start = (address as u64 + offset as u64) % size;
+        self.read(start as u32, length)
+    }
     /// Write at a specific location
     ///
     /// address must be inside the storage size. length must be lower or equal to the storage size.
     ///
     /// This operation can only set 1 bits to 0 but not back. If you want to reset bits to 1 use the erase function.
     fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError>;
+    /// Write multiple regions in order
+    ///
+    /// Storages that can write multiple regions at once should override this to shorten the window in which a power loss leaves only some of the regions written. The default writes the regions one after another, so if a write fails, the regions before it are already written.
+    fn write_batch(&self, writes: &[(u32, &[u8])]) -> Result<(), StorageError> {
+        for (address, data) in writes {
+            self.write(*address, data)?;
+        }
+        Ok(())
+    }
     /// Reset a block of bits to 1
     ///
     /// address must be inside the storage size. length must be lower or equal to the storage size. address must be block aligned. length must be a multiple of block size
@@ -86,6 +129,13 @@ pub trait Storage {
     /// Write a metadata key from persistent storage
     fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()>;
 
+    /// Make sure all previous writes and erases are durable
+    ///
+    /// Storages that cache writes need to write them back here. The default does nothing, which is correct for storages that write through.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
     /// Write metadata and return a memorymapped slice to the metadata
     fn write_readback(&self, address: u32, data: &[u8]) -> Result<&'static [u8], StorageError> {
         self.write(address, data)?;
@@ -0,0 +1,215 @@
+//! A Storage that keeps its content in a file, so it survives restarting the program
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{simulated::SizedSimulatedStorage, EraseStorageError, Storage, StorageError};
+
+/// A disk backed storage with the geometry used in most tests
+pub type DiskStorage = SizedDiskStorage<{ 16 * 4096 }, 4096>;
+
+/// A storage that is backed by a file
+///
+/// The content is kept in memory, so reads can return slices into it like on a memory mapped flash. Every write and erase is also written to the file, [Storage::flush] makes them durable. The metadata is stored as one file per key in a directory next to the file.
+///
+/// ```
+/// use rudelblinken_filesystem::storage::disk::DiskStorage;
+/// let path = std::env::temp_dir().join(format!("rudelblinken-disk-doctest-{}", std::process::id()));
+/// let storage = DiskStorage::open(&path).unwrap();
+/// # drop(storage);
+/// # std::fs::remove_file(&path).unwrap();
+/// # std::fs::remove_dir_all(path.with_extension("metadata")).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct SizedDiskStorage<const SIZE: u32, const BLOCK_SIZE: u32> {
+    memory: SizedSimulatedStorage<SIZE, BLOCK_SIZE>,
+    file: Mutex<File>,
+    metadata_directory: PathBuf,
+}
+
+impl<const SIZE: u32, const BLOCK_SIZE: u32> SizedDiskStorage<SIZE, BLOCK_SIZE> {
+    /// Open the storage in the file at `path`
+    ///
+    /// The file is created with erased content if it does not exist yet. The metadata is stored in `path` with the extension `metadata`.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        if content.len() != SIZE as usize {
+            content = vec![0xff; SIZE as usize];
+            file.set_len(0)?;
+            file.write_all(&content)?;
+        }
+        let memory = SizedSimulatedStorage::<SIZE, BLOCK_SIZE>::new();
+        // The simulated storage rejects writes of its full size, so the content is loaded block by block
+        for (block, data) in content.chunks(BLOCK_SIZE as usize).enumerate() {
+            memory
+                .write(block as u32 * BLOCK_SIZE, data)
+                .map_err(std::io::Error::other)?;
+        }
+        let metadata_directory = path.with_extension("metadata");
+        std::fs::create_dir_all(&metadata_directory)?;
+        Ok(SizedDiskStorage {
+            memory,
+            file: Mutex::new(file),
+            metadata_directory,
+        })
+    }
+
+    /// Write the in memory content of `length` bytes at `address` to the file
+    fn write_back(&self, address: u32, length: u32) -> Result<(), StorageError> {
+        let content = self.memory.read(address, length)?;
+        // The content wraps around at the end of the storage
+        let (first, second) = content.split_at(content.len().min((SIZE - address) as usize));
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| StorageError::Other("Failed to lock the file".into()))?;
+        file.seek(SeekFrom::Start(address as u64))?;
+        file.write_all(first)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(second)?;
+        Ok(())
+    }
+}
+
+impl<const SIZE: u32, const BLOCK_SIZE: u32> Storage for SizedDiskStorage<SIZE, BLOCK_SIZE> {
+    const BLOCKS: u32 = SIZE / BLOCK_SIZE;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        self.memory.read(address, length)
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.memory.write(address, data)?;
+        self.write_back(address, data.len() as u32)
+    }
+
+    fn write_batch(&self, writes: &[(u32, &[u8])]) -> Result<(), StorageError> {
+        self.memory.write_batch(writes)?;
+        for (address, data) in writes {
+            self.write_back(*address, data.len() as u32)?;
+        }
+        Ok(())
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        self.memory.erase(address, length)?;
+        for block in 0..length / BLOCK_SIZE {
+            self.write_back(address + block * BLOCK_SIZE, BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn read_metadata(&self, key: &str) -> std::io::Result<Box<[u8]>> {
+        Ok(std::fs::read(self.metadata_directory.join(key))?.into_boxed_slice())
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> std::io::Result<()> {
+        std::fs::write(self.metadata_directory.join(key), value)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.file
+            .lock()
+            .map_err(|_| StorageError::Other("Failed to lock the file".into()))?
+            .sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Filesystem;
+
+    /// Path to a storage file that is unique to this test and removed when dropped
+    struct TemporaryPath(PathBuf);
+
+    impl TemporaryPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "rudelblinken-disk-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            let _ = std::fs::remove_dir_all(path.with_extension("metadata"));
+            TemporaryPath(path)
+        }
+    }
+
+    impl Drop for TemporaryPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_dir_all(self.0.with_extension("metadata"));
+        }
+    }
+
+    #[test]
+    fn a_new_storage_is_erased() {
+        let path = TemporaryPath::new("erased");
+        let storage = DiskStorage::open(&path.0).unwrap();
+        assert!(storage
+            .read(0, 4096)
+            .unwrap()
+            .iter()
+            .all(|byte| *byte == 0xff));
+        assert_eq!(
+            std::fs::metadata(&path.0).unwrap().len(),
+            DiskStorage::BLOCKS as u64 * DiskStorage::BLOCK_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn writes_and_erases_survive_a_reopen() {
+        let path = TemporaryPath::new("raw");
+        let storage = DiskStorage::open(&path.0).unwrap();
+        storage.write(4096, &[1, 2, 3]).unwrap();
+        storage.write(8192, &[4, 5, 6]).unwrap();
+        storage.erase(8192, 4096).unwrap();
+        // Wraps around the end of the storage
+        storage.write(16 * 4096 - 2, &[7, 8, 9, 10]).unwrap();
+        storage.write_metadata("key", &[11]).unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+
+        let storage = DiskStorage::open(&path.0).unwrap();
+        assert_eq!(storage.read(4096, 3).unwrap(), &[1, 2, 3]);
+        assert_eq!(storage.read(8192, 3).unwrap(), &[0xff, 0xff, 0xff]);
+        assert_eq!(storage.read(16 * 4096 - 2, 4).unwrap(), &[7, 8, 9, 10]);
+        assert_eq!(storage.read(0, 2).unwrap(), &[9, 10]);
+        assert_eq!(&*storage.read_metadata("key").unwrap(), &[11]);
+    }
+
+    #[test]
+    fn files_survive_dropping_and_reopening_the_storage() {
+        let path = TemporaryPath::new("filesystem");
+        let owned_storage = DiskStorage::open(&path.0).unwrap();
+        let storage =
+            unsafe { std::mem::transmute::<&DiskStorage, &'static DiskStorage>(&owned_storage) };
+        let mut filesystem = Filesystem::new(storage);
+        let content = [7u8; 3000];
+        let hash: [u8; 32] = blake3::hash(&content).into();
+        filesystem.write_file("fancy", &content, &hash).unwrap();
+        drop(filesystem);
+        drop(owned_storage);
+
+        let owned_storage = DiskStorage::open(&path.0).unwrap();
+        let storage =
+            unsafe { std::mem::transmute::<&DiskStorage, &'static DiskStorage>(&owned_storage) };
+        let filesystem = Filesystem::new(storage);
+        let file = filesystem.read_file("fancy").unwrap();
+        assert_eq!(file.upgrade().unwrap().as_ref(), &content);
+    }
+}
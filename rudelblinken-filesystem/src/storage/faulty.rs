@@ -0,0 +1,147 @@
+//! A Storage wrapper that simulates a power loss, to test that the filesystem survives it
+
+use std::sync::Mutex;
+
+use super::{EraseStorageError, Storage, StorageError};
+
+#[derive(Debug, Default)]
+struct FaultState {
+    /// Number of writes that still succeed before the power is lost
+    writes_until_failure: Option<u32>,
+    /// Number of erases that still succeed before the power is lost
+    erases_until_failure: Option<u32>,
+    powered_off: bool,
+    /// Number of successful flushes
+    flushes: u32,
+}
+
+impl FaultState {
+    /// Count an operation and lose power if it is the one that should fail
+    fn operation(remaining: &mut Option<u32>, powered_off: &mut bool) -> bool {
+        if *powered_off {
+            return false;
+        }
+        match remaining {
+            Some(0) => {
+                *remaining = None;
+                *powered_off = true;
+                return false;
+            }
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+        true
+    }
+}
+
+/// Wraps a storage and loses power after a configured number of operations
+///
+/// Once the power is lost, every write, erase and metadata write fails without touching the storage, like on a device that is switched off. Reads still work, so the state of the storage can be inspected. Call [FaultyStorage::reboot] and create a new [Filesystem](crate::Filesystem) over the same storage to simulate the next boot.
+///
+/// ```
+/// use rudelblinken_filesystem::storage::{faulty::FaultyStorage, simulated::SimulatedStorage};
+/// use rudelblinken_filesystem::Filesystem;
+///
+/// let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+/// let storage: &'static FaultyStorage<_> = Box::leak(Box::new(FaultyStorage::new(storage)));
+/// let mut filesystem = Filesystem::new(storage);
+/// storage.fail_after_writes(1);
+/// assert!(filesystem.write_file("fancy", &[1, 2, 3], &[0u8; 32]).is_err());
+///
+/// storage.reboot();
+/// let filesystem = Filesystem::new(storage);
+/// assert!(filesystem.read_file("fancy").is_none());
+/// ```
+pub struct FaultyStorage<T: Storage + 'static> {
+    storage: &'static T,
+    state: Mutex<FaultState>,
+}
+
+impl<T: Storage + 'static> FaultyStorage<T> {
+    /// Wrap a storage. It does not fail until configured to
+    pub fn new(storage: &'static T) -> Self {
+        FaultyStorage {
+            storage,
+            state: Default::default(),
+        }
+    }
+
+    /// Lose power on the write after the next `writes` writes
+    pub fn fail_after_writes(&self, writes: u32) {
+        self.state.lock().unwrap().writes_until_failure = Some(writes);
+    }
+
+    /// Lose power on the erase after the next `erases` erases
+    pub fn fail_after_erases(&self, erases: u32) {
+        self.state.lock().unwrap().erases_until_failure = Some(erases);
+    }
+
+    /// Whether the power was lost
+    pub fn powered_off(&self) -> bool {
+        self.state.lock().unwrap().powered_off
+    }
+
+    /// Restore the power and clear all configured failures
+    pub fn reboot(&self) {
+        *self.state.lock().unwrap() = FaultState::default();
+    }
+
+    /// Number of successful flushes since the last reboot
+    pub fn flushes(&self) -> u32 {
+        self.state.lock().unwrap().flushes
+    }
+
+    /// The wrapped storage
+    pub fn inner(&self) -> &'static T {
+        self.storage
+    }
+}
+
+impl<T: Storage + 'static> Storage for FaultyStorage<T> {
+    const BLOCKS: u32 = T::BLOCKS;
+    const BLOCK_SIZE: u32 = T::BLOCK_SIZE;
+    const MAX_FILES: u32 = T::MAX_FILES;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        self.storage.read(address, length)
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if !FaultState::operation(&mut state.writes_until_failure, &mut state.powered_off) {
+            return Err(StorageError::Other("Simulated power loss".into()));
+        }
+        self.storage.write(address, data)
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if !FaultState::operation(&mut state.erases_until_failure, &mut state.powered_off) {
+            return Err(StorageError::Other("Simulated power loss".into()).into());
+        }
+        self.storage.erase(address, length)
+    }
+
+    fn read_metadata(&self, key: &str) -> Result<Box<[u8]>, std::io::Error> {
+        self.storage.read_metadata(key)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
+        if self.powered_off() {
+            return Err(std::io::Error::other("Simulated power loss"));
+        }
+        self.storage.write_metadata(key, value)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let mut state = self.state.lock().unwrap();
+        if state.powered_off {
+            return Err(StorageError::Other("Simulated power loss".into()));
+        }
+        self.storage.flush()?;
+        state.flushes += 1;
+        Ok(())
+    }
+}
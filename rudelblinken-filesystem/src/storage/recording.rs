@@ -0,0 +1,136 @@
+//! A Storage wrapper that records every operation, to test the order in which the filesystem touches the storage
+
+use std::sync::Mutex;
+
+use super::{EraseStorageError, Storage, StorageError};
+
+/// An operation that modified a [RecordingStorage]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageOp {
+    /// `length` bytes were written at `address`
+    Write {
+        /// Start of the write
+        address: u32,
+        /// Number of bytes written
+        length: u32,
+    },
+    /// The regions were written as one batch
+    WriteBatch {
+        /// `(address, length)` of every region, in order
+        writes: Vec<(u32, u32)>,
+    },
+    /// `length` bytes were erased starting at `address`
+    Erase {
+        /// Start of the erased range
+        address: u32,
+        /// Number of bytes erased
+        length: u32,
+    },
+    /// The metadata key `key` was written
+    WriteMetadata {
+        /// The key that was written
+        key: String,
+    },
+    /// The storage was flushed
+    Flush,
+}
+
+/// Wraps a storage and records every write, erase, metadata write and flush in order
+///
+/// Reads are not recorded. Operations are recorded before they are forwarded, so failed operations show up as well.
+///
+/// ```
+/// use rudelblinken_filesystem::storage::{
+///     recording::{RecordingStorage, StorageOp},
+///     simulated::SimulatedStorage,
+/// };
+/// use rudelblinken_filesystem::Filesystem;
+///
+/// let storage: &'static SimulatedStorage = Box::leak(Box::new(SimulatedStorage::new()));
+/// let storage: &'static RecordingStorage<_> = Box::leak(Box::new(RecordingStorage::new(storage)));
+/// let mut filesystem = Filesystem::new(storage);
+/// storage.clear();
+/// filesystem.write_file("fancy", &[1, 2, 3], &[0u8; 32]).unwrap();
+/// assert_eq!(storage.operations().last(), Some(&StorageOp::Flush));
+/// ```
+pub struct RecordingStorage<T: Storage + 'static> {
+    storage: &'static T,
+    operations: Mutex<Vec<StorageOp>>,
+}
+
+impl<T: Storage + 'static> RecordingStorage<T> {
+    /// Wrap a storage. Nothing is recorded yet
+    pub fn new(storage: &'static T) -> Self {
+        RecordingStorage {
+            storage,
+            operations: Default::default(),
+        }
+    }
+
+    /// All operations since the storage was created or last cleared, in the order they happened
+    pub fn operations(&self) -> Vec<StorageOp> {
+        self.operations.lock().unwrap().clone()
+    }
+
+    /// Forget all recorded operations
+    pub fn clear(&self) {
+        self.operations.lock().unwrap().clear();
+    }
+
+    /// The wrapped storage
+    pub fn inner(&self) -> &'static T {
+        self.storage
+    }
+
+    fn record(&self, operation: StorageOp) {
+        self.operations.lock().unwrap().push(operation);
+    }
+}
+
+impl<T: Storage + 'static> Storage for RecordingStorage<T> {
+    const BLOCKS: u32 = T::BLOCKS;
+    const BLOCK_SIZE: u32 = T::BLOCK_SIZE;
+    const MAX_FILES: u32 = T::MAX_FILES;
+
+    fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
+        self.storage.read(address, length)
+    }
+
+    fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
+        self.record(StorageOp::Write {
+            address,
+            length: data.len() as u32,
+        });
+        self.storage.write(address, data)
+    }
+
+    /// Records the batch as a single operation
+    fn write_batch(&self, writes: &[(u32, &[u8])]) -> Result<(), StorageError> {
+        self.record(StorageOp::WriteBatch {
+            writes: writes
+                .iter()
+                .map(|(address, data)| (*address, data.len() as u32))
+                .collect(),
+        });
+        self.storage.write_batch(writes)
+    }
+
+    fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        self.record(StorageOp::Erase { address, length });
+        self.storage.erase(address, length)
+    }
+
+    fn read_metadata(&self, key: &str) -> Result<Box<[u8]>, std::io::Error> {
+        self.storage.read_metadata(key)
+    }
+
+    fn write_metadata(&self, key: &str, value: &[u8]) -> Result<(), std::io::Error> {
+        self.record(StorageOp::WriteMetadata { key: key.into() });
+        self.storage.write_metadata(key, value)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        self.record(StorageOp::Flush);
+        self.storage.flush()
+    }
+}
@@ -7,45 +7,91 @@ use std::{
 
 use super::{EraseStorageError, Storage, StorageError};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C, align(4096))]
-struct AlignedBuffer<const SIZE: usize>([u8; SIZE]);
+struct AlignedChunk([u8; 4096]);
+
+/// A simulated storage with the geometry used in most tests
+pub type SimulatedStorage = SizedSimulatedStorage<{ 16 * 4096 }, 4096>;
+
+/// A simulated storage with the geometry of the storage partition on the ESP32-C3
+pub type Esp32C3SimulatedStorage = SizedSimulatedStorage<{ 256 * 4096 }, 4096>;
 
 #[derive(Debug)]
 /// A storage that is backed by a heap allocated buffer
 ///
+/// `SIZE` is the size of the storage in bytes and needs to be a multiple of `BLOCK_SIZE`. Use [SimulatedStorage] if you don't care about the geometry.
+///
 /// ```
-/// use rudelblinken_filesystem::storage::simulated::SimulatedStorage;
+/// use rudelblinken_filesystem::storage::simulated::{SimulatedStorage, SizedSimulatedStorage};
 /// let storage = SimulatedStorage::new();
+/// let bigger_storage = SizedSimulatedStorage::<{ 64 * 4096 }, 4096>::new();
 /// ```
-pub struct SimulatedStorage {
-    pool: Box<AlignedBuffer<{ Self::SIZE as usize * 2 }>>,
-    pool_ptr: *mut [u8; Self::SIZE as usize * 2],
+pub struct SizedSimulatedStorage<const SIZE: u32, const BLOCK_SIZE: u32> {
+    /// Holds the storage twice, so reads across the end wrap around to the start
+    ///
+    /// Allocated as a box and freed when the storage is dropped.
+    pool: *mut [AlignedChunk],
     key_value: Arc<Mutex<HashMap<String, Box<[u8]>>>>,
 }
 
-unsafe impl Send for SimulatedStorage {}
-unsafe impl Sync for SimulatedStorage {}
+unsafe impl<const SIZE: u32, const BLOCK_SIZE: u32> Send
+    for SizedSimulatedStorage<SIZE, BLOCK_SIZE>
+{
+}
+unsafe impl<const SIZE: u32, const BLOCK_SIZE: u32> Sync
+    for SizedSimulatedStorage<SIZE, BLOCK_SIZE>
+{
+}
+
+impl<const SIZE: u32, const BLOCK_SIZE: u32> Drop for SizedSimulatedStorage<SIZE, BLOCK_SIZE> {
+    fn drop(&mut self) {
+        // SAFETY: The pool was created from a box in `new` and is only freed here
+        drop(unsafe { Box::from_raw(self.pool) });
+    }
+}
 
-impl Default for SimulatedStorage {
+impl<const SIZE: u32, const BLOCK_SIZE: u32> Default for SizedSimulatedStorage<SIZE, BLOCK_SIZE> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl SimulatedStorage {
+impl<const SIZE: u32, const BLOCK_SIZE: u32> SizedSimulatedStorage<SIZE, BLOCK_SIZE> {
     /// Size of the storage
-    pub const SIZE: u32 = Self::BLOCKS * Self::BLOCK_SIZE;
+    pub const SIZE: u32 = SIZE;
 
     /// Create a new storage for testing purposes
-    pub fn new() -> SimulatedStorage {
-        let mut pool = Box::new(AlignedBuffer([0b11111111u8; Self::SIZE as usize * 2]));
-        SimulatedStorage {
-            pool_ptr: &mut (pool.0),
-            pool,
+    pub fn new() -> Self {
+        const {
+            assert!(
+                BLOCK_SIZE > 0 && SIZE > 0 && SIZE.is_multiple_of(BLOCK_SIZE),
+                "The size of a simulated storage needs to be a multiple of its block size"
+            )
+        };
+        let chunks = (SIZE as usize * 2).div_ceil(size_of::<AlignedChunk>());
+        let pool = vec![AlignedChunk([0b11111111u8; 4096]); chunks].into_boxed_slice();
+        SizedSimulatedStorage {
+            pool: Box::into_raw(pool),
             key_value: Default::default(),
         }
     }
+
+    /// Check that a write of `length` bytes at `address` fits into the storage
+    fn check_write(address: u32, length: usize) -> Result<(), StorageError> {
+        if address >= Self::SIZE {
+            return Err(StorageError::AddressTooBig);
+        }
+        if length as u32 >= Self::SIZE {
+            return Err(StorageError::SizeTooBig);
+        }
+        Ok(())
+    }
+
+    /// The backing buffer. It contains the storage twice
+    fn pool(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.pool.cast::<u8>(), SIZE as usize * 2) }
+    }
 }
 
 /// Copies zeroes from src to dest and ignores ones in src.
@@ -58,9 +104,9 @@ fn copy_zeroes_from_slice(dest: &mut [u8], src: &[u8]) {
     dest.copy_from_slice(&new_data);
 }
 
-impl Storage for SimulatedStorage {
-    const BLOCKS: u32 = 16;
-    const BLOCK_SIZE: u32 = 4096;
+impl<const SIZE: u32, const BLOCK_SIZE: u32> Storage for SizedSimulatedStorage<SIZE, BLOCK_SIZE> {
+    const BLOCKS: u32 = SIZE / BLOCK_SIZE;
+    const BLOCK_SIZE: u32 = BLOCK_SIZE;
 
     fn read(&self, address: u32, length: u32) -> Result<&'static [u8], StorageError> {
         if address >= Self::SIZE {
@@ -71,7 +117,7 @@ impl Storage for SimulatedStorage {
         }
         let static_slice = unsafe {
             std::mem::transmute::<&[u8], &'static [u8]>(
-                &self.pool.0[address as usize..(address + length) as usize],
+                &self.pool()[address as usize..(address + length) as usize],
             )
         };
 
@@ -79,13 +125,9 @@ impl Storage for SimulatedStorage {
     }
 
     fn write(&self, address: u32, data: &[u8]) -> Result<(), StorageError> {
-        if address >= Self::SIZE {
-            return Err(StorageError::AddressTooBig);
-        }
-        if data.len() as u32 >= Self::SIZE {
-            return Err(StorageError::SizeTooBig);
-        }
-        let pool = unsafe { &mut *self.pool_ptr };
+        Self::check_write(address, data.len())?;
+        let pool =
+            unsafe { std::slice::from_raw_parts_mut(self.pool.cast::<u8>(), SIZE as usize * 2) };
 
         copy_zeroes_from_slice(
             &mut pool[address as usize..address as usize + data.len()],
@@ -109,6 +151,17 @@ impl Storage for SimulatedStorage {
         Ok(())
     }
 
+    /// Checks all writes before writing anything, so an invalid write does not leave the batch half written
+    fn write_batch(&self, writes: &[(u32, &[u8])]) -> Result<(), StorageError> {
+        for (address, data) in writes {
+            Self::check_write(*address, data.len())?;
+        }
+        for (address, data) in writes {
+            self.write(*address, data)?;
+        }
+        Ok(())
+    }
+
     fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
         if address % Self::BLOCK_SIZE != 0 || length % Self::BLOCK_SIZE != 0 {
             return Err(EraseStorageError::SizeNotAMultipleOfPageSize);
@@ -116,13 +169,18 @@ impl Storage for SimulatedStorage {
         if (address + length) > Self::BLOCKS * Self::BLOCK_SIZE {
             return Err(EraseStorageError::SizeNotAMultipleOfPageSize);
         }
-        let pool = unsafe { &mut *self.pool_ptr };
+        let pool =
+            unsafe { std::slice::from_raw_parts_mut(self.pool.cast::<u8>(), SIZE as usize * 2) };
 
         let number_of_blocks = length.div_ceil(Self::BLOCK_SIZE);
         for block in 0..number_of_blocks {
             let base_address = address + block * Self::BLOCK_SIZE;
             pool[base_address as usize..(base_address + Self::BLOCK_SIZE) as usize]
-                .copy_from_slice(&[0b11111111u8; Self::BLOCK_SIZE as usize]);
+                .fill(0b11111111u8);
+            // Also erase the copy, so reads across the end do not return stale content
+            let copy_address = Self::SIZE + base_address;
+            pool[copy_address as usize..(copy_address + Self::BLOCK_SIZE) as usize]
+                .fill(0b11111111u8);
         }
         Ok(())
     }
@@ -132,7 +190,8 @@ impl Storage for SimulatedStorage {
             .key_value
             .lock()
             .map_err(|_| std::io::Error::other("Failed to lock mutex"))?
-            .get(key).cloned()
+            .get(key)
+            .cloned()
             .ok_or(std::io::Error::other("Failed to get a key for that value"));
     }
 
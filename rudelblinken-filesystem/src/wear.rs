@@ -0,0 +1,142 @@
+//! Count how often every block was erased
+//!
+//! The counters are stored in the storage metadata, split over multiple keys, because some storages only support small metadata values.
+//!
+//! Writing the metadata after every erase would wear out the metadata storage instead, so new erases are collected in RAM first. The counters under a key are only written once [PERSIST_AFTER] erases were collected for them or when the last user of the counters gets dropped. Erases that were not written yet are lost on a power loss, which is fine for statistics.
+use crate::storage::{EraseStorageError, Storage};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// Number of counters that are stored under one metadata key
+const COUNTERS_PER_KEY: u32 = 32;
+/// Number of collected erases after which the counters under a key are written
+pub(crate) const PERSIST_AFTER: u32 = 16;
+
+/// Metadata key for the counters of the blocks starting at `chunk * COUNTERS_PER_KEY`
+fn counter_key(chunk: u32) -> String {
+    format!("wear_{}", chunk)
+}
+
+/// Read the counters stored under one key. Missing counters are zero
+fn read_chunk<T: Storage>(storage: &T, chunk: u32) -> [u32; COUNTERS_PER_KEY as usize] {
+    let mut counters = [0u32; COUNTERS_PER_KEY as usize];
+    let Ok(bytes) = storage.read_metadata(&counter_key(chunk)) else {
+        return counters;
+    };
+    for (counter, bytes) in counters.iter_mut().zip(bytes.chunks_exact(4)) {
+        *counter = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    counters
+}
+
+/// The erase counters of a storage
+///
+/// A filesystem shares them with all of its files, so the erases of files that outlive the filesystem are still counted. The erases that were not written yet are written when the last of them is dropped.
+pub(crate) struct Wear<T: Storage + 'static> {
+    storage: &'static T,
+    /// Erases that were not written yet, by the chunk of counters
+    pending: Mutex<BTreeMap<u32, [u32; COUNTERS_PER_KEY as usize]>>,
+}
+
+impl<T: Storage + 'static> Wear<T> {
+    /// Create the counters for `storage`
+    ///
+    /// Only one instance should exist for every storage, otherwise the instances overwrite each other's counters.
+    pub fn new(storage: &'static T) -> Arc<Self> {
+        Arc::new(Self {
+            storage,
+            pending: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Add the pending erases to the counters under one key and write them
+    ///
+    /// The erases stay pending if writing fails.
+    fn persist_chunk(
+        &self,
+        pending: &mut BTreeMap<u32, [u32; COUNTERS_PER_KEY as usize]>,
+        chunk: u32,
+    ) {
+        let Some(erases) = pending.get(&chunk) else {
+            return;
+        };
+        let mut counters = read_chunk(self.storage, chunk);
+        for (counter, erases) in counters.iter_mut().zip(erases) {
+            *counter = counter.saturating_add(*erases);
+        }
+        let bytes: Vec<u8> = counters.iter().flat_map(|c| c.to_le_bytes()).collect();
+        match self.storage.write_metadata(&counter_key(chunk), &bytes) {
+            Ok(()) => {
+                pending.remove(&chunk);
+            }
+            Err(error) => println!("Failed to update the erase counters: {}", error),
+        }
+    }
+
+    /// Erase a range of blocks and count the erase
+    ///
+    /// Use this instead of [Storage::erase] for all erases of the filesystem. Failing to update the counters is not an error, as they are only statistics.
+    pub fn erase(&self, address: u32, length: u32) -> Result<(), EraseStorageError> {
+        self.storage.erase(address, length)?;
+
+        let Ok(mut pending) = self.pending.lock() else {
+            return Ok(());
+        };
+        // The storage rejects erases past its end, but a counter outside of it must never be touched
+        let first_block = address / T::BLOCK_SIZE;
+        let end_block = first_block
+            .saturating_add(length / T::BLOCK_SIZE)
+            .min(T::BLOCKS);
+        let mut block = first_block;
+        while block < end_block {
+            let chunk = block / COUNTERS_PER_KEY;
+            let erases = pending
+                .entry(chunk)
+                .or_insert([0; COUNTERS_PER_KEY as usize]);
+            while block < end_block && block / COUNTERS_PER_KEY == chunk {
+                let counter = &mut erases[(block % COUNTERS_PER_KEY) as usize];
+                *counter = counter.saturating_add(1);
+                block += 1;
+            }
+            if erases.iter().sum::<u32>() >= PERSIST_AFTER {
+                self.persist_chunk(&mut pending, chunk);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write all pending erases
+    pub fn flush(&self) {
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        for chunk in 0..T::BLOCKS.div_ceil(COUNTERS_PER_KEY) {
+            self.persist_chunk(&mut pending, chunk);
+        }
+    }
+
+    /// Get the number of erases of every block, including the ones that were not written yet
+    pub fn erase_counts(&self) -> Vec<u32> {
+        let pending = self.pending.lock().ok();
+        (0..T::BLOCKS.div_ceil(COUNTERS_PER_KEY))
+            .flat_map(|chunk| {
+                let mut counters = read_chunk(self.storage, chunk);
+                if let Some(erases) = pending.as_ref().and_then(|pending| pending.get(&chunk)) {
+                    for (counter, erases) in counters.iter_mut().zip(erases) {
+                        *counter = counter.saturating_add(*erases);
+                    }
+                }
+                counters
+            })
+            .take(T::BLOCKS as usize)
+            .collect()
+    }
+}
+
+impl<T: Storage + 'static> Drop for Wear<T> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -1,4 +1,6 @@
 fn main() {
     embuild::espidf::sysenv::output();
     println!("cargo:rerun-if-changed=../wasm-binaries/binaries/board_test.wasm");
+    // The public key for signed firmware updates is compiled into the firmware
+    println!("cargo:rerun-if-env-changed=RUDELBLINKEN_UPDATE_KEY");
 }
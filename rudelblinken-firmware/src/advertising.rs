@@ -0,0 +1,146 @@
+//! Advertising interval and transmit power
+//!
+//! Dense swarms can advertise less often to reduce collisions and lower the transmit power to save battery. The settings are stored in NVS and applied without a reboot. WASM guests can still change the interval with `configure-advertisement` and the transmit power with `set-advertisement-tx-power` while they run. The stored settings apply again once they change.
+use crate::{config, BLE_DEVICE};
+use esp32_nimble::{
+    enums::{PowerLevel, PowerType},
+    BLEDevice, BLEError,
+};
+
+/// Shortest advertising interval allowed by the BLE spec in units of 0.625 ms (20 ms)
+pub const MIN_ADVERTISING_INTERVAL: u16 = 0x0020;
+/// Longest advertising interval allowed by the BLE spec in units of 0.625 ms (10.24 s)
+pub const MAX_ADVERTISING_INTERVAL: u16 = 0x4000;
+
+/// Length of the encoded settings
+pub const ENCODED_LENGTH: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvertisingSettings {
+    /// Minimum advertising interval in units of 0.625 ms
+    pub min_interval: u16,
+    /// Maximum advertising interval in units of 0.625 ms
+    pub max_interval: u16,
+    /// Transmit power in dBm. Only multiples of 3 between -12 and 9 are supported
+    pub tx_power: i8,
+}
+
+impl AdvertisingSettings {
+    /// The settings that are used until others were stored
+    pub const DEFAULT: AdvertisingSettings = AdvertisingSettings {
+        min_interval: 100,
+        max_interval: 150,
+        tx_power: 3,
+    };
+
+    /// Decode settings written by a client
+    ///
+    /// The layout is the minimum and maximum interval as little endian `u16` followed by the transmit power as `i8`. Returns `None` if the length does not match. Values outside of the supported range are [clamped](AdvertisingSettings::clamped) with a warning.
+    pub fn decode(encoded: &[u8]) -> Option<Self> {
+        let encoded: &[u8; ENCODED_LENGTH] = encoded.try_into().ok()?;
+        let settings = AdvertisingSettings {
+            min_interval: u16::from_le_bytes([encoded[0], encoded[1]]),
+            max_interval: u16::from_le_bytes([encoded[2], encoded[3]]),
+            tx_power: encoded[4] as i8,
+        };
+        let clamped = settings.clamped();
+        if clamped != settings {
+            tracing::warn!(
+                ?settings,
+                ?clamped,
+                "Advertising settings are out of range and were clamped"
+            );
+        }
+        return Some(clamped);
+    }
+
+    /// Clamp the settings into the range supported by BLE and the radio
+    ///
+    /// The intervals are clamped to [MIN_ADVERTISING_INTERVAL]..=[MAX_ADVERTISING_INTERVAL] and the maximum interval is raised to the minimum interval if it is lower. The transmit power is rounded down to the next supported level.
+    pub fn clamped(&self) -> Self {
+        let min_interval = self
+            .min_interval
+            .clamp(MIN_ADVERTISING_INTERVAL, MAX_ADVERTISING_INTERVAL);
+        let max_interval = self
+            .max_interval
+            .clamp(min_interval, MAX_ADVERTISING_INTERVAL);
+        return AdvertisingSettings {
+            min_interval,
+            max_interval,
+            tx_power: supported_tx_power(self.tx_power),
+        };
+    }
+
+    /// Encode the settings in the layout read by [AdvertisingSettings::decode]
+    pub fn encode(&self) -> [u8; ENCODED_LENGTH] {
+        let min_interval = self.min_interval.to_le_bytes();
+        let max_interval = self.max_interval.to_le_bytes();
+        return [
+            min_interval[0],
+            min_interval[1],
+            max_interval[0],
+            max_interval[1],
+            self.tx_power as u8,
+        ];
+    }
+
+    /// The power level of the radio that matches the transmit power
+    pub fn power_level(&self) -> Option<PowerLevel> {
+        return power_level(self.tx_power);
+    }
+}
+
+/// Round the transmit power in dBm down to the next level the radio supports
+///
+/// The radio supports multiples of 3 between -12 and 9 dBm.
+pub fn supported_tx_power(tx_power: i8) -> i8 {
+    let tx_power = tx_power.clamp(-12, 9);
+    return tx_power - tx_power.rem_euclid(3);
+}
+
+/// The power level of the radio for a transmit power in dBm, if the radio supports it
+pub fn power_level(tx_power: i8) -> Option<PowerLevel> {
+    return match tx_power {
+        -12 => Some(PowerLevel::N12),
+        -9 => Some(PowerLevel::N9),
+        -6 => Some(PowerLevel::N6),
+        -3 => Some(PowerLevel::N3),
+        0 => Some(PowerLevel::N0),
+        3 => Some(PowerLevel::P3),
+        6 => Some(PowerLevel::P6),
+        9 => Some(PowerLevel::P9),
+        _ => None,
+    };
+}
+
+/// The stored advertising settings, or the defaults if none were stored
+pub fn advertising_settings() -> AdvertisingSettings {
+    return config::advertising_settings::get()
+        .and_then(|encoded| AdvertisingSettings::decode(&encoded))
+        .unwrap_or(AdvertisingSettings::DEFAULT);
+}
+
+/// Store new advertising settings and apply them. `None` restores the defaults
+///
+/// The settings are stored in NVS, so they survive reboots.
+pub fn set_advertising_settings(settings: Option<AdvertisingSettings>) -> Result<(), BLEError> {
+    config::advertising_settings::set(&settings.map(|settings| settings.encode()));
+    return apply_advertising_settings();
+}
+
+/// Apply the stored advertising settings to the running advertisement
+pub fn apply_advertising_settings() -> Result<(), BLEError> {
+    let settings = advertising_settings();
+    BLEDevice::take().set_power(
+        PowerType::Advertising,
+        settings.power_level().unwrap_or(PowerLevel::P3),
+    )?;
+
+    let mut ble_advertising = BLE_DEVICE.get_advertising().lock();
+    ble_advertising.stop()?;
+    ble_advertising
+        .min_interval(settings.min_interval)
+        .max_interval(settings.max_interval);
+    ble_advertising.start()?;
+    return Ok(());
+}
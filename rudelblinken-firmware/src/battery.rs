@@ -0,0 +1,61 @@
+//! Advertise the battery level
+//!
+//! The battery voltage is sampled periodically and advertised as service data of the standard battery service (0x180F). It is a separate field from the manufacturer data, so the advertisements of the WASM guests stay unchanged.
+use crate::{update_advertisement, wasm_service::wasm_host::measure_voltage};
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::Duration,
+};
+
+/// Time between two voltage measurements
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Voltage of an empty LiPo cell in millivolts
+const EMPTY_VOLTAGE: u32 = 3300;
+/// Voltage of a full LiPo cell in millivolts
+const FULL_VOLTAGE: u32 = 4200;
+/// Marker for "not measured yet"
+const UNKNOWN_LEVEL: u8 = u8::MAX;
+
+static BATTERY_LEVEL: AtomicU8 = AtomicU8::new(UNKNOWN_LEVEL);
+
+/// The last measured battery level in percent
+pub fn battery_level() -> Option<u8> {
+    match BATTERY_LEVEL.load(Ordering::Relaxed) {
+        UNKNOWN_LEVEL => None,
+        level => Some(level),
+    }
+}
+
+/// Convert a voltage in millivolts to a battery level in percent
+fn voltage_to_level(voltage: u32) -> u8 {
+    let voltage = voltage.clamp(EMPTY_VOLTAGE, FULL_VOLTAGE);
+    return ((voltage - EMPTY_VOLTAGE) * 100 / (FULL_VOLTAGE - EMPTY_VOLTAGE)) as u8;
+}
+
+/// Measure the battery level and update the advertisement if it changed
+fn update_battery_level() {
+    let Some(voltage) = measure_voltage() else {
+        return;
+    };
+    let level = voltage_to_level(voltage);
+    if BATTERY_LEVEL.swap(level, Ordering::Relaxed) == level {
+        return;
+    }
+    ::tracing::debug!(target: "battery", "Battery at {}mV ({}%)", voltage, level);
+
+    if let Err(error) = update_advertisement() {
+        ::tracing::warn!(target: "battery", "Failed to update the advertisement: {:?}", error);
+    }
+}
+
+/// Start sampling the battery voltage in the background
+pub fn start_battery_monitor() {
+    std::thread::Builder::new()
+        .name("battery".to_owned())
+        .stack_size(0x2000)
+        .spawn(|| loop {
+            update_battery_level();
+            std::thread::sleep(SAMPLE_INTERVAL);
+        })
+        .unwrap();
+}
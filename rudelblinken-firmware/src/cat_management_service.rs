@@ -1,6 +1,11 @@
 //! The cat management service is reponsible for managing the currently running program and its environment
+use crate::advertising::{advertising_settings, set_advertising_settings, AdvertisingSettings};
 use crate::config::{self, get_config, set_config, LedStripColor, WasmGuestConfig};
+use crate::health::health_report;
+use crate::rudel::{rudel_id, set_rudel_id};
 use crate::service_helpers::DocumentableCharacteristic;
+use crate::storage::get_filesystem;
+use crate::{update_advertisement, update_scan_response};
 use esp32_nimble::{
     cpfd::{ChrFormat, ChrUnit},
     utilities::{mutex::Mutex, BleUuid},
@@ -8,9 +13,13 @@ use esp32_nimble::{
 };
 use esp_idf_sys::{self as _};
 use main_program::WasmRunner;
+use rudelblinken_filesystem::FilesystemDeleteError;
 use rudelblinken_runtime::host::LedColor;
-use std::sync::Arc;
-use tracing::error;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use tracing::{error, info};
 mod main_program;
 
 const CAT_MANAGEMENT_SERVICE: u16 = 0x7992;
@@ -18,6 +27,21 @@ const CAT_MANAGEMENT_SERVICE_PROGRAM_HASH: u16 = 0x7893;
 const CAT_MANAGEMENT_SERVICE_NAME: u16 = 0x7894;
 const CAT_MANAGEMENT_SERVICE_STRIP_COLOR: u16 = 0x7895;
 const CAT_MANAGEMENT_SERVICE_WASM_GUEST_CONFIG: u16 = 0x7896;
+const CAT_MANAGEMENT_SERVICE_FREE_SPACE: u16 = 0x7897;
+const CAT_MANAGEMENT_SERVICE_RUDEL_ID: u16 = 0x7898;
+const CAT_MANAGEMENT_SERVICE_ERASE_FILESYSTEM: u16 = 0x7899;
+const CAT_MANAGEMENT_SERVICE_ADVERTISING_SETTINGS: u16 = 0x789A;
+const CAT_MANAGEMENT_SERVICE_HEALTH: u16 = 0x789B;
+
+/// The maximum length of a BLE attribute value
+const MAX_WASM_GUEST_CONFIG_LENGTH: usize = 512;
+/// Names need to be long enough to be recognizable and short enough to fit into the scan response
+const MIN_NAME_LENGTH: usize = 3;
+const MAX_NAME_LENGTH: usize = 16;
+/// Value that has to be written to erase the filesystem, so it is not erased by accident
+const ERASE_FILESYSTEM_CONFIRMATION: &[u8] = b"erase";
+/// Set while the filesystem is being erased
+static ERASING: AtomicBool = AtomicBool::new(false);
 
 const CAT_MANAGEMENT_SERVICE_UUID: BleUuid = BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE);
 const CAT_MANAGEMENT_SERVICE_PROGRAM_HASH_UUID: BleUuid =
@@ -27,6 +51,16 @@ const CAT_MANAGEMENT_SERVICE_STRIP_COLOR_UUID: BleUuid =
     BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_STRIP_COLOR);
 const CAT_MANAGEMENT_SERVICE_WASM_GUEST_CONFIG_UUID: BleUuid =
     BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_WASM_GUEST_CONFIG);
+const CAT_MANAGEMENT_SERVICE_FREE_SPACE_UUID: BleUuid =
+    BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_FREE_SPACE);
+const CAT_MANAGEMENT_SERVICE_RUDEL_ID_UUID: BleUuid =
+    BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_RUDEL_ID);
+const CAT_MANAGEMENT_SERVICE_ERASE_FILESYSTEM_UUID: BleUuid =
+    BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_ERASE_FILESYSTEM);
+const CAT_MANAGEMENT_SERVICE_ADVERTISING_SETTINGS_UUID: BleUuid =
+    BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_ADVERTISING_SETTINGS);
+const CAT_MANAGEMENT_SERVICE_HEALTH_UUID: BleUuid =
+    BleUuid::from_uuid16(CAT_MANAGEMENT_SERVICE_HEALTH);
 
 pub struct CatManagementService {
     pub wasm_runner: WasmRunner,
@@ -45,7 +79,7 @@ impl CatManagementService {
             NimbleProperties::WRITE | NimbleProperties::READ,
         );
         program_hash_characteristic.document(
-            "Current program hash",
+            "Hash of the running program (write to run a different program)",
             ChrFormat::Utf8s,
             0,
             ChrUnit::Unitless,
@@ -79,8 +113,63 @@ impl CatManagementService {
             ChrUnit::Unitless,
         );
 
+        let free_space_characteristic = service.lock().create_characteristic(
+            CAT_MANAGEMENT_SERVICE_FREE_SPACE_UUID,
+            NimbleProperties::READ,
+        );
+        free_space_characteristic.document(
+            "Free space on the filesystem in bytes",
+            ChrFormat::Uint32,
+            0,
+            ChrUnit::Unitless,
+        );
+
+        let rudel_id_characteristic = service.lock().create_characteristic(
+            CAT_MANAGEMENT_SERVICE_RUDEL_ID_UUID,
+            NimbleProperties::WRITE | NimbleProperties::READ,
+        );
+        rudel_id_characteristic.document(
+            "Rudel id (u16, empty if the device is not in a Rudel)",
+            ChrFormat::Uint16,
+            0,
+            ChrUnit::Unitless,
+        );
+
+        let erase_filesystem_characteristic = service.lock().create_characteristic(
+            CAT_MANAGEMENT_SERVICE_ERASE_FILESYSTEM_UUID,
+            NimbleProperties::WRITE,
+        );
+        erase_filesystem_characteristic.document(
+            "Erase filesystem (write \"erase\" to delete all files)",
+            ChrFormat::Utf8s,
+            0,
+            ChrUnit::Unitless,
+        );
+
+        let advertising_settings_characteristic = service.lock().create_characteristic(
+            CAT_MANAGEMENT_SERVICE_ADVERTISING_SETTINGS_UUID,
+            NimbleProperties::WRITE | NimbleProperties::READ,
+        );
+        advertising_settings_characteristic.document(
+            "Advertising settings (min and max interval as u16 in 0.625 ms, tx power as i8 in dBm; write nothing to reset)",
+            ChrFormat::Struct,
+            0,
+            ChrUnit::Unitless,
+        );
+
+        let health_characteristic = service
+            .lock()
+            .create_characteristic(CAT_MANAGEMENT_SERVICE_HEALTH_UUID, NimbleProperties::READ);
+        health_characteristic.document(
+            "Health (uptime in us as u64, free heap, largest free heap block, boot count as u32, last reset reason as u8)",
+            ChrFormat::Struct,
+            0,
+            ChrUnit::Unitless,
+        );
+
+        // Report the program that is actually running, so clients can check that a new program was started
         program_hash_characteristic.lock().on_read(move |value, _| {
-            let hash = config::main_program::get();
+            let hash = WasmRunner::running_program();
             value.set_value(&hash.unwrap_or([0u8; 32]));
         });
         let cat_management_service_clone = cat_management_service.clone();
@@ -99,12 +188,12 @@ impl CatManagementService {
         });
         name_characteristic.lock().on_write(move |args| {
             let data = args.recv_data();
-            if data.len() <= 3 {
-                error!("Name too short");
+            if data.len() < MIN_NAME_LENGTH {
+                error!(len = data.len(), "Name too short");
                 return;
             }
-            if data.len() > 16 {
-                error!("Name too long");
+            if data.len() > MAX_NAME_LENGTH {
+                error!(len = data.len(), "Name too long");
                 return;
             }
 
@@ -113,7 +202,11 @@ impl CatManagementService {
                 return;
             };
 
+            // The name is stored in NVS, so it survives reboots
             config::device_name::set(&Some(new_name));
+            if let Err(error) = update_scan_response() {
+                error!(?error, "Failed to advertise the new name");
+            }
         });
 
         strip_color_characteristic.lock().on_read(move |value, _| {
@@ -140,11 +233,131 @@ impl CatManagementService {
         wasm_guest_config_characteristic
             .lock()
             .on_write(move |args| {
-                set_config::<WasmGuestConfig>(args.recv_data().to_vec());
+                let data = args.recv_data();
+                if data.len() > MAX_WASM_GUEST_CONFIG_LENGTH {
+                    error!(len = data.len(), "wasm guest config too long");
+                    return;
+                }
+                set_config::<WasmGuestConfig>(data.to_vec());
             });
 
+        free_space_characteristic.lock().on_read(move |value, _| {
+            let free_space = get_filesystem()
+                .ok()
+                .and_then(|filesystem| filesystem.read().ok().map(|reader| reader.free_space()))
+                .unwrap_or(0);
+            value.set_value(&free_space.to_le_bytes());
+        });
+
+        rudel_id_characteristic.lock().on_read(move |value, _| {
+            match rudel_id() {
+                Some(id) => value.set_value(&id.to_le_bytes()),
+                None => value.set_value(&[]),
+            };
+        });
+        rudel_id_characteristic.lock().on_write(move |args| {
+            let data = args.recv_data();
+            match data.len() {
+                0 => set_rudel_id(None),
+                2 => set_rudel_id(Some(u16::from_le_bytes([data[0], data[1]]))),
+                _ => {
+                    error!(
+                        len = data.len(),
+                        "rudel id write with length other than 0 or 2"
+                    );
+                    return;
+                }
+            }
+            if let Err(error) = update_advertisement() {
+                error!(?error, "Failed to advertise the new rudel id");
+            }
+        });
+
+        erase_filesystem_characteristic
+            .lock()
+            .on_write(move |args| {
+                if args.recv_data() != ERASE_FILESYSTEM_CONFIRMATION {
+                    error!("Erase filesystem write without confirmation");
+                    return;
+                }
+                // Erasing takes a while and would block the BLE stack, so it runs on its own thread
+                if ERASING.swap(true, Ordering::SeqCst) {
+                    error!("The filesystem is already being erased");
+                    return;
+                }
+                let spawned = std::thread::Builder::new()
+                    .name("erase_filesystem".to_owned())
+                    .stack_size(0x2000)
+                    .spawn(|| {
+                        erase_filesystem();
+                        ERASING.store(false, Ordering::SeqCst);
+                    });
+                if let Err(error) = spawned {
+                    ERASING.store(false, Ordering::SeqCst);
+                    error!(?error, "Failed to start erasing the filesystem");
+                }
+            });
+
+        advertising_settings_characteristic
+            .lock()
+            .on_read(move |value, _| {
+                value.set_value(&advertising_settings().encode());
+            });
+        advertising_settings_characteristic
+            .lock()
+            .on_write(move |args| {
+                let data = args.recv_data();
+                let settings = if data.is_empty() {
+                    None
+                } else {
+                    let Some(settings) = AdvertisingSettings::decode(data) else {
+                        error!(?data, "advertising settings have the wrong length");
+                        return;
+                    };
+                    Some(settings)
+                };
+                if let Err(error) = set_advertising_settings(settings) {
+                    error!(?error, "Failed to apply the advertising settings");
+                }
+            });
+
+        health_characteristic.lock().on_read(move |value, _| {
+            value.set_value(&health_report());
+        });
+
         // TODO: Age files on file system
 
         cat_management_service
     }
 }
+
+/// Delete all files on the filesystem
+///
+/// The filesystem is formatted if no file is in use. Otherwise the running program keeps its file until it is stopped, as files are only deleted after their last reader is dropped.
+fn erase_filesystem() {
+    let Ok(filesystem) = get_filesystem() else {
+        error!("Failed to get the filesystem");
+        return;
+    };
+    let Ok(mut filesystem) = filesystem.write() else {
+        error!("Failed to lock the filesystem");
+        return;
+    };
+    match filesystem.format() {
+        Ok(()) => {
+            info!("Formatted the filesystem");
+            return;
+        }
+        Err(FilesystemDeleteError::FileInUse) => {}
+        Err(error) => {
+            error!("Failed to format the filesystem: {}", error);
+            return;
+        }
+    }
+    for file in filesystem.dump_directory() {
+        if let Err(error) = filesystem.delete_file(&file.name) {
+            error!("Failed to delete {}: {}", file.name, error);
+        }
+    }
+    info!("Erased the filesystem");
+}
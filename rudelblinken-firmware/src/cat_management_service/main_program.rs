@@ -33,15 +33,16 @@
 //!
 use crate::config::{failure_counter, failure_flag, main_program};
 use crate::wasm_service::wasm_host::HostEvent;
-use crate::wasm_service::wasm_host::WasmHost;
+use crate::wasm_service::wasm_host::{turn_off_leds, WasmHost};
 use crate::{wasm_service, BLE_DEVICE};
 use esp32_nimble::BLEScan;
 use esp_idf_hal::task;
 use load_main_program::load_main_program;
-use rudelblinken_runtime::host::Advertisement;
+use rudelblinken_runtime::host::{Advertisement, UNKNOWN_RSSI};
+use rudelblinken_runtime::linker::YieldTermination;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::Sender;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{sync::mpsc, time::Duration};
 use tracing::{error, info, warn};
 
@@ -55,6 +56,9 @@ const PROGRAM_SUCCESS_DURATION: Duration = Duration::from_secs(30);
 /// The max number of consecutive crashed a program is allowed to have before its deleted
 const MAX_CONSECUTIVE_FAILURES: u32 = 5;
 
+/// Hash of the program that is currently executing. `None` while the default program or no program is running
+static RUNNING_PROGRAM: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+
 fn log_heap_stats() {
     info!(
         free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() },
@@ -103,6 +107,13 @@ impl WasmRunner {
         self.sender.send(HostEvent::ProgramChanged()).unwrap();
     }
 
+    /// Get the hash of the program that is currently executing
+    ///
+    /// This differs from the main program until the runner picked up a newly set main program. It is `None` while the default program is running.
+    pub fn running_program() -> Option<[u8; 32]> {
+        return *RUNNING_PROGRAM.lock().unwrap();
+    }
+
     /// The main loop of the wasm runner. Won't return
     fn runner_thread(mut host: WasmHost) -> ! {
         std::thread::sleep(MAIN_PROGRAM_DELAY);
@@ -152,11 +163,20 @@ impl WasmRunner {
                 }
                 failure_flag::set(&false);
             });
+            *RUNNING_PROGRAM.lock().unwrap() = program.hash();
             let result = instance.run();
+            *RUNNING_PROGRAM.lock().unwrap() = None;
             process_exited_by_now.store(true, Ordering::Relaxed);
+            // Release the old program before loading the next one, so its file can be deleted
+            drop(instance);
+            drop(program);
+            turn_off_leds();
 
             match result {
                 Ok(_) => info!("Wasm module finished execution"),
+                Err(err) if err.downcast_ref::<YieldTermination>().is_some() => {
+                    info!("Stopped the wasm module, because the program changed")
+                }
                 Err(err) => {
                     error!("Wasm module failed to execute: {}", err);
                 }
@@ -191,6 +211,7 @@ impl WasmRunner {
                                     data,
                                     data_length: data_length as u8,
                                     received_at: now,
+                                    rssi: i8::try_from(dev.rssi()).unwrap_or(UNKNOWN_RSSI),
                                 }))
                                 .unwrap();
                         }
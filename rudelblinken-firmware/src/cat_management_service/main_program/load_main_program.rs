@@ -1,5 +1,6 @@
 //! Load the main program from the filesystem or return the default program
 use crate::config::main_program;
+use crate::file_upload_service::is_decompressing;
 use crate::storage::{get_filesystem, CreateStorageError};
 use crate::{storage::FlashStorage, wasm_service::wasm_host::WasmHost};
 use esp_idf_sys::{
@@ -17,6 +18,8 @@ const LOAD_MAIN_PROGRAM_RETRY_DELAY: Duration = Duration::from_millis(200);
 const MAX_MAIN_PROGRAM_FS_LOCK_ATTEMPTS: usize = 50;
 /// The max number of attempts to read the main program before deleting it and returning the default program
 const MAX_MAIN_PROGRAM_UPGRADE_ATTEMPTS: usize = 5;
+/// Every WASM module starts with these bytes
+const WASM_MAGIC: [u8; 4] = *b"\0asm";
 
 /// A wasm program as a byte slice
 ///
@@ -28,6 +31,15 @@ pub enum WasmProgram {
     Default,
     MainProgram(File<FlashStorage, { FileState::Reader }>),
 }
+impl WasmProgram {
+    /// The hash of the program file. The default program has no hash
+    pub fn hash(&self) -> Option<[u8; 32]> {
+        match self {
+            WasmProgram::Default => None,
+            WasmProgram::MainProgram(file) => Some(*file.hash()),
+        }
+    }
+}
 impl AsRef<[u8]> for WasmProgram {
     fn as_ref(&self) -> &[u8] {
         match self {
@@ -129,10 +141,21 @@ pub fn load_main_program(host: &mut WasmHost) -> WasmProgram {
             continue;
         };
         let Some(file) = filesystem_reader.read_file_by_hash(&current_main_program) else {
+            // A compressed upload is decompressed in the background, so the program may not exist yet
+            if is_decompressing(&current_main_program) {
+                continue;
+            }
             // If the main program does not exist on the filesystem, we can remove the reference to it
             main_program::set(&None);
             return WasmProgram::Default;
         };
+        // Only read the magic, so a file that is not a WASM module is never opened as the main program
+        let mut magic = [0u8; WASM_MAGIC.len()];
+        if file.read_range(0, &mut magic).is_ok() && magic != WASM_MAGIC {
+            tracing::warn!("The main program is not a WASM module; Using the default program");
+            main_program::set(&None);
+            return WasmProgram::Default;
+        }
         let Ok(reader) = file.upgrade() else {
             // If the file is not readable, it may have been deleted or is still beeing created.
             // We wait a bit and delete it, if it does not become available
@@ -298,13 +298,14 @@ macro_rules! config_value {
 
                 Some(target)
             },
-            |v: Option<&[u8]>| match v.map(|v| v.try_into()) {
-                Some(Result::<&[u8; $max_size + 1], _>::Ok(buffer)) => {
-                    let length = buffer[$max_size] as usize;
+            // The last byte is the length. Shorter buffers were written with a smaller max size and are still accepted
+            |v: Option<&[u8]>| match v.and_then(|v| v.split_last()) {
+                Some((length, buffer)) => {
+                    let length = std::cmp::min(*length as usize, buffer.len());
                     let string = String::from_utf8_lossy(&buffer[..length]).to_string();
                     return Some(string);
                 }
-                _ => {
+                None => {
                     return None;
                 }
             }
@@ -358,6 +359,9 @@ macro_rules! config_value {
 
 config_value!(failure_flag, bool);
 config_value!(failure_counter, u32);
+config_value!(boot_count, u32);
 config_value!(main_program, Option<[u8; 32]>);
-config_value!(device_name, Option<String>, 8);
+config_value!(device_name, Option<String>, 16);
 config_value!(mac_address, Option<[u8; 6]>);
+config_value!(rudel_id, Option<[u8; 2]>);
+config_value!(advertising_settings, Option<[u8; 5]>);
@@ -1,11 +1,13 @@
 use crate::storage::{get_filesystem, CreateStorageError, FlashStorage};
+pub use decompress::is_decompressing;
 use incomplete_file::{IncompleteFile, ReceiveChunkError, VerifyFileError};
 use rudelblinken_filesystem::file::{FileState, UpgradeFileError};
 use thiserror::Error;
 use upload_request::UploadRequest;
+mod decompress;
 mod incomplete_file;
 mod low_level;
-mod upload_request;
+pub(crate) mod upload_request;
 
 #[derive(Debug)]
 pub struct FileUploadService {
@@ -40,6 +42,13 @@ pub enum FileUploadError {
     FailedToCreateFile(String),
 }
 
+/// A random name for a new file
+fn random_file_name() -> String {
+    let mut bytes = [0u8; 4];
+    unsafe { esp_idf_sys::esp_fill_random(bytes.as_mut_ptr() as *mut core::ffi::c_void, 4) };
+    return format!("fw-{}", u32::from_le_bytes(bytes));
+}
+
 impl FileUploadService {
     /// Start an upload with the last received settings. Cancels a currently ongoing upload
     fn start_upload(&mut self, upload_request: &UploadRequest) -> Result<(), FileUploadError> {
@@ -47,27 +56,31 @@ impl FileUploadService {
         ::tracing::info!(target: "file-upload", "Received hash {:?}", upload_request.hash);
 
         let checksums =
-            self.load_checksums(&upload_request.checksums, &upload_request.chunk_count())?;
+            Self::load_checksums(&upload_request.checksums, &upload_request.chunk_count())?;
 
-        let mut bytes = [0u8; 4];
-        unsafe { esp_idf_sys::esp_fill_random(bytes.as_mut_ptr() as *mut core::ffi::c_void, 4) };
-        let random_name = format!("fw-{}", u32::from_le_bytes(bytes));
+        // A compressed upload is stored under the upload hash until it is decompressed, so it is not mistaken for the decompressed file
+        let upload_hash = upload_request.upload_hash();
+        let random_name = random_file_name();
         let writer = {
             let mut filesystem_writer = get_filesystem()?
                 .write()
                 .map_err(|_| FileUploadError::LockFilesystemError)?;
             filesystem_writer
-                .get_file_writer(&random_name, upload_request.file_size, &upload_request.hash)
+                .get_file_writer(&random_name, upload_request.file_size, &upload_hash)
                 .map_err(|error| FileUploadError::FailedToCreateFile(format!("{}", error)))?
         };
 
+        let decompressed_hash = upload_request
+            .is_compressed()
+            .then_some(upload_request.hash);
         let file = IncompleteFile::new(
-            upload_request.hash,
+            upload_hash,
             checksums.clone(),
             upload_request.chunk_size,
             upload_request.file_size,
             writer,
             random_name,
+            decompressed_hash,
         );
         self.currently_receiving = Some(file);
         Ok(())
@@ -103,14 +116,13 @@ impl FileUploadService {
             let incomplete_file = maybe_current_upload
                 .take()
                 .ok_or(FileUploadError::NoUploadActive)?;
-            incomplete_file.into_file(&get_filesystem().unwrap().read().unwrap())?;
+            incomplete_file.into_file(get_filesystem()?)?;
         }
         Ok(())
     }
 
     /// Read a file from the filesystem
     pub fn get_file(
-        &self,
         hash: &[u8; 32],
     ) -> Option<rudelblinken_filesystem::file::File<FlashStorage, { FileState::Weak }>> {
         let filesystem = get_filesystem().unwrap();
@@ -121,9 +133,10 @@ impl FileUploadService {
         filesystem_reader.read_file_by_hash(hash)
     }
 
-    /// Load
-    fn load_checksums(
-        &self,
+    /// Load the checksums of an upload request
+    ///
+    /// For uploads with more than 32 chunks this reads the previously uploaded checksums file.
+    pub(crate) fn load_checksums(
         checksums: &[u8; 32],
         chunk_count: &u32,
     ) -> Result<Vec<u8>, FileUploadError> {
@@ -134,7 +147,7 @@ impl FileUploadService {
         }
 
         let hash: &[u8; 32] = checksums.into();
-        let Some(file) = self.get_file(hash) else {
+        let Some(file) = Self::get_file(hash) else {
             return Err(FileUploadError::ChecksumFileDoesNotExist);
         };
         let new_checksums: Vec<u8> = file
@@ -0,0 +1,122 @@
+//! Decompress a compressed upload into a new file
+//!
+//! The chunks of an upload can arrive in any order, so a compressed upload is first stored as it is. Once it is complete, it is decompressed into a new file and deleted. Decompressing takes a while, so it runs on its own thread instead of the BLE callback that received the last chunk.
+use crate::{file_upload_service::random_file_name, storage::FlashStorage};
+use rudelblinken_filesystem::{
+    compression::inflate,
+    file::{File as FileContent, FileState},
+    Filesystem,
+};
+use std::sync::{Mutex, RwLock};
+use thiserror::Error;
+
+/// Hashes of the files that are currently being decompressed
+static DECOMPRESSING: Mutex<Vec<[u8; 32]>> = Mutex::new(Vec::new());
+
+#[derive(Error, Debug, Clone)]
+pub enum DecompressError {
+    #[error("Failed to lock filesystem")]
+    LockFilesystemError,
+    #[error("Failed to read the compressed upload: {0}")]
+    FailedToReadUpload(String),
+    #[error("The compressed upload is too short to contain the length of the file")]
+    MissingLength,
+    #[error("Failed to decompress the upload: {0}")]
+    FailedToDecompress(String),
+    #[error("Hashes of the decompressed file do not match")]
+    HashMismatch,
+    #[error("Failed to create the decompressed file: {0}")]
+    FailedToCreateFile(String),
+    #[error("Failed to start decompressing: {0}")]
+    FailedToStartThread(String),
+}
+
+/// Whether the file with `hash` is currently being decompressed and will exist soon
+pub fn is_decompressing(hash: &[u8; 32]) -> bool {
+    return DECOMPRESSING
+        .lock()
+        .map(|decompressing| decompressing.contains(hash))
+        .unwrap_or(false);
+}
+
+/// Decompress the complete upload `compressed_name` into a new file in the background and delete the upload
+///
+/// `hash` is the hash of the decompressed file. The upload is deleted even if decompressing fails, as it can not be used for anything else.
+pub(super) fn decompress_upload(
+    filesystem: &'static RwLock<Filesystem<FlashStorage>>,
+    compressed: FileContent<FlashStorage, { FileState::Weak }>,
+    compressed_name: String,
+    hash: [u8; 32],
+) -> Result<(), DecompressError> {
+    if let Ok(mut decompressing) = DECOMPRESSING.lock() {
+        decompressing.push(hash);
+    }
+    let spawned = std::thread::Builder::new()
+        .name("decompress".to_owned())
+        .stack_size(0x4000)
+        .spawn(move || {
+            if let Err(error) = decompress_into_new_file(filesystem, compressed, &hash) {
+                ::tracing::error!(target: "file-upload", "Failed to decompress the upload: {}", error);
+            }
+            delete_upload(filesystem, &compressed_name);
+            finish_decompressing(&hash);
+        });
+    if let Err(error) = spawned {
+        finish_decompressing(&hash);
+        delete_upload(filesystem, &compressed_name);
+        return Err(DecompressError::FailedToStartThread(error.to_string()));
+    }
+    return Ok(());
+}
+
+fn finish_decompressing(hash: &[u8; 32]) {
+    if let Ok(mut decompressing) = DECOMPRESSING.lock() {
+        decompressing.retain(|other| other != hash);
+    }
+}
+
+fn delete_upload(filesystem: &RwLock<Filesystem<FlashStorage>>, compressed_name: &str) {
+    match filesystem.write() {
+        Ok(mut filesystem) => {
+            if let Err(error) = filesystem.delete_file(compressed_name) {
+                ::tracing::warn!(target: "file-upload", "Failed to delete the compressed upload: {}", error);
+            }
+        }
+        Err(_) => {
+            ::tracing::warn!(target: "file-upload", "Failed to lock the filesystem to delete the compressed upload")
+        }
+    }
+}
+
+fn decompress_into_new_file(
+    filesystem: &RwLock<Filesystem<FlashStorage>>,
+    compressed: FileContent<FlashStorage, { FileState::Weak }>,
+    hash: &[u8; 32],
+) -> Result<(), DecompressError> {
+    let compressed = compressed
+        .upgrade()
+        .map_err(|error| DecompressError::FailedToReadUpload(error.to_string()))?;
+    let (length, input) = compressed
+        .split_first_chunk::<4>()
+        .ok_or(DecompressError::MissingLength)?;
+    let length = u32::from_le_bytes(*length);
+
+    let name = random_file_name();
+    let mut writer = filesystem
+        .write()
+        .map_err(|_| DecompressError::LockFilesystemError)?
+        .get_file_writer(&name, length, hash)
+        .map_err(|error| DecompressError::FailedToCreateFile(error.to_string()))?;
+
+    let decompressed_hash = inflate(input, length, &mut writer)
+        .map_err(|error| DecompressError::FailedToDecompress(error.to_string()))?;
+    if &decompressed_hash != hash {
+        return Err(DecompressError::HashMismatch);
+    }
+    ::tracing::info!(target: "file-upload", "Decompressed {} bytes into {} bytes", compressed.len(), length);
+
+    writer
+        .commit()
+        .map_err(|error| DecompressError::FailedToCreateFile(error.to_string()))?;
+    return Ok(());
+}
@@ -1,10 +1,11 @@
+use super::decompress::{decompress_upload, DecompressError};
 use crate::storage::FlashStorage;
 use itertools::Itertools;
 use rudelblinken_filesystem::{
     file::{File as FileContent, FileState},
     Filesystem,
 };
-use std::io::{Seek, Write};
+use std::sync::RwLock;
 use thiserror::Error;
 
 #[derive(Debug)]
@@ -16,6 +17,8 @@ pub(super) struct IncompleteFile {
     length: u32,
     name: String,
     hash: [u8; 32],
+    /// Hash of the decompressed file, if the upload is compressed. `hash` is the upload hash then
+    decompressed_hash: Option<[u8; 32]>,
 }
 
 #[derive(Error, Debug, Clone)]
@@ -32,6 +35,10 @@ pub enum VerifyFileError {
     NotComplete,
     #[error("Hashes do not match")]
     HashMismatch,
+    #[error("Failed to lock filesystem")]
+    LockFilesystemError,
+    #[error(transparent)]
+    DecompressError(#[from] DecompressError),
 }
 
 impl IncompleteFile {
@@ -42,6 +49,7 @@ impl IncompleteFile {
         length: u32,
         writer: FileContent<FlashStorage, { FileState::Writer }>,
         name: String,
+        decompressed_hash: Option<[u8; 32]>,
     ) -> Self {
         Self {
             incomplete_file: writer,
@@ -51,6 +59,7 @@ impl IncompleteFile {
             length,
             name,
             hash,
+            decompressed_hash,
         }
     }
 
@@ -77,11 +86,8 @@ impl IncompleteFile {
             return Err(ReceiveChunkError::WrongChecksum);
         }
 
-        let offset = self.chunk_length as usize * index as usize;
-        self.incomplete_file
-            .seek(std::io::SeekFrom::Start(offset as u64))
-            .unwrap();
-        self.incomplete_file.write(data).unwrap();
+        let offset = self.chunk_length as u32 * index as u32;
+        self.incomplete_file.write_at(offset, data).unwrap();
         // self.incomplete_file.content[offset..(data.len() + offset)].copy_from_slice(data);
         self.received_chunks[index as usize] = true;
 
@@ -101,6 +107,8 @@ impl IncompleteFile {
         self.received_chunks.iter().all(|received| *received)
     }
     /// Verify that the received file is complete and has the correct hash
+    ///
+    /// Compressed uploads are only checked for completeness, their hash is checked while decompressing them.
     pub fn verify_hash(
         self,
         filesystem: &Filesystem<FlashStorage>,
@@ -110,6 +118,9 @@ impl IncompleteFile {
         }
         self.incomplete_file.commit().unwrap();
         let file = filesystem.read_file(&self.name).unwrap();
+        if self.decompressed_hash.is_some() {
+            return Ok(file);
+        }
         let mut hasher = blake3::Hasher::new();
         hasher.update(file.upgrade().unwrap().as_ref());
 
@@ -126,14 +137,27 @@ impl IncompleteFile {
         Ok(file)
     }
     /// Get the uploaded file, if the upload is finished, otherwise this return None and you just destroyed your incomplete file for no reason
+    ///
+    /// Compressed uploads are decompressed into a new file in the background, so this returns `None` for them. Use [super::is_decompressing] to check whether the file exists yet.
     pub fn into_file(
         self,
-        filesystem: &Filesystem<FlashStorage>,
-    ) -> Result<FileContent<FlashStorage, { FileState::Weak }>, VerifyFileError> {
-        let file = self.verify_hash(filesystem)?;
-        Ok(file)
+        filesystem: &'static RwLock<Filesystem<FlashStorage>>,
+    ) -> Result<Option<FileContent<FlashStorage, { FileState::Weak }>>, VerifyFileError> {
+        let name = self.name.clone();
+        let decompressed_hash = self.decompressed_hash;
+        let file = self.verify_hash(
+            &filesystem
+                .read()
+                .map_err(|_| VerifyFileError::LockFilesystemError)?,
+        )?;
+        let Some(decompressed_hash) = decompressed_hash else {
+            return Ok(Some(file));
+        };
+        decompress_upload(filesystem, file, name, decompressed_hash)?;
+        Ok(None)
     }
 
+    /// The hash that identifies the upload. For compressed uploads this is not the hash of the file
     pub fn get_hash(&self) -> &[u8; 32] {
         &self.hash
     }
@@ -1,6 +1,21 @@
 // This file exists twice, once here and once in rudelctl
 use zerocopy::{Immutable, IntoBytes, KnownLayout, TryFromBytes};
 
+/// Flag of an upload whose data is compressed
+///
+/// The data is the length of the decompressed file as little endian `u32`, followed by the file as a raw deflate stream. The file size, the chunk size and the checksums describe the compressed data, the hash is the hash of the decompressed file.
+pub const COMPRESSED: u16 = 1;
+
+/// The hash reported while receiving a compressed upload of the file with `hash`
+///
+/// This keeps a compressed upload from being resumed with the chunks of an uncompressed upload of the same file and the other way round.
+pub fn compressed_upload_hash(hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"rudelblinken compressed upload");
+    hasher.update(hash);
+    return hasher.finalize().into();
+}
+
 // TODO: Implement better debug printing
 #[derive(Debug, Clone, TryFromBytes, IntoBytes, Immutable, KnownLayout, PartialEq, PartialOrd)]
 #[repr(C)]
@@ -17,8 +32,8 @@ pub struct UploadRequest {
     pub file_name: [u8; 16],
     /// Size of a single chunk
     pub chunk_size: u16,
-    /// Unused padding. Reserved for future use
-    pub _padding: u16,
+    /// Flags like [COMPRESSED]. Firmwares without support for a flag ignore it
+    pub flags: u16,
 }
 
 impl UploadRequest {
@@ -26,4 +41,17 @@ impl UploadRequest {
     pub fn chunk_count(&self) -> u32 {
         self.file_size.div_ceil(self.chunk_size as u32)
     }
+
+    /// Whether the data is compressed, see [COMPRESSED]
+    pub fn is_compressed(&self) -> bool {
+        return self.flags & COMPRESSED != 0;
+    }
+
+    /// The hash that identifies the upload while it is received
+    pub fn upload_hash(&self) -> [u8; 32] {
+        if self.is_compressed() {
+            return compressed_upload_hash(&self.hash);
+        }
+        return self.hash;
+    }
 }
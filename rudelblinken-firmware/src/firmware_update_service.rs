@@ -0,0 +1,359 @@
+//! Update the firmware over BLE
+//!
+//! The protocol is the same as for the file upload service, but the chunks are written to the next OTA partition instead of the filesystem. Once all chunks are received and the hash matches, the new partition is marked as bootable and the device reboots. If the new firmware does not come up, the bootloader rolls back to the previous one.
+//!
+//! Updates need to be signed, otherwise anyone in range could flash their own firmware. The upload is the app image followed by an ed25519 signature of the blake3 hash of the image. The signature is checked against the key in `RUDELBLINKEN_UPDATE_KEY` at build time, before the new partition is marked as bootable. Firmwares built without a key reject all updates. `rudelctl generate-update-key` creates a key pair and `rudelctl update-firmware --signing-key` signs the image.
+use crate::file_upload_service::{
+    upload_request::UploadRequest, FileUploadError, FileUploadService,
+};
+use ed25519_dalek::{Signature, VerifyingKey, SIGNATURE_LENGTH};
+use esp32_nimble::utilities::mutex::Mutex;
+use esp_idf_sys::{
+    esp, esp_chip_id_t_ESP_CHIP_ID_ESP32C3, esp_ota_abort, esp_ota_begin, esp_ota_end,
+    esp_ota_get_next_update_partition, esp_ota_handle_t, esp_ota_set_boot_partition,
+    esp_ota_write_with_offset, esp_partition_read, esp_partition_t, esp_restart, EspError,
+};
+use std::{
+    sync::{mpsc::Receiver, Arc},
+    time::Duration,
+};
+use thiserror::Error;
+mod low_level;
+
+/// The first byte of every ESP-IDF app image
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+/// Offset of the chip id in the image header
+const ESP_IMAGE_CHIP_ID_OFFSET: usize = 12;
+/// The app description follows the image header and the first segment header
+const ESP_APP_DESC_OFFSET: usize = 32;
+const ESP_APP_DESC_MAGIC: u32 = 0xABCD5432;
+/// Time between accepting a new firmware and rebooting into it, so the last write can still be acknowledged
+const REBOOT_DELAY: Duration = Duration::from_secs(1);
+/// Public key that signs the updates as 64 hex digits, set when building the firmware
+const UPDATE_KEY: Option<&str> = option_env!("RUDELBLINKEN_UPDATE_KEY");
+
+/// The key that signs updates. `None` if the firmware was built without one
+fn update_key() -> Option<VerifyingKey> {
+    let hex = UPDATE_KEY?;
+    let mut key = [0u8; 32];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    return VerifyingKey::from_bytes(&key).ok();
+}
+
+/// Pointer to a partition in the partition table
+///
+/// The partition table is static, so the pointer stays valid forever
+#[derive(Debug, Clone, Copy)]
+struct Partition(*const esp_partition_t);
+
+unsafe impl Send for Partition {}
+
+#[derive(Error, Debug, Clone)]
+pub enum FirmwareUpdateError {
+    #[error(transparent)]
+    FileUploadError(#[from] FileUploadError),
+    #[error("There is no OTA partition to write the update to")]
+    NoUpdatePartition,
+    #[error("The firmware has {size} bytes, but the update partition only has {available}")]
+    FirmwareTooLarge { size: u32, available: u32 },
+    #[error("Cannot receive chunk when no update is active")]
+    NoUpdateActive,
+    #[error("Received chunk is way too short")]
+    ReceivedChunkWayTooShort,
+    #[error("Chunk has an invalid length")]
+    InvalidChunkLength,
+    #[error("Chunk has the wrong checksum")]
+    WrongChecksum,
+    #[error("Firmware updates can not be compressed")]
+    CompressedUpdate,
+    #[error("The update is not an app image for this chip")]
+    InvalidImage,
+    #[error("Hashes do not match")]
+    HashMismatch,
+    #[error("This firmware was built without a key for updates")]
+    UpdatesDisabled,
+    #[error("The update is too short to contain a signature")]
+    MissingSignature,
+    #[error("The update is not signed with the update key")]
+    InvalidSignature,
+    #[error("ESP-IDF OTA error: {0}")]
+    OtaError(#[from] EspError),
+}
+
+/// A firmware update that is currently being received
+#[derive(Debug)]
+struct IncompleteUpdate {
+    handle: esp_ota_handle_t,
+    partition: Partition,
+    checksums: Vec<u8>,
+    received_chunks: Vec<bool>,
+    chunk_length: u16,
+    /// Length of the upload, including the signature
+    length: u32,
+    hash: [u8; 32],
+    key: VerifyingKey,
+}
+
+impl IncompleteUpdate {
+    fn receive_chunk(&mut self, data: &[u8], index: u16) -> Result<(), FirmwareUpdateError> {
+        let Some(expected_checksum) = self.checksums.get(index as usize) else {
+            return Err(FirmwareUpdateError::InvalidChunkLength);
+        };
+        let expected_length = if index as usize == self.checksums.len() - 1 {
+            self.length as usize - self.chunk_length as usize * index as usize
+        } else {
+            self.chunk_length as usize
+        };
+        if data.len() != expected_length {
+            return Err(FirmwareUpdateError::InvalidChunkLength);
+        }
+
+        let crc8_generator = crc::Crc::<u8>::new(&crc::CRC_8_LTE);
+        if crc8_generator.checksum(data) != *expected_checksum {
+            return Err(FirmwareUpdateError::WrongChecksum);
+        }
+
+        // Check the header early, so we dont receive a whole image that can never boot
+        if index == 0 && !looks_like_app_image(data) {
+            return Err(FirmwareUpdateError::InvalidImage);
+        }
+
+        let offset = self.chunk_length as u32 * index as u32;
+        esp!(unsafe {
+            esp_ota_write_with_offset(self.handle, data.as_ptr().cast(), data.len(), offset)
+        })?;
+        self.received_chunks[index as usize] = true;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received_chunks.iter().all(|received| *received)
+    }
+
+    fn get_status(&self) -> (u16, Vec<u16>) {
+        let missing_chunks: Vec<u16> = self
+            .received_chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, received)| !**received)
+            .map(|(index, _)| index as u16)
+            .collect();
+        let progress = self.received_chunks.len() as u16 - missing_chunks.len() as u16;
+        (progress, missing_chunks)
+    }
+
+    /// Hash the data that was written to the update partition
+    ///
+    /// Returns the hash of the whole upload, the hash of the image without the signature and the signature.
+    fn hash_partition(&self) -> Result<([u8; 32], [u8; 32], Signature), FirmwareUpdateError> {
+        let image_length = self.length as usize - SIGNATURE_LENGTH;
+        let mut hasher = blake3::Hasher::new();
+        let mut image_hasher = blake3::Hasher::new();
+        let mut buffer = [0u8; 4096];
+        let mut offset = 0usize;
+        while offset < image_length {
+            let length = std::cmp::min(buffer.len(), image_length - offset);
+            esp!(unsafe {
+                esp_partition_read(self.partition.0, offset, buffer.as_mut_ptr().cast(), length)
+            })?;
+            hasher.update(&buffer[0..length]);
+            image_hasher.update(&buffer[0..length]);
+            offset += length;
+        }
+        let mut signature = [0u8; SIGNATURE_LENGTH];
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition.0,
+                image_length,
+                signature.as_mut_ptr().cast(),
+                SIGNATURE_LENGTH,
+            )
+        })?;
+        hasher.update(&signature);
+        Ok((
+            hasher.finalize().into(),
+            image_hasher.finalize().into(),
+            Signature::from_bytes(&signature),
+        ))
+    }
+
+    /// Verify the received firmware and its signature and make it the boot partition
+    fn finish(self) -> Result<(), FirmwareUpdateError> {
+        let (hash, image_hash, signature) = match self.hash_partition() {
+            Ok(hashes) => hashes,
+            Err(error) => {
+                self.abort();
+                return Err(error);
+            }
+        };
+        if hash != self.hash {
+            ::tracing::warn!(target: "firmware-update", "Hashes dont match.\nExpected: {:?}\nGot     : {:?}", self.hash, hash);
+            self.abort();
+            return Err(FirmwareUpdateError::HashMismatch);
+        }
+        if self.key.verify_strict(&image_hash, &signature).is_err() {
+            ::tracing::warn!(target: "firmware-update", "Rejecting an update that is not signed with the update key");
+            self.abort();
+            return Err(FirmwareUpdateError::InvalidSignature);
+        }
+        // esp_ota_end also validates the image and frees the handle, even if it fails
+        esp!(unsafe { esp_ota_end(self.handle) })?;
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition.0) })?;
+        Ok(())
+    }
+
+    fn abort(self) {
+        unsafe { esp_ota_abort(self.handle) };
+    }
+}
+
+/// Check the image header and the app description
+fn looks_like_app_image(first_chunk: &[u8]) -> bool {
+    if first_chunk.first() != Some(&ESP_IMAGE_MAGIC) {
+        return false;
+    }
+    let Some(chip_id) = first_chunk.get(ESP_IMAGE_CHIP_ID_OFFSET..ESP_IMAGE_CHIP_ID_OFFSET + 2)
+    else {
+        // The chunk is too short to contain more of the header
+        return true;
+    };
+    if u16::from_le_bytes([chip_id[0], chip_id[1]]) as u32 != esp_chip_id_t_ESP_CHIP_ID_ESP32C3 {
+        return false;
+    }
+    let Some(app_desc_magic) = first_chunk.get(ESP_APP_DESC_OFFSET..ESP_APP_DESC_OFFSET + 4) else {
+        return true;
+    };
+    u32::from_le_bytes(app_desc_magic.try_into().unwrap()) == ESP_APP_DESC_MAGIC
+}
+
+#[derive(Debug)]
+pub struct FirmwareUpdateService {
+    currently_receiving: Option<IncompleteUpdate>,
+    last_error: Option<FirmwareUpdateError>,
+}
+
+impl FirmwareUpdateService {
+    /// Start a new firmware update. Cancels a currently ongoing update
+    ///
+    /// Erasing the update partition takes a while, so this runs on the worker thread and only locks the service to swap the current update. Chunks that arrive before the erase is done are rejected, clients wait until the current hash matches their request before sending them.
+    fn start_update(
+        service: &Mutex<Self>,
+        upload_request: &UploadRequest,
+    ) -> Result<(), FirmwareUpdateError> {
+        ::tracing::info!(target: "firmware-update", "Received request {:?}", upload_request);
+
+        if let Some(previous_update) = service.lock().currently_receiving.take() {
+            ::tracing::info!(target: "firmware-update", "Aborting the previous update");
+            previous_update.abort();
+        }
+
+        let Some(key) = update_key() else {
+            return Err(FirmwareUpdateError::UpdatesDisabled);
+        };
+        if upload_request.is_compressed() {
+            return Err(FirmwareUpdateError::CompressedUpdate);
+        }
+        if upload_request.file_size as usize <= SIGNATURE_LENGTH {
+            return Err(FirmwareUpdateError::MissingSignature);
+        }
+
+        let checksums = FileUploadService::load_checksums(
+            &upload_request.checksums,
+            &upload_request.chunk_count(),
+        )?;
+
+        let partition = unsafe { esp_ota_get_next_update_partition(std::ptr::null()) };
+        if partition.is_null() {
+            return Err(FirmwareUpdateError::NoUpdatePartition);
+        }
+        let available = unsafe { (*partition).size };
+        if upload_request.file_size > available {
+            return Err(FirmwareUpdateError::FirmwareTooLarge {
+                size: upload_request.file_size,
+                available,
+            });
+        }
+
+        // Erases the part of the partition that will be written
+        let mut handle: esp_ota_handle_t = 0;
+        esp!(unsafe { esp_ota_begin(partition, upload_request.file_size as usize, &mut handle) })?;
+
+        service.lock().currently_receiving = Some(IncompleteUpdate {
+            handle,
+            partition: Partition(partition),
+            received_chunks: vec![false; checksums.len()],
+            checksums,
+            chunk_length: upload_request.chunk_size,
+            length: upload_request.file_size,
+            hash: upload_request.hash,
+            key,
+        });
+        Ok(())
+    }
+
+    /// Start the updates requested through `requests` one after another
+    ///
+    /// Only the latest of the queued requests is started, because it would cancel the ones before it anyway.
+    fn run_update_worker(service: Arc<Mutex<Self>>, requests: Receiver<UploadRequest>) {
+        while let Ok(mut upload_request) = requests.recv() {
+            while let Ok(newer_request) = requests.try_recv() {
+                upload_request = newer_request;
+            }
+            if let Err(e) = Self::start_update(&service, &upload_request) {
+                service.lock().log_error(e);
+            }
+        }
+    }
+
+    /// Called when an error occurs
+    fn log_error(&mut self, error: FirmwareUpdateError) {
+        ::tracing::error!(target: "firmware-update", "{}", error);
+        self.last_error = Some(error);
+    }
+
+    /// Called when a new chunk is received
+    fn data_write(&mut self, chunk: &[u8]) -> Result<(), FirmwareUpdateError> {
+        if chunk.len() < 3 {
+            return Err(FirmwareUpdateError::ReceivedChunkWayTooShort);
+        }
+
+        let index = u16::from_le_bytes([chunk[0], chunk[1]]);
+        let data = &chunk[2..];
+
+        let Some(current_update) = self.currently_receiving.as_mut() else {
+            return Err(FirmwareUpdateError::NoUpdateActive);
+        };
+        current_update.receive_chunk(data, index)?;
+        if !current_update.is_complete() {
+            return Ok(());
+        }
+
+        let update = self
+            .currently_receiving
+            .take()
+            .ok_or(FirmwareUpdateError::NoUpdateActive)?;
+        update.finish()?;
+        ::tracing::info!(target: "firmware-update", "Firmware update received. Rebooting into the new firmware");
+        std::thread::spawn(|| {
+            std::thread::sleep(REBOOT_DELAY);
+            unsafe { esp_restart() };
+        });
+        Ok(())
+    }
+
+    /// Get the hash of the current update.
+    fn current_hash(&self) -> Option<&[u8; 32]> {
+        self.currently_receiving
+            .as_ref()
+            .map(|incomplete_update| &incomplete_update.hash)
+    }
+
+    /// Get the status of the current update.
+    fn get_status(&self) -> Option<(u16, Vec<u16>)> {
+        self.currently_receiving
+            .as_ref()
+            .map(|incomplete_update| incomplete_update.get_status())
+    }
+}
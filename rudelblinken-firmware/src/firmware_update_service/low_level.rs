@@ -0,0 +1,218 @@
+use crate::{
+    file_upload_service::{upload_request::UploadRequest, FileUploadError},
+    service_helpers::DocumentableCharacteristic,
+};
+use esp32_nimble::{
+    cpfd::{ChrFormat, ChrUnit},
+    utilities::{mutex::Mutex, BleUuid},
+    BLEServer, BLEService, NimbleProperties,
+};
+use std::sync::{
+    mpsc::{channel, Sender},
+    Arc,
+};
+use zerocopy::TryFromBytes;
+
+use super::{FirmwareUpdateError, FirmwareUpdateService};
+
+const FIRMWARE_UPDATE_SERVICE: u16 = 0x9170;
+// Write data chunks here
+const FIRMWARE_UPDATE_SERVICE_DATA: u16 = 0x9171;
+// Write an upload request here to initiate an update.
+const FIRMWARE_UPDATE_SERVICE_START_UPDATE: u16 = 0x9172;
+// Read this to get the number of received chunks and the IDs of some missing chunks. Returns a list of u16
+const FIRMWARE_UPDATE_SERVICE_UPDATE_PROGRESS: u16 = 0x9173;
+// Read here to get the last error as a string
+const FIRMWARE_UPDATE_SERVICE_LAST_ERROR: u16 = 0x9174;
+// Read to get the hash of the current update.
+const FIRMWARE_UPDATE_SERVICE_CURRENT_HASH: u16 = 0x9176;
+
+const FIRMWARE_UPDATE_SERVICE_UUID: BleUuid = BleUuid::from_uuid16(FIRMWARE_UPDATE_SERVICE);
+const FIRMWARE_UPDATE_SERVICE_DATA_UUID: BleUuid =
+    BleUuid::from_uuid16(FIRMWARE_UPDATE_SERVICE_DATA);
+const FIRMWARE_UPDATE_SERVICE_START_UPDATE_UUID: BleUuid =
+    BleUuid::from_uuid16(FIRMWARE_UPDATE_SERVICE_START_UPDATE);
+const FIRMWARE_UPDATE_SERVICE_UPDATE_PROGRESS_UUID: BleUuid =
+    BleUuid::from_uuid16(FIRMWARE_UPDATE_SERVICE_UPDATE_PROGRESS);
+const FIRMWARE_UPDATE_SERVICE_LAST_ERROR_UUID: BleUuid =
+    BleUuid::from_uuid16(FIRMWARE_UPDATE_SERVICE_LAST_ERROR);
+const FIRMWARE_UPDATE_SERVICE_CURRENT_HASH_UUID: BleUuid =
+    BleUuid::from_uuid16(FIRMWARE_UPDATE_SERVICE_CURRENT_HASH);
+
+fn setup_service(server: &mut BLEServer) -> Arc<Mutex<BLEService>> {
+    server.create_service(FIRMWARE_UPDATE_SERVICE_UUID)
+}
+
+fn setup_data_characteristic(
+    service: &Arc<Mutex<BLEService>>,
+    firmware_update_service: &Arc<Mutex<FirmwareUpdateService>>,
+) {
+    let data_characteristic = service.lock().create_characteristic(
+        FIRMWARE_UPDATE_SERVICE_DATA_UUID,
+        NimbleProperties::WRITE_NO_RSP | NimbleProperties::WRITE,
+    );
+    data_characteristic.document(
+        "Firmware Chunk Upload",
+        ChrFormat::Struct,
+        0,
+        ChrUnit::Unitless,
+    );
+
+    let firmware_update_service_clone = firmware_update_service.clone();
+    data_characteristic.lock().on_write(move |args| {
+        let mut service = firmware_update_service_clone.lock();
+        let chunk = args.recv_data();
+        if let Err(e) = service.data_write(chunk) {
+            service.log_error(e);
+        }
+    });
+}
+
+fn setup_start_update_characteristic(
+    service: &Arc<Mutex<BLEService>>,
+    firmware_update_service: &Arc<Mutex<FirmwareUpdateService>>,
+    start_requests: Sender<UploadRequest>,
+) {
+    let start_update_characteristic = service.lock().create_characteristic(
+        FIRMWARE_UPDATE_SERVICE_START_UPDATE_UUID,
+        NimbleProperties::WRITE,
+    );
+    start_update_characteristic.document(
+        "Firmware Update Request",
+        ChrFormat::Struct,
+        0,
+        ChrUnit::Unitless,
+    );
+
+    let firmware_update_service_clone = firmware_update_service.clone();
+    start_update_characteristic.lock().on_write(move |args| {
+        let received_data = args.recv_data();
+        let upload_request = match UploadRequest::try_ref_from_bytes(received_data) {
+            Ok(upload_request) => upload_request,
+            Err(e) => {
+                firmware_update_service_clone.lock().log_error(
+                    FirmwareUpdateError::FileUploadError(FileUploadError::MalformedUploadRequest(
+                        e.to_string(),
+                    )),
+                );
+                return;
+            }
+        };
+
+        // Starting an update erases flash, which would stall the BLE host task
+        if start_requests.send(upload_request.clone()).is_err() {
+            ::tracing::error!(target: "firmware-update", "The update worker is not running");
+        }
+    });
+}
+
+fn setup_current_hash_characteristic(
+    service: &Arc<Mutex<BLEService>>,
+    firmware_update_service: &Arc<Mutex<FirmwareUpdateService>>,
+) {
+    let current_hash_characteristic = service.lock().create_characteristic(
+        FIRMWARE_UPDATE_SERVICE_CURRENT_HASH_UUID,
+        NimbleProperties::READ,
+    );
+    current_hash_characteristic.document(
+        "Hash of the current update",
+        ChrFormat::Struct,
+        0,
+        ChrUnit::Unitless,
+    );
+
+    let firmware_update_service_clone = firmware_update_service.clone();
+    current_hash_characteristic.lock().on_read(move |value, _| {
+        let service = firmware_update_service_clone.lock();
+        let current_hash = match service.current_hash() {
+            Some(current_hash) => current_hash,
+            None => &[0u8; 32],
+        };
+        value.set_value(current_hash);
+    });
+}
+
+fn setup_update_status_characteristic(
+    service: &Arc<Mutex<BLEService>>,
+    firmware_update_service: &Arc<Mutex<FirmwareUpdateService>>,
+) {
+    let update_status_characteristic = service.lock().create_characteristic(
+        FIRMWARE_UPDATE_SERVICE_UPDATE_PROGRESS_UUID,
+        NimbleProperties::READ,
+    );
+    update_status_characteristic.document(
+        "Number of received chunks + Missing Chunks",
+        ChrFormat::Struct,
+        0,
+        ChrUnit::Unitless,
+    );
+
+    let firmware_update_service_clone = firmware_update_service.clone();
+    update_status_characteristic
+        .lock()
+        .on_read(move |value, _| {
+            let service = firmware_update_service_clone.lock();
+            let (progress, missing_chunks) = service.get_status().unwrap_or((0, Vec::new()));
+
+            let mut update_status: Vec<u8> = Vec::new();
+            update_status.extend_from_slice(&progress.to_le_bytes());
+            update_status.extend(
+                missing_chunks
+                    .into_iter()
+                    .take(100)
+                    .flat_map(u16::to_le_bytes),
+            );
+
+            value.set_value(&update_status);
+        });
+}
+
+fn setup_last_error_characteristic(
+    service: &Arc<Mutex<BLEService>>,
+    firmware_update_service: &Arc<Mutex<FirmwareUpdateService>>,
+) {
+    let last_error_characteristic = service.lock().create_characteristic(
+        FIRMWARE_UPDATE_SERVICE_LAST_ERROR_UUID,
+        NimbleProperties::READ,
+    );
+    last_error_characteristic.document("Last error", ChrFormat::Utf8s, 0, ChrUnit::Unitless);
+
+    let firmware_update_service_clone = firmware_update_service.clone();
+    last_error_characteristic.lock().on_read(move |value, _| {
+        let service = firmware_update_service_clone.lock();
+        let Some(last_error) = &service.last_error else {
+            value.set_value(&[]);
+            return;
+        };
+        value.set_value(last_error.to_string().as_bytes());
+    });
+}
+
+impl FirmwareUpdateService {
+    /// Create a new FirmwareUpdateService and set up the necessary characteristics.
+    pub fn new(server: &mut BLEServer) -> Arc<Mutex<Self>> {
+        let firmware_update_service = Arc::new(Mutex::new(FirmwareUpdateService {
+            currently_receiving: None,
+            last_error: None,
+        }));
+
+        let (start_requests, received_requests) = channel();
+        let worker_service = firmware_update_service.clone();
+        std::thread::Builder::new()
+            .name("firmware_update".to_owned())
+            .stack_size(0x2000)
+            .spawn(move || {
+                FirmwareUpdateService::run_update_worker(worker_service, received_requests)
+            })
+            .unwrap();
+
+        let service = setup_service(server);
+        setup_data_characteristic(&service, &firmware_update_service);
+        setup_start_update_characteristic(&service, &firmware_update_service, start_requests);
+        setup_current_hash_characteristic(&service, &firmware_update_service);
+        setup_update_status_characteristic(&service, &firmware_update_service);
+        setup_last_error_characteristic(&service, &firmware_update_service);
+
+        firmware_update_service
+    }
+}
@@ -0,0 +1,43 @@
+//! Report the health of the device
+//!
+//! Deployed devices are hard to debug without a serial connection, so the cat management service exposes the uptime, the heap usage, the number of boots and the reason of the last reset.
+use crate::config;
+
+/// Length of the encoded health report
+pub const HEALTH_REPORT_LENGTH: usize = 21;
+
+/// Count this boot. Call this once during startup
+pub fn count_boot() {
+    config::boot_count::set(&config::boot_count::get().wrapping_add(1));
+}
+
+/// Encode the current health of the device
+///
+/// ## Layout
+///
+/// | Bytes   | Content                                                   |
+/// |---------|-----------------------------------------------------------|
+/// | 0..8    | Uptime in microseconds as little endian `u64`             |
+/// | 8..12   | Free heap in bytes as little endian `u32`                 |
+/// | 12..16  | Largest free heap block in bytes as little endian `u32`   |
+/// | 16..20  | Number of boots as little endian `u32`                    |
+/// | 20      | Reason of the last reset as `esp_reset_reason_t`          |
+pub fn health_report() -> [u8; HEALTH_REPORT_LENGTH] {
+    let (uptime, free_heap, largest_free_block, reset_reason) = unsafe {
+        (
+            esp_idf_sys::esp_timer_get_time() as u64,
+            esp_idf_sys::heap_caps_get_free_size(esp_idf_sys::MALLOC_CAP_DEFAULT) as u32,
+            esp_idf_sys::heap_caps_get_largest_free_block(esp_idf_sys::MALLOC_CAP_DEFAULT) as u32,
+            esp_idf_sys::esp_reset_reason() as u8,
+        )
+    };
+    let boot_count = config::boot_count::get();
+
+    let mut report = [0u8; HEALTH_REPORT_LENGTH];
+    report[0..8].copy_from_slice(&uptime.to_le_bytes());
+    report[8..12].copy_from_slice(&free_heap.to_le_bytes());
+    report[12..16].copy_from_slice(&largest_free_block.to_le_bytes());
+    report[16..20].copy_from_slice(&boot_count.to_le_bytes());
+    report[20] = reset_reason;
+    return report;
+}
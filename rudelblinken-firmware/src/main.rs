@@ -1,22 +1,37 @@
 #![feature(once_cell_try)]
 
+use advertising::advertising_settings;
+use battery::{battery_level, start_battery_monitor};
 use cat_management_service::CatManagementService;
 use esp32_nimble::{
     enums::{ConnMode, DiscMode, PowerLevel, PowerType},
-    BLEAdvertisementData, BLEDevice, BLEServer,
+    BLEDevice, BLEError, BLEServer,
 };
 use esp_idf_sys::{self as _, heap_caps_print_heap_info, MALLOC_CAP_DEFAULT};
 use file_upload_service::FileUploadService;
+use firmware_update_service::FirmwareUpdateService;
 use name::initialize_name;
 use nrf_logging_service::SerialLoggingService;
-use std::{sync::LazyLock, time::Duration};
+use rudel::rudel_id;
+use rudelblinken_runtime::advertisement_layout::{
+    encode_advertisement, encode_scan_response, APPEARANCE, MAX_NAME_LENGTH, NAME_PREFIX,
+};
+use std::{
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
 use storage::get_filesystem;
 
+mod advertising;
+mod battery;
 mod cat_management_service;
 mod config;
 mod file_upload_service;
+mod firmware_update_service;
+mod health;
 mod name;
 mod nrf_logging_service;
+mod rudel;
 pub mod service_helpers;
 pub mod storage;
 mod wasm_service;
@@ -84,7 +99,12 @@ fn setup_ble_server() -> &'static mut BLEServer {
         .set_power(PowerType::Default, PowerLevel::P3)
         .unwrap();
     ble_device
-        .set_power(PowerType::Advertising, PowerLevel::P3)
+        .set_power(
+            PowerType::Advertising,
+            advertising_settings()
+                .power_level()
+                .unwrap_or(PowerLevel::P3),
+        )
         .unwrap();
     ble_device
         .set_power(PowerType::Scan, PowerLevel::P3)
@@ -156,28 +176,68 @@ pub fn print_memory_info() {
 
 pub static BLE_DEVICE: LazyLock<&'static mut BLEDevice> = LazyLock::new(|| BLEDevice::take());
 
-/// Create an BLE advertisement data with the given manufacturer data and common rudelblinken data
+/// The manufacturer data of the current advertisement
+static MANUFACTURER_DATA: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Create a raw BLE advertisement with the given manufacturer data and common rudelblinken data
+///
+/// If `data` is `None`, the manufacturer data of the previous advertisement is kept. Returns `None` if `data` does not fit into the advertisement. The layout is described in [rudelblinken_runtime::advertisement_layout].
+///
+/// The device name is not part of the advertisement, see [create_ble_scan_response].
+pub fn create_ble_advertisment(data: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut manufacturer_data = MANUFACTURER_DATA.lock().unwrap();
+    let advertisement = encode_advertisement(
+        data.or(manufacturer_data.as_deref()),
+        rudel_id(),
+        battery_level(),
+    )?;
+    if let Some(data) = data {
+        *manufacturer_data = Some(data.to_vec());
+    }
+    Some(advertisement)
+}
+
+/// Create the raw BLE scan response with the device name and the appearance
+///
+/// The name is sent in the scan response, so it does not take space from the manufacturer data of the WASM guest. `rudelctl` scans actively and receives the scan response. Other devices scan passively and only look at the advertisement.
 ///
 /// This also updates the device name
-pub fn create_ble_advertisment(data: Option<&[u8]>) -> BLEAdvertisementData {
-    let name = config::device_name::get().unwrap_or_default();
-    let advertised_name = "[rb]".to_string() + &name;
+pub fn create_ble_scan_response() -> Vec<u8> {
+    let mut name = config::device_name::get().unwrap_or_default();
+    // Names are validated when they are set, so this only happens with a corrupted config
+    while name.len() > MAX_NAME_LENGTH {
+        name.pop();
+    }
+    let advertised_name = NAME_PREFIX.to_string() + &name;
 
     // Set the values for the generic access service
     //
     // Technically we would only need to do this after a reboot or a change of the name
     unsafe {
         esp_idf_sys::ble_svc_gap_device_name_set(advertised_name.as_ptr().cast());
-        esp_idf_sys::ble_svc_gap_device_appearance_set(0x07C0);
+        esp_idf_sys::ble_svc_gap_device_appearance_set(APPEARANCE);
     }
     let _ = BLEDevice::set_device_name(&advertised_name);
 
-    let mut advertisement = BLEAdvertisementData::new();
-    advertisement.name(&advertised_name).appearance(0x07C0);
-    if let Some(data) = data {
-        advertisement.manufacturer_data(data);
-    }
-    advertisement
+    encode_scan_response(&name).unwrap_or_default()
+}
+
+/// Advertise the name that is currently stored in the config
+pub fn update_scan_response() -> Result<(), BLEError> {
+    let scan_response = create_ble_scan_response();
+    BLE_DEVICE
+        .get_advertising()
+        .lock()
+        .set_raw_scan_response_data(&scan_response)
+}
+
+/// Advertise the Rudel id and the battery level that are currently known
+pub fn update_advertisement() -> Result<(), BLEError> {
+    let advertisement = create_ble_advertisment(None).unwrap_or_default();
+    BLE_DEVICE
+        .get_advertising()
+        .lock()
+        .set_raw_data(&advertisement)
 }
 
 fn main() {
@@ -192,6 +252,7 @@ fn main() {
 
     fix_mac_address();
     initialize_name();
+    health::count_boot();
 
     let server = setup_ble_server();
 
@@ -202,23 +263,37 @@ fn main() {
 
     let _file_upload_service = FileUploadService::new(server);
 
+    let _firmware_update_service = FirmwareUpdateService::new(server);
+
     let _cat_management_service = CatManagementService::new(server);
 
     // Starting advertising also starts the ble server. We cant add or change the services/attributes after the ble server started.
     {
+        let settings = advertising_settings();
         let ble_advertising = BLE_DEVICE.get_advertising();
-        let mut data = create_ble_advertisment(None);
-        ble_advertising.lock().set_data(&mut data).unwrap();
+        let data = create_ble_advertisment(None).unwrap();
+        ble_advertising.lock().set_raw_data(&data).unwrap();
+        ble_advertising
+            .lock()
+            .set_raw_scan_response_data(&create_ble_scan_response())
+            .unwrap();
         ble_advertising
             .lock()
             .advertisement_type(ConnMode::Und)
             .disc_mode(DiscMode::Gen)
-            .scan_response(false)
-            .min_interval(100)
-            .max_interval(150);
+            .scan_response(true)
+            .min_interval(settings.min_interval)
+            .max_interval(settings.max_interval);
         ble_advertising.lock().start().unwrap();
     }
 
+    start_battery_monitor();
+
+    // We got this far, so this firmware works well enough to receive another update. Prevent the bootloader from rolling back to the previous firmware on the next reboot.
+    unsafe {
+        esp_idf_sys::esp_ota_mark_app_valid_cancel_rollback();
+    }
+
     loop {
         std::thread::sleep(Duration::from_secs(1));
     }
@@ -0,0 +1,16 @@
+//! Advertise the Rudel id
+//!
+//! Devices can be assigned to a Rudel, so `rudelctl --rudel` only talks to the devices of one Rudel when multiple Rudels share a room. Like the battery level, the id is advertised as service data instead of manufacturer data, so the advertisements of the WASM guests stay unchanged. The advertisement always reserves room for it, see [rudelblinken_runtime::advertisement_layout].
+use crate::config;
+
+/// The Rudel this device belongs to
+pub fn rudel_id() -> Option<u16> {
+    return config::rudel_id::get().map(u16::from_le_bytes);
+}
+
+/// Assign the device to a Rudel or remove it from its Rudel
+///
+/// The id is stored in NVS, so it survives reboots. It is advertised right away.
+pub fn set_rudel_id(id: Option<u16>) {
+    config::rudel_id::set(&id.map(u16::to_le_bytes));
+}
@@ -1,10 +1,11 @@
 use crate::{
+    advertising::{power_level, supported_tx_power},
     config::{self, get_config, LedStripColor, WasmGuestConfig},
     create_ble_advertisment,
     wasm_service::wasm_host::{singlecolor::LED_PIN, ws2812::WS2812},
     BLE_DEVICE,
 };
-use esp32_nimble::utilities::mutex::Mutex;
+use esp32_nimble::{enums::PowerType, utilities::mutex::Mutex, BLEDevice};
 use esp_idf_hal::{
     adc::{
         self,
@@ -18,10 +19,10 @@ use esp_idf_hal::{
 use esp_idf_sys::adc_atten_t_ADC_ATTEN_DB_12;
 use rudelblinken_runtime::{
     host::{
-        self, Advertisement, AdvertisementSettings, AmbientLightType, Host, LedColor, LedInfo,
-        LogLevel, VibrationSensorType, VoltageSensorType,
+        self, Advertisement, AdvertisementSettings, AmbientLightType, BrightnessCurve, Host,
+        KvError, LedColor, LedInfo, LogLevel, VibrationSensorType, VoltageSensorType, YieldStatus,
     },
-    linker::linker::WrappedCaller,
+    linker::{linker::WrappedCaller, YieldTermination},
 };
 use std::{
     sync::mpsc::{channel, Receiver, Sender},
@@ -32,6 +33,7 @@ use std::{
     time::Duration,
 };
 
+mod kv_store;
 pub mod singlecolor;
 pub mod ws2812;
 
@@ -72,6 +74,32 @@ pub static VOLTAGE_SENSOR_ADC: LazyLock<
     Mutex::new(pin)
 });
 
+/// Measure the supply voltage in millivolts
+///
+/// Returns `None` if the ADC could not be read
+pub fn measure_voltage() -> Option<u32> {
+    const SAMPLES: u32 = 20;
+    let mut sum_of_measurements = 0u32;
+    let mut number_of_measurements = 0u32;
+    for _ in 0..SAMPLES {
+        match VOLTAGE_SENSOR_ADC.lock().read() {
+            Ok(v) => {
+                number_of_measurements += 1;
+                sum_of_measurements += v as u32;
+            }
+            Err(err) => {
+                tracing::warn!(?err, "reading voltage failed");
+            }
+        };
+    }
+    if number_of_measurements == 0 {
+        return None;
+    }
+    let average_measurement = sum_of_measurements / number_of_measurements;
+    let calibrated_voltage = average_measurement * 2;
+    return Some(calibrated_voltage);
+}
+
 #[derive(Clone)]
 pub struct WasmHostConfiguration {
     reset_fuel: u32,
@@ -99,10 +127,14 @@ pub enum HostEvent {
 #[derive(Clone)]
 pub struct WasmHost {
     pub host_events: Arc<Mutex<Receiver<HostEvent>>>,
+    /// An event that interrupted a sleep. It is handled at the next yield
+    pending_event: Arc<Mutex<Option<HostEvent>>>,
     // TODO: Actually use this. We build this to allow bidirectional communication between the host and the wasm guest in the emulator, but dont need that currently
     #[allow(dead_code)]
     pub wasm_events: Sender<WasmEvent>,
     config: WasmHostConfiguration,
+    /// Set by the guest. Every program starts with a clone of the initial host, so this is linear again for the next program
+    brightness_curve: BrightnessCurve,
 }
 
 impl WasmHost {
@@ -116,21 +148,33 @@ impl WasmHost {
             wasm_receiver,
             WasmHost {
                 host_events: Arc::new(Mutex::new(host_receiver)),
+                pending_event: Arc::new(Mutex::new(None)),
                 wasm_events: wasm_sender,
                 config: WasmHostConfiguration::default(),
+                brightness_curve: BrightnessCurve::Linear,
             },
         );
     }
 }
 
+/// Turn the LEDs off, so a stopped guest does not leave them on
+pub fn turn_off_leds() {
+    if USE_WS2812 {
+        WS2812.lock().set_duty(0);
+    } else if let Err(error) = LED_PIN.lock().set_duty(0) {
+        tracing::warn!(?error, "turning off the LEDs failed");
+    }
+}
+
 static LAST_UPDATE: LazyLock<Mutex<Instant>> = LazyLock::new(|| Mutex::new(Instant::now()));
 
 impl Host for WasmHost {
     fn yield_now(
         caller: &mut WrappedCaller<'_, Self>,
         micros: u64,
-    ) -> Result<u32, rudelblinken_runtime::Error> {
+    ) -> Result<YieldStatus, rudelblinken_runtime::Error> {
         let yield_until = unsafe { esp_idf_sys::esp_timer_get_time() } as u64 + micros;
+        let mut status = YieldStatus::Idle;
 
         loop {
             // Sleep for 1 freeRTOS tick to force yielding
@@ -143,18 +187,24 @@ impl Host for WasmHost {
             }
 
             loop {
-                let receiver = caller.data().host_events.lock();
-                let Ok(event) = receiver.try_recv() else {
-                    break;
+                let pending_event = caller.data().pending_event.lock().take();
+                let event = match pending_event {
+                    Some(event) => event,
+                    None => {
+                        let receiver = caller.data().host_events.lock();
+                        let Ok(event) = receiver.try_recv() else {
+                            break;
+                        };
+                        event
+                    }
                 };
-                drop(receiver);
                 match event {
                     HostEvent::AdvertisementReceived(advertisement) => {
                         caller.on_advertisement(advertisement)?;
+                        status = YieldStatus::CallbacksDelivered;
                     }
                     HostEvent::ProgramChanged() => {
-                        // TODO: Improve termination behaviour
-                        return Err(rudelblinken_runtime::Error::new("Terminated as requested"));
+                        return Err(rudelblinken_runtime::Error::host(YieldTermination));
                     }
                 }
             }
@@ -165,15 +215,35 @@ impl Host for WasmHost {
 
         let reset_fuel = caller.data().config.reset_fuel;
         caller.inner().set_fuel(reset_fuel as u64).unwrap();
-        Ok(reset_fuel)
+        Ok(status)
     }
 
     fn sleep(
-        _caller: &mut WrappedCaller<'_, Self>,
+        caller: &mut WrappedCaller<'_, Self>,
         micros: u64,
     ) -> Result<(), rudelblinken_runtime::Error> {
-        std::thread::sleep(Duration::from_micros(micros));
-        Ok(())
+        let start = unsafe { esp_idf_sys::esp_timer_get_time() } as u64;
+        let sleep_until = start.saturating_add(micros);
+        // The guest did not yield since the last sleep was interrupted
+        if caller.data().pending_event.lock().is_some() {
+            return Ok(());
+        }
+
+        loop {
+            let now = unsafe { esp_idf_sys::esp_timer_get_time() } as u64;
+            if now >= sleep_until {
+                return Ok(());
+            }
+            // Return early, so the guest can handle the event at its next yield
+            if let Ok(event) = caller.data().host_events.lock().try_recv() {
+                *caller.data().pending_event.lock() = Some(event);
+                return Ok(());
+            }
+            // Sleep in freeRTOS ticks to notice new events
+            std::thread::sleep(
+                Duration::from_micros(sleep_until - now).min(Duration::from_millis(1)),
+            );
+        }
     }
 
     fn time(_caller: &mut WrappedCaller<'_, Self>) -> Result<u64, rudelblinken_runtime::Error> {
@@ -181,6 +251,16 @@ impl Host for WasmHost {
         Ok(time as u64)
     }
 
+    fn get_random(
+        _caller: &mut WrappedCaller<'_, Self>,
+    ) -> Result<u64, rudelblinken_runtime::Error> {
+        // esp_random uses the hardware RNG, which is seeded by the radio while BLE is active
+        let random = unsafe {
+            ((esp_idf_sys::esp_random() as u64) << 32) | esp_idf_sys::esp_random() as u64
+        };
+        Ok(random)
+    }
+
     fn log(
         _caller: &mut WrappedCaller<'_, Self>,
         level: LogLevel,
@@ -211,6 +291,21 @@ impl Host for WasmHost {
         Ok(get_config::<WasmGuestConfig>())
     }
 
+    fn kv_get(
+        _caller: &mut WrappedCaller<'_, Self>,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, rudelblinken_runtime::Error> {
+        Ok(kv_store::get(key))
+    }
+
+    fn kv_set(
+        _caller: &mut WrappedCaller<'_, Self>,
+        key: &str,
+        value: &[u8],
+    ) -> Result<Result<(), KvError>, rudelblinken_runtime::Error> {
+        Ok(kv_store::set(key, value))
+    }
+
     fn set_leds(
         _caller: &mut WrappedCaller<'_, Self>,
         first_id: u16,
@@ -241,12 +336,39 @@ impl Host for WasmHost {
         }
     }
 
+    fn set_rgb_range(
+        caller: &mut WrappedCaller<'_, Self>,
+        first_id: u16,
+        count: u16,
+        color: &LedColor,
+        lux: u32,
+    ) -> Result<u32, rudelblinken_runtime::Error> {
+        // There is only a single LED
+        if first_id == 0 && 0 < count {
+            Self::set_rgb(caller, color, lux)
+        } else {
+            Ok(0)
+        }
+    }
+
     fn led_count(
         _caller: &mut WrappedCaller<'_, Self>,
     ) -> Result<u16, rudelblinken_runtime::Error> {
         Ok(1)
     }
 
+    fn brightness_curve(caller: &mut WrappedCaller<'_, Self>) -> BrightnessCurve {
+        return caller.data().brightness_curve;
+    }
+
+    fn set_brightness_curve(
+        caller: &mut WrappedCaller<'_, Self>,
+        curve: BrightnessCurve,
+    ) -> Result<(), rudelblinken_runtime::Error> {
+        caller.data_mut().brightness_curve = curve;
+        return Ok(());
+    }
+
     fn get_led_info(
         _caller: &mut WrappedCaller<'_, Self>,
         id: u16,
@@ -299,6 +421,14 @@ impl Host for WasmHost {
         Ok(0)
     }
 
+    fn set_vibration_threshold(
+        _caller: &mut WrappedCaller<'_, Self>,
+        _threshold: u32,
+    ) -> Result<(), rudelblinken_runtime::Error> {
+        // There is no vibration sensor, so the threshold is never crossed
+        Ok(())
+    }
+
     fn get_voltage_sensor_type(
         _caller: &mut WrappedCaller<'_, Self>,
     ) -> Result<VoltageSensorType, rudelblinken_runtime::Error> {
@@ -308,24 +438,7 @@ impl Host for WasmHost {
     fn get_voltage(
         _caller: &mut WrappedCaller<'_, Self>,
     ) -> Result<u32, rudelblinken_runtime::Error> {
-        const SAMPLES: u32 = 20;
-        let mut sum_of_measurements = 0u32;
-        let mut number_of_measurements = 0u32;
-        for _ in 0..SAMPLES {
-            match VOLTAGE_SENSOR_ADC.lock().read() {
-                Ok(v) => {
-                    number_of_measurements += 1;
-                    sum_of_measurements += v as u32;
-                }
-                Err(err) => {
-                    tracing::warn!(?err, "reading voltage failed");
-                }
-            };
-        }
-        let average_measurement = sum_of_measurements / number_of_measurements;
-        let calibrated_voltage = average_measurement * 2;
-
-        return Ok(calibrated_voltage);
+        return Ok(measure_voltage().unwrap_or(0));
     }
 
     fn configure_advertisement(
@@ -334,6 +447,15 @@ impl Host for WasmHost {
     ) -> Result<u32, rudelblinken_runtime::Error> {
         let min_interval = settings.min_interval.clamp(100, 1000);
         let max_interval = settings.max_interval.clamp(min_interval, 1500);
+        if min_interval != settings.min_interval || max_interval != settings.max_interval {
+            tracing::warn!(
+                requested_min = settings.min_interval,
+                requested_max = settings.max_interval,
+                min_interval,
+                max_interval,
+                "Clamped the advertising interval requested by the guest"
+            );
+        }
 
         let mut ble_advertising = BLE_DEVICE.get_advertising().lock();
         ble_advertising
@@ -357,8 +479,10 @@ impl Host for WasmHost {
         //     .stop()
         //     .map_err(|err| rudelblinken_runtime::Error::new(format!("{:?}", err)))?;
 
-        let mut advertisment = create_ble_advertisment(Some(&data));
-        if let Err(_) = ble_advertising.set_data(&mut advertisment) {
+        let Some(advertisment) = create_ble_advertisment(Some(&data)) else {
+            return Ok(1);
+        };
+        if let Err(_) = ble_advertising.set_raw_data(&advertisment) {
             return Ok(1);
         }
         // ble_advertising
@@ -367,4 +491,21 @@ impl Host for WasmHost {
 
         Ok(0)
     }
+
+    fn set_advertisement_tx_power(
+        _caller: &mut WrappedCaller<'_, Self>,
+        tx_power: i8,
+    ) -> Result<i8, rudelblinken_runtime::Error> {
+        let supported = supported_tx_power(tx_power);
+        let Some(level) = power_level(supported) else {
+            return Err(rudelblinken_runtime::Error::new(format!(
+                "No power level for {} dBm",
+                supported
+            )));
+        };
+        BLEDevice::take()
+            .set_power(PowerType::Advertising, level)
+            .map_err(|err| rudelblinken_runtime::Error::new(format!("{:?}", err)))?;
+        Ok(supported)
+    }
 }
@@ -0,0 +1,30 @@
+//! Persistent key-value store for the WASM guest
+//!
+//! The entries are stored by [rudelblinken_filesystem::kv_store], this only maps its errors to the codes the guest understands.
+use crate::storage::get_filesystem;
+use rudelblinken_filesystem::kv_store::{self, KvStoreError};
+use rudelblinken_runtime::host::KvError;
+
+pub fn get(key: &str) -> Option<Vec<u8>> {
+    let filesystem = get_filesystem().ok()?.read().ok()?;
+    return kv_store::get(&filesystem, key);
+}
+
+pub fn set(key: &str, value: &[u8]) -> Result<(), KvError> {
+    let mut filesystem = get_filesystem()
+        .map_err(|_| KvError::StorageFailure)?
+        .write()
+        .map_err(|_| KvError::StorageFailure)?;
+    kv_store::set(&mut filesystem, key, value).map_err(|error| match error {
+        KvStoreError::KeyTooLong => KvError::KeyTooLong,
+        KvStoreError::StoreFull => {
+            ::tracing::warn!(target: "kv-store", "Not storing {}, because the store is full", key);
+            KvError::StoreFull
+        }
+        KvStoreError::FilesystemWriteError(error) => {
+            ::tracing::error!(target: "kv-store", "Failed to store {}: {}", key, error);
+            KvError::StorageFailure
+        }
+    })?;
+    return Ok(());
+}
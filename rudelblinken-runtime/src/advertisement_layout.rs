@@ -0,0 +1,116 @@
+//! Layout of the advertisements sent by the rudelblinken firmware
+//!
+//! A legacy BLE advertisement carries at most 31 bytes. The firmware reserves room for its own fields, so the manufacturer data of a guest always fits, no matter which optional fields are present. The device name does not fit next to them and is sent in the scan response instead.
+//!
+//! ## Advertisement
+//!
+//! | Field                            | Length   | Content                                    |
+//! |----------------------------------|----------|--------------------------------------------|
+//! | Flags                            | 3        | General discoverable, BR/EDR not supported |
+//! | Manufacturer data                | 2 + data | Set by the guest, omitted until then       |
+//! | Service data `0x7992` (optional) | 6        | Rudel id as a little endian `u16`          |
+//! | Service data `0x180F` (optional) | 5        | Battery level in percent as a `u8`         |
+//!
+//! ## Scan response
+//!
+//! | Field               | Length   | Content                            |
+//! |---------------------|----------|------------------------------------|
+//! | Complete local name | 2 + name | `[rb]` followed by the device name |
+//! | Appearance          | 4        | `0x07C0`                           |
+
+/// Maximum length of a legacy advertisement or scan response
+pub const MAX_ADVERTISEMENT_LENGTH: usize = 31;
+
+/// UUID of the service data with the Rudel id. This is the UUID of the cat management service
+pub const RUDEL_SERVICE: u16 = 0x7992;
+/// UUID of the standard bluetooth battery service
+pub const BATTERY_SERVICE: u16 = 0x180F;
+/// Appearance of the device. This is the generic wearable computer
+pub const APPEARANCE: u16 = 0x07C0;
+/// Prefix of the advertised name, so clients can recognize rudelblinken devices
+pub const NAME_PREFIX: &str = "[rb]";
+/// Maximum length of a device name in bytes, without the prefix
+pub const MAX_NAME_LENGTH: usize = 16;
+
+// Every field has a length and a type byte
+const FLAGS_LENGTH: usize = 2 + 1;
+const MANUFACTURER_DATA_HEADER_LENGTH: usize = 2;
+const RUDEL_ID_LENGTH: usize = 2 + 2 + 2;
+const BATTERY_LEVEL_LENGTH: usize = 2 + 2 + 1;
+
+/// Maximum length of the manufacturer data in bytes, including the 2 byte company identifier
+///
+/// This is the space that is left after all fields of the firmware are reserved.
+pub const MAX_MANUFACTURER_DATA_LENGTH: usize = MAX_ADVERTISEMENT_LENGTH
+    - FLAGS_LENGTH
+    - MANUFACTURER_DATA_HEADER_LENGTH
+    - RUDEL_ID_LENGTH
+    - BATTERY_LEVEL_LENGTH;
+
+const TYPE_FLAGS: u8 = 0x01;
+const TYPE_COMPLETE_NAME: u8 = 0x09;
+const TYPE_SERVICE_DATA_16: u8 = 0x16;
+const TYPE_APPEARANCE: u8 = 0x19;
+const TYPE_MANUFACTURER_DATA: u8 = 0xFF;
+/// LE general discoverable, BR/EDR not supported
+const FLAGS: u8 = 0x06;
+
+fn push_field(buffer: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    buffer.push(1 + content.len() as u8);
+    buffer.push(kind);
+    buffer.extend_from_slice(content);
+}
+
+/// Encode a raw advertisement
+///
+/// Returns `None` if the manufacturer data is longer than [MAX_MANUFACTURER_DATA_LENGTH].
+pub fn encode_advertisement(
+    manufacturer_data: Option<&[u8]>,
+    rudel_id: Option<u16>,
+    battery_level: Option<u8>,
+) -> Option<Vec<u8>> {
+    let mut advertisement = Vec::with_capacity(MAX_ADVERTISEMENT_LENGTH);
+    push_field(&mut advertisement, TYPE_FLAGS, &[FLAGS]);
+    if let Some(data) = manufacturer_data {
+        if data.len() > MAX_MANUFACTURER_DATA_LENGTH {
+            return None;
+        }
+        push_field(&mut advertisement, TYPE_MANUFACTURER_DATA, data);
+    }
+    if let Some(id) = rudel_id {
+        let [uuid_0, uuid_1] = RUDEL_SERVICE.to_le_bytes();
+        let [id_0, id_1] = id.to_le_bytes();
+        push_field(
+            &mut advertisement,
+            TYPE_SERVICE_DATA_16,
+            &[uuid_0, uuid_1, id_0, id_1],
+        );
+    }
+    if let Some(level) = battery_level {
+        let [uuid_0, uuid_1] = BATTERY_SERVICE.to_le_bytes();
+        push_field(
+            &mut advertisement,
+            TYPE_SERVICE_DATA_16,
+            &[uuid_0, uuid_1, level],
+        );
+    }
+    return Some(advertisement);
+}
+
+/// Encode a raw scan response with the advertised name and the appearance
+///
+/// Returns `None` if the name is longer than [MAX_NAME_LENGTH].
+pub fn encode_scan_response(name: &str) -> Option<Vec<u8>> {
+    if name.len() > MAX_NAME_LENGTH {
+        return None;
+    }
+    let mut scan_response = Vec::with_capacity(MAX_ADVERTISEMENT_LENGTH);
+    let advertised_name = [NAME_PREFIX.as_bytes(), name.as_bytes()].concat();
+    push_field(&mut scan_response, TYPE_COMPLETE_NAME, &advertised_name);
+    push_field(
+        &mut scan_response,
+        TYPE_APPEARANCE,
+        &APPEARANCE.to_le_bytes(),
+    );
+    return Some(scan_response);
+}
@@ -1,12 +1,18 @@
 use std::{
-    sync::mpsc::{channel, Receiver, Sender},
+    collections::{HashMap, VecDeque},
+    sync::{
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, Mutex,
+    },
     time::{Duration, Instant},
 };
 
 use crate::{
+    fuel::FuelMeter,
     host::{
-        Advertisement, AdvertisementSettings, AmbientLightType, Host, LedColor, LedInfo, LogLevel,
-        VibrationSensorType, VoltageSensorType,
+        Advertisement, AdvertisementSettings, AmbientLightType, BrightnessCurve, Host, KvError,
+        LedColor, LedInfo, LogLevel, VibrationSensorType, VibrationTrigger, VoltageSensorType,
+        YieldStatus,
     },
     linker::linker::WrappedCaller,
 };
@@ -14,11 +20,257 @@ use crate::{
 #[derive(Clone, Debug)]
 pub enum Event {
     AdvertisementReceived(Advertisement),
+    /// Set the vibration level. Calls on-vibration if it crosses the threshold set by the guest
+    Vibration(u32),
+}
+
+/// Maximum deviation of a voltage measurement from the supply voltage in millivolts
+const VOLTAGE_NOISE: u64 = 10;
+
+/// Number of LEDs of the emulated host
+const LED_COUNT: u16 = 500;
+
+/// Time source of the emulated host
+///
+/// The clock follows the monotonic system clock until it is advanced manually. From then on it only moves when it is advanced or when the guest sleeps, which makes tests reproducible.
+///
+/// Clones share the same time, so a test can keep a clone to control the time of a running host.
+#[derive(Clone, Debug)]
+pub struct Clock {
+    start_time: Instant,
+    virtual_time: Arc<Mutex<Option<u64>>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        return Clock {
+            start_time: Instant::now(),
+            virtual_time: Arc::new(Mutex::new(None)),
+        };
+    }
+
+    /// A clock that starts at 0 and only moves when it is advanced
+    pub fn new_virtual() -> Self {
+        return Clock {
+            start_time: Instant::now(),
+            virtual_time: Arc::new(Mutex::new(Some(0))),
+        };
+    }
+
+    /// Microseconds since the clock was created
+    pub fn now(&self) -> u64 {
+        let virtual_time = self.virtual_time.lock().unwrap();
+        return virtual_time.unwrap_or_else(|| self.start_time.elapsed().as_micros() as u64);
+    }
+
+    /// Advance the clock by the given number of microseconds
+    ///
+    /// The first call switches the clock from the system clock to virtual time.
+    pub fn advance(&self, micros: u64) {
+        let mut virtual_time = self.virtual_time.lock().unwrap();
+        let now = virtual_time.unwrap_or_else(|| self.start_time.elapsed().as_micros() as u64);
+        *virtual_time = Some(now.saturating_add(micros));
+    }
+
+    /// Whether the clock has been switched to virtual time
+    pub fn is_virtual(&self) -> bool {
+        return self.virtual_time.lock().unwrap().is_some();
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Supply voltage of the emulated host
+///
+/// Until a voltage is set, the host reports that it has no voltage sensor. Clones share the same voltage, so a test can keep a clone to change the voltage of a running host, e.g. to unplug USB.
+#[derive(Clone, Debug, Default)]
+pub struct Supply {
+    millivolts: Arc<Mutex<Option<u32>>>,
+}
+
+impl Supply {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Set the supply voltage in millivolts
+    pub fn set(&self, millivolts: u32) {
+        *self.millivolts.lock().unwrap() = Some(millivolts);
+    }
+
+    /// The supply voltage in millivolts, if it was set
+    pub fn get(&self) -> Option<u32> {
+        return *self.millivolts.lock().unwrap();
+    }
+}
+
+/// State of a single emulated LED
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedState {
+    pub color: LedColor,
+    pub lux: u32,
+}
+
+/// LEDs of the emulated host
+///
+/// All LEDs start out black and off. Clones share the same LEDs, so a test can keep a clone to check what the guest displayed after the host was moved into the instance.
+#[derive(Clone, Debug)]
+pub struct Leds {
+    leds: Arc<Mutex<Vec<LedState>>>,
+}
+
+impl Leds {
+    pub fn new() -> Self {
+        let off = LedState {
+            color: LedColor::new(0, 0, 0),
+            lux: 0,
+        };
+        return Leds {
+            leds: Arc::new(Mutex::new(vec![off; LED_COUNT as usize])),
+        };
+    }
+
+    /// Set `count` LEDs starting at `first_id`. LEDs past the end are ignored
+    pub fn set_range(&self, first_id: u16, count: u16, color: LedColor, lux: u32) {
+        let mut leds = self.leds.lock().unwrap();
+        let start = std::cmp::min(first_id as usize, leds.len());
+        let end = std::cmp::min(first_id as usize + count as usize, leds.len());
+        leds[start..end].fill(LedState { color, lux });
+    }
+
+    /// Set the lux of the LEDs starting at `first_id` without changing their color. Values past the end are ignored
+    pub fn set_lux(&self, first_id: u16, lux: &[u16]) {
+        let mut leds = self.leds.lock().unwrap();
+        for (led, lux) in leds.iter_mut().skip(first_id as usize).zip(lux) {
+            led.lux = *lux as u32;
+        }
+    }
+
+    /// The state of the LED with the given id, if it exists
+    pub fn get(&self, id: u16) -> Option<LedState> {
+        return self.leds.lock().unwrap().get(id as usize).copied();
+    }
+}
+
+impl Default for Leds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Contents of the key-value store of the emulated host
+///
+/// Clones share the same entries. Pass a clone to a new host to simulate a reboot, as the store of a real device survives it.
+#[derive(Clone, Debug, Default)]
+pub struct KvStore {
+    entries: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// The value stored under `key`
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        return self.entries.lock().unwrap().get(key).cloned();
+    }
+
+    /// Store `value` under `key`, replacing the previous value
+    pub fn set(&self, key: &str, value: &[u8]) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), value.to_vec());
+    }
+}
+
+/// Records the log messages of the guest
+///
+/// Clones share the same messages, so a test can keep a clone to check what the guest logged after the host was moved into the instance.
+#[derive(Clone, Debug, Default)]
+pub struct LogRecorder {
+    messages: Arc<(Mutex<Vec<(LogLevel, String)>>, Condvar)>,
+}
+
+impl LogRecorder {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Add a message
+    pub fn record(&self, level: LogLevel, message: &str) {
+        let (messages, recorded) = &*self.messages;
+        messages.lock().unwrap().push((level, message.to_string()));
+        recorded.notify_all();
+    }
+
+    /// All messages recorded so far, oldest first
+    pub fn logs(&self) -> Vec<(LogLevel, String)> {
+        return self.messages.0.lock().unwrap().clone();
+    }
+
+    /// The messages recorded at `level` so far, oldest first
+    pub fn logs_at(&self, level: LogLevel) -> Vec<String> {
+        return self
+            .messages
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(message_level, _)| *message_level == level)
+            .map(|(_, message)| message.clone())
+            .collect();
+    }
+
+    /// Wait until a message containing `expected` was recorded
+    ///
+    /// Returns `false` if there is none after `timeout`. This lets a test react to a guest running on another thread.
+    pub fn wait_for(&self, expected: &str, timeout: Duration) -> bool {
+        let (messages, recorded) = &*self.messages;
+        let messages = messages.lock().unwrap();
+        let (_, result) = recorded
+            .wait_timeout_while(messages, timeout, |messages| {
+                !messages
+                    .iter()
+                    .any(|(_, message)| message.contains(expected))
+            })
+            .unwrap();
+        return !result.timed_out();
+    }
 }
 
 pub struct EmulatedHost {
-    pub start_time: Instant,
+    pub clock: Clock,
     pub events: Receiver<Event>,
+    /// Events that interrupted a sleep. They are handled at the next yield
+    pending_events: VecDeque<Event>,
+    pub supply: Supply,
+    /// Records the log messages of the guest instead of printing them, if set
+    pub log_recorder: Option<LogRecorder>,
+    last_advertisement: Option<Vec<u8>>,
+    advertisement_tx_power: Option<i8>,
+    /// State of the pseudo random number generator used for `get_random`
+    random_state: u64,
+    /// Contents of the key-value store. Give a new host a clone to keep the entries across a simulated reboot
+    pub kv_store: KvStore,
+    /// Set this to record the fuel consumption of the guest
+    pub fuel_meter: Option<FuelMeter>,
+    /// The last vibration level sent with [Event::Vibration]. The host has no vibration sensor until one was sent
+    pub vibration: Option<u32>,
+    vibration_trigger: VibrationTrigger,
+    /// The LEDs as set by the guest
+    pub leds: Leds,
+    /// Reported by `get_led_info` for every LED
+    pub led_info: LedInfo,
+    /// Applied to the lux set by the guest. Defaults to [BrightnessCurve::Linear] until the guest sets another curve
+    pub brightness_curve: BrightnessCurve,
+    /// Every sleep takes up to this many microseconds longer than requested, like on a real device. Defaults to 0
+    pub sleep_jitter: u64,
 }
 
 impl EmulatedHost {
@@ -27,41 +279,162 @@ impl EmulatedHost {
         return (
             sender,
             EmulatedHost {
-                start_time: Instant::now(),
+                clock: Clock::new(),
                 events: receiver,
+                pending_events: VecDeque::new(),
+                supply: Supply::new(),
+                log_recorder: None,
+                last_advertisement: None,
+                advertisement_tx_power: None,
+                random_state: 0,
+                kv_store: KvStore::new(),
+                fuel_meter: None,
+                vibration: None,
+                vibration_trigger: VibrationTrigger::new(),
+                leds: Leds::new(),
+                led_info: LedInfo {
+                    color: LedColor::new(0, 0, 0),
+                    max_lux: 0,
+                },
+                brightness_curve: BrightnessCurve::Linear,
+                sleep_jitter: 0,
             },
         );
     }
+
+    /// Advance the time seen by the guest by the given number of microseconds
+    ///
+    /// After this was called, the time only moves when it is advanced again or when the guest sleeps. See [Clock].
+    pub fn advance_time(&self, micros: u64) {
+        self.clock.advance(micros);
+    }
+
+    /// Seed the pseudo random number generator used for `get_random`
+    ///
+    /// Hosts with the same seed produce the same sequence of random numbers. The seed defaults to 0.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.random_state = seed;
+    }
+
+    /// Get the next number from the pseudo random number generator (splitmix64)
+    pub fn next_random(&mut self) -> u64 {
+        self.random_state = self.random_state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.random_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    /// The advertisement data the guest set last, if it set any
+    pub fn last_advertisement(&self) -> Option<&[u8]> {
+        return self.last_advertisement.as_deref();
+    }
+
+    /// The transmit power the guest set last in dBm, if it set any
+    pub fn advertisement_tx_power(&self) -> Option<i8> {
+        return self.advertisement_tx_power;
+    }
+
+    /// Whether an event is waiting to be handled at the next yield
+    pub fn event_pending(&mut self) -> bool {
+        if let Ok(event) = self.events.try_recv() {
+            self.pending_events.push_back(event);
+        }
+        return !self.pending_events.is_empty();
+    }
+
+    /// Take the next event that needs to be handled, oldest first
+    fn next_event(&mut self) -> Option<Event> {
+        return self
+            .pending_events
+            .pop_front()
+            .or_else(|| self.events.try_recv().ok());
+    }
+
+    /// Sleep for the given number of microseconds plus up to [EmulatedHost::sleep_jitter]
+    ///
+    /// Returns early when an event arrives, so the guest can handle it at its next yield. Returns the number of microseconds that actually passed.
+    ///
+    /// With a virtual clock this only advances the clock, so the guest sees the time pass without waiting for it.
+    pub fn sleep_micros(&mut self, micros: u64) -> u64 {
+        if self.event_pending() {
+            return 0;
+        }
+        let jitter = match self.sleep_jitter {
+            0 => 0,
+            sleep_jitter => self.next_random() % (sleep_jitter + 1),
+        };
+        let micros = micros.saturating_add(jitter);
+        if self.clock.is_virtual() {
+            self.clock.advance(micros);
+            return micros;
+        }
+
+        let start = Instant::now();
+        let duration = Duration::from_micros(micros);
+        match self.events.recv_timeout(duration) {
+            Ok(event) => self.pending_events.push_back(event),
+            Err(RecvTimeoutError::Timeout) => {}
+            // Nothing can interrupt the sleep anymore
+            Err(RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(duration.saturating_sub(start.elapsed()))
+            }
+        }
+        return start.elapsed().as_micros() as u64;
+    }
 }
 
 impl Host for EmulatedHost {
-    fn yield_now(caller: &mut WrappedCaller<'_, Self>, micros: u64) -> Result<u32, wasmi::Error> {
-        std::thread::sleep(Duration::from_micros(micros));
-        while let Ok(event) = caller.data_mut().events.try_recv() {
+    fn yield_now(
+        caller: &mut WrappedCaller<'_, Self>,
+        micros: u64,
+    ) -> Result<YieldStatus, wasmi::Error> {
+        // Virtual time only moves when it gets advanced explicitly
+        if !caller.data().clock.is_virtual() {
+            std::thread::sleep(Duration::from_micros(micros));
+        }
+        let mut status = YieldStatus::Idle;
+        while let Some(event) = caller.data_mut().next_event() {
             match event {
                 Event::AdvertisementReceived(advertisement) => {
                     caller.on_advertisement(advertisement)?;
                 }
+                Event::Vibration(magnitude) => {
+                    let host = caller.data_mut();
+                    host.vibration = Some(magnitude);
+                    if host.vibration_trigger.update(magnitude) {
+                        caller.on_vibration(magnitude)?;
+                    }
+                }
             }
+            status = YieldStatus::CallbacksDelivered;
         }
         caller.inner().set_fuel(999_999).unwrap();
-        return Ok(999_999);
+        return Ok(status);
     }
 
-    fn sleep(_caller: &mut WrappedCaller<'_, Self>, micros: u64) -> Result<(), wasmi::Error> {
-        std::thread::sleep(Duration::from_micros(micros));
+    fn sleep(caller: &mut WrappedCaller<'_, Self>, micros: u64) -> Result<(), wasmi::Error> {
+        caller.data_mut().sleep_micros(micros);
         return Ok(());
     }
 
     fn time(caller: &mut WrappedCaller<'_, Self>) -> Result<u64, wasmi::Error> {
-        return Ok(caller.data().start_time.elapsed().as_micros() as u64);
+        return Ok(caller.data().clock.now());
+    }
+
+    fn get_random(caller: &mut WrappedCaller<'_, Self>) -> Result<u64, wasmi::Error> {
+        return Ok(caller.data_mut().next_random());
     }
 
     fn log(
-        _caller: &mut WrappedCaller<'_, Self>,
+        caller: &mut WrappedCaller<'_, Self>,
         level: LogLevel,
         message: &str,
     ) -> Result<(), wasmi::Error> {
+        if let Some(recorder) = &caller.data().log_recorder {
+            recorder.record(level, message);
+            return Ok(());
+        }
         println!("{}: {}", level, message);
         return Ok(());
     }
@@ -74,34 +447,78 @@ impl Host for EmulatedHost {
         return Ok(vec![]);
     }
 
+    fn kv_get(
+        caller: &mut WrappedCaller<'_, Self>,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, wasmi::Error> {
+        return Ok(caller.data().kv_store.get(key));
+    }
+
+    fn kv_set(
+        caller: &mut WrappedCaller<'_, Self>,
+        key: &str,
+        value: &[u8],
+    ) -> Result<Result<(), KvError>, wasmi::Error> {
+        caller.data().kv_store.set(key, value);
+        return Ok(Ok(()));
+    }
+
     fn set_leds(
-        _caller: &mut WrappedCaller<'_, Self>,
-        _first_id: u16,
-        _lux: &[u16],
+        caller: &mut WrappedCaller<'_, Self>,
+        first_id: u16,
+        lux: &[u16],
     ) -> Result<u32, wasmi::Error> {
-        Ok(0)
+        caller.data().leds.set_lux(first_id, lux);
+        return Ok(0);
     }
 
     fn set_rgb(
-        _caller: &mut WrappedCaller<'_, Self>,
-        _color: &crate::host::LedColor,
-        _lux: u32,
+        caller: &mut WrappedCaller<'_, Self>,
+        color: &crate::host::LedColor,
+        lux: u32,
     ) -> Result<u32, wasmi::Error> {
+        caller.data().leds.set_range(0, LED_COUNT, *color, lux);
+        return Ok(0);
+    }
+
+    fn set_rgb_range(
+        caller: &mut WrappedCaller<'_, Self>,
+        first_id: u16,
+        count: u16,
+        color: &LedColor,
+        lux: u32,
+    ) -> Result<u32, wasmi::Error> {
+        caller.data().leds.set_range(first_id, count, *color, lux);
         return Ok(0);
     }
 
     fn led_count(_caller: &mut WrappedCaller<'_, Self>) -> Result<u16, wasmi::Error> {
-        return Ok(500);
+        return Ok(LED_COUNT);
+    }
+
+    fn brightness_curve(caller: &mut WrappedCaller<'_, Self>) -> BrightnessCurve {
+        return caller.data().brightness_curve;
+    }
+
+    fn set_brightness_curve(
+        caller: &mut WrappedCaller<'_, Self>,
+        curve: BrightnessCurve,
+    ) -> Result<(), wasmi::Error> {
+        caller.data_mut().brightness_curve = curve;
+        return Ok(());
     }
 
     fn get_led_info(
-        _caller: &mut WrappedCaller<'_, Self>,
-        _id: u16,
+        caller: &mut WrappedCaller<'_, Self>,
+        id: u16,
     ) -> Result<crate::host::LedInfo, wasmi::Error> {
-        return Ok(LedInfo {
-            color: LedColor::new(0, 0, 0),
-            max_lux: 0,
-        });
+        if id >= LED_COUNT {
+            return Ok(LedInfo {
+                color: LedColor::new(0, 0, 0),
+                max_lux: 0,
+            });
+        }
+        return Ok(caller.data().led_info);
     }
 
     fn get_ambient_light_type(
@@ -115,23 +532,43 @@ impl Host for EmulatedHost {
     }
 
     fn get_vibration_sensor_type(
-        _caller: &mut WrappedCaller<'_, Self>,
+        caller: &mut WrappedCaller<'_, Self>,
     ) -> Result<VibrationSensorType, wasmi::Error> {
-        Ok(VibrationSensorType::None)
+        return Ok(match caller.data().vibration {
+            Some(_) => VibrationSensorType::Ball,
+            None => VibrationSensorType::None,
+        });
     }
 
-    fn get_vibration(_caller: &mut WrappedCaller<'_, Self>) -> Result<u32, wasmi::Error> {
-        return Ok(0);
+    fn get_vibration(caller: &mut WrappedCaller<'_, Self>) -> Result<u32, wasmi::Error> {
+        return Ok(caller.data().vibration.unwrap_or(0));
+    }
+
+    fn set_vibration_threshold(
+        caller: &mut WrappedCaller<'_, Self>,
+        threshold: u32,
+    ) -> Result<(), wasmi::Error> {
+        caller.data_mut().vibration_trigger.set_threshold(threshold);
+        return Ok(());
     }
 
     fn get_voltage_sensor_type(
-        _caller: &mut WrappedCaller<'_, Self>,
+        caller: &mut WrappedCaller<'_, Self>,
     ) -> Result<VoltageSensorType, wasmi::Error> {
-        Ok(VoltageSensorType::None)
+        return Ok(match caller.data().supply.get() {
+            Some(_) => VoltageSensorType::Basic,
+            None => VoltageSensorType::None,
+        });
     }
 
-    fn get_voltage(_caller: &mut WrappedCaller<'_, Self>) -> Result<u32, wasmi::Error> {
-        return Ok(0);
+    fn get_voltage(caller: &mut WrappedCaller<'_, Self>) -> Result<u32, wasmi::Error> {
+        let Some(millivolts) = caller.data().supply.get() else {
+            return Ok(0);
+        };
+        // Real measurements are noisy, the noise is seeded like `get_random`
+        let noise = caller.data_mut().next_random() % (2 * VOLTAGE_NOISE + 1);
+        let voltage = (millivolts as u64 + noise).saturating_sub(VOLTAGE_NOISE);
+        return Ok(voltage as u32);
     }
 
     fn configure_advertisement(
@@ -142,9 +579,23 @@ impl Host for EmulatedHost {
     }
 
     fn set_advertisement_data(
-        _context: &mut WrappedCaller<'_, Self>,
-        _data: &[u8],
+        context: &mut WrappedCaller<'_, Self>,
+        data: &[u8],
     ) -> Result<u32, wasmi::Error> {
+        context.data_mut().last_advertisement = Some(data.to_vec());
         return Ok(0);
     }
+
+    fn set_advertisement_tx_power(
+        context: &mut WrappedCaller<'_, Self>,
+        tx_power: i8,
+    ) -> Result<i8, wasmi::Error> {
+        // There is no radio, so every power is supported
+        context.data_mut().advertisement_tx_power = Some(tx_power);
+        return Ok(tx_power);
+    }
+
+    fn fuel_meter(&mut self) -> Option<&mut FuelMeter> {
+        return self.fuel_meter.as_mut();
+    }
 }
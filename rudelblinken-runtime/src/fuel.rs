@@ -0,0 +1,103 @@
+//! Measure how much fuel a guest consumes
+//!
+//! wasmi charges fuel for every executed instruction, so the consumed fuel is a good estimate for the work a guest does. The meter reads the fuel of the store on every host call and attributes the fuel consumed since the previous host call to the called function. This shows which parts of a program are expensive, e.g. the computation before every `set-leds`.
+//!
+//! Hosts enable the meter by returning it from [Host::fuel_meter](crate::host::Host::fuel_meter).
+use std::collections::BTreeMap;
+
+/// Fuel consumption attributed to a single host function
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HostCallFuel {
+    /// How often the function was called
+    pub calls: u64,
+    /// Fuel the guest consumed before calling the function, since its previous host call
+    pub fuel: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct FuelMeter {
+    /// Fuel of the store when the guest last got control back
+    last_fuel: Option<u64>,
+    /// Fuel of the store when it was last refueled
+    fuel_at_reset: Option<u64>,
+    /// Fuel of the store when the currently running host calls were entered, innermost last
+    entered_with: Vec<u64>,
+    /// Fuel consumed since the meter was created
+    total: u64,
+    host_calls: BTreeMap<&'static str, HostCallFuel>,
+}
+
+impl FuelMeter {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Record that the guest started running with `fuel`
+    pub(crate) fn start(&mut self, fuel: u64) {
+        self.last_fuel = Some(fuel);
+        self.fuel_at_reset = Some(fuel);
+    }
+
+    /// Record that the guest stopped running with `fuel` left
+    pub(crate) fn stop(&mut self, fuel: u64) {
+        if let Some(last_fuel) = self.last_fuel.take() {
+            self.total += last_fuel.saturating_sub(fuel);
+        }
+    }
+
+    /// Record that the guest called `function` with `fuel` left
+    pub(crate) fn enter(&mut self, function: &'static str, fuel: u64) {
+        // The fuel may have been reset by a callback in between, that is not counted
+        let consumed = self
+            .last_fuel
+            .map_or(0, |last_fuel| last_fuel.saturating_sub(fuel));
+        self.total += consumed;
+        let host_call = self.host_calls.entry(function).or_default();
+        host_call.calls += 1;
+        host_call.fuel += consumed;
+
+        // Callbacks can call host functions while this call is running
+        self.last_fuel = Some(fuel);
+        self.entered_with.push(fuel);
+    }
+
+    /// Record that a host function returned to the guest with `fuel` left
+    pub(crate) fn leave(&mut self, fuel: u64) {
+        if let Some(entered_with) = self.entered_with.pop() {
+            if fuel > entered_with {
+                self.fuel_at_reset = Some(fuel);
+            }
+        }
+        self.last_fuel = Some(fuel);
+    }
+
+    /// Fuel consumed since the store was last refueled, given the `fuel` it has left
+    pub fn consumed_since_reset(&self, fuel: u64) -> Option<u64> {
+        return self
+            .fuel_at_reset
+            .map(|fuel_at_reset| fuel_at_reset.saturating_sub(fuel));
+    }
+
+    /// Fuel consumed since the meter was created
+    pub fn total(&self) -> u64 {
+        return self.total;
+    }
+
+    /// Fuel consumption per host function, sorted by name
+    pub fn host_calls(&self) -> &BTreeMap<&'static str, HostCallFuel> {
+        return &self.host_calls;
+    }
+}
+
+impl std::fmt::Display for FuelMeter {
+    /// Formats the meter like `total: 1280 fuel, log: 1200 fuel, set-leds: 80 fuel`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "total: {} fuel", self.total)?;
+        let mut host_calls = self.host_calls.iter().collect::<Vec<_>>();
+        host_calls.sort_by(|(_, a), (_, b)| b.fuel.cmp(&a.fuel));
+        for (function, host_call) in host_calls {
+            write!(f, ", {}: {} fuel", function, host_call.fuel)?;
+        }
+        return Ok(());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::linker::linker::WrappedCaller;
+use crate::{fuel::FuelMeter, linker::linker::WrappedCaller};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(i32)]
@@ -44,17 +44,44 @@ pub struct SemanticVersion {
 }
 
 impl SemanticVersion {
-    pub fn new(major: u8, minor: u8, patch: u8) -> Self {
+    pub const fn new(major: u8, minor: u8, patch: u8) -> Self {
         SemanticVersion {
             major,
             minor,
             patch,
         }
     }
+
+    /// Parse a version like `0.0.1`
+    pub fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.split('.').map(|part| part.parse::<u8>().ok());
+        let (Some(Some(major)), Some(Some(minor)), Some(Some(patch)), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+        return Some(SemanticVersion::new(major, minor, patch));
+    }
+
+    /// Whether a guest built against this version runs on a host that provides `host`
+    ///
+    /// The host needs to be at least as new as the guest. Before 1.0.0 every minor version may break guests, so the minor versions need to match as well.
+    pub fn runs_on(&self, host: SemanticVersion) -> bool {
+        if self.major != host.major || (self.major == 0 && self.minor != host.minor) {
+            return false;
+        }
+        return *self <= host;
+    }
+}
+
+impl std::fmt::Display for SemanticVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct LedColor {
     pub red: u8,
     pub green: u8,
@@ -77,6 +104,47 @@ pub struct LedInfo {
     pub max_lux: u16,
 }
 
+/// How the host maps the lux requested by the guest to the lux of the LEDs
+///
+/// Brightness is not perceived linearly, so even steps in lux look uneven. With a gamma curve, guests can send perceptually even values and the host corrects them. Guests opt in with `set-brightness-curve`. Until then the host uses [BrightnessCurve::Linear], so guests that correct the values themselves are not corrected twice.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BrightnessCurve {
+    /// Pass the lux through unchanged
+    #[default]
+    Linear,
+    /// Raise the lux relative to the maximum lux of the LED to this power. 2.2 is a common choice
+    ///
+    /// Use [BrightnessCurve::gamma] to create it from an untrusted value.
+    Gamma(f32),
+}
+
+impl BrightnessCurve {
+    /// Create the curve for a gamma requested by the guest
+    ///
+    /// Returns `None` if the gamma is NaN or not positive. A gamma of 1 is [BrightnessCurve::Linear].
+    pub fn gamma(gamma: f32) -> Option<Self> {
+        if gamma.is_nan() || gamma <= 0.0 {
+            return None;
+        }
+        if gamma == 1.0 {
+            return Some(BrightnessCurve::Linear);
+        }
+        return Some(BrightnessCurve::Gamma(gamma));
+    }
+
+    /// Map the lux requested by the guest for an LED with the given maximum lux
+    ///
+    /// With a gamma curve, values above the maximum are clamped to it. An LED without a known maximum (0) uses the full `u16` range.
+    pub fn apply(&self, lux: u32, max_lux: u16) -> u32 {
+        let BrightnessCurve::Gamma(gamma) = *self else {
+            return lux;
+        };
+        let max_lux = if max_lux == 0 { u16::MAX } else { max_lux } as f32;
+        let fraction = (lux as f32 / max_lux).min(1.0);
+        return (fraction.powf(gamma) * max_lux).round() as u32;
+    }
+}
+
 /// Information about the ambient light sensor.
 ///
 /// This could be extended in the future to indicate more types of sensors in future hardware revisions.
@@ -150,8 +218,15 @@ pub struct Advertisement {
     /// how many of the data bytes are actually used
     pub data_length: u8,
     pub received_at: u64,
+    /// Signal strength in dBm, or [UNKNOWN_RSSI] if the host does not know it
+    pub rssi: i8,
 }
 
+/// Value of [Advertisement::rssi] if the signal strength is unknown
+///
+/// This is the value Bluetooth uses for "RSSI not available".
+pub const UNKNOWN_RSSI: i8 = 127;
+
 /// Configure the BLE advertisements
 #[repr(C)]
 #[derive(Clone, Copy)]
@@ -168,18 +243,103 @@ impl ::core::fmt::Debug for AdvertisementSettings {
     }
 }
 
+/// Maximum length of the advertisement data in bytes, including the 2 byte company identifier
+///
+/// This is the space the rudelblinken firmware leaves for the manufacturer data after reserving room for its own fields, see [crate::advertisement_layout]. Data of this length always fits.
+pub const MAX_ADVERTISEMENT_DATA_LENGTH: usize =
+    crate::advertisement_layout::MAX_MANUFACTURER_DATA_LENGTH;
+
+/// Error codes returned by `set-advertisement-data`
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvertisementError {
+    /// The data does not fit into the advertisement together with the other fields of the host
+    Rejected = 1,
+    /// The data is longer than [MAX_ADVERTISEMENT_DATA_LENGTH]
+    DataTooLong = 2,
+}
+
+/// Maximum length of a key in the key-value store in bytes
+pub const MAX_KV_KEY_LENGTH: usize = 64;
+/// Maximum length of a value in the key-value store in bytes
+pub const MAX_KV_VALUE_LENGTH: usize = 256;
+
+/// Error codes returned by `kv-set`
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvError {
+    KeyTooLong = 1,
+    ValueTooLong = 2,
+    StorageFailure = 3,
+    StoreFull = 4,
+}
+
+/// Detects when the vibration level rises above the threshold set by the guest
+///
+/// Hosts pass every measurement to [VibrationTrigger::update] and call `on-vibration` when it returns true, so the guest is called once per crossing and not for every measurement above the threshold. A threshold of 0 disables the callback, which is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VibrationTrigger {
+    threshold: u32,
+    above: bool,
+}
+
+impl VibrationTrigger {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Set the threshold. The next measurement above it triggers the callback, even if the previous one was above it as well
+    pub fn set_threshold(&mut self, threshold: u32) {
+        self.threshold = threshold;
+        self.above = false;
+    }
+
+    pub fn threshold(&self) -> u32 {
+        return self.threshold;
+    }
+
+    /// Record a measurement. Returns true if the callback should be called
+    pub fn update(&mut self, magnitude: u32) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let above = magnitude >= self.threshold;
+        let crossed = above && !self.above;
+        self.above = above;
+        return crossed;
+    }
+}
+
+/// Returned by `yield-now`, so the guest knows whether it can yield for longer next time
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldStatus {
+    /// No callbacks were delivered while yielding
+    Idle = 0,
+    /// At least one callback was delivered while yielding
+    CallbacksDelivered = 1,
+}
+
 pub trait Host
 where
     Self: Sized,
 {
     #[doc = "You need to yield periodically, as the watchdog will kill you if you dont"]
-    fn yield_now(context: &mut WrappedCaller<'_, Self>, micros: u64) -> Result<u32, wasmi::Error>;
+    fn yield_now(
+        context: &mut WrappedCaller<'_, Self>,
+        micros: u64,
+    ) -> Result<YieldStatus, wasmi::Error>;
     #[doc = " Sleep for a given amount of time."]
+    ///
+    /// Return early when an event is pending that would cause a callback, so the guest can handle it at its next yield. The guest uses `time` to find out how long it actually slept.
     fn sleep(context: &mut WrappedCaller<'_, Self>, micros: u64) -> Result<(), wasmi::Error>;
 
     #[doc = " Returns the number of microseconds that have passed since boot"]
     fn time(context: &mut WrappedCaller<'_, Self>) -> Result<u64, wasmi::Error>;
 
+    /// Returns 64 random bits
+    fn get_random(context: &mut WrappedCaller<'_, Self>) -> Result<u64, wasmi::Error>;
+
     #[doc = " Log a message"]
     fn log(
         context: &mut WrappedCaller<'_, Self>,
@@ -195,6 +355,23 @@ where
     /// The configuration set on the host via BLE; to be treaded as an opaque byte slice
     fn get_config(context: &mut WrappedCaller<'_, Self>) -> Result<Vec<u8>, wasmi::Error>;
 
+    /// Read a value from the persistent key-value store
+    ///
+    /// The key is at most [MAX_KV_KEY_LENGTH] bytes long
+    fn kv_get(
+        context: &mut WrappedCaller<'_, Self>,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, wasmi::Error>;
+
+    /// Store a value in the persistent key-value store
+    ///
+    /// The lengths of the key and value are already checked against [MAX_KV_KEY_LENGTH] and [MAX_KV_VALUE_LENGTH]
+    fn kv_set(
+        context: &mut WrappedCaller<'_, Self>,
+        key: &str,
+        value: &[u8],
+    ) -> Result<Result<(), KvError>, wasmi::Error>;
+
     fn set_leds(
         context: &mut WrappedCaller<'_, Self>,
         first_id: u16,
@@ -205,7 +382,28 @@ where
         color: &LedColor,
         lux: u32,
     ) -> Result<u32, wasmi::Error>;
+    /// Set `count` LEDs starting at `first_id` to the same color and lux
+    ///
+    /// LEDs past the end of the strip are ignored.
+    fn set_rgb_range(
+        context: &mut WrappedCaller<'_, Self>,
+        first_id: u16,
+        count: u16,
+        color: &LedColor,
+        lux: u32,
+    ) -> Result<u32, wasmi::Error>;
     fn led_count(context: &mut WrappedCaller<'_, Self>) -> Result<u16, wasmi::Error>;
+    /// The curve that is applied to the lux passed to `set-leds`, `set-rgb` and `set-rgb-range`
+    ///
+    /// The glue applies it before calling the LED functions, so they get the corrected lux. This is [BrightnessCurve::Linear] until the guest sets another curve.
+    fn brightness_curve(context: &mut WrappedCaller<'_, Self>) -> BrightnessCurve;
+    /// Store the curve the guest requested with `set-brightness-curve`
+    ///
+    /// The glue already rejected invalid curves.
+    fn set_brightness_curve(
+        context: &mut WrappedCaller<'_, Self>,
+        curve: BrightnessCurve,
+    ) -> Result<(), wasmi::Error>;
     fn get_led_info(
         context: &mut WrappedCaller<'_, Self>,
         id: u16,
@@ -222,6 +420,13 @@ where
         context: &mut WrappedCaller<'_, Self>,
     ) -> Result<VibrationSensorType, wasmi::Error>;
     fn get_vibration(context: &mut WrappedCaller<'_, Self>) -> Result<u32, wasmi::Error>;
+    /// Call `on-vibration` when the vibration rises above `threshold`. 0 disables the callback
+    ///
+    /// Use a [VibrationTrigger] to detect the crossings.
+    fn set_vibration_threshold(
+        context: &mut WrappedCaller<'_, Self>,
+        threshold: u32,
+    ) -> Result<(), wasmi::Error>;
 
     fn get_voltage_sensor_type(
         context: &mut WrappedCaller<'_, Self>,
@@ -236,6 +441,20 @@ where
         context: &mut WrappedCaller<'_, Self>,
         data: &[u8],
     ) -> Result<u32, wasmi::Error>;
+    /// Set the transmit power of the advertisements in dBm
+    ///
+    /// Round down to the next power level the radio supports and return the power that is used now.
+    fn set_advertisement_tx_power(
+        context: &mut WrappedCaller<'_, Self>,
+        tx_power: i8,
+    ) -> Result<i8, wasmi::Error>;
+
+    /// The meter that records the fuel consumption of the guest
+    ///
+    /// Return `None` to disable profiling, which is the default.
+    fn fuel_meter(&mut self) -> Option<&mut FuelMeter> {
+        return None;
+    }
 }
 
 pub fn to_error_code<T, E>(result: Result<T, E>, code: u32) -> Result<u32, wasmi::Error> {
@@ -19,7 +19,9 @@
 //! instance.run().unwrap();
 //! ```
 
+pub mod advertisement_layout;
 pub mod emulated_host;
+pub mod fuel;
 pub mod host;
 pub mod linker;
 
@@ -28,8 +30,18 @@ pub use wasmi::Error;
 
 #[cfg(test)]
 mod tests {
-    use super::emulated_host::EmulatedHost;
-    use super::linker::setup;
+    use super::advertisement_layout::{
+        encode_advertisement, encode_scan_response, MAX_ADVERTISEMENT_LENGTH,
+        MAX_MANUFACTURER_DATA_LENGTH, MAX_NAME_LENGTH,
+    };
+    use super::emulated_host::{EmulatedHost, Event, LedState, LogRecorder};
+    use super::fuel::FuelMeter;
+    use super::host::{
+        Advertisement, AdvertisementError, BrightnessCurve, LedColor, LedInfo, LogLevel,
+        SemanticVersion, YieldStatus, MAX_ADVERTISEMENT_DATA_LENGTH, UNKNOWN_RSSI,
+    };
+    use super::linker::{setup, YieldTermination, HOST_VERSION, RESET_FUEL};
+    use std::time::Duration;
 
     #[test]
     fn can_execute_helloworld() {
@@ -44,9 +56,395 @@ mod tests {
     fn logging_works() {
         let module_bytes = std::fs::read("../wasm-binaries/binaries/test_logging.wasm").unwrap();
 
+        let (_, mut host) = EmulatedHost::new();
+        let recorder = LogRecorder::new();
+        host.log_recorder = Some(recorder.clone());
+        let mut instance = setup(&module_bytes, host).unwrap();
+        instance.run().unwrap();
+
+        assert_eq!(
+            recorder.logs(),
+            vec![
+                (LogLevel::Info, "This is a info message".to_string()),
+                (LogLevel::Warn, "This is a warn message".to_string()),
+                (LogLevel::Error, "This is a error message".to_string()),
+                (LogLevel::Debug, "This is a debug message".to_string()),
+                (LogLevel::Trace, "This is a trace message".to_string()),
+            ]
+        );
+        for level in [
+            LogLevel::Error,
+            LogLevel::Warn,
+            LogLevel::Info,
+            LogLevel::Debug,
+            LogLevel::Trace,
+        ] {
+            assert_eq!(
+                recorder.logs_at(level),
+                vec![format!(
+                    "This is a {} message",
+                    level.to_string().to_lowercase()
+                )]
+            );
+        }
+    }
+
+    /// Logs `vibration` every time on-vibration is called
+    const VIBRATION_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.2" "yield-now" (func $yield_now (param i64) (result i32)))
+            (import "rudel:base/base@0.0.2" "log" (func $log (param i32 i32 i32)))
+            (import "rudel:base/hardware@0.0.2" "set-vibration-threshold" (func $set_vibration_threshold (param i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "vibration")
+            (func (export "rudel:base/run@0.0.2#run")
+                (call $set_vibration_threshold (i32.const 100))
+                (drop (call $yield_now (i64.const 0))))
+            (func (export "rudel:base/hardware-guest@0.0.2#on-vibration") (param i32)
+                (call $log (i32.const 2) (i32.const 0) (i32.const 9))))
+    "#;
+
+    #[test]
+    fn on_vibration_is_called_once_per_crossing() {
+        let (sender, mut host) = EmulatedHost::new();
+        let recorder = LogRecorder::new();
+        host.log_recorder = Some(recorder.clone());
+        for magnitude in [50, 150, 200, 100, 20, 120] {
+            sender.send(Event::Vibration(magnitude)).unwrap();
+        }
+        let mut instance = setup(VIBRATION_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        assert_eq!(
+            recorder.logs(),
+            vec![
+                (LogLevel::Info, "vibration".to_string()),
+                (LogLevel::Info, "vibration".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn on_vibration_is_optional() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/hello_world.wasm").unwrap();
+
         let (_, host) = EmulatedHost::new();
         let mut instance = setup(&module_bytes, host).unwrap();
+        instance.on_vibration(1000).unwrap();
+    }
+
+    /// A guest that logs the value stored under `phase`, or stores `42` there if there is none
+    const KV_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.2" "log" (func $log (param i32 i32 i32)))
+            (import "rudel:base/base@0.0.2" "kv-get" (func $kv_get (param i32 i32 i32)))
+            (import "rudel:base/base@0.0.2" "kv-set" (func $kv_set (param i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "phase")
+            (data (i32.const 16) "42")
+            (data (i32.const 32) "stored")
+            (func (export "cabi_realloc") (param i32 i32 i32 i32) (result i32)
+                (i32.const 1024))
+            (func (export "rudel:base/run@0.0.2#run")
+                (call $kv_get (i32.const 0) (i32.const 5) (i32.const 64))
+                (if (i32.load8_u (i32.const 64))
+                    (then
+                        (call $log (i32.const 2) (i32.load (i32.const 68)) (i32.load (i32.const 72))))
+                    (else
+                        (drop (call $kv_set (i32.const 0) (i32.const 5) (i32.const 16) (i32.const 2)))
+                        (call $log (i32.const 2) (i32.const 32) (i32.const 6))))))
+    "#;
+
+    #[test]
+    fn the_key_value_store_survives_a_reboot() {
+        let recorder = LogRecorder::new();
+        let (_, mut host) = EmulatedHost::new();
+        host.log_recorder = Some(recorder.clone());
+        let kv_store = host.kv_store.clone();
+        let mut instance = setup(KV_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+        drop(instance);
+
+        // A new instance with the same store, like after a reboot
+        let (_, mut host) = EmulatedHost::new();
+        host.log_recorder = Some(recorder.clone());
+        host.kv_store = kv_store.clone();
+        let mut instance = setup(KV_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        assert_eq!(
+            recorder.logs(),
+            vec![
+                (LogLevel::Info, "stored".to_string()),
+                (LogLevel::Info, "42".to_string()),
+            ]
+        );
+        assert_eq!(kv_store.get("phase"), Some(b"42".to_vec()));
+    }
+
+    /// A guest that sets `count` LEDs starting at `first_id` to orange with the given lux
+    fn rgb_range_guest(first_id: u16, count: u16, lux: u32) -> String {
+        return format!(
+            r#"
+            (module
+                (import "rudel:base/hardware@0.0.2" "set-rgb-range" (func $set_rgb_range (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "rudel:base/run@0.0.2#run")
+                    (drop (call $set_rgb_range (i32.const {first_id}) (i32.const {count}) (i32.const 255) (i32.const 128) (i32.const 0) (i32.const {lux})))))
+            "#
+        );
+    }
+
+    #[test]
+    fn set_rgb_range_sets_a_segment() {
+        let (_, host) = EmulatedHost::new();
+        let leds = host.leds.clone();
+        let mut instance = setup(rgb_range_guest(10, 3, 1000).as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        let orange = LedState {
+            color: LedColor::new(255, 128, 0),
+            lux: 1000,
+        };
+        assert_eq!(leds.get(9).unwrap().lux, 0);
+        assert_eq!(leds.get(10), Some(orange));
+        assert_eq!(leds.get(11), Some(orange));
+        assert_eq!(leds.get(12), Some(orange));
+        assert_eq!(leds.get(13).unwrap().lux, 0);
+    }
+
+    /// Requests a brightness curve, traps unless the host answers with `accepted` and then sets LED 0 to 500 lux
+    fn brightness_curve_guest(gamma: &str, accepted: bool) -> String {
+        let accepted = accepted as i32;
+        return format!(
+            r#"
+            (module
+                (import "rudel:base/hardware@0.0.2" "set-brightness-curve" (func $set_brightness_curve (param f32) (result i32)))
+                (import "rudel:base/hardware@0.0.2" "set-rgb-range" (func $set_rgb_range (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "rudel:base/run@0.0.2#run")
+                    (if (i32.ne (call $set_brightness_curve (f32.const {gamma})) (i32.const {accepted}))
+                        (then unreachable))
+                    (drop (call $set_rgb_range (i32.const 0) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (i32.const 500)))))
+            "#
+        );
+    }
+
+    #[test]
+    fn the_brightness_curve_is_linear_unless_the_guest_opts_in() {
+        let (_, mut host) = EmulatedHost::new();
+        host.led_info.max_lux = 1000;
+        let leds = host.leds.clone();
+        let mut instance = setup(rgb_range_guest(0, 1, 500).as_bytes(), host).unwrap();
+        instance.run().unwrap();
+        assert_eq!(leds.get(0).unwrap().lux, 500);
+    }
+
+    #[test]
+    fn the_guest_can_opt_into_a_brightness_curve() {
+        let (_, mut host) = EmulatedHost::new();
+        host.led_info.max_lux = 1000;
+        let leds = host.leds.clone();
+        let mut instance = setup(brightness_curve_guest("2", true).as_bytes(), host).unwrap();
+        instance.run().unwrap();
+        assert_eq!(leds.get(0).unwrap().lux, 250);
+    }
+
+    #[test]
+    fn invalid_brightness_curves_are_rejected() {
+        for gamma in ["nan", "0", "-2", "-inf"] {
+            let (_, mut host) = EmulatedHost::new();
+            host.led_info.max_lux = 1000;
+            let leds = host.leds.clone();
+            let mut instance =
+                setup(brightness_curve_guest(gamma, false).as_bytes(), host).unwrap();
+            instance.run().unwrap();
+            assert_eq!(leds.get(0).unwrap().lux, 500, "gamma {gamma}");
+        }
+    }
+
+    #[test]
+    fn the_host_can_be_accessed_between_runs() {
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(rgb_range_guest(0, 1, 500).as_bytes(), host).unwrap();
+        instance.run().unwrap();
+        assert_eq!(instance.state().leds.get(0).unwrap().lux, 500);
+
+        instance.state_mut().led_info.max_lux = 1000;
+        instance.state_mut().brightness_curve = BrightnessCurve::Gamma(2.0);
+        instance.run().unwrap();
+        assert_eq!(instance.state().leds.get(0).unwrap().lux, 250);
+    }
+
+    #[test]
+    fn gamma_curves_keep_the_range_of_the_led() {
+        assert_eq!(BrightnessCurve::Linear.apply(1234, 1000), 1234);
+        let gamma = BrightnessCurve::Gamma(2.2);
+        assert_eq!(gamma.apply(0, 1000), 0);
+        assert_eq!(gamma.apply(1000, 1000), 1000);
+        assert_eq!(gamma.apply(5000, 1000), 1000);
+        assert!(gamma.apply(500, 1000) < 500);
+        assert_eq!(gamma.apply(u16::MAX as u32, 0), u16::MAX as u32);
+        assert_eq!(BrightnessCurve::gamma(1.0), Some(BrightnessCurve::Linear));
+        assert_eq!(BrightnessCurve::gamma(2.2), Some(gamma));
+        assert_eq!(BrightnessCurve::gamma(f32::NAN), None);
+        assert_eq!(BrightnessCurve::gamma(0.0), None);
+        assert_eq!(BrightnessCurve::gamma(-1.0), None);
+    }
+
+    #[test]
+    fn set_rgb_range_ignores_leds_past_the_end() {
+        let (_, host) = EmulatedHost::new();
+        let leds = host.leds.clone();
+        let mut instance = setup(rgb_range_guest(498, 5, 7).as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        assert_eq!(leds.get(497).unwrap().lux, 0);
+        assert_eq!(leds.get(498).unwrap().lux, 7);
+        assert_eq!(leds.get(499).unwrap().lux, 7);
+        assert_eq!(leds.get(500), None);
+    }
+
+    /// Calls `set_leds(0, &[10, 20, 30])`
+    const SET_LEDS_GUEST: &str = r#"
+        (module
+            (import "rudel:base/hardware@0.0.1" "set-leds" (func $set_leds (param i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\0a\00\14\00\1e\00")
+            (func (export "rudel:base/run@0.0.1#run")
+                (drop (call $set_leds (i32.const 0) (i32.const 0) (i32.const 3)))))
+    "#;
+
+    #[test]
+    fn set_leds_records_every_value() {
+        let (_, host) = EmulatedHost::new();
+        let leds = host.leds.clone();
+        let mut instance = setup(SET_LEDS_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        let lux: Vec<u32> = (0..4).map(|id| leds.get(id).unwrap().lux).collect();
+        assert_eq!(lux, vec![10, 20, 30, 0]);
+    }
+
+    /// Traps unless `get_led_info(0).max_lux` is 1234
+    const LED_INFO_GUEST: &str = r#"
+        (module
+            (import "rudel:base/hardware@0.0.1" "get-led-info" (func $get_led_info (param i32 i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.1#run")
+                (call $get_led_info (i32.const 0) (i32.const 16))
+                (if (i32.ne (i32.load16_u (i32.const 20)) (i32.const 1234))
+                    (then unreachable))))
+    "#;
+
+    #[test]
+    fn get_led_info_reports_the_configured_max_lux() {
+        let (_, mut host) = EmulatedHost::new();
+        host.led_info = LedInfo {
+            color: LedColor::new(255, 255, 255),
+            max_lux: 1234,
+        };
+        let mut instance = setup(LED_INFO_GUEST.as_bytes(), host).unwrap();
         instance.run().unwrap();
+
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(LED_INFO_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap_err();
+    }
+
+    #[test]
+    fn a_missing_run_export_is_explained() {
+        let (_, host) = EmulatedHost::new();
+        let Err(error) = setup(r#"(module (memory (export "memory") 1))"#.as_bytes(), host) else {
+            panic!("A module without a run export should be rejected");
+        };
+        assert!(error
+            .to_string()
+            .contains("Did you use #[rudelblinken_sdk::main]"));
+    }
+
+    #[test]
+    fn a_guest_for_another_interface_version_is_rejected() {
+        let (_, host) = EmulatedHost::new();
+        let guest = r#"
+            (module
+                (import "rudel:base/base@0.1.0" "yield-now" (func $yield_now (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "rudel:base/run@0.0.1#run")
+                    (drop (call $yield_now (i64.const 0)))))
+        "#;
+        let Err(error) = setup(guest.as_bytes(), host) else {
+            panic!("A guest for another interface version should be rejected");
+        };
+        let error = error.to_string();
+        assert!(error.contains("rudel:base/base version 0.1.0"));
+        assert!(error.contains(&format!("provides version {}", HOST_VERSION)));
+    }
+
+    #[test]
+    fn a_guest_for_a_newer_interface_version_is_rejected() {
+        let (_, host) = EmulatedHost::new();
+        let newer_version = SemanticVersion::new(
+            HOST_VERSION.major,
+            HOST_VERSION.minor,
+            HOST_VERSION.patch + 1,
+        );
+        let guest = format!(
+            r#"
+            (module
+                (import "rudel:base/base@{newer_version}" "yield-now" (func $yield_now (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "rudel:base/run@{newer_version}#run")
+                    (drop (call $yield_now (i64.const 0)))))
+        "#
+        );
+        let Err(error) = setup(guest.as_bytes(), host) else {
+            panic!("A guest for a newer interface version should be rejected");
+        };
+        assert!(error
+            .to_string()
+            .contains(&format!("rudel:base/base version {}", newer_version)));
+    }
+
+    #[test]
+    fn a_guest_for_an_older_interface_version_runs() {
+        let (_, host) = EmulatedHost::new();
+        let guest = r#"
+            (module
+                (import "rudel:base/base@0.0.0" "yield-now" (func $yield_now (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "rudel:base/run@0.0.0#run")
+                    (drop (call $yield_now (i64.const 0)))))
+        "#;
+        let mut instance = setup(guest.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+    }
+
+    #[test]
+    fn guests_run_on_newer_hosts_of_the_same_minor_version() {
+        let guest = SemanticVersion::new(0, 1, 2);
+        assert!(guest.runs_on(SemanticVersion::new(0, 1, 2)));
+        assert!(guest.runs_on(SemanticVersion::new(0, 1, 3)));
+        assert!(!guest.runs_on(SemanticVersion::new(0, 1, 1)));
+        assert!(!guest.runs_on(SemanticVersion::new(0, 2, 2)));
+        assert!(!guest.runs_on(SemanticVersion::new(1, 1, 2)));
+
+        let guest = SemanticVersion::new(1, 1, 2);
+        assert!(guest.runs_on(SemanticVersion::new(1, 2, 0)));
+        assert!(!guest.runs_on(SemanticVersion::new(1, 1, 1)));
+        assert!(!guest.runs_on(SemanticVersion::new(2, 0, 0)));
+    }
+
+    #[test]
+    fn semantic_versions_are_parsed() {
+        assert_eq!(
+            SemanticVersion::parse("1.2.3"),
+            Some(SemanticVersion::new(1, 2, 3))
+        );
+        assert_eq!(SemanticVersion::parse("1.2"), None);
+        assert_eq!(SemanticVersion::parse("1.2.3.4"), None);
+        assert_eq!(SemanticVersion::parse("1.2.x"), None);
+        assert_eq!(SemanticVersion::new(0, 0, 1).to_string(), "0.0.1");
     }
 
     #[test]
@@ -61,6 +459,621 @@ mod tests {
             wasmi::core::TrapCode::OutOfFuel
         );
     }
+    #[test]
+    fn fuel_meter_attributes_fuel_to_host_calls() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/test_logging.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        host.fuel_meter = Some(FuelMeter::new());
+        let mut instance = setup(&module_bytes, host).unwrap();
+        instance.run().unwrap();
+
+        let total = instance.total_fuel_consumed().unwrap();
+        assert!(total > 0);
+        let meter = instance.fuel_meter().unwrap();
+        let log = meter.host_calls().get("log").unwrap();
+        assert!(log.calls > 0);
+        let attributed: u64 = meter.host_calls().values().map(|call| call.fuel).sum();
+        assert!(attributed <= total);
+    }
+
+    #[test]
+    fn fuel_consumed_since_reset_includes_a_killed_loop() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/infinite_loop.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        host.fuel_meter = Some(FuelMeter::new());
+        let mut instance = setup(&module_bytes, host).unwrap();
+        instance.run().unwrap_err();
+        assert_eq!(instance.fuel_consumed_since_reset(), Some(99999));
+    }
+
+    #[test]
+    fn fuel_is_not_measured_without_a_meter() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/test_logging.wasm").unwrap();
+
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(&module_bytes, host).unwrap();
+        instance.run().unwrap();
+        assert_eq!(instance.total_fuel_consumed(), None);
+    }
+
+    #[test]
+    fn board_test_detects_usb_before_battery() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/board_test.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        let recorder = LogRecorder::new();
+        host.log_recorder = Some(recorder.clone());
+        host.advance_time(0);
+        let supply = host.supply.clone();
+        supply.set(5000);
+        let mut instance = setup(&module_bytes, host).unwrap();
+        let stop = instance.stop_handle();
+        let guest = std::thread::spawn(move || instance.run());
+
+        assert!(recorder.wait_for("5V power supply detected", Duration::from_secs(10)));
+        supply.set(3700);
+        assert!(recorder.wait_for("Battery power supply working", Duration::from_secs(10)));
+
+        stop.request_stop();
+        guest.join().unwrap().unwrap_err();
+    }
+
+    #[test]
+    fn advertisements_can_be_delivered_to_a_linked_guest() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/hello_world.wasm").unwrap();
+
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(&module_bytes, host).unwrap();
+        let mut data = [0u8; 32];
+        data[0..3].copy_from_slice(&[1, 2, 3]);
+        instance
+            .on_advertisement(Advertisement {
+                company: 0x0ca7,
+                address: [1, 2, 3, 4, 5, 6, 0, 0],
+                data,
+                data_length: 3,
+                received_at: 0,
+                rssi: -60,
+            })
+            .unwrap();
+    }
+
+    /// Traps unless the RSSI of the advertisement is -60
+    const RSSI_GUEST: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.2#run"))
+            (func (export "rudel:base/ble-guest@0.0.2#on-advertisement")
+                (param $address i64) (param $company i32)
+                (param i32 i32 i32 i32 i32 i32 i32 i32)
+                (param $data_length i32) (param $received_at i64) (param $rssi i32)
+                (if (i32.ne (local.get $rssi) (i32.const -60))
+                    (then unreachable))))
+    "#;
+
+    #[test]
+    fn the_guest_receives_the_rssi() {
+        let advertisement = |rssi| Advertisement {
+            company: 0x0ca7,
+            address: [1, 2, 3, 4, 5, 6, 0, 0],
+            data: [0u8; 32],
+            data_length: 0,
+            received_at: 0,
+            rssi,
+        };
+
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(RSSI_GUEST.as_bytes(), host).unwrap();
+        instance.on_advertisement(advertisement(-60)).unwrap();
+        instance.on_advertisement(advertisement(-61)).unwrap_err();
+        instance
+            .on_advertisement(advertisement(UNKNOWN_RSSI))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn queued_advertisements_are_delivered_in_bounded_batches() {
+        let module_bytes = std::fs::read("../wasm-binaries/binaries/hello_world.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        host.fuel_meter = Some(FuelMeter::new());
+        let mut instance = setup(&module_bytes, host).unwrap();
+        for index in 0..3 {
+            instance.queue_advertisement(Advertisement {
+                company: 0x0ca7,
+                address: [index, 2, 3, 4, 5, 6, 0, 0],
+                data: [0u8; 32],
+                data_length: 0,
+                received_at: 0,
+                rssi: UNKNOWN_RSSI,
+            });
+        }
+
+        assert_eq!(instance.deliver_pending(2).unwrap(), 2);
+        assert_eq!(instance.pending_advertisements(), 1);
+        // The callbacks are metered like the run loop
+        assert!(instance.total_fuel_consumed().unwrap() > 0);
+        assert_eq!(instance.deliver_pending(5).unwrap(), 1);
+        assert_eq!(instance.deliver_pending(5).unwrap(), 0);
+        assert_eq!(instance.pending_advertisements(), 0);
+    }
+
+    #[test]
+    fn a_stop_request_terminates_a_yielding_guest() {
+        let module_bytes =
+            std::fs::read("../wasm-binaries/binaries/infinite_loop_yielding.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        // The guest logs on every iteration
+        host.log_recorder = Some(LogRecorder::new());
+        let mut instance = setup(&module_bytes, host).unwrap();
+        // The guest loops forever, so it can only return at a yield
+        instance.request_stop();
+
+        let error = instance.run().unwrap_err();
+        assert!(error.downcast_ref::<YieldTermination>().is_some());
+    }
+
+    #[test]
+    fn a_stop_handle_terminates_a_guest_on_another_thread() {
+        let module_bytes =
+            std::fs::read("../wasm-binaries/binaries/infinite_loop_yielding.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        let recorder = LogRecorder::new();
+        host.log_recorder = Some(recorder.clone());
+        let mut instance = setup(&module_bytes, host).unwrap();
+        let stop = instance.stop_handle();
+        let guest = std::thread::spawn(move || instance.run());
+
+        // Wait until the guest is running
+        assert!(recorder.wait_for("", Duration::from_secs(10)));
+        stop.request_stop();
+        let error = guest.join().unwrap().unwrap_err();
+        assert!(error.downcast_ref::<YieldTermination>().is_some());
+    }
+
+    #[test]
+    fn reference_sync_advertises_its_progress() {
+        let module_bytes =
+            std::fs::read("../wasm-binaries/binaries/reference_sync_v1.wasm").unwrap();
+
+        let (_, mut host) = EmulatedHost::new();
+        host.led_info.max_lux = 2500;
+        let leds = host.leds.clone();
+        let mut instance = setup(&module_bytes, host).unwrap();
+        let stop = instance.stop_handle();
+        let guest = std::thread::spawn(move || {
+            let result = instance.run();
+            (instance, result)
+        });
+
+        // The guest sets the LED after it advertised its progress
+        let start = std::time::Instant::now();
+        while leds.get(0).unwrap().lux == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        stop.request_stop();
+        let (instance, result) = guest.join().unwrap();
+        let error = result.unwrap_err();
+        assert!(error.downcast_ref::<YieldTermination>().is_some());
+
+        let data = instance.state().last_advertisement().unwrap();
+        // Company identifier 0x0000, followed by the magic bytes and the progress
+        assert_eq!(data.len(), 7);
+        assert_eq!(data[0..5], [0x00, 0x00, 0xca, 0x7e, 0xa2]);
+    }
+
+    /// Advertises `01 02` on the first run and `03 04 05` on every later run
+    const ADVERTISING_GUEST: &str = r#"
+        (module
+            (import "rudel:base/ble@0.0.1" "set-advertisement-data" (func $set_advertisement_data (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\01\02")
+            (data (i32.const 16) "\03\04\05")
+            (global $runs (mut i32) (i32.const 0))
+            (func (export "rudel:base/run@0.0.1#run")
+                (if (i32.eqz (global.get $runs))
+                    (then (drop (call $set_advertisement_data (i32.const 0) (i32.const 2))))
+                    (else (drop (call $set_advertisement_data (i32.const 16) (i32.const 3)))))
+                (global.set $runs (i32.add (global.get $runs) (i32.const 1)))))
+    "#;
+
+    #[test]
+    fn the_host_records_the_last_advertisement() {
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(ADVERTISING_GUEST.as_bytes(), host).unwrap();
+        assert_eq!(instance.state().last_advertisement(), None);
+
+        instance.run().unwrap();
+        assert_eq!(
+            instance.state().last_advertisement(),
+            Some([1, 2].as_slice())
+        );
+        instance.run().unwrap();
+        assert_eq!(
+            instance.state().last_advertisement(),
+            Some([3, 4, 5].as_slice())
+        );
+    }
+
+    /// Sets the transmit power to -6 dBm and traps unless the host uses it
+    const TX_POWER_GUEST: &str = r#"
+        (module
+            (import "rudel:base/ble@0.0.2" "set-advertisement-tx-power" (func $set_advertisement_tx_power (param i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.2#run")
+                (if (i32.ne (call $set_advertisement_tx_power (i32.const -6)) (i32.const -6))
+                    (then unreachable))))
+    "#;
+
+    #[test]
+    fn the_guest_can_set_the_tx_power() {
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(TX_POWER_GUEST.as_bytes(), host).unwrap();
+        assert_eq!(instance.state().advertisement_tx_power(), None);
+        instance.run().unwrap();
+        assert_eq!(instance.state().advertisement_tx_power(), Some(-6));
+    }
+
+    /// Advertises one byte more than allowed and then exactly as much as allowed, and reports the results as the lux of the first two LEDs
+    fn too_long_advertisement_guest() -> String {
+        return format!(
+            r#"
+            (module
+                (import "rudel:base/ble@0.0.2" "set-advertisement-data" (func $set_advertisement_data (param i32 i32) (result i32)))
+                (import "rudel:base/hardware@0.0.2" "set-rgb-range" (func $set_rgb_range (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "rudel:base/run@0.0.2#run")
+                    (drop (call $set_rgb_range (i32.const 0) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (call $set_advertisement_data (i32.const 0) (i32.const {too_long}))))
+                    (drop (call $set_rgb_range (i32.const 1) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (call $set_advertisement_data (i32.const 0) (i32.const {longest}))))))
+            "#,
+            too_long = MAX_ADVERTISEMENT_DATA_LENGTH + 1,
+            longest = MAX_ADVERTISEMENT_DATA_LENGTH,
+        );
+    }
+
+    #[test]
+    fn too_long_advertisement_data_is_rejected_by_the_glue() {
+        let (_, host) = EmulatedHost::new();
+        let leds = host.leds.clone();
+        let mut instance = setup(too_long_advertisement_guest().as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        assert_eq!(
+            leds.get(0).unwrap().lux,
+            AdvertisementError::DataTooLong as u32
+        );
+        assert_eq!(leds.get(1).unwrap().lux, 0);
+        assert_eq!(
+            instance.state().last_advertisement(),
+            Some([0; MAX_ADVERTISEMENT_DATA_LENGTH].as_slice())
+        );
+    }
+
+    #[test]
+    fn virtual_clock_only_moves_when_advanced() {
+        let (_, host) = EmulatedHost::new();
+        let clock = host.clock.clone();
+        assert!(!clock.is_virtual());
+
+        host.advance_time(200_000);
+        let start = clock.now();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert_eq!(clock.now(), start);
+
+        host.advance_time(200_000);
+        assert_eq!(clock.now(), start + 200_000);
+    }
+
+    #[test]
+    fn sleeping_advances_the_virtual_clock() {
+        let (_, mut host) = EmulatedHost::new();
+        host.advance_time(0);
+        let start = host.clock.now();
+        host.sleep_micros(20_000);
+        assert_eq!(host.clock.now(), start + 20_000);
+
+        host.sleep_jitter = 500;
+        for _ in 0..10 {
+            let before = host.clock.now();
+            host.sleep_micros(20_000);
+            let slept = host.clock.now() - before;
+            assert!((20_000..=20_500).contains(&slept), "slept {}", slept);
+        }
+    }
+
+    #[test]
+    fn sleeping_takes_at_least_the_requested_time() {
+        let (_, mut host) = EmulatedHost::new();
+        let start = host.clock.now();
+        host.sleep_micros(20_000);
+        assert!(host.clock.now() - start >= 20_000);
+    }
+
+    /// Advertises the status of two yields. An advertisement is waiting for the first one
+    const YIELD_STATUS_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.2" "yield-now" (func $yield_now (param i64) (result i32)))
+            (import "rudel:base/ble@0.0.2" "set-advertisement-data" (func $set_advertisement_data (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/ble-guest@0.0.2#on-advertisement")
+                (param i64 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32))
+            (func (export "rudel:base/run@0.0.2#run")
+                (i32.store8 (i32.const 0) (call $yield_now (i64.const 0)))
+                (i32.store8 (i32.const 1) (call $yield_now (i64.const 0)))
+                (drop (call $set_advertisement_data (i32.const 0) (i32.const 2)))))
+    "#;
+
+    #[test]
+    fn yield_reports_whether_callbacks_were_delivered() {
+        let (events, host) = EmulatedHost::new();
+        events
+            .send(Event::AdvertisementReceived(Advertisement {
+                company: 0x0ca7,
+                address: [1, 2, 3, 4, 5, 6, 0, 0],
+                data: [0u8; 32],
+                data_length: 0,
+                received_at: 0,
+                rssi: UNKNOWN_RSSI,
+            }))
+            .unwrap();
+        let mut instance = setup(YIELD_STATUS_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        assert_eq!(
+            instance.state().last_advertisement(),
+            Some(
+                [
+                    YieldStatus::CallbacksDelivered as u8,
+                    YieldStatus::Idle as u8
+                ]
+                .as_slice()
+            )
+        );
+    }
+
+    /// Advertises the value returned by a yield, as a guest built against 0.0.1
+    const OLD_YIELD_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.1" "yield-now" (func $yield_now (param i64) (result i32)))
+            (import "rudel:base/ble@0.0.1" "set-advertisement-data" (func $set_advertisement_data (param i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.1#run")
+                (i32.store (i32.const 0) (call $yield_now (i64.const 0)))
+                (drop (call $set_advertisement_data (i32.const 0) (i32.const 4)))))
+    "#;
+
+    #[test]
+    fn yield_returns_the_remaining_fuel_to_old_guests() {
+        let (_, host) = EmulatedHost::new();
+        let mut instance = setup(OLD_YIELD_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+
+        let advertisement = instance.state().last_advertisement().unwrap();
+        let fuel = u32::from_le_bytes(advertisement.try_into().unwrap());
+        assert!(fuel > 1000, "fuel {}", fuel);
+    }
+
+    #[test]
+    fn an_advertisement_interrupts_a_long_sleep() {
+        let (events, mut host) = EmulatedHost::new();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            events
+                .send(Event::AdvertisementReceived(Advertisement {
+                    company: 0x0ca7,
+                    address: [1, 2, 3, 4, 5, 6, 0, 0],
+                    data: [0u8; 32],
+                    data_length: 0,
+                    received_at: 0,
+                    rssi: UNKNOWN_RSSI,
+                }))
+                .unwrap();
+        });
+
+        let start = std::time::Instant::now();
+        let slept = host.sleep_micros(10_000_000);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(slept < 5_000_000, "slept {}", slept);
+        // The advertisement is kept for the next yield
+        assert!(host.event_pending());
+        sender.join().unwrap();
+    }
+
+    /// Sleeps for as long as possible and yields. Sets LED 0 to 1 lux when it receives an advertisement
+    const SLEEPING_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.2" "sleep" (func $sleep (param i64)))
+            (import "rudel:base/base@0.0.2" "yield-now" (func $yield_now (param i64) (result i32)))
+            (import "rudel:base/hardware@0.0.2" "set-rgb-range" (func $set_rgb_range (param i32 i32 i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.2#run")
+                (call $sleep (i64.const -1))
+                (drop (call $yield_now (i64.const 0))))
+            (func (export "rudel:base/ble-guest@0.0.2#on-advertisement")
+                (param i64 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32)
+                (drop (call $set_rgb_range (i32.const 0) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (i32.const 1)))))
+    "#;
+
+    #[test]
+    fn a_sleeping_guest_handles_an_advertisement_promptly() {
+        let (events, host) = EmulatedHost::new();
+        let leds = host.leds.clone();
+        let mut instance = setup(SLEEPING_GUEST.as_bytes(), host).unwrap();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            events
+                .send(Event::AdvertisementReceived(Advertisement {
+                    company: 0x0ca7,
+                    address: [1, 2, 3, 4, 5, 6, 0, 0],
+                    data: [0u8; 32],
+                    data_length: 0,
+                    received_at: 0,
+                    rssi: UNKNOWN_RSSI,
+                }))
+                .unwrap();
+        });
+
+        let start = std::time::Instant::now();
+        instance.run().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(leds.get(0).unwrap().lux, 1);
+        sender.join().unwrap();
+    }
+
+    /// Runs the loop of the SDK executor with a timer that ticks every millisecond, like `blink-async`
+    ///
+    /// Every poll that is not ready yields until the next tick. Every tick does about 3000 fuel of work and sets LED 0 to the number of ticks. Stops after 100 ticks. Sets LED 1 to 1 lux when it receives an advertisement.
+    const ASYNC_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.2" "time" (func $time (result i64)))
+            (import "rudel:base/base@0.0.2" "yield-now" (func $yield_now (param i64) (result i32)))
+            (import "rudel:base/hardware@0.0.2" "set-rgb-range" (func $set_rgb_range (param i32 i32 i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.2#run")
+                (local $next i64) (local $now i64) (local $ticks i32) (local $work i32)
+                (local.set $next (i64.add (call $time) (i64.const 1000)))
+                (loop $tick
+                    (block $ready
+                        (loop $poll
+                            (local.set $now (call $time))
+                            (br_if $ready (i64.ge_u (local.get $now) (local.get $next)))
+                            (drop (call $yield_now (i64.sub (local.get $next) (local.get $now))))
+                            (br $poll)))
+                    (local.set $next (i64.add (local.get $next) (i64.const 1000)))
+                    (if (i64.le_u (local.get $next) (local.get $now))
+                        (then (local.set $next (i64.add (local.get $now) (i64.const 1000)))))
+                    (local.set $work (i32.const 0))
+                    (loop $busy
+                        (local.set $work (i32.add (local.get $work) (i32.const 1)))
+                        (br_if $busy (i32.lt_u (local.get $work) (i32.const 500))))
+                    (local.set $ticks (i32.add (local.get $ticks) (i32.const 1)))
+                    (drop (call $set_rgb_range (i32.const 0) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (local.get $ticks)))
+                    (br_if $tick (i32.lt_u (local.get $ticks) (i32.const 100)))))
+            (func (export "rudel:base/ble-guest@0.0.2#on-advertisement")
+                (param i64 i32 i32 i32 i32 i32 i32 i32 i32 i32 i32 i64 i32)
+                (drop (call $set_rgb_range (i32.const 1) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (i32.const 1)))))
+    "#;
+
+    #[test]
+    fn an_async_guest_keeps_running_while_it_awaits_a_timer() {
+        let (events, mut host) = EmulatedHost::new();
+        host.fuel_meter = Some(FuelMeter::new());
+        let leds = host.leds.clone();
+        let clock = host.clock.clone();
+        events
+            .send(Event::AdvertisementReceived(Advertisement {
+                company: 0x0ca7,
+                address: [1, 2, 3, 4, 5, 6, 0, 0],
+                data: [0u8; 32],
+                data_length: 0,
+                received_at: 0,
+                rssi: UNKNOWN_RSSI,
+            }))
+            .unwrap();
+        let mut instance = setup(ASYNC_GUEST.as_bytes(), host).unwrap();
+
+        let start = clock.now();
+        instance.run().unwrap();
+        assert!(clock.now() - start >= 100 * 1000);
+        assert_eq!(leds.get(0).unwrap().lux, 100);
+        // The advertisement was delivered while the guest waited for a tick
+        assert_eq!(leds.get(1).unwrap().lux, 1);
+        // The guest used more fuel than it gets for a run, it is refueled while it awaits
+        assert!(instance.total_fuel_consumed().unwrap() > RESET_FUEL);
+        let yields = instance.fuel_meter().unwrap().host_calls()["yield-now"];
+        assert!(yields.calls >= 100);
+    }
+
+    #[test]
+    fn a_pending_event_interrupts_a_virtual_sleep() {
+        let (events, mut host) = EmulatedHost::new();
+        host.advance_time(0);
+        let start = host.clock.now();
+        events.send(Event::Vibration(5)).unwrap();
+
+        assert_eq!(host.sleep_micros(20_000), 0);
+        assert_eq!(host.clock.now(), start);
+        assert!(host.event_pending());
+    }
+
+    #[test]
+    fn random_numbers_are_reproducible() {
+        let (_, mut first) = EmulatedHost::new();
+        let (_, mut second) = EmulatedHost::new();
+        first.set_seed(42);
+        second.set_seed(42);
+        let first_numbers: Vec<u64> = (0..4).map(|_| first.next_random()).collect();
+        let second_numbers: Vec<u64> = (0..4).map(|_| second.next_random()).collect();
+        assert_eq!(first_numbers, second_numbers);
+        assert_ne!(first_numbers[0], first_numbers[1]);
+    }
+
+    /// A guest that uses the lower 32 bits of two random numbers as the lux of the first two LEDs
+    const RANDOM_GUEST: &str = r#"
+        (module
+            (import "rudel:base/base@0.0.2" "get-random" (func $get_random (result i64)))
+            (import "rudel:base/hardware@0.0.2" "set-rgb-range" (func $set_rgb_range (param i32 i32 i32 i32 i32 i32) (result i32)))
+            (memory (export "memory") 1)
+            (func (export "rudel:base/run@0.0.2#run")
+                (drop (call $set_rgb_range (i32.const 0) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (i32.wrap_i64 (call $get_random))))
+                (drop (call $set_rgb_range (i32.const 1) (i32.const 1) (i32.const 255) (i32.const 255) (i32.const 255) (i32.wrap_i64 (call $get_random))))))
+    "#;
+
+    /// Run [RANDOM_GUEST] on a host with the given seed and return the random numbers it got
+    fn random_numbers_of_guest(seed: u64) -> (u32, u32) {
+        let (_, mut host) = EmulatedHost::new();
+        host.set_seed(seed);
+        let leds = host.leds.clone();
+        let mut instance = setup(RANDOM_GUEST.as_bytes(), host).unwrap();
+        instance.run().unwrap();
+        return (leds.get(0).unwrap().lux, leds.get(1).unwrap().lux);
+    }
+
+    #[test]
+    fn guests_get_reproducible_random_numbers() {
+        let (first, second) = random_numbers_of_guest(42);
+        assert_ne!(first, second);
+        assert_eq!(random_numbers_of_guest(42), (first, second));
+        assert_ne!(random_numbers_of_guest(43), (first, second));
+    }
+
+    #[test]
+    fn the_advertisement_fits_with_every_field() {
+        let data = [0xab; MAX_MANUFACTURER_DATA_LENGTH];
+        let advertisement = encode_advertisement(Some(&data), Some(0x1234), Some(42)).unwrap();
+        assert_eq!(advertisement.len(), MAX_ADVERTISEMENT_LENGTH);
+        assert_eq!(
+            advertisement[3..].to_vec(),
+            [
+                [16, 0xFF].as_slice(),
+                &data,
+                &[5, 0x16, 0x92, 0x79, 0x34, 0x12],
+                &[4, 0x16, 0x0F, 0x18, 42],
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn too_long_manufacturer_data_is_rejected() {
+        let data = [0xab; MAX_MANUFACTURER_DATA_LENGTH + 1];
+        assert_eq!(encode_advertisement(Some(&data), None, None), None);
+    }
+
+    #[test]
+    fn the_longest_name_fits_into_the_scan_response() {
+        let name = "n".repeat(MAX_NAME_LENGTH);
+        let scan_response = encode_scan_response(&name).unwrap();
+        assert!(scan_response.len() <= MAX_ADVERTISEMENT_LENGTH);
+        assert_eq!(scan_response[2..6], *b"[rb]");
+        assert_eq!(encode_scan_response(&"n".repeat(MAX_NAME_LENGTH + 1)), None);
+    }
+
     // // How would I even test this?
     // #[test]
     // fn infinite_loop_does_not_get_killed_if_it_yields() {
@@ -1,30 +1,227 @@
 pub mod glue;
 pub mod linker;
 
-use crate::host::Host;
-use linker::{link_base, link_ble, link_hardware};
+use crate::{
+    fuel::FuelMeter,
+    host::{Advertisement, Host, SemanticVersion},
+};
+use linker::{
+    call_on_advertisement, find_export, link_base, link_ble, link_hardware,
+    ON_ADVERTISEMENT_EXPORT, ON_VIBRATION_EXPORT, RUN_EXPORT,
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use wasmi::{Config, Engine, Instance, Linker, Module, Store};
 
 const MAJOR: u8 = 0;
 const MINOR: u8 = 0;
-const PATCH: u8 = 1;
+const PATCH: u8 = 2;
+
+/// Version of the rudel interfaces provided by the host
+pub const HOST_VERSION: SemanticVersion = SemanticVersion::new(MAJOR, MINOR, PATCH);
+
+/// Versions of the rudel interfaces the host links its functions in, oldest first
+///
+/// These are all versions guests can be built against, see [SemanticVersion::runs_on].
+fn linked_versions() -> impl DoubleEndedIterator<Item = SemanticVersion> {
+    return (0..=PATCH).map(|patch| SemanticVersion::new(MAJOR, MINOR, patch));
+}
+
+/// Fuel the guest gets when it is linked and before every callback delivered by [LinkedHost::deliver_pending]
+pub const RESET_FUEL: u64 = 99999;
+
+/// Returned when the guest has no run export. This usually means that the entry point was not marked
+const MISSING_RUN_EXPORT: &str =
+    "The module does not export the rudel run entry point. Did you use #[rudelblinken_sdk::main]?";
+
+/// Requests a running guest to stop
+///
+/// Stopping is cooperative: The guest stops the next time it calls `yield-now` or `sleep`, which then fail with [YieldTermination]. A guest that never yields only stops when it runs out of fuel. Clones share the same request, so a handle can be moved to another thread while the guest runs.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Stop the guest at its next yield
+    pub fn request_stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether a stop was requested
+    pub fn stop_requested(&self) -> bool {
+        return self.0.load(Ordering::Relaxed);
+    }
+}
+
+/// The error returned by [LinkedHost::run], if the guest was stopped with a [StopHandle]
+///
+/// Check for it with `error.downcast_ref::<YieldTermination>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct YieldTermination;
+
+impl std::fmt::Display for YieldTermination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The guest was stopped at a yield")
+    }
+}
+
+impl wasmi::core::HostError for YieldTermination {}
 
 pub struct LinkedHost<T: Host> {
     instance: Instance,
     store: Store<T>,
+    stop: StopHandle,
+    /// Advertisements waiting to be delivered by [LinkedHost::deliver_pending]
+    pending_advertisements: VecDeque<Advertisement>,
 }
 
 impl<T: Host> LinkedHost<T> {
-    fn new(instance: Instance, store: Store<T>) -> Self {
-        return LinkedHost { instance, store };
+    fn new(instance: Instance, store: Store<T>, stop: StopHandle) -> Self {
+        return LinkedHost {
+            instance,
+            store,
+            stop,
+            pending_advertisements: VecDeque::new(),
+        };
     }
+
+    /// Stop the guest at its next yield. See [StopHandle]
+    pub fn request_stop(&self) {
+        self.stop.request_stop();
+    }
+
+    /// A handle to stop the guest from another thread while it runs
+    pub fn stop_handle(&self) -> StopHandle {
+        return self.stop.clone();
+    }
+
     pub fn run(&mut self) -> Result<(), wasmi::Error> {
-        let run = self
-            .instance
-            .get_typed_func::<(), ()>(&self.store, "rudel:base/run@0.0.1#run")?;
-        run.call(&mut self.store, ())?;
+        let Some(run) = find_export(RUN_EXPORT, |name| self.instance.get_func(&self.store, name))
+        else {
+            return Err(wasmi::Error::new(MISSING_RUN_EXPORT));
+        };
+        let Ok(run) = run.typed::<(), ()>(&self.store) else {
+            return Err(wasmi::Error::new(
+                "run does not have a matching function signature",
+            ));
+        };
+        if let Ok(fuel) = self.store.get_fuel() {
+            if let Some(meter) = self.store.data_mut().fuel_meter() {
+                meter.start(fuel);
+            }
+        }
+        let result = run.call(&mut self.store, ());
+        if let Ok(fuel) = self.store.get_fuel() {
+            if let Some(meter) = self.store.data_mut().fuel_meter() {
+                meter.stop(fuel);
+            }
+        }
+        result?;
         return Ok(());
     }
+
+    /// Deliver an advertisement to the guest
+    ///
+    /// Calls the on-advertisement export of the guest, which is also provided by guests using `#[on_event]`. Fails if the guest does not export it.
+    pub fn on_advertisement(&mut self, advertisement: Advertisement) -> Result<(), wasmi::Error> {
+        let Some(on_advertisement) = find_export(ON_ADVERTISEMENT_EXPORT, |name| {
+            self.instance.get_func(&self.store, name)
+        }) else {
+            return Err(wasmi::Error::new("on-advertisement not found"));
+        };
+        return call_on_advertisement(&mut self.store, on_advertisement, &advertisement);
+    }
+
+    /// Queue an advertisement to be delivered by [LinkedHost::deliver_pending]
+    pub fn queue_advertisement(&mut self, advertisement: Advertisement) {
+        self.pending_advertisements.push_back(advertisement);
+    }
+
+    /// Number of queued advertisements that were not delivered yet
+    pub fn pending_advertisements(&self) -> usize {
+        return self.pending_advertisements.len();
+    }
+
+    /// Deliver up to `max` queued advertisements, oldest first, and return how many were delivered
+    ///
+    /// The guest gets [RESET_FUEL] before every callback, so an expensive callback does not starve the next one. As the number of callbacks is bounded, callers can interleave them with other work, e.g. a simulator that runs many nodes. If a callback fails, its advertisement is dropped and the error is returned. The remaining advertisements stay queued.
+    pub fn deliver_pending(&mut self, max: usize) -> Result<usize, wasmi::Error> {
+        let mut delivered = 0;
+        while delivered < max {
+            let Some(advertisement) = self.pending_advertisements.pop_front() else {
+                break;
+            };
+            self.store.set_fuel(RESET_FUEL)?;
+            if let Some(meter) = self.store.data_mut().fuel_meter() {
+                meter.start(RESET_FUEL);
+            }
+            let result = self.on_advertisement(advertisement);
+            if let Ok(fuel) = self.store.get_fuel() {
+                if let Some(meter) = self.store.data_mut().fuel_meter() {
+                    meter.stop(fuel);
+                }
+            }
+            result?;
+            delivered += 1;
+        }
+        return Ok(delivered);
+    }
+
+    /// Deliver a vibration to the guest
+    ///
+    /// Calls the on-vibration export of the guest. Does nothing if the guest does not export it, as that export is optional.
+    pub fn on_vibration(&mut self, magnitude: u32) -> Result<(), wasmi::Error> {
+        let Some(on_vibration) = find_export(ON_VIBRATION_EXPORT, |name| {
+            self.instance.get_func(&self.store, name)
+        }) else {
+            return Ok(());
+        };
+        let Ok(on_vibration) = on_vibration.typed::<i32, ()>(&self.store) else {
+            return Err(wasmi::Error::new(
+                "on-vibration does not have a matching function signature",
+            ));
+        };
+        on_vibration.call(&mut self.store, magnitude as i32)?;
+        return Ok(());
+    }
+
+    /// The host, e.g. to read what the guest did between runs
+    pub fn state(&self) -> &T {
+        return self.store.data();
+    }
+
+    /// The host, e.g. to change its state before the next run
+    pub fn state_mut(&mut self) -> &mut T {
+        return self.store.data_mut();
+    }
+
+    /// The fuel meter of the host, if it has one
+    pub fn fuel_meter(&mut self) -> Option<&mut FuelMeter> {
+        return self.store.data_mut().fuel_meter();
+    }
+
+    /// Fuel the guest consumed since the store was last refueled
+    ///
+    /// Returns `None` if the host has no [FuelMeter].
+    pub fn fuel_consumed_since_reset(&mut self) -> Option<u64> {
+        let fuel = self.store.get_fuel().ok()?;
+        return self.fuel_meter()?.consumed_since_reset(fuel);
+    }
+
+    /// Fuel the guest consumed since the host was linked
+    ///
+    /// Returns `None` if the host has no [FuelMeter].
+    pub fn total_fuel_consumed(&mut self) -> Option<u64> {
+        return Some(self.fuel_meter()?.total());
+    }
 }
 
 pub fn setup<T: Host>(wasm: &[u8], host: T) -> Result<LinkedHost<T>, wasmi::Error> {
@@ -34,28 +231,58 @@ pub fn setup<T: Host>(wasm: &[u8], host: T) -> Result<LinkedHost<T>, wasmi::Erro
             .ignore_custom_sections(true),
     );
     let module = Module::new(&engine, wasm)?;
+    check_interface_versions(&module)?;
 
     let mut store = Store::new(&engine, host);
-    store.set_fuel(99999).unwrap();
+    store.set_fuel(RESET_FUEL).unwrap();
 
     let mut linker = <Linker<T>>::new(&engine);
 
-    setup_linker(&mut linker, &mut store)?;
+    let stop = StopHandle::new();
+    setup_linker(&mut linker, &mut store, &stop)?;
 
     let instance = linker.instantiate_and_start(&mut store, &module)?;
+    // Fail early, so the guest is not accepted just to fail once it is run
+    if find_export(RUN_EXPORT, |name| instance.get_func(&store, name)).is_none() {
+        return Err(wasmi::Error::new(MISSING_RUN_EXPORT));
+    }
 
-    let linked_instance = LinkedHost::new(instance, store);
+    let linked_instance = LinkedHost::new(instance, store, stop);
     return Ok(linked_instance);
 }
 
+/// Fail if the guest imports the rudel interfaces in a version the host can not run
+///
+/// The version is part of the import module names, like `rudel:base/base@0.0.1`. Guests built against an older compatible version are accepted, see [SemanticVersion::runs_on]. Linking would fail anyway, but only with an unknown import error that does not mention the version.
+fn check_interface_versions(module: &Module) -> Result<(), wasmi::Error> {
+    for import in module.imports() {
+        let Some((interface, version)) = import.module().rsplit_once('@') else {
+            continue;
+        };
+        if !interface.starts_with("rudel:") {
+            continue;
+        }
+        let compatible =
+            SemanticVersion::parse(version).is_some_and(|version| version.runs_on(HOST_VERSION));
+        if !compatible {
+            return Err(wasmi::Error::new(format!(
+                "The guest was built against {} version {}, but the host provides version {}. Rebuild the guest with a rudelblinken-sdk that matches the firmware",
+                interface, version, HOST_VERSION
+            )));
+        }
+    }
+    return Ok(());
+}
+
 /// Link the host functions provided by T.
 ///
-/// This functions will provide the rudel-host functions to the linker by generating glue code for the functionality provided by the host implementation T
+/// This functions will provide the rudel-host functions to the linker by generating glue code for the functionality provided by the host implementation T. `yield-now` and `sleep` fail with [YieldTermination] once `stop` was requested.
 pub fn setup_linker<T: Host>(
     linker: &mut Linker<T>,
     store: &mut Store<T>,
+    stop: &StopHandle,
 ) -> Result<(), wasmi::Error> {
-    link_base(linker, store)?;
+    link_base(linker, store, stop)?;
     link_hardware(linker, store)?;
     link_ble(linker, store)?;
 
@@ -1,8 +1,9 @@
 /// Provides functions that glue the relatively raw host functions to the implementation of Host
 use super::{linker::WrappedCaller, MAJOR, MINOR, PATCH};
 use crate::host::{
-    AdvertisementSettings, AmbientLightType, Host, LedColor, LedInfo, LogLevel, SemanticVersion,
-    VibrationSensorType, VoltageSensorType,
+    AdvertisementError, AdvertisementSettings, AmbientLightType, BrightnessCurve, Host, KvError,
+    LedColor, LedInfo, LogLevel, SemanticVersion, VibrationSensorType, VoltageSensorType,
+    YieldStatus, MAX_ADVERTISEMENT_DATA_LENGTH, MAX_KV_KEY_LENGTH, MAX_KV_VALUE_LENGTH,
 };
 
 /// `get-base-version: func() -> semantic-version;`
@@ -13,12 +14,24 @@ pub(super) fn get_base_version<T: Host>(
     *version = SemanticVersion::new(MAJOR, MINOR, PATCH);
     return Ok(());
 }
-/// `yield-now: func();`
+/// `yield-now: func(micros: u64) -> u32;`
 pub(super) fn yield_now<T: Host>(
     mut caller: WrappedCaller<'_, T>,
     micros: u64,
 ) -> Result<u32, wasmi::Error> {
-    return T::yield_now(&mut caller, micros);
+    let status: YieldStatus = T::yield_now(&mut caller, micros)?;
+    return Ok(status as u32);
+}
+/// `yield-now: func(micros: u64) -> u32;` for guests built against 0.0.1
+///
+/// These guests expect the remaining fuel instead of the [YieldStatus].
+pub(super) fn yield_now_remaining_fuel<T: Host>(
+    mut caller: WrappedCaller<'_, T>,
+    micros: u64,
+) -> Result<u32, wasmi::Error> {
+    T::yield_now(&mut caller, micros)?;
+    let fuel = caller.inner().get_fuel().unwrap_or(0);
+    return Ok(fuel.min(u32::MAX as u64) as u32);
 }
 /// `sleep: func(micros: u64);`
 pub(super) fn sleep<T: Host>(
@@ -31,6 +44,10 @@ pub(super) fn sleep<T: Host>(
 pub(super) fn time<T: Host>(mut caller: WrappedCaller<'_, T>) -> Result<u64, wasmi::Error> {
     return T::time(&mut caller);
 }
+/// `get-random: func() -> u64;`
+pub(super) fn get_random<T: Host>(mut caller: WrappedCaller<'_, T>) -> Result<u64, wasmi::Error> {
+    return T::get_random(&mut caller);
+}
 /// `log: func(level: log-level, message: string)  -> ();`
 pub(super) fn log<T: Host>(
     mut caller: WrappedCaller<'_, T>,
@@ -59,6 +76,35 @@ pub(super) fn get_config<T: Host>(
     T::get_config(caller)
 }
 
+/// `kv-get: func(key: string) -> option<list<u8>>;`
+pub(super) fn kv_get<T: Host>(
+    caller: &mut WrappedCaller<'_, T>,
+    key: &str,
+) -> Result<Option<Vec<u8>>, wasmi::Error> {
+    if key.len() > MAX_KV_KEY_LENGTH {
+        return Ok(None);
+    }
+    return T::kv_get(caller, key);
+}
+
+/// `kv-set: func(key: string, value: list<u8>) -> u32;`
+pub(super) fn kv_set<T: Host>(
+    mut caller: WrappedCaller<'_, T>,
+    key: &str,
+    value: &[u8],
+) -> Result<u32, wasmi::Error> {
+    if key.len() > MAX_KV_KEY_LENGTH {
+        return Ok(KvError::KeyTooLong as u32);
+    }
+    if value.len() > MAX_KV_VALUE_LENGTH {
+        return Ok(KvError::ValueTooLong as u32);
+    }
+    return match T::kv_set(&mut caller, key, value)? {
+        Ok(()) => Ok(0),
+        Err(error) => Ok(error as u32),
+    };
+}
+
 /// `get-hardware-version: func() -> semantic-version;`
 pub(super) fn get_hardware_version<T: Host>(
     mut _caller: WrappedCaller<'_, T>,
@@ -73,7 +119,17 @@ pub(super) fn set_leds<T: Host>(
     first_id: u16,
     leds: &[u16],
 ) -> Result<u32, wasmi::Error> {
-    T::set_leds(&mut caller, first_id, leds)
+    let curve = T::brightness_curve(&mut caller);
+    if curve == BrightnessCurve::Linear {
+        return T::set_leds(&mut caller, first_id, leds);
+    }
+    let mut corrected = Vec::with_capacity(leds.len());
+    for (index, lux) in leds.iter().enumerate() {
+        let id = first_id.saturating_add(index as u16);
+        let max_lux = T::get_led_info(&mut caller, id)?.max_lux;
+        corrected.push(curve.apply(*lux as u32, max_lux).min(u16::MAX as u32) as u16);
+    }
+    T::set_leds(&mut caller, first_id, &corrected)
 }
 /// `set-rgb: func(color: led-color, lux: u32) -> ();`
 pub(super) fn set_rgb<T: Host>(
@@ -81,8 +137,44 @@ pub(super) fn set_rgb<T: Host>(
     color: &LedColor,
     lux: u32,
 ) -> Result<u32, wasmi::Error> {
+    let lux = corrected_lux(&mut caller, 0, lux)?;
     T::set_rgb(&mut caller, color, lux)
 }
+/// `set-rgb-range: func(first-id: u16, count: u16, color: led-color, lux: u32) -> u32;`
+pub(super) fn set_rgb_range<T: Host>(
+    mut caller: WrappedCaller<'_, T>,
+    first_id: u16,
+    count: u16,
+    color: &LedColor,
+    lux: u32,
+) -> Result<u32, wasmi::Error> {
+    let lux = corrected_lux(&mut caller, first_id, lux)?;
+    T::set_rgb_range(&mut caller, first_id, count, color, lux)
+}
+/// Apply the brightness curve the guest set for the LED with the given id
+fn corrected_lux<T: Host>(
+    caller: &mut WrappedCaller<'_, T>,
+    id: u16,
+    lux: u32,
+) -> Result<u32, wasmi::Error> {
+    let curve = T::brightness_curve(caller);
+    if curve == BrightnessCurve::Linear {
+        return Ok(lux);
+    }
+    let max_lux = T::get_led_info(caller, id)?.max_lux;
+    return Ok(curve.apply(lux, max_lux));
+}
+/// `set-brightness-curve: func(gamma: f32) -> bool;`
+pub(super) fn set_brightness_curve<T: Host>(
+    mut caller: WrappedCaller<'_, T>,
+    gamma: f32,
+) -> Result<bool, wasmi::Error> {
+    let Some(curve) = BrightnessCurve::gamma(gamma) else {
+        return Ok(false);
+    };
+    T::set_brightness_curve(&mut caller, curve)?;
+    return Ok(true);
+}
 /// `led-count: func() -> u32;`
 pub(super) fn led_count<T: Host>(mut caller: WrappedCaller<'_, T>) -> Result<u16, wasmi::Error> {
     return T::led_count(&mut caller);
@@ -120,6 +212,13 @@ pub(super) fn get_vibration<T: Host>(
 ) -> Result<u32, wasmi::Error> {
     T::get_vibration(&mut caller)
 }
+/// `set-vibration-threshold: func(threshold: u32);`
+pub(super) fn set_vibration_threshold<T: Host>(
+    mut caller: WrappedCaller<'_, T>,
+    threshold: u32,
+) -> Result<(), wasmi::Error> {
+    T::set_vibration_threshold(&mut caller, threshold)
+}
 /// `get-voltage-sensor-type: func() -> voltage-sensor-type;`
 pub(super) fn get_voltage_sensor_type<T: Host>(
     mut caller: WrappedCaller<'_, T>,
@@ -148,10 +247,21 @@ pub(super) fn configure_advertisement<T: Host>(
     T::configure_advertisement(&mut caller, settings)
 }
 
-/// `set-advertisement-data: func(data: advertisement-data) -> ();`
+/// `set-advertisement-data: func(data: advertisement-data) -> u32;`
 pub(super) fn set_advertisement_data<T: Host>(
     mut caller: WrappedCaller<'_, T>,
     data: &[u8],
 ) -> Result<u32, wasmi::Error> {
+    if data.len() > MAX_ADVERTISEMENT_DATA_LENGTH {
+        return Ok(AdvertisementError::DataTooLong as u32);
+    }
     T::set_advertisement_data(&mut caller, data)
 }
+
+/// `set-advertisement-tx-power: func(tx-power: s8) -> s8;`
+pub(super) fn set_advertisement_tx_power<T: Host>(
+    mut caller: WrappedCaller<'_, T>,
+    tx_power: i8,
+) -> Result<i8, wasmi::Error> {
+    T::set_advertisement_tx_power(&mut caller, tx_power)
+}
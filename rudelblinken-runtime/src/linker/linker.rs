@@ -1,9 +1,28 @@
 use crate::host::{
     Advertisement, AdvertisementSettings, Host, LedColor, LedInfo, LogLevel, SemanticVersion,
 };
-use wasmi::{Caller, Extern, Func, Linker, Memory, Store};
+use wasmi::{AsContextMut, Caller, Extern, Func, Linker, Memory, Store};
 
-use super::glue;
+use super::{glue, linked_versions, StopHandle, YieldTermination};
+
+/// An export of the guest as the name of its interface and the name of the function
+///
+/// The export name of the guest also contains the version it was built against, like `rudel:base/run@0.0.1#run`. Use [find_export] to look it up.
+pub type Export = (&'static str, &'static str);
+
+/// The entry point of the guest. Guests built with `#[main]` provide it.
+pub const RUN_EXPORT: Export = ("rudel:base/run", "run");
+/// The export that receives advertisements. Guests built with `#[on_advertisement]` and `#[on_event]` both provide it.
+pub const ON_ADVERTISEMENT_EXPORT: Export = ("rudel:base/ble-guest", "on-advertisement");
+/// The export that receives vibrations. It is optional, guests built with `#[on_vibration]` provide it.
+pub const ON_VIBRATION_EXPORT: Export = ("rudel:base/hardware-guest", "on-vibration");
+
+/// Find `export` in whatever version the guest was built against
+pub fn find_export<R>(export: Export, mut lookup: impl FnMut(&str) -> Option<R>) -> Option<R> {
+    return linked_versions()
+        .rev()
+        .find_map(|version| lookup(&format!("{}@{}#{}", export.0, version, export.1)));
+}
 
 #[repr(transparent)]
 pub struct WrappedCaller<'a, T: Host + Sized>(Caller<'a, T>);
@@ -12,6 +31,15 @@ impl<'a, T: Host> WrappedCaller<'a, T> {
     pub fn new(caller: Caller<'a, T>) -> WrappedCaller<'a, T> {
         return WrappedCaller(caller);
     }
+    /// Wrap the caller of the host function `function` and record the call in the fuel meter
+    pub fn enter(mut caller: Caller<'a, T>, function: &'static str) -> WrappedCaller<'a, T> {
+        if let Ok(fuel) = caller.get_fuel() {
+            if let Some(meter) = caller.data_mut().fuel_meter() {
+                meter.enter(function, fuel);
+            }
+        }
+        return WrappedCaller(caller);
+    }
     pub fn inner(&mut self) -> &mut Caller<'a, T> {
         return &mut self.0;
     }
@@ -45,7 +73,7 @@ impl<'a, T: Host> WrappedCaller<'a, T> {
     }
 
     pub fn run(&mut self) -> Result<(), wasmi::Error> {
-        let Some(run) = self.0.get_export("rudel:base/run@0.0.1#run") else {
+        let Some(run) = find_export(RUN_EXPORT, |name| self.0.get_export(name)) else {
             return Err(wasmi::Error::new("run not found"));
         };
         let Extern::Func(run) = run else {
@@ -61,44 +89,124 @@ impl<'a, T: Host> WrappedCaller<'a, T> {
     }
 
     pub fn on_advertisement(&mut self, advertisement: Advertisement) -> Result<(), wasmi::Error> {
-        let Some(run) = self
-            .0
-            .get_export("rudel:base/ble-guest@0.0.1#on-advertisement")
-        else {
+        let Some(run) = find_export(ON_ADVERTISEMENT_EXPORT, |name| self.0.get_export(name)) else {
             return Err(wasmi::Error::new("on-advertisement not found"));
         };
         let Extern::Func(run) = run else {
             return Err(wasmi::Error::new("on-advertisement is not a function"));
         };
-        let Ok(run) =
-            run.typed::<(u64, u32, u32, u32, u32, u32, u32, u32, u32, u32, u32, u64), ()>(&self.0)
-        else {
+        return call_on_advertisement(&mut self.0, run, &advertisement);
+    }
+
+    /// Deliver a vibration to the guest
+    ///
+    /// Does nothing if the guest does not export on-vibration, as it only needs to if it wants to react to vibrations.
+    pub fn on_vibration(&mut self, magnitude: u32) -> Result<(), wasmi::Error> {
+        let Some(run) = find_export(ON_VIBRATION_EXPORT, |name| self.0.get_export(name)) else {
+            return Ok(());
+        };
+        let Extern::Func(run) = run else {
+            return Err(wasmi::Error::new("on-vibration is not a function"));
+        };
+        let Ok(run) = run.typed::<i32, ()>(&self.0) else {
             return Err(wasmi::Error::new(
-                "on-advertisement does not have a matching function signature",
+                "on-vibration does not have a matching function signature",
             ));
         };
 
-        let address = u64::from_le_bytes(advertisement.address);
-        let company = advertisement.company as u32;
-        let data = unsafe { std::mem::transmute::<[u8; 32], [u32; 8]>(advertisement.data) };
-        run.call(
-            &mut self.0,
+        run.call(&mut self.0, magnitude as i32)?;
+        return Ok(());
+    }
+}
+
+/// Parameters of the on-advertisement export: address, company, 8 words of data, data length, receive time and RSSI
+pub(crate) type OnAdvertisementParams = (
+    u64,
+    u32,
+    u32,
+    u32,
+    u32,
+    u32,
+    u32,
+    u32,
+    u32,
+    u32,
+    u32,
+    u64,
+    i32,
+);
+
+/// Parameters of the on-advertisement export of guests built before the advertisement had an RSSI
+pub(crate) type LegacyOnAdvertisementParams =
+    (u64, u32, u32, u32, u32, u32, u32, u32, u32, u32, u32, u64);
+
+/// Convert an advertisement to the parameters of the on-advertisement export
+pub(crate) fn lower_advertisement(advertisement: &Advertisement) -> OnAdvertisementParams {
+    let address = u64::from_le_bytes(advertisement.address);
+    let company = advertisement.company as u32;
+    let data = unsafe { std::mem::transmute::<[u8; 32], [u32; 8]>(advertisement.data) };
+    return (
+        address,
+        company,
+        data[0],
+        data[1],
+        data[2],
+        data[3],
+        data[4],
+        data[5],
+        data[6],
+        data[7],
+        advertisement.data_length as u32,
+        advertisement.received_at,
+        advertisement.rssi as i32,
+    );
+}
+
+/// Call an on-advertisement export with the advertisement
+///
+/// Guests built before the advertisement had an RSSI export a function without it, so they still get the advertisement, just without the RSSI.
+pub(crate) fn call_on_advertisement(
+    mut context: impl AsContextMut,
+    on_advertisement: Func,
+    advertisement: &Advertisement,
+) -> Result<(), wasmi::Error> {
+    if let Ok(typed) = on_advertisement.typed::<OnAdvertisementParams, ()>(&context) {
+        return typed.call(&mut context, lower_advertisement(advertisement));
+    }
+    if let Ok(typed) = on_advertisement.typed::<LegacyOnAdvertisementParams, ()>(&context) {
+        let (address, company, d0, d1, d2, d3, d4, d5, d6, d7, data_length, received_at, _) =
+            lower_advertisement(advertisement);
+        return typed.call(
+            &mut context,
             (
                 address,
                 company,
-                data[0],
-                data[1],
-                data[2],
-                data[3],
-                data[4],
-                data[5],
-                data[6],
-                data[7],
-                advertisement.data_length as u32,
-                advertisement.received_at,
+                d0,
+                d1,
+                d2,
+                d3,
+                d4,
+                d5,
+                d6,
+                d7,
+                data_length,
+                received_at,
             ),
-        )?;
-        return Ok(());
+        );
+    }
+    return Err(wasmi::Error::new(
+        "on-advertisement does not have a matching function signature",
+    ));
+}
+
+impl<'a, T: Host> Drop for WrappedCaller<'a, T> {
+    /// Record in the fuel meter that the host function returned to the guest
+    fn drop(&mut self) {
+        if let Ok(fuel) = self.0.get_fuel() {
+            if let Some(meter) = self.0.data_mut().fuel_meter() {
+                meter.leave(fuel);
+            }
+        }
     }
 }
 
@@ -179,14 +287,41 @@ fn get_mut_array<T: Host, const L: usize>(
 
 /// Link the host functions provided by T.
 ///
-/// This functions will provide the rudel-host functions to the linker by generating glue code for the functionality provided by the host implementation T
+/// This functions will provide the rudel-host functions to the linker by generating glue code for the functionality provided by the host implementation T. The function is linked in every version in [linked_versions], so guests built against an older version find it as well.
 pub fn link_function<T: Host>(
     linker: &mut Linker<T>,
     module: &str,
     function: &str,
     implementation: impl Into<Extern>,
 ) -> Result<(), wasmi::Error> {
-    linker.define(&format!("{}@0.0.1", module), function, implementation)?;
+    let implementation: Extern = implementation.into();
+    for version in linked_versions() {
+        linker.define(&format!("{}@{}", module, version), function, implementation)?;
+    }
+    return Ok(());
+}
+
+/// Link a host function whose behavior changed in version `since`
+///
+/// Guests built against a version before `since` get `before`, all others get `implementation`.
+pub fn link_changed_function<T: Host>(
+    linker: &mut Linker<T>,
+    module: &str,
+    function: &str,
+    since: SemanticVersion,
+    before: impl Into<Extern>,
+    implementation: impl Into<Extern>,
+) -> Result<(), wasmi::Error> {
+    let before: Extern = before.into();
+    let implementation: Extern = implementation.into();
+    for version in linked_versions() {
+        let versioned = if version < since {
+            before
+        } else {
+            implementation
+        };
+        linker.define(&format!("{}@{}", module, version), function, versioned)?;
+    }
     return Ok(());
 }
 
@@ -196,7 +331,12 @@ pub fn link_function<T: Host>(
 pub fn link_base<T: Host>(
     linker: &mut Linker<T>,
     mut store: &mut Store<T>,
+    stop: &StopHandle,
 ) -> Result<(), wasmi::Error> {
+    let old_yield_stop = stop.clone();
+    let yield_stop = stop.clone();
+    let sleep_stop = stop.clone();
+
     // __attribute__((__import_module__("rudel:base/base@0.0.1"), __import_name__("get-base-version")))
     // extern void __wasm_import_rudel_base_base_get_base_version(uint8_t *);
     link_function(
@@ -206,7 +346,7 @@ pub fn link_base<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, offset: i32| -> Result<(), wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "get-base-version");
                 let memory = get_memory(caller.as_ref())?;
                 let slice = get_mut_slice(&memory, caller.as_mut(), offset as u32, 4)?;
                 // SAFETY: Should be safe because the layout should match
@@ -223,14 +363,29 @@ pub fn link_base<T: Host>(
 
     // __attribute__((__import_module__("rudel:base/base@0.0.1"), __import_name__("yield-now")))
     // extern void __wasm_import_rudel_base_base_yield_now(void);
-    link_function(
+    // Before 0.0.2 yield-now returned the remaining fuel
+    link_changed_function(
         linker,
         "rudel:base/base",
         "yield-now",
+        SemanticVersion::new(0, 0, 2),
         Func::wrap(
             &mut store,
-            |caller: Caller<'_, T>, micros: u64| -> Result<u32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+            move |caller: Caller<'_, T>, micros: u64| -> Result<u32, wasmi::Error> {
+                if old_yield_stop.stop_requested() {
+                    return Err(wasmi::Error::host(YieldTermination));
+                }
+                let caller = WrappedCaller::enter(caller, "yield-now");
+                return glue::yield_now_remaining_fuel(caller, micros);
+            },
+        ),
+        Func::wrap(
+            &mut store,
+            move |caller: Caller<'_, T>, micros: u64| -> Result<u32, wasmi::Error> {
+                if yield_stop.stop_requested() {
+                    return Err(wasmi::Error::host(YieldTermination));
+                }
+                let caller = WrappedCaller::enter(caller, "yield-now");
                 return glue::yield_now(caller, micros);
             },
         ),
@@ -244,8 +399,11 @@ pub fn link_base<T: Host>(
         "sleep",
         Func::wrap(
             &mut store,
-            |caller: Caller<'_, T>, micros: u64| -> Result<(), wasmi::Error> {
-                let caller = WrappedCaller(caller);
+            move |caller: Caller<'_, T>, micros: u64| -> Result<(), wasmi::Error> {
+                if sleep_stop.stop_requested() {
+                    return Err(wasmi::Error::host(YieldTermination));
+                }
+                let caller = WrappedCaller::enter(caller, "sleep");
                 return glue::sleep(caller, micros);
             },
         ),
@@ -260,12 +418,27 @@ pub fn link_base<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<u64, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "time");
                 return glue::time(caller);
             },
         ),
     )?;
 
+    // __attribute__((__import_module__("rudel:base/base@0.0.1"), __import_name__("get-random")))
+    // extern int64_t __wasm_import_rudel_base_base_get_random(void);
+    link_function(
+        linker,
+        "rudel:base/base",
+        "get-random",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>| -> Result<u64, wasmi::Error> {
+                let caller = WrappedCaller::enter(caller, "get-random");
+                return glue::get_random(caller);
+            },
+        ),
+    )?;
+
     // __attribute__((__import_module__("rudel:base/base@0.0.1"), __import_name__("log")))
     // extern void __wasm_import_rudel_base_base_log(int32_t, uint8_t *, size_t);
     link_function(
@@ -279,7 +452,7 @@ pub fn link_base<T: Host>(
              message_offset: i32,
              message_length: i32|
              -> Result<(), wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "log");
 
                 let log_level = LogLevel::lift(level);
 
@@ -303,7 +476,7 @@ pub fn link_base<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, offset: i32| -> Result<(), wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "get-name");
                 let memory = get_memory(caller.as_ref())?;
                 let data = get_mut_array::<T, 16>(&memory, caller.as_mut(), offset)?;
                 return glue::get_name(caller, data);
@@ -320,7 +493,7 @@ pub fn link_base<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, ret: i32| -> Result<(), wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "get-config");
                 let memory = get_memory(caller.as_ref())?;
 
                 // typedef struct {
@@ -353,6 +526,79 @@ pub fn link_base<T: Host>(
         ),
     )?;
 
+    // __attribute__((__import_module__("rudel:base/base@0.0.1"), __import_name__("kv-get")))
+    // extern void __wasm_import_rudel_base_base_kv_get(uint8_t *, size_t, uint8_t *);
+    link_function(
+        linker,
+        "rudel:base/base",
+        "kv-get",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>,
+             key_offset: i32,
+             key_length: i32,
+             ret: i32|
+             -> Result<(), wasmi::Error> {
+                let mut caller = WrappedCaller::enter(caller, "kv-get");
+                let memory = get_memory(caller.as_ref())?;
+                let key_data = get_slice(&memory, caller.as_ref(), key_offset, key_length)?;
+                let key = match std::str::from_utf8(key_data) {
+                    Ok(s) => s,
+                    Err(_) => return Err(wasmi::Error::new("invalid utf-8")),
+                };
+
+                let value = glue::kv_get(&mut caller, key)?;
+
+                // typedef struct {
+                //   bool is_some;
+                //   rudel_list_u8_t val;
+                // } rudel_option_list_u8_t;
+                let Some(value) = value else {
+                    let option = get_mut_array::<T, 12>(&memory, caller.as_mut(), ret)?;
+                    option[0] = 0;
+                    return Ok(());
+                };
+                // alignment for u8 is 1 byte
+                let ptr = caller.realloc(0, 0, 1, value.len() as u32)?;
+                let dst = get_mut_slice(&memory, caller.as_mut(), ptr, value.len() as u32)?;
+                dst.copy_from_slice(&value);
+
+                let option = get_mut_array::<T, 12>(&memory, caller.as_mut(), ret)?;
+                option[0] = 1;
+                option[4..8].copy_from_slice(&ptr.to_le_bytes());
+                option[8..12].copy_from_slice(&(value.len() as u32).to_le_bytes());
+                Ok(())
+            },
+        ),
+    )?;
+
+    // __attribute__((__import_module__("rudel:base/base@0.0.1"), __import_name__("kv-set")))
+    // extern int32_t __wasm_import_rudel_base_base_kv_set(uint8_t *, size_t, uint8_t *, size_t);
+    link_function(
+        linker,
+        "rudel:base/base",
+        "kv-set",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>,
+             key_offset: i32,
+             key_length: i32,
+             value_offset: i32,
+             value_length: i32|
+             -> Result<u32, wasmi::Error> {
+                let caller = WrappedCaller::enter(caller, "kv-set");
+                let memory = get_memory(caller.as_ref())?;
+                let key_data = get_slice(&memory, caller.as_ref(), key_offset, key_length)?;
+                let key = match std::str::from_utf8(key_data) {
+                    Ok(s) => s,
+                    Err(_) => return Err(wasmi::Error::new("invalid utf-8")),
+                };
+                let value = get_slice(&memory, caller.as_ref(), value_offset, value_length)?;
+                return glue::kv_set(caller, key, value);
+            },
+        ),
+    )?;
+
     return Ok(());
 }
 
@@ -372,7 +618,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, offset: i32| -> Result<(), wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "get-hardware-version");
                 let memory = get_memory(caller.as_ref())?;
                 let slice = get_mut_slice(&memory, caller.as_mut(), offset as u32, 4)?;
                 // SAFETY: Should be safe because the layout should match
@@ -400,7 +646,7 @@ pub fn link_hardware<T: Host>(
              offset: i32,
              length: i32|
              -> Result<u32, wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "set-leds");
                 let memory = get_memory(caller.as_ref())?;
                 let slice = get_slice(&memory, caller.as_mut(), offset, length * 2)?;
                 // SAFETY: Should be safe because the layout should match
@@ -428,7 +674,7 @@ pub fn link_hardware<T: Host>(
              blue: i32,
              lux: i32|
              -> Result<u32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "set-rgb");
                 let color = LedColor {
                     red: red.to_le_bytes()[0],
                     green: green.to_le_bytes()[0],
@@ -440,6 +686,49 @@ pub fn link_hardware<T: Host>(
         ),
     )?;
 
+    // __attribute__((__import_module__("rudel:base/hardware@0.0.1"), __import_name__("set-rgb-range")))
+    // extern int32_t __wasm_import_rudel_base_hardware_set_rgb_range(int32_t, int32_t, int32_t, int32_t, int32_t, int32_t);
+    link_function(
+        linker,
+        "rudel:base/hardware",
+        "set-rgb-range",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>,
+             first_id: i32,
+             count: i32,
+             red: i32,
+             green: i32,
+             blue: i32,
+             lux: i32|
+             -> Result<u32, wasmi::Error> {
+                let caller = WrappedCaller::enter(caller, "set-rgb-range");
+                let color = LedColor {
+                    red: red.to_le_bytes()[0],
+                    green: green.to_le_bytes()[0],
+                    blue: blue.to_le_bytes()[0],
+                };
+
+                glue::set_rgb_range(caller, first_id as u16, count as u16, &color, lux as u32)
+            },
+        ),
+    )?;
+
+    // __attribute__((__import_module__("rudel:base/hardware@0.0.2"), __import_name__("set-brightness-curve")))
+    // extern int32_t __wasm_import_rudel_base_hardware_set_brightness_curve(float);
+    link_function(
+        linker,
+        "rudel:base/hardware",
+        "set-brightness-curve",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>, gamma: f32| -> Result<i32, wasmi::Error> {
+                let caller = WrappedCaller::enter(caller, "set-brightness-curve");
+                glue::set_brightness_curve(caller, gamma).map(|result| result as i32)
+            },
+        ),
+    )?;
+
     // __attribute__((__import_module__("rudel:base/hardware@0.0.1"), __import_name__("led-count")))
     // extern int32_t __wasm_import_rudel_base_hardware_led_count(void);
     link_function(
@@ -449,7 +738,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "led-count");
                 glue::led_count(caller).map(|result| result as i32)
             },
         ),
@@ -464,7 +753,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, id: i32, offset: i32| -> Result<(), wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "get-led-info");
                 let memory = get_memory(caller.as_ref())?;
                 let slice = get_mut_slice(&memory, caller.as_mut(), offset as u32, 6)?;
                 // Layout in memory is
@@ -492,7 +781,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "get-ambient-light-type");
                 return glue::get_ambient_light_type(caller).map(|result| result.lower());
             },
         ),
@@ -507,7 +796,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "get-ambient-light");
                 return glue::get_ambient_light(caller).map(|result| result as i32);
             },
         ),
@@ -522,7 +811,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "get-vibration-sensor-type");
                 return glue::get_vibration_sensor_type(caller).map(|result| result.lower());
             },
         ),
@@ -537,12 +826,27 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "get-vibration");
                 return glue::get_vibration(caller).map(|result| result as i32);
             },
         ),
     )?;
 
+    // __attribute__((__import_module__("rudel:base/hardware@0.0.1"), __import_name__("set-vibration-threshold")))
+    // extern void __wasm_import_rudel_base_hardware_set_vibration_threshold(int32_t);
+    link_function(
+        linker,
+        "rudel:base/hardware",
+        "set-vibration-threshold",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>, threshold: i32| -> Result<(), wasmi::Error> {
+                let caller = WrappedCaller::enter(caller, "set-vibration-threshold");
+                return glue::set_vibration_threshold(caller, threshold as u32);
+            },
+        ),
+    )?;
+
     // __attribute__((__import_module__("rudel:base/hardware@0.0.1"), __import_name__("get-voltage-sensor-type")))
     // extern int32_t __wasm_import_rudel_base_hardware_voltage_type(void);
     link_function(
@@ -552,7 +856,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "get-voltage-sensor-type");
                 return glue::get_voltage_sensor_type(caller).map(|result| result.lower());
             },
         ),
@@ -567,7 +871,7 @@ pub fn link_hardware<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>| -> Result<i32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "get-voltage");
                 return glue::get_voltage(caller).map(|result| result as i32);
             },
         ),
@@ -592,7 +896,7 @@ pub fn link_ble<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, offset: i32| -> Result<(), wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "get-ble-version");
                 let memory = get_memory(caller.as_ref())?;
                 let slice = get_mut_slice(&memory, caller.as_mut(), offset as u32, 4)?;
                 // SAFETY: Should be safe because the layout should match
@@ -619,7 +923,7 @@ pub fn link_ble<T: Host>(
              min_interval: i32,
              max_interval: i32|
              -> Result<u32, wasmi::Error> {
-                let caller = WrappedCaller(caller);
+                let caller = WrappedCaller::enter(caller, "configure-advertisement");
 
                 glue::configure_advertisement(
                     caller,
@@ -641,7 +945,7 @@ pub fn link_ble<T: Host>(
         Func::wrap(
             &mut store,
             |caller: Caller<'_, T>, offset: i32, length: i32| -> Result<u32, wasmi::Error> {
-                let mut caller = WrappedCaller(caller);
+                let mut caller = WrappedCaller::enter(caller, "set-advertisement-data");
                 let memory = get_memory(caller.as_ref())?;
                 let slice = get_slice(&memory, caller.as_mut(), offset, length)?;
                 // // Remove lifetime
@@ -652,5 +956,21 @@ pub fn link_ble<T: Host>(
         ),
     )?;
 
+    // __attribute__((__import_module__("rudel:base/ble@0.0.2"), __import_name__("set-advertisement-tx-power")))
+    // extern int32_t __wasm_import_rudel_base_ble_set_advertisement_tx_power(int32_t);
+    link_function(
+        linker,
+        "rudel:base/ble",
+        "set-advertisement-tx-power",
+        Func::wrap(
+            &mut store,
+            |caller: Caller<'_, T>, tx_power: i32| -> Result<i32, wasmi::Error> {
+                let caller = WrappedCaller::enter(caller, "set-advertisement-tx-power");
+                glue::set_advertisement_tx_power(caller, tx_power as i8)
+                    .map(|tx_power| tx_power as i32)
+            },
+        ),
+    )?;
+
     return Ok(());
 }
@@ -61,6 +61,14 @@
 //! }
 //! ```
 //!
+//! Use `#[main(allocator = false)]` to skip the allocator, e.g. to supply your own `#[global_allocator]`.
+//!
+//! Use `#[main(async)]` for an `async fn main`. It is run by `rudelblinken_sdk::executor::block_on`, which returns to the host whenever the program awaits `rudelblinken_sdk::executor::sleep` or a timer. The arguments can be combined, like `#[main(async, allocator = false)]`.
+//!
+//! `#[on_event]` can be used instead of `#[on_advertisement]` for a function named `on_event`. Both generate the same `on-advertisement` export, which is the only one the runtime calls.
+//!
+//! `#[on_vibration]` marks an optional `on_vibration(magnitude: u32)` function that is called when the vibration sensor crosses the threshold set with `rudelblinken_sdk::set_vibration_threshold`.
+//!
 //! ### Other languages
 //!
 //! If you want more control over the generated code, you can also use the
@@ -68,58 +76,61 @@
 use quote::quote;
 use syn::{spanned::Spanned, FnArg, ItemFn};
 
-fn process_on_advertisement(
-    input: proc_macro::TokenStream,
-) -> Result<proc_macro::TokenStream, syn::Error> {
-    let synput: ItemFn = syn::parse(input)?;
-
+/// Check that a function marked with a callback attribute can be used as that callback
+///
+/// `handler_name` is the name of the attribute and the required name of the function. `parameter` describes the only argument of the callback.
+fn validate_handler(
+    synput: &ItemFn,
+    handler_name: &str,
+    parameter: &str,
+) -> Result<(), syn::Error> {
     if let Some(constness) = synput.sig.constness {
         return Err(syn::Error::new(
             constness.span(),
-            "on_advertisement function cannot be const",
+            format!("{} function cannot be const", handler_name),
         ));
     }
     if let Some(asyncness) = synput.sig.asyncness {
         return Err(syn::Error::new(
             asyncness.span(),
-            "on_advertisement function cannot be async (for now)",
+            format!("{} function cannot be async (for now)", handler_name),
         ));
     }
     if let Some(unsafety) = synput.sig.unsafety {
         return Err(syn::Error::new(
             unsafety.span(),
-            "on_advertisement function cannot be unsafe",
+            format!("{} function cannot be unsafe", handler_name),
         ));
     }
-    if let Some(abi) = synput.sig.abi {
+    if let Some(abi) = &synput.sig.abi {
         return Err(syn::Error::new(
             abi.span(),
-            "on_advertisement function cannot have an ABI (for now)",
+            format!("{} function cannot have an ABI (for now)", handler_name),
         ));
     }
 
-    if synput.sig.ident.to_string() != "on_advertisement" {
+    if synput.sig.ident.to_string() != handler_name {
         return Err(syn::Error::new(
             synput.sig.ident.span(),
-            "on_advertisement function must be named `on_advertisement`",
+            format!("{0} function must be named `{0}`", handler_name),
         ));
     }
     if synput.sig.generics.params.len() > 0 {
         return Err(syn::Error::new(
             synput.sig.generics.span(),
-            "on_advertisement function cannot have generics",
+            format!("{} function cannot have generics", handler_name),
         ));
     }
-    if let Some(variadic) = synput.sig.variadic {
+    if let Some(variadic) = &synput.sig.variadic {
         return Err(syn::Error::new(
             variadic.span(),
-            "on_advertisement cannot have variadic arguments",
+            format!("{} cannot have variadic arguments", handler_name),
         ));
     }
     if let syn::ReturnType::Type(_, _) = synput.sig.output {
         return Err(syn::Error::new(
             synput.sig.output.span(),
-            "on_advertisement cannot return a value",
+            format!("{} cannot return a value", handler_name),
         ));
     }
 
@@ -128,23 +139,40 @@ fn process_on_advertisement(
         None => {
             return Err(syn::Error::new(
                 synput.sig.span(),
-                "on_advertisement function must have at least one argument",
+                format!("{} function must have at least one argument", handler_name),
             ))
         }
         Some(FnArg::Receiver(input)) => {
             return Err(syn::Error::new(
                 input.span(),
-                "on_advertisement function needs to take a advertisement as its parameter",
+                format!(
+                    "{} function needs to take {} as its parameter",
+                    handler_name, parameter
+                ),
             ))
         }
     };
     if synput.sig.inputs.len() != 1 {
         return Err(syn::Error::new(
             synput.sig.inputs.first().span(),
-            "on_advertisement takes exactly one argument",
+            format!("{} takes exactly one argument", handler_name),
         ));
     }
 
+    return Ok(());
+}
+
+/// Generate the `BleGuest` implementation for a function marked with `#[on_advertisement]` or `#[on_event]`
+///
+/// Both attributes implement the same `rudel:base/ble-guest#on-advertisement` export. `handler_name` is the name of the attribute and the required name of the function.
+fn process_ble_handler(
+    input: proc_macro::TokenStream,
+    handler_name: &str,
+) -> Result<proc_macro::TokenStream, syn::Error> {
+    let synput: ItemFn = syn::parse(input)?;
+
+    validate_handler(&synput, handler_name, "a advertisement")?;
+
     // let mut inputs = Punctuated::<FnArg, Comma>::new();
     // inputs.push(FnArg::Typed(PatType {
     //     attrs: Vec::new(),
@@ -155,15 +183,36 @@ fn process_on_advertisement(
     //     )),
     // }));
 
+    // The guest only exports `on-advertisement`, so the handler is always implemented under that name
+    let mut sig = synput.sig.clone();
+    sig.ident = syn::Ident::new("on_advertisement", synput.sig.ident.span());
     let on_advertisement_impl = syn::ImplItemFn {
         attrs: synput.attrs,
         vis: syn::Visibility::Inherited,
         defaultness: None,
-        sig: synput.sig.clone(),
+        sig,
         block: *synput.block,
     };
 
+    let missing_main = format!(
+        "You need to mark a function with `#[rudelblinken_sdk::main]` to use `#[rudelblinken_sdk::{}]`",
+        handler_name
+    );
+
     let stream = quote!(
+        // Stand in for the struct generated by `#[main]`. If `#[main]` is used, its struct shadows
+        // the glob import. Otherwise this one is used and the deprecation explains what is missing,
+        // instead of "cannot find type `RudelblinkenMain`".
+        #[doc(hidden)]
+        mod _rudelblinken_missing_main {
+            #[allow(dead_code)]
+            #[deprecated(note = #missing_main)]
+            pub struct RudelblinkenMain;
+        }
+        #[allow(unused_imports)]
+        use _rudelblinken_missing_main::*;
+
+        #[deny(deprecated)]
         impl ::rudelblinken_sdk::BleGuest for RudelblinkenMain {
             #on_advertisement_impl
         }
@@ -174,7 +223,68 @@ fn process_on_advertisement(
     return Ok(stream.into());
 }
 
-fn process_main(input: proc_macro::TokenStream) -> Result<proc_macro::TokenStream, syn::Error> {
+/// Generate the `rudel:base/hardware-guest#on-vibration` export for a function marked with `#[on_vibration]`
+///
+/// The export is optional, so it is not part of the generated bindings. The function is kept as it is and called from a hand written export.
+fn process_vibration_handler(
+    input: proc_macro::TokenStream,
+) -> Result<proc_macro::TokenStream, syn::Error> {
+    let synput: ItemFn = syn::parse(input)?;
+
+    validate_handler(&synput, "on_vibration", "the magnitude")?;
+
+    let stream = quote!(
+        #synput
+
+        const _: () = {
+            #[export_name = "rudel:base/hardware-guest@0.0.2#on-vibration"]
+            unsafe extern "C" fn export_on_vibration(arg0: i32) {
+                ::rudelblinken_sdk::__on_vibration_cabi(on_vibration, arg0)
+            }
+        };
+    );
+
+    return Ok(stream.into());
+}
+
+/// Arguments of the `#[main]` attribute
+struct MainArgs {
+    /// Whether to install the talc allocator as the global allocator
+    allocator: bool,
+    /// Whether main is an `async fn` that is run by the executor of the SDK
+    is_async: bool,
+}
+
+impl MainArgs {
+    fn parse(args: proc_macro::TokenStream) -> Result<Self, syn::Error> {
+        let mut main_args = MainArgs {
+            allocator: true,
+            is_async: false,
+        };
+        let parser = syn::meta::parser(|meta| {
+            if meta.path.is_ident("allocator") {
+                let value: syn::LitBool = meta.value()?.parse()?;
+                main_args.allocator = value.value;
+                return Ok(());
+            }
+            if meta.path.is_ident("async") {
+                main_args.is_async = true;
+                return Ok(());
+            }
+            return Err(
+                meta.error("unsupported main argument, expected `allocator = false` or `async`")
+            );
+        });
+        syn::parse::Parser::parse(parser, args)?;
+        return Ok(main_args);
+    }
+}
+
+fn process_main(
+    args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> Result<proc_macro::TokenStream, syn::Error> {
+    let args = MainArgs::parse(args)?;
     let synput: ItemFn = syn::parse(input)?;
 
     if let Some(constness) = synput.sig.constness {
@@ -183,11 +293,20 @@ fn process_main(input: proc_macro::TokenStream) -> Result<proc_macro::TokenStrea
             "main function cannot be const",
         ));
     }
-    if let Some(asyncness) = synput.sig.asyncness {
-        return Err(syn::Error::new(
-            asyncness.span(),
-            "main function cannot be async (for now)",
-        ));
+    match (synput.sig.asyncness, args.is_async) {
+        (Some(asyncness), false) => {
+            return Err(syn::Error::new(
+                asyncness.span(),
+                "use `#[main(async)]` for an async main function",
+            ));
+        }
+        (None, true) => {
+            return Err(syn::Error::new(
+                synput.sig.fn_token.span(),
+                "`#[main(async)]` needs an `async fn main`",
+            ));
+        }
+        _ => {}
     }
     if let Some(unsafety) = synput.sig.unsafety {
         return Err(syn::Error::new(
@@ -235,6 +354,16 @@ fn process_main(input: proc_macro::TokenStream) -> Result<proc_macro::TokenStrea
 
     let vis = synput.vis;
 
+    // The generated export is synchronous, so an async main is driven by the executor of the SDK
+    let block = *synput.block;
+    let block: syn::Block = if args.is_async {
+        syn::parse_quote!({
+            ::rudelblinken_sdk::executor::block_on(async move #block)
+        })
+    } else {
+        block
+    };
+
     let main_impl = syn::ImplItemFn {
         attrs: synput.attrs,
         vis: syn::Visibility::Inherited,
@@ -252,20 +381,32 @@ fn process_main(input: proc_macro::TokenStream) -> Result<proc_macro::TokenStrea
             variadic: None,
             output: syn::ReturnType::Default,
         },
-        block: *synput.block,
+        block,
+    };
+
+    let allocator = if args.allocator {
+        quote!(
+            // Use a custom allocator, because we can only use one page of
+            // memory but that is not supported by the default allocator
+            const HEAP_SIZE: usize = 36624;
+            static mut HEAP: [u8; HEAP_SIZE] = [0u8; HEAP_SIZE];
+            #[global_allocator]
+            static ALLOCATOR: ::talc::Talck<::spin::Mutex<()>, ::talc::ClaimOnOom> =
+                ::talc::Talc::new(unsafe {
+                    ::talc::ClaimOnOom::new(::talc::Span::from_array((&raw const HEAP).cast_mut()))
+                })
+                .lock();
+        )
+    } else {
+        quote!()
     };
 
     let stream = quote!(
-        // Use a custom allocator, because we can only use one page of
-        // memory but that is not supported by the default allocator
-        const HEAP_SIZE: usize = 36624;
-        static mut HEAP: [u8; HEAP_SIZE] = [0u8; HEAP_SIZE];
-        #[global_allocator]
-        static ALLOCATOR: ::talc::Talck<::spin::Mutex<()>, ::talc::ClaimOnOom> =
-            ::talc::Talc::new(unsafe {
-                ::talc::ClaimOnOom::new(::talc::Span::from_array((&raw const HEAP).cast_mut()))
-            })
-            .lock();
+        #allocator
+
+        // Makes the error for a second `#[main]` point out the problem
+        #[allow(dead_code, non_upper_case_globals)]
+        const only_one_function_can_be_marked_with_main: () = ();
 
         #vis struct RudelblinkenMain;
 
@@ -290,7 +431,7 @@ fn process_main(input: proc_macro::TokenStream) -> Result<proc_macro::TokenStrea
             use super::RudelblinkenMain;
             #[allow(dead_code)]
             trait OnAdvertismentNotImplemented {
-                const NO_BLE_GUEST: () = panic!("You also need to mark a function with `#[rudelblinken_sdk::on_advertisement]`");
+                const NO_BLE_GUEST: () = panic!("You also need to mark a function with `#[rudelblinken_sdk::on_advertisement]` or `#[rudelblinken_sdk::on_event]`");
             }
             impl<T: ?Sized> OnAdvertismentNotImplemented for T {}
             struct Wrapper<T: ?Sized>(core::marker::PhantomData<T>);
@@ -305,12 +446,15 @@ fn process_main(input: proc_macro::TokenStream) -> Result<proc_macro::TokenStrea
     return Ok(stream.into());
 }
 
+/// Mark the entry point of the program
+///
+/// By default this also installs a small heap as the global allocator. Use `#[main(allocator = false)]` to provide your own allocator instead. Use `#[main(async)]` to mark an `async fn main`.
 #[proc_macro_attribute]
 pub fn main(
-    _args: proc_macro::TokenStream,
+    args: proc_macro::TokenStream,
     input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    let result = match process_main(input) {
+    let result = match process_main(args, input) {
         Ok(stream) => stream,
         Err(err) => err.to_compile_error().into(),
     };
@@ -323,7 +467,39 @@ pub fn on_advertisement(
     _args: proc_macro::TokenStream,
     input: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
-    let result = match process_on_advertisement(input) {
+    let result = match process_ble_handler(input, "on_advertisement") {
+        Ok(stream) => stream,
+        Err(err) => err.to_compile_error().into(),
+    };
+
+    return result.into();
+}
+
+/// Same as [macro@on_advertisement], but for functions named `on_event`.
+///
+/// Both attributes generate the same export, so the runtime delivers advertisements to either of them.
+#[proc_macro_attribute]
+pub fn on_event(
+    _args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let result = match process_ble_handler(input, "on_event") {
+        Ok(stream) => stream,
+        Err(err) => err.to_compile_error().into(),
+    };
+
+    return result.into();
+}
+
+/// Mark a function that is called when the vibration sensor crosses the threshold set with `set_vibration_threshold`
+///
+/// The function needs to be named `on_vibration` and take the magnitude as a `u32`. Nothing is called until a threshold is set.
+#[proc_macro_attribute]
+pub fn on_vibration(
+    _args: proc_macro::TokenStream,
+    input: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    let result = match process_vibration_handler(input) {
         Ok(stream) => stream,
         Err(err) => err.to_compile_error().into(),
     };
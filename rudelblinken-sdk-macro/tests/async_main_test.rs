@@ -0,0 +1,11 @@
+use rudelblinken_sdk::executor::{sleep, Timer};
+
+#[rudelblinken_sdk_macro::main(async)]
+pub async fn main() {
+    let mut timer = Timer::new(1000);
+    timer.tick().await;
+    sleep(500).await;
+}
+
+#[rudelblinken_sdk_macro::on_advertisement]
+fn on_advertisement(_: rudelblinken_sdk::Advertisement) {}
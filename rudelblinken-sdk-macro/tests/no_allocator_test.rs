@@ -0,0 +1,10 @@
+#[global_allocator]
+static ALLOCATOR: std::alloc::System = std::alloc::System;
+
+#[rudelblinken_sdk_macro::main(allocator = false)]
+pub fn main() {
+    println!("Hello, world!");
+}
+
+#[rudelblinken_sdk_macro::on_advertisement]
+fn on_advertisement(_: rudelblinken_sdk::Advertisement) {}
@@ -0,0 +1,4 @@
+#[rudelblinken_sdk_macro::on_event]
+fn on_event(_: rudelblinken_sdk::Advertisement) {}
+
+fn main() {}
@@ -0,0 +1,12 @@
+#[rudelblinken_sdk_macro::main]
+pub fn main() {
+    rudelblinken_sdk::set_vibration_threshold(100);
+}
+
+#[rudelblinken_sdk_macro::on_advertisement]
+fn on_advertisement(_: rudelblinken_sdk::Advertisement) {}
+
+#[rudelblinken_sdk_macro::on_vibration]
+fn on_vibration(magnitude: u32) {
+    println!("Vibration with magnitude {}", magnitude);
+}
@@ -2,4 +2,10 @@
 fn tests() {
     let t = trybuild::TestCases::new();
     t.pass("tests/simple_test.rs");
+    t.pass("tests/on_event_test.rs");
+    t.pass("tests/no_allocator_test.rs");
+    t.pass("tests/on_vibration_test.rs");
+    t.pass("tests/async_main_test.rs");
+    t.compile_fail("tests/on_event_without_main.rs");
+    t.compile_fail("tests/two_mains.rs");
 }
@@ -0,0 +1,8 @@
+#[rudelblinken_sdk_macro::main(allocator = false)]
+fn main() {}
+
+#[rudelblinken_sdk_macro::main(allocator = false)]
+fn main() {}
+
+#[rudelblinken_sdk_macro::on_advertisement]
+fn on_advertisement(_: rudelblinken_sdk::Advertisement) {}
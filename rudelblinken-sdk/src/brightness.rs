@@ -0,0 +1,102 @@
+//! Brightness curves for LEDs
+//!
+//! A cycle of a blinking program is usually tracked as a phase from 0 to 65535. [phase_to_fraction] turns the phase into a smooth sine wave and [ambient_adjusted_lux] turns that into the lux to pass to [set_leds](crate::set_leds). The LEDs are dimmer in a dark room and brighter in daylight, so they are visible without being glaring.
+//!
+//! ```ignore
+//! let max_lux = get_led_info(0).max_lux;
+//! let fraction = brightness::phase_to_fraction(progress);
+//! let lux = brightness::ambient_adjusted_lux(get_ambient_light(), fraction, max_lux);
+//! set_leds(0, &[lux]);
+//! ```
+
+/// Ambient light in lux at and below which the LEDs use [MIN_BRIGHTNESS]
+pub const MIN_AMBIENT: u32 = 0;
+/// Ambient light in lux at and above which the LEDs use [MAX_BRIGHTNESS]
+pub const MAX_AMBIENT: u32 = 1250;
+/// Peak brightness in dark surroundings, in thousandths of the maximum lux of the LED
+pub const MIN_BRIGHTNESS: u32 = 200;
+/// Peak brightness in bright surroundings, in thousandths of the maximum lux of the LED
+pub const MAX_BRIGHTNESS: u32 = 800;
+
+/// One period of a sine wave, scaled to 1-255
+///
+/// The wave starts at its midpoint, peaks at a quarter and bottoms out at three quarters of the table.
+pub const SINE_TABLE: [u8; 256] = [
+    0x80, 0x83, 0x86, 0x89, 0x8C, 0x90, 0x93, 0x96, 0x99, 0x9C, 0x9F, 0xA2, 0xA5, 0xA8, 0xAB, 0xAE,
+    0xB1, 0xB3, 0xB6, 0xB9, 0xBC, 0xBF, 0xC1, 0xC4, 0xC7, 0xC9, 0xCC, 0xCE, 0xD1, 0xD3, 0xD5, 0xD8,
+    0xDA, 0xDC, 0xDE, 0xE0, 0xE2, 0xE4, 0xE6, 0xE8, 0xEA, 0xEB, 0xED, 0xEF, 0xF0, 0xF1, 0xF3, 0xF4,
+    0xF5, 0xF6, 0xF8, 0xF9, 0xFA, 0xFA, 0xFB, 0xFC, 0xFD, 0xFD, 0xFE, 0xFE, 0xFE, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFE, 0xFE, 0xFE, 0xFD, 0xFD, 0xFC, 0xFB, 0xFA, 0xFA, 0xF9, 0xF8, 0xF6,
+    0xF5, 0xF4, 0xF3, 0xF1, 0xF0, 0xEF, 0xED, 0xEB, 0xEA, 0xE8, 0xE6, 0xE4, 0xE2, 0xE0, 0xDE, 0xDC,
+    0xDA, 0xD8, 0xD5, 0xD3, 0xD1, 0xCE, 0xCC, 0xC9, 0xC7, 0xC4, 0xC1, 0xBF, 0xBC, 0xB9, 0xB6, 0xB3,
+    0xB1, 0xAE, 0xAB, 0xA8, 0xA5, 0xA2, 0x9F, 0x9C, 0x99, 0x96, 0x93, 0x90, 0x8C, 0x89, 0x86, 0x83,
+    0x80, 0x7D, 0x7A, 0x77, 0x74, 0x70, 0x6D, 0x6A, 0x67, 0x64, 0x61, 0x5E, 0x5B, 0x58, 0x55, 0x52,
+    0x4F, 0x4D, 0x4A, 0x47, 0x44, 0x41, 0x3F, 0x3C, 0x39, 0x37, 0x34, 0x32, 0x2F, 0x2D, 0x2B, 0x28,
+    0x26, 0x24, 0x22, 0x20, 0x1E, 0x1C, 0x1A, 0x18, 0x16, 0x15, 0x13, 0x11, 0x10, 0x0F, 0x0D, 0x0C,
+    0x0B, 0x0A, 0x08, 0x07, 0x06, 0x06, 0x05, 0x04, 0x03, 0x03, 0x02, 0x02, 0x02, 0x01, 0x01, 0x01,
+    0x01, 0x01, 0x01, 0x01, 0x02, 0x02, 0x02, 0x03, 0x03, 0x04, 0x05, 0x06, 0x06, 0x07, 0x08, 0x0A,
+    0x0B, 0x0C, 0x0D, 0x0F, 0x10, 0x11, 0x13, 0x15, 0x16, 0x18, 0x1A, 0x1C, 0x1E, 0x20, 0x22, 0x24,
+    0x26, 0x28, 0x2B, 0x2D, 0x2F, 0x32, 0x34, 0x37, 0x39, 0x3C, 0x3F, 0x41, 0x44, 0x47, 0x4A, 0x4D,
+    0x4F, 0x52, 0x55, 0x58, 0x5B, 0x5E, 0x61, 0x64, 0x67, 0x6A, 0x6D, 0x70, 0x74, 0x77, 0x7A, 0x7D,
+];
+
+/// Map the phase of a cycle onto a sine wave
+///
+/// A full cycle is 65536 steps. The result is a fraction of the peak brightness, from 1 to 255.
+pub fn phase_to_fraction(phase: u16) -> u8 {
+    return SINE_TABLE[(phase >> 8) as usize];
+}
+
+/// Calculate the lux for an LED
+///
+/// `ambient` is the ambient light in lux, `fraction` the brightness relative to the peak from 0 to 255 and `max_lux` the maximum of the LED from [LedInfo](crate::LedInfo). The peak brightness is interpolated linearly between [MIN_BRIGHTNESS] and [MAX_BRIGHTNESS] as the ambient light goes from [MIN_AMBIENT] to [MAX_AMBIENT]. The result is squared, because the eye perceives brightness roughly logarithmically, which makes the fades look even.
+pub fn ambient_adjusted_lux(ambient: u32, fraction: u8, max_lux: u16) -> u16 {
+    if max_lux == 0 {
+        return 0;
+    }
+    let ambient = ambient.clamp(MIN_AMBIENT, MAX_AMBIENT) - MIN_AMBIENT;
+    let peak = MIN_BRIGHTNESS as u64
+        + (MAX_BRIGHTNESS - MIN_BRIGHTNESS) as u64 * ambient as u64
+            / (MAX_AMBIENT - MIN_AMBIENT) as u64;
+    let level = max_lux as u64 * peak * fraction as u64 / (1000 * 255);
+    return (level * level / max_lux as u64) as u16;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_follows_the_sine_wave() {
+        assert_eq!(phase_to_fraction(0), 0x80);
+        assert_eq!(phase_to_fraction(0x4000), 0xFF);
+        assert_eq!(phase_to_fraction(0xC000), 0x01);
+        assert_eq!(phase_to_fraction(0xFFFF), 0x7D);
+    }
+
+    #[test]
+    fn lux_scales_with_ambient_light() {
+        assert_eq!(ambient_adjusted_lux(0, 255, 2500), 100);
+        assert_eq!(ambient_adjusted_lux(625, 255, 2500), 625);
+        assert_eq!(ambient_adjusted_lux(MAX_AMBIENT, 255, 2500), 1600);
+    }
+
+    #[test]
+    fn ambient_light_is_clamped() {
+        assert_eq!(
+            ambient_adjusted_lux(u32::MAX, 255, 2500),
+            ambient_adjusted_lux(MAX_AMBIENT, 255, 2500)
+        );
+    }
+
+    #[test]
+    fn lux_is_quadratic_in_the_fraction() {
+        assert_eq!(ambient_adjusted_lux(MAX_AMBIENT, 0, 2500), 0);
+        assert_eq!(ambient_adjusted_lux(MAX_AMBIENT, 51, 2500), 64);
+    }
+
+    #[test]
+    fn dark_leds_stay_dark() {
+        assert_eq!(ambient_adjusted_lux(MAX_AMBIENT, 255, 0), 0);
+    }
+}
@@ -0,0 +1,76 @@
+//! Structured sections of the program config
+//!
+//! The config from [get_config](crate::get_config) is a blob of bytes that is set with `rudelctl config`. Its meaning is up to the program, but this module defines a layout for common settings, so they can be changed without recompiling the program.
+//!
+//! ## Palette
+//!
+//! A palette of RGB colors is stored at the start of the config:
+//!
+//! | Offset | Length      | Content                                 |
+//! | ------ | ----------- | --------------------------------------- |
+//! | 0      | 1           | Number of colors                        |
+//! | 1      | 3 per color | Red, green and blue of every color      |
+//!
+//! The bytes after the palette are left to the program. Set a palette with `rudelctl config set-palette ff0000 00ff00`.
+use crate::LedColor;
+use alloc::vec::Vec;
+
+/// Length of one color in the palette
+const COLOR_LENGTH: usize = 3;
+
+/// Get the palette from the config
+///
+/// Returns an empty palette if the config does not contain a valid palette.
+pub fn palette() -> Vec<LedColor> {
+    return parse_palette(&crate::get_config());
+}
+
+/// Parse a palette in the layout described in the [module documentation](self)
+///
+/// Returns an empty palette if the data is shorter than the palette it announces.
+pub fn parse_palette(config: &[u8]) -> Vec<LedColor> {
+    let Some((&count, colors)) = config.split_first() else {
+        return Vec::new();
+    };
+    let Some(colors) = colors.get(..count as usize * COLOR_LENGTH) else {
+        return Vec::new();
+    };
+    return colors
+        .chunks_exact(COLOR_LENGTH)
+        .map(|color| LedColor {
+            red: color[0],
+            green: color[1],
+            blue: color[2],
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(color: &LedColor) -> (u8, u8, u8) {
+        return (color.red, color.green, color.blue);
+    }
+
+    #[test]
+    fn parses_the_colors_of_the_palette() {
+        let palette = parse_palette(&[2, 0xff, 0, 0, 0, 0x80, 0x40]);
+        let colors: Vec<_> = palette.iter().map(rgb).collect();
+        assert_eq!(colors, [(0xff, 0, 0), (0, 0x80, 0x40)]);
+    }
+
+    #[test]
+    fn ignores_the_bytes_after_the_palette() {
+        let palette = parse_palette(&[1, 1, 2, 3, 42, 43]);
+        let colors: Vec<_> = palette.iter().map(rgb).collect();
+        assert_eq!(colors, [(1, 2, 3)]);
+    }
+
+    #[test]
+    fn malformed_palettes_are_empty() {
+        assert!(parse_palette(&[]).is_empty());
+        assert!(parse_palette(&[0]).is_empty());
+        assert!(parse_palette(&[2, 1, 2, 3, 4, 5]).is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+//! Run an async main function
+//!
+//! Programs need to yield to the host regularly. Instead of calling [yield_now](crate::yield_now) in a hand written loop, an async program awaits [sleep], [yield_now] or a [Timer]. Every await that is not ready yet returns control to the host until the earliest wake-up is due, so callbacks are still delivered while the program waits.
+//!
+//! Use `#[rudelblinken_sdk_macro::main(async)]` to run an `async fn main` with [block_on]:
+//!
+//! ```ignore
+//! #[rudelblinken_sdk_macro::main(async)]
+//! async fn main() {
+//!     let mut timer = Timer::new(500_000);
+//!     loop {
+//!         timer.tick().await;
+//!         // Toggle the LEDs
+//!     }
+//! }
+//! ```
+//!
+//! The executor polls a single future on the only thread of the guest. It never blocks, it only yields to the host, so the fuel and watchdog limits of the host apply as usual. Futures that are not driven by this module are polled again after the shortest possible yield.
+use core::{
+    future::Future,
+    pin::{pin, Pin},
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// Time in microseconds at which the executor started the current poll
+static NOW: AtomicU64 = AtomicU64::new(0);
+/// Earliest time in microseconds a pending future wants to be polled again. `u64::MAX` if no future asked for a time
+static NEXT_WAKEUP: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// The time in microseconds at which the executor started the current poll
+///
+/// Everything that is polled together sees the same time.
+pub fn now() -> u64 {
+    return NOW.load(Ordering::Relaxed);
+}
+
+/// Ask the executor to poll again at `micros`
+fn wake_at(micros: u64) {
+    NEXT_WAKEUP.fetch_min(micros, Ordering::Relaxed);
+}
+
+/// Run `future` to completion, yielding to the host while it waits
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    return run(future, crate::time, |micros| {
+        crate::yield_now(micros);
+    });
+}
+
+/// Run `future` with the given clock and way to yield. [block_on] uses the host for both
+fn run<F: Future>(future: F, time: impl Fn() -> u64, mut yield_for: impl FnMut(u64)) -> F::Output {
+    let mut future = pin!(future);
+    let mut context = Context::from_waker(Waker::noop());
+    loop {
+        let now = time();
+        NOW.store(now, Ordering::Relaxed);
+        NEXT_WAKEUP.store(u64::MAX, Ordering::Relaxed);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        let wakeup = NEXT_WAKEUP.load(Ordering::Relaxed);
+        let micros = if wakeup == u64::MAX {
+            0
+        } else {
+            wakeup.saturating_sub(now)
+        };
+        yield_for(micros);
+    }
+}
+
+/// Future returned by [sleep] and [sleep_until]
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<()> {
+        if now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        wake_at(self.deadline);
+        return Poll::Pending;
+    }
+}
+
+/// Wait for `micros` microseconds
+///
+/// The time is measured from the start of the current poll, see [now].
+pub fn sleep(micros: u64) -> Sleep {
+    return sleep_until(now().saturating_add(micros));
+}
+
+/// Wait until the time is `deadline` microseconds
+pub fn sleep_until(deadline: u64) -> Sleep {
+    return Sleep { deadline };
+}
+
+/// Future returned by [yield_now]
+#[derive(Debug)]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _context: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        wake_at(now());
+        return Poll::Pending;
+    }
+}
+
+/// Return to the host for the shortest possible time
+///
+/// Use this in loops that do not sleep, like [crate::yield_now] with 0 microseconds.
+pub fn yield_now() -> YieldNow {
+    return YieldNow { yielded: false };
+}
+
+/// Ticks every `period` microseconds
+///
+/// Unlike sleeping for the period in a loop, the ticks do not drift when the work between them takes time. If a tick is late by more than a period, the missed ticks are skipped.
+#[derive(Debug)]
+pub struct Timer {
+    period: u64,
+    next: u64,
+}
+
+impl Timer {
+    /// A timer that first ticks one period from now. Create it inside the async main function, so [now] is set
+    pub fn new(period: u64) -> Self {
+        let period = period.max(1);
+        return Timer {
+            period,
+            next: now().saturating_add(period),
+        };
+    }
+
+    /// Wait for the next tick
+    pub fn tick(&mut self) -> Sleep {
+        let deadline = self.next;
+        self.next = deadline.saturating_add(self.period);
+        if self.next <= now() {
+            self.next = now().saturating_add(self.period);
+        }
+        return sleep_until(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use std::{sync::Mutex, vec, vec::Vec};
+
+    /// The executor state is global, so the tests must not run at the same time
+    static EXECUTOR: Mutex<()> = Mutex::new(());
+
+    /// Run `future` with a clock that advances by the yielded time plus `delay`. Returns the output and the yielded times
+    fn run_with_delay<F: Future>(future: F, delay: u64) -> (F::Output, Vec<u64>) {
+        let _guard = EXECUTOR
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let clock = Cell::new(0);
+        let yields = RefCell::new(Vec::new());
+        let output = run(
+            future,
+            || clock.get(),
+            |micros| {
+                yields.borrow_mut().push(micros);
+                clock.set(clock.get() + micros + delay);
+            },
+        );
+        return (output, yields.into_inner());
+    }
+
+    #[test]
+    fn sleeping_yields_until_the_deadline() {
+        let (output, yields) = run_with_delay(
+            async {
+                sleep(1000).await;
+                sleep(500).await;
+                return 42;
+            },
+            0,
+        );
+        assert_eq!(output, 42);
+        assert_eq!(yields, vec![1000, 500]);
+    }
+
+    #[test]
+    fn yield_now_returns_to_the_host_once() {
+        let (_, yields) = run_with_delay(yield_now(), 0);
+        assert_eq!(yields, vec![0]);
+    }
+
+    #[test]
+    fn ready_futures_do_not_yield() {
+        let (_, yields) = run_with_delay(async { sleep(0).await }, 0);
+        assert_eq!(yields, Vec::<u64>::new());
+    }
+
+    #[test]
+    fn timers_do_not_drift_when_the_host_is_late() {
+        let (ticks, yields) = run_with_delay(
+            async {
+                let mut timer = Timer::new(1000);
+                let mut ticks = Vec::new();
+                for _ in 0..3 {
+                    timer.tick().await;
+                    ticks.push(now());
+                }
+                return ticks;
+            },
+            100,
+        );
+        assert_eq!(ticks, vec![1100, 2100, 3100]);
+        assert_eq!(yields, vec![1000, 900, 900]);
+    }
+
+    #[test]
+    fn timers_skip_missed_ticks() {
+        let (ticks, _) = run_with_delay(
+            async {
+                let mut timer = Timer::new(1000);
+                timer.tick().await;
+                sleep(2500).await;
+                let mut ticks = Vec::new();
+                for _ in 0..2 {
+                    timer.tick().await;
+                    ticks.push(now());
+                }
+                return ticks;
+            },
+            0,
+        );
+        // The tick at 2000 is late, the one at 3000 was missed
+        assert_eq!(ticks, vec![3500, 4500]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Persistent key-value store
+//!
+//! Use this to keep a little state across reboots, like calibration values or the last progress of a cycle. The store is meant for small values that change rarely, as every write goes to flash.
+
+use alloc::vec::Vec;
+
+/// Maximum length of a key in bytes
+pub const MAX_KEY_LENGTH: usize = 64;
+/// Maximum length of a value in bytes
+pub const MAX_VALUE_LENGTH: usize = 256;
+
+/// Errors that can occur when storing a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvError {
+    /// The key is longer than [MAX_KEY_LENGTH]
+    KeyTooLong,
+    /// The value is longer than [MAX_VALUE_LENGTH]
+    ValueTooLong,
+    /// The host failed to store the value
+    StorageFailure,
+    /// The host has no room for another entry or for a bigger value
+    StoreFull,
+}
+
+impl core::fmt::Display for KvError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KvError::KeyTooLong => write!(f, "The key is longer than {} bytes", MAX_KEY_LENGTH),
+            KvError::ValueTooLong => {
+                write!(f, "The value is longer than {} bytes", MAX_VALUE_LENGTH)
+            }
+            KvError::StorageFailure => write!(f, "The host failed to store the value"),
+            KvError::StoreFull => write!(f, "The key-value store is full"),
+        }
+    }
+}
+
+/// Read the value stored under `key`
+///
+/// Returns `None` if nothing was stored under that key.
+pub fn get(key: &str) -> Option<Vec<u8>> {
+    if key.len() > MAX_KEY_LENGTH {
+        return None;
+    }
+    crate::rudel::rudel::base::base::kv_get(key)
+}
+
+/// Store `value` under `key`, replacing the previous value
+pub fn set(key: &str, value: &[u8]) -> Result<(), KvError> {
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(KvError::KeyTooLong);
+    }
+    if value.len() > MAX_VALUE_LENGTH {
+        return Err(KvError::ValueTooLong);
+    }
+    match crate::rudel::rudel::base::base::kv_set(key, value) {
+        0 => Ok(()),
+        1 => Err(KvError::KeyTooLong),
+        2 => Err(KvError::ValueTooLong),
+        4 => Err(KvError::StoreFull),
+        _ => Err(KvError::StorageFailure),
+    }
+}
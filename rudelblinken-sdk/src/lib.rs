@@ -1,23 +1,42 @@
 //! # Rudelblinken SDK
 //!
 //! This is the SDK for the Rudelblinken platform. It provides a set of functions to interact with the connected hardware.
+//!
+//! ## `no_std`
+//!
+//! The SDK uses the standard library by default. Disable the default `std` feature to build it with only `core` and `alloc`, which keeps the standard library out of your program and makes it smaller. You still need a global allocator and, as with every `no_std` binary, a `#[panic_handler]`. [SharedState](sync::SharedState) is built on the standard library's mutex, so the [sync] module is only available with `std`.
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(split_array)]
 
+extern crate alloc;
+// The tests use the standard library even without the `std` feature
+#[cfg(all(test, not(feature = "std")))]
+extern crate std;
+
+use alloc::{string::String, vec::Vec};
+
+pub mod brightness;
+pub mod config;
+pub mod executor;
+pub mod kv;
+pub mod random;
 mod rudel;
+pub mod sensor;
+#[cfg(feature = "std")]
+pub mod sync;
 pub use rudel::{
     export, exports,
     exports::rudel::base::ble_guest::{Advertisement, Guest as BleGuest},
     exports::rudel::base::run::Guest,
-    rudel::base::base::{get_base_version, log, sleep, time, yield_now, LogLevel, SemanticVersion},
-    rudel::base::ble::{
-        configure_advertisement, get_ble_version, set_advertisement_data, AdvertisementData,
-        AdvertisementSettings,
+    rudel::base::base::{
+        get_base_version, get_random, log, sleep, time, LogLevel, SemanticVersion,
     },
+    rudel::base::ble::{get_ble_version, set_advertisement_tx_power, AdvertisementData},
     rudel::base::hardware::{
         get_ambient_light, get_ambient_light_type, get_hardware_version, get_led_info,
         get_vibration, get_vibration_sensor_type, get_voltage, get_voltage_sensor_type, led_count,
-        set_leds, set_rgb, AmbientLightType, LedColor, LedInfo, VibrationSensorType,
-        VoltageSensorType,
+        set_brightness_curve, set_leds, set_rgb, set_rgb_range, set_vibration_threshold,
+        AmbientLightType, LedColor, LedInfo, VibrationSensorType, VoltageSensorType,
     },
 };
 
@@ -27,34 +46,232 @@ pub fn get_name() -> String {
         tuple.0, tuple.1, tuple.2, tuple.3, tuple.4, tuple.5, tuple.6, tuple.7, tuple.8, tuple.9,
         tuple.10, tuple.11, tuple.12, tuple.13, tuple.14, tuple.15,
     ];
-    let length = array
-        .iter()
-        .enumerate()
-        .find(|(_, x)| **x == 0)
-        .map(|(index, _)| index)
-        .unwrap_or(0);
-    let array = &array[0..length];
-    String::from_utf8_lossy(array).to_string()
+    name_from_bytes(&array)
+}
+
+/// Decode the name returned by the host
+///
+/// The name ends at the first nul byte. A name with the full 16 bytes has no terminator.
+fn name_from_bytes(array: &[u8; 16]) -> String {
+    let length = array.iter().position(|x| *x == 0).unwrap_or(array.len());
+    String::from_utf8_lossy(&array[0..length]).into_owned()
 }
 
 pub fn get_config() -> Vec<u8> {
     rudel::rudel::base::base::get_config()
 }
 
+/// What happened while yielding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldStatus {
+    /// No callbacks were delivered. Nothing is pending, so you can yield for longer next time
+    Idle,
+    /// At least one callback was delivered
+    CallbacksDelivered,
+}
+
+impl YieldStatus {
+    /// Decode the value returned by the `yield-now` import
+    fn from_raw(status: u32) -> Self {
+        match status {
+            1 => YieldStatus::CallbacksDelivered,
+            _ => YieldStatus::Idle,
+        }
+    }
+}
+
+/// You need to yield periodically, as the watchdog will kill you if you dont
+///
+/// Will try to sleep for the given duration while still serving callbacks. Use micros = 0 to sleep for the minimum duration.
+///
+/// This is the same as [yield_with_status], but returns the status as a raw number. It is kept for programs written before the status existed, back then it returned the remaining fuel.
+pub fn yield_now(micros: u64) -> u32 {
+    return rudel::rudel::base::base::yield_now(micros);
+}
+
+/// Yield like [yield_now] and report whether callbacks were delivered
+///
+/// Programs that only react to advertisements can use the returned status to back off while nothing happens.
+pub fn yield_with_status(micros: u64) -> YieldStatus {
+    return YieldStatus::from_raw(rudel::rudel::base::base::yield_now(micros));
+}
+
+/// Called by the on-vibration export generated by `#[rudelblinken_sdk::on_vibration]`
+///
+/// The export is optional, so it is not part of the generated bindings.
+#[doc(hidden)]
+pub fn __on_vibration_cabi(handler: fn(u32), magnitude: i32) {
+    #[cfg(target_arch = "wasm32")]
+    wit_bindgen::rt::run_ctors_once();
+    handler(magnitude as u32);
+}
+
+/// Maximum length of the advertisement data in bytes
+///
+/// A legacy BLE advertisement has 31 bytes. The host reserves 3 bytes for the flags, 6 bytes for the Rudel id and 5 bytes for the battery level. The header of the manufacturer data field takes another 2 bytes, which leaves 15 bytes. The data starts with the 2 byte company identifier, so 13 bytes remain for the payload.
+///
+/// The device name is sent in the scan response, so it does not reduce the budget.
+pub const MAX_ADVERTISEMENT_DATA_LENGTH: usize = 15;
+
+/// Errors that can occur when setting the advertisement data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvertisementError {
+    /// The data is longer than [MAX_ADVERTISEMENT_DATA_LENGTH]
+    DataTooLong,
+    /// The host could not set the data
+    Rejected,
+}
+
+impl core::fmt::Display for AdvertisementError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AdvertisementError::DataTooLong => write!(
+                f,
+                "The advertisement data is longer than {} bytes",
+                MAX_ADVERTISEMENT_DATA_LENGTH
+            ),
+            AdvertisementError::Rejected => {
+                write!(f, "The host could not fit the data into the advertisement")
+            }
+        }
+    }
+}
+
+/// Set the manufacturer data of the advertisement
+///
+/// The data starts with the 2 byte company identifier. See [MAX_ADVERTISEMENT_DATA_LENGTH] for the available space.
+///
+/// ## Compatibility
+///
+/// This used to return the raw status code of the host as `u32`, and data that was too long was silently dropped by the host. Programs that ignored the status now have to handle or explicitly ignore the `Result`, e.g. with `let _ = set_advertisement_data(&data);`. The host interface did not change, so programs built against an older SDK keep working.
+pub fn set_advertisement_data(data: &[u8]) -> Result<(), AdvertisementError> {
+    if data.len() > MAX_ADVERTISEMENT_DATA_LENGTH {
+        return Err(AdvertisementError::DataTooLong);
+    }
+    match rudel::rudel::base::ble::set_advertisement_data(&data.to_vec()) {
+        0 => Ok(()),
+        2 => Err(AdvertisementError::DataTooLong),
+        _ => Err(AdvertisementError::Rejected),
+    }
+}
+
+/// Constructors for [AdvertisementData]
+///
+/// [AdvertisementData] is a plain byte vector, so the constructors live in this trait.
+pub trait AdvertisementDataExt: Sized {
+    /// Manufacturer data with the company identifier `company` followed by `data`
+    ///
+    /// Fails if the result is longer than [MAX_ADVERTISEMENT_DATA_LENGTH].
+    fn manufacturer(company: u16, data: &[u8]) -> Result<Self, AdvertisementError>;
+}
+
+impl AdvertisementDataExt for AdvertisementData {
+    fn manufacturer(company: u16, data: &[u8]) -> Result<Self, AdvertisementError> {
+        if data.len() + 2 > MAX_ADVERTISEMENT_DATA_LENGTH {
+            return Err(AdvertisementError::DataTooLong);
+        }
+        let mut advertisement_data = Vec::with_capacity(data.len() + 2);
+        advertisement_data.extend_from_slice(&company.to_le_bytes());
+        advertisement_data.extend_from_slice(data);
+        return Ok(advertisement_data);
+    }
+}
+
+/// Configure the BLE advertisements
+///
+/// The intervals are passed to `configure-advertisement` and the transmit power to `set-advertisement-tx-power`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvertisementSettings {
+    /// Shortest interval between two advertisements in milliseconds
+    pub min_interval: u16,
+    /// Longest interval between two advertisements in milliseconds
+    pub max_interval: u16,
+    /// Transmit power in dBm. `None` keeps the transmit power of the host
+    pub tx_power: Option<i8>,
+}
+
+impl AdvertisementSettings {
+    /// Advertise every `interval` milliseconds
+    pub fn new(interval: u16) -> Self {
+        return Self {
+            min_interval: interval,
+            max_interval: interval,
+            tx_power: None,
+        };
+    }
+    /// Set the shortest interval between two advertisements in milliseconds
+    pub fn with_min_interval(self, min_interval: u16) -> Self {
+        return Self {
+            min_interval,
+            max_interval: self.max_interval.max(min_interval),
+            ..self
+        };
+    }
+    /// Set the longest interval between two advertisements in milliseconds
+    pub fn with_max_interval(self, max_interval: u16) -> Self {
+        return Self {
+            min_interval: self.min_interval.min(max_interval),
+            max_interval,
+            ..self
+        };
+    }
+    /// Set the transmit power in dBm
+    ///
+    /// The host rounds down to the next power level its radio supports. The rudelblinken firmware supports multiples of 3 between -12 and 9 dBm.
+    pub fn with_tx_power(self, tx_power: i8) -> Self {
+        return Self {
+            tx_power: Some(tx_power),
+            ..self
+        };
+    }
+    /// Configure the advertisements with these settings
+    ///
+    /// The rudelblinken firmware clamps the intervals to 100-1500 milliseconds.
+    pub fn apply(self) -> Result<(), AdvertisementError> {
+        match configure_advertisement(self) {
+            0 => Ok(()),
+            _ => Err(AdvertisementError::Rejected),
+        }
+    }
+}
+
+/// Configure the advertising intervals and, if set, the transmit power
+///
+/// Returns the raw status code of `configure-advertisement`. The transmit power is only set if the intervals were accepted. Use [AdvertisementSettings::apply] for a `Result`.
+///
+/// ## Compatibility
+///
+/// [AdvertisementSettings] used to be the record of the host interface, which has no transmit power. Programs that build the settings with a struct literal need to add `tx_power: None`. The host interface did not change, so programs built against an older SDK keep working.
+pub fn configure_advertisement(settings: AdvertisementSettings) -> u32 {
+    let status = rudel::rudel::base::ble::configure_advertisement(
+        rudel::rudel::base::ble::AdvertisementSettings {
+            min_interval: settings.min_interval,
+            max_interval: settings.max_interval,
+        },
+    );
+    if let (0, Some(tx_power)) = (status, settings.tx_power) {
+        set_advertisement_tx_power(tx_power);
+    }
+    return status;
+}
+
+/// Value of the RSSI of an advertisement if the host does not know the signal strength
+pub const UNKNOWN_RSSI: i8 = 127;
+
 impl exports::rudel::base::ble_guest::Advertisement {
     /// Get the manufacturer data as a byte array.
     ///
     /// Only the first self.data_length bytes are valid. You should probably use `get_data` instead.
     pub unsafe fn get_data_array(&self) -> &[u8; 32] {
         // SAFETY: Does the same as the safe function below, but without copying
-        return unsafe { std::mem::transmute::<_, &[u8; 32]>(&self.data) };
+        return unsafe { core::mem::transmute::<_, &[u8; 32]>(&self.data) };
     }
     /// Get the manufacturer data as a byte array.
     ///
     /// Only the first self.data_length bytes are valid. You should probably use `get_data_mut` instead.
     pub unsafe fn get_data_array_mut(&mut self) -> &mut [u8; 32] {
         // SAFETY: Does the same as the safe function below, but without copying
-        return unsafe { std::mem::transmute::<_, &mut [u8; 32]>(&mut self.data) };
+        return unsafe { core::mem::transmute::<_, &mut [u8; 32]>(&mut self.data) };
     }
     // // The same as the above
     // pub fn get_data_array_safe(&self) -> [u8; 32] {
@@ -71,27 +288,175 @@ impl exports::rudel::base::ble_guest::Advertisement {
     // }
     /// Get the manufacturer data as a slice
     pub fn get_data(&self) -> &[u8] {
-        let length = std::cmp::min(self.data_length as usize, 32);
+        let length = core::cmp::min(self.data_length as usize, 32);
         let array = unsafe { self.get_data_array() };
         return &array[..length];
     }
     /// Get the manufacturer data as a slice
     pub fn get_data_mut(&mut self) -> &mut [u8] {
-        let length = std::cmp::min(self.data_length as usize, 32);
+        let length = core::cmp::min(self.data_length as usize, 32);
         let array = unsafe { self.get_data_array_mut() };
         return &mut array[..length];
     }
+    /// Get the manufacturer data, if it was sent with the company identifier `company`
+    ///
+    /// The company identifier is not part of the returned data.
+    pub fn manufacturer(&self, company: u16) -> Option<&[u8]> {
+        if self.company != company {
+            return None;
+        }
+        return Some(self.get_data());
+    }
+    /// Get the signal strength of the advertisement in dBm, if the host knows it
+    pub fn get_rssi(&self) -> Option<i8> {
+        if self.rssi == UNKNOWN_RSSI {
+            return None;
+        }
+        return Some(self.rssi);
+    }
     /// Get the sender address
     pub fn get_address(&self) -> &[u8; 6] {
         let (start, _) =
-            unsafe { std::mem::transmute::<&u64, &[u8; 8]>(&self.address) }.split_array_ref::<6>();
+            unsafe { core::mem::transmute::<&u64, &[u8; 8]>(&self.address) }.split_array_ref::<6>();
         return start;
     }
     /// Get the sender address
     pub fn get_address_mut(&mut self) -> &mut [u8; 6] {
         let (start, _) =
-            unsafe { std::mem::transmute::<&mut u64, &mut [u8; 8]>(&mut self.address) }
+            unsafe { core::mem::transmute::<&mut u64, &mut [u8; 8]>(&mut self.address) }
                 .split_array_mut::<6>();
         return start;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_can_use_all_16_bytes() {
+        assert_eq!(name_from_bytes(b"abcdefghijklmnop"), "abcdefghijklmnop");
+    }
+
+    #[test]
+    fn name_of_zeros_is_empty() {
+        assert_eq!(name_from_bytes(&[0; 16]), "");
+    }
+
+    #[test]
+    fn name_ends_at_the_first_nul() {
+        assert_eq!(name_from_bytes(b"cat\0\0\0\0\0\0\0\0\0\0\0\0\0"), "cat");
+        assert_eq!(name_from_bytes(b"cat\0dog\0\0\0\0\0\0\0\0\0"), "cat");
+    }
+
+    #[test]
+    fn yield_status_is_decoded() {
+        assert_eq!(YieldStatus::from_raw(0), YieldStatus::Idle);
+        assert_eq!(YieldStatus::from_raw(1), YieldStatus::CallbacksDelivered);
+        // Hosts before 0.0.2 returned the remaining fuel
+        assert_eq!(YieldStatus::from_raw(99999), YieldStatus::Idle);
+    }
+
+    #[test]
+    fn manufacturer_data_starts_with_the_company_identifier() {
+        let data = AdvertisementData::manufacturer(0x1234, &[0xca, 0x7e]).unwrap();
+        assert_eq!(data, [0x34, 0x12, 0xca, 0x7e]);
+    }
+
+    #[test]
+    fn manufacturer_data_that_does_not_fit_is_rejected() {
+        let payload = [0u8; MAX_ADVERTISEMENT_DATA_LENGTH - 2];
+        assert!(AdvertisementData::manufacturer(0, &payload).is_ok());
+        let payload = [0u8; MAX_ADVERTISEMENT_DATA_LENGTH - 1];
+        assert_eq!(
+            AdvertisementData::manufacturer(0, &payload),
+            Err(AdvertisementError::DataTooLong)
+        );
+    }
+
+    fn advertisement(company: u16, data: &[u8]) -> Advertisement {
+        let mut bytes = [0u8; 32];
+        bytes[..data.len()].copy_from_slice(data);
+        let words: [u32; 8] = core::array::from_fn(|index| {
+            u32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap())
+        });
+        return Advertisement {
+            address: 0,
+            company,
+            data: words.into(),
+            data_length: data.len() as u8,
+            received_at: 0,
+            rssi: UNKNOWN_RSSI,
+        };
+    }
+
+    #[test]
+    fn manufacturer_returns_the_data_for_a_matching_company() {
+        let advertisement = advertisement(0x0ca7, &[1, 2, 3]);
+        assert_eq!(
+            advertisement.manufacturer(0x0ca7),
+            Some([1, 2, 3].as_slice())
+        );
+    }
+
+    #[test]
+    fn manufacturer_returns_empty_data_if_nothing_was_sent() {
+        let advertisement = advertisement(0x0ca7, &[]);
+        assert_eq!(advertisement.manufacturer(0x0ca7), Some([].as_slice()));
+    }
+
+    #[test]
+    fn manufacturer_returns_nothing_for_another_company() {
+        let advertisement = advertisement(0x0ca7, &[1, 2, 3]);
+        assert_eq!(advertisement.manufacturer(0x1234), None);
+    }
+
+    #[test]
+    fn data_can_be_empty() {
+        let advertisement = advertisement(0x0ca7, &[]);
+        assert_eq!(advertisement.get_data(), [0u8; 0]);
+    }
+
+    #[test]
+    fn data_can_use_all_32_bytes() {
+        let data: [u8; 32] = core::array::from_fn(|index| index as u8);
+        let advertisement = advertisement(0x0ca7, &data);
+        assert_eq!(advertisement.get_data(), data);
+    }
+
+    #[test]
+    fn data_length_is_limited_to_32_bytes() {
+        let data = [0xab; 32];
+        let mut advertisement = advertisement(0x0ca7, &data);
+        advertisement.data_length = 40;
+        assert_eq!(advertisement.get_data(), data);
+    }
+
+    #[test]
+    fn rssi_is_only_returned_if_known() {
+        let mut advertisement = advertisement(0x0ca7, &[1, 2, 3]);
+        assert_eq!(advertisement.get_rssi(), None);
+        advertisement.rssi = -72;
+        assert_eq!(advertisement.get_rssi(), Some(-72));
+    }
+
+    #[test]
+    fn interval_setters_keep_the_range_valid() {
+        let settings = AdvertisementSettings::new(200).with_max_interval(100);
+        assert_eq!((settings.min_interval, settings.max_interval), (100, 100));
+        let settings = AdvertisementSettings::new(100).with_max_interval(150);
+        assert_eq!((settings.min_interval, settings.max_interval), (100, 150));
+        let settings = settings.with_min_interval(300);
+        assert_eq!((settings.min_interval, settings.max_interval), (300, 300));
+    }
+
+    #[test]
+    fn the_tx_power_is_only_set_if_requested() {
+        assert_eq!(AdvertisementSettings::new(100).tx_power, None);
+        let settings = AdvertisementSettings::new(100)
+            .with_tx_power(-6)
+            .with_max_interval(150);
+        assert_eq!(settings.tx_power, Some(-6));
+        assert_eq!((settings.min_interval, settings.max_interval), (100, 150));
+    }
+}
@@ -0,0 +1,28 @@
+//! Random numbers
+//!
+//! Programs run on `wasm32-unknown-unknown`, so there is no `getrandom`. These helpers draw from [get_random](crate::get_random), which uses the hardware random number generator on a device and a seedable generator in the emulator. Use them to jitter the timing of a program, so devices that were switched on together do not stay in lock-step.
+//!
+//! ```ignore
+//! // Wait between 100 and 150 ms
+//! sleep(100_000 + random_below(50_000) as u64);
+//! ```
+use crate::get_random;
+
+/// 32 random bits
+pub fn random_u32() -> u32 {
+    return get_random() as u32;
+}
+
+/// A random number in `0..bound`. Returns 0 if `bound` is 0
+pub fn random_below(bound: u32) -> u32 {
+    // Multiplying avoids the bias towards small numbers that a modulo would have
+    return ((random_u32() as u64 * bound as u64) >> 32) as u32;
+}
+
+/// Fill `buffer` with random bytes
+pub fn random_bytes(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let random = get_random().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Read the sensors of the board
+//!
+//! The raw functions like [get_ambient_light](crate::get_ambient_light) return `u32::MAX` when there is no reading, and the board might not have the sensor at all. [Sensor] checks both and returns `None` instead, so the sentinel can not end up in a calculation by accident.
+//!
+//! ```ignore
+//! let mut ambient = MovingAverage::new(32);
+//! loop {
+//!     let lux = ambient.update(Sensor::ambient());
+//!     // ...
+//! }
+//! ```
+use crate::{
+    get_ambient_light, get_ambient_light_type, get_vibration, get_vibration_sensor_type,
+    AmbientLightType, VibrationSensorType,
+};
+
+/// Value returned by the host when a sensor has no reading
+const NO_READING: u32 = u32::MAX;
+
+/// Access to the sensors of the board
+pub struct Sensor;
+
+impl Sensor {
+    /// The ambient light in lux, or `None` if there is no sensor or no reading
+    pub fn ambient() -> Option<u32> {
+        if get_ambient_light_type() == AmbientLightType::None {
+            return None;
+        }
+        return filter_reading(get_ambient_light());
+    }
+
+    /// The vibration level, or `None` if there is no sensor or no reading
+    pub fn vibration() -> Option<u32> {
+        if get_vibration_sensor_type() == VibrationSensorType::None {
+            return None;
+        }
+        return filter_reading(get_vibration());
+    }
+}
+
+/// Turn the sentinel for a missing reading into `None`
+fn filter_reading(reading: u32) -> Option<u32> {
+    if reading == NO_READING {
+        return None;
+    }
+    return Some(reading);
+}
+
+/// Exponential moving average of a sensor, to smooth out noisy readings
+#[derive(Debug, Clone, Copy)]
+pub struct MovingAverage {
+    /// Weight of a new reading, out of 256
+    weight: u32,
+    value: Option<u32>,
+}
+
+impl MovingAverage {
+    /// Create an average where each new reading contributes `weight / 256`
+    ///
+    /// A weight of 0 is treated as 1, as the average would never move otherwise.
+    pub fn new(weight: u8) -> Self {
+        Self {
+            weight: core::cmp::max(weight, 1) as u32,
+            value: None,
+        }
+    }
+
+    /// Add a reading and return the new average
+    ///
+    /// Missing readings are skipped. The first reading is taken as it is. Returns `None` until there was a reading.
+    pub fn update(&mut self, reading: Option<u32>) -> Option<u32> {
+        let Some(reading) = reading else {
+            return self.value;
+        };
+        let average = match self.value {
+            None => reading,
+            Some(value) => {
+                let delta = (reading as i64 - value as i64) * self.weight as i64 / 256;
+                (value as i64 + delta) as u32
+            }
+        };
+        self.value = Some(average);
+        return self.value;
+    }
+
+    /// The current average, if there was a reading
+    pub fn value(&self) -> Option<u32> {
+        return self.value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentinel_is_no_reading() {
+        assert_eq!(filter_reading(u32::MAX), None);
+        assert_eq!(filter_reading(0), Some(0));
+        assert_eq!(filter_reading(1234), Some(1234));
+    }
+
+    #[test]
+    fn average_starts_at_the_first_reading() {
+        let mut average = MovingAverage::new(64);
+        assert_eq!(average.update(None), None);
+        assert_eq!(average.update(Some(1000)), Some(1000));
+    }
+
+    #[test]
+    fn average_moves_towards_new_readings() {
+        let mut average = MovingAverage::new(64);
+        average.update(Some(1000));
+        assert_eq!(average.update(Some(2000)), Some(1250));
+        assert_eq!(average.update(Some(0)), Some(938));
+    }
+
+    #[test]
+    fn missing_readings_keep_the_average() {
+        let mut average = MovingAverage::new(128);
+        average.update(Some(100));
+        assert_eq!(average.update(None), Some(100));
+        assert_eq!(average.value(), Some(100));
+    }
+}
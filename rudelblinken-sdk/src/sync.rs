@@ -0,0 +1,96 @@
+//! Share state between the run loop and the callbacks
+//!
+//! Callbacks like [on_advertisement](crate::BleGuest::on_advertisement) are only delivered while the program is inside [yield_now](crate::yield_now), so they interrupt the run loop. If both lock the same mutex, the one that loses has to give up or spin, and the events it carried are lost.
+//!
+//! [SharedState] avoids that by never letting a callback touch the state. Callbacks [push](SharedState::push) their events into a small queue and the run loop applies all pending events when it [updates](SharedState::update) the state. Events are applied in the order they arrived, exactly once, and the run loop never waits for a callback.
+//!
+//! ```ignore
+//! static STATE: LazyLock<SharedState<CycleState, Nudge>> =
+//!     LazyLock::new(|| SharedState::new(CycleState::new(), 16));
+//!
+//! impl Guest for Program {
+//!     fn run() {
+//!         loop {
+//!             yield_now(0);
+//!             let progress = STATE.update(
+//!                 |state, nudge| state.register_nudge(nudge),
+//!                 |state| state.update_progress(),
+//!             );
+//!             // ...
+//!         }
+//!     }
+//! }
+//!
+//! impl BleGuest for Program {
+//!     fn on_advertisement(advertisement: Advertisement) {
+//!         STATE.push(Nudge::from(advertisement));
+//!     }
+//! }
+//! ```
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+/// State owned by the run loop, updated by events from the callbacks
+///
+/// See the [module documentation](self) for an example.
+pub struct SharedState<T, E> {
+    state: Mutex<T>,
+    pending: Mutex<VecDeque<E>>,
+    /// Maximum number of pending events
+    capacity: usize,
+}
+
+/// Lock a mutex, ignoring that a previous holder panicked
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    return mutex.lock().unwrap_or_else(PoisonError::into_inner);
+}
+
+impl<T, E> SharedState<T, E> {
+    /// Create a new shared state that keeps up to `capacity` pending events
+    ///
+    /// The queue is allocated upfront, so pushing an event never allocates.
+    pub fn new(state: T, capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(state),
+            pending: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    /// Queue an event for the next [SharedState::update]
+    ///
+    /// Call this from callbacks. If the queue is full the oldest event is dropped, as newer events are usually more relevant. Returns `false` if an event was dropped.
+    pub fn push(&self, event: E) -> bool {
+        let mut pending = lock(&self.pending);
+        let mut dropped = false;
+        if pending.len() >= self.capacity {
+            pending.pop_front();
+            dropped = true;
+        }
+        if self.capacity > 0 {
+            pending.push_back(event);
+        }
+        return !dropped && self.capacity > 0;
+    }
+
+    /// Apply all pending events with `apply` and then run `update` on the state
+    ///
+    /// Call this from the run loop, never from a callback. Callbacks that are delivered while `update` runs (e.g. because it yields) are queued for the next call.
+    pub fn update<R>(
+        &self,
+        mut apply: impl FnMut(&mut T, E),
+        update: impl FnOnce(&mut T) -> R,
+    ) -> R {
+        let mut state = lock(&self.state);
+        // Take the events one at a time, so the queue is not locked while they are applied
+        loop {
+            let Some(event) = lock(&self.pending).pop_front() else {
+                break;
+            };
+            apply(&mut state, event);
+        }
+        return update(&mut state);
+    }
+}
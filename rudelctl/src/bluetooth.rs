@@ -1,12 +1,18 @@
-use bluer::DiscoveryFilter;
+use bluer::{DiscoveryFilter, UuidExt};
 use futures::{
     pin_mut,
     stream::{AbortHandle, Abortable},
-    StreamExt as STTT,
+    Stream, StreamExt as STTT,
 };
 use futures_time::stream::StreamExt;
 use futures_time::time::Duration;
-use std::{collections::HashSet, future::Future};
+use rudelblinken_runtime::advertisement_layout::RUDEL_SERVICE;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+};
+use uuid::Uuid;
 
 #[derive(Debug)]
 pub enum Outcome {
@@ -14,20 +20,108 @@ pub enum Outcome {
     Ignored,
 }
 
-pub async fn scan_for<Fut, Err>(
-    duration: Duration,
-    // Just give a big number if you dont want a limit
-    max_devices: u32,
-    name_filter: impl Fn(&str) -> bool,
+/// Get the Rudel id from the service data of an advertisement
+///
+/// The id is a little endian `u16`. Devices that are not part of a Rudel do not advertise an id.
+pub fn parse_rudel_id(service_data: &HashMap<Uuid, Vec<u8>>) -> Option<u16> {
+    let data = service_data.get(&Uuid::from_u16(RUDEL_SERVICE))?;
+    let id: [u8; 2] = data.get(0..2)?.try_into().ok()?;
+    return Some(u16::from_le_bytes(id));
+}
+
+/// Get the Rudel id from the advertisement of a device
+pub async fn advertised_rudel_id(device: &bluer::Device) -> Option<u16> {
+    let service_data = device.service_data().await.ok().flatten()?;
+    return parse_rudel_id(&service_data);
+}
+
+/// A rudelblinken device seen during a scan
+#[derive(Clone)]
+pub struct ScannedDevice {
+    pub device: bluer::Device,
+    pub name: String,
+    /// Signal strength of the last advertisement in dBm, if known
+    pub rssi: Option<i16>,
+}
+
+/// Something that happened to a rudelblinken device during a scan
+#[derive(Clone)]
+pub enum ScanEvent {
+    /// The device was seen for the first time
+    Found(ScannedDevice),
+    /// The device sent another advertisement or its properties changed
+    Updated(ScannedDevice),
+    /// The device has not been seen for a while
+    Lost {
+        address: bluer::Address,
+        name: String,
+    },
+}
+
+/// State of the stream returned by [scan_stream]
+struct ScanState<F> {
+    adapter: bluer::Adapter,
+    events: Pin<Box<dyn Stream<Item = bluer::AdapterEvent>>>,
+    /// Names of the devices that were found and not lost yet
+    seen: HashMap<bluer::Address, String>,
+    name_filter: F,
+    rudel: Option<u16>,
+}
+
+impl<F: Fn(&str) -> bool> ScanState<F> {
+    /// Get the device if it matches the filters
+    async fn scanned_device(&self, address: bluer::Address) -> Option<ScannedDevice> {
+        let device = self.adapter.device(address).ok()?;
+        let name = device.name().await.ok().flatten()?;
+        if !(self.name_filter)(&name) {
+            return None;
+        }
+        if self.rudel.is_some() && advertised_rudel_id(&device).await != self.rudel {
+            return None;
+        }
+        let rssi = device.rssi().await.ok().flatten();
+        return Some(ScannedDevice { device, name, rssi });
+    }
+
+    /// Wait for the next event of a device that matches the filters
+    async fn next_event(&mut self) -> Option<ScanEvent> {
+        loop {
+            match self.events.next().await? {
+                bluer::AdapterEvent::PropertyChanged(property) => {
+                    log::debug!("Adapter property changed: {:?}", property);
+                }
+                bluer::AdapterEvent::DeviceRemoved(address) => {
+                    log::debug!("Device removed: {:?}", address);
+                    if let Some(name) = self.seen.remove(&address) {
+                        return Some(ScanEvent::Lost { address, name });
+                    }
+                }
+                bluer::AdapterEvent::DeviceAdded(address) => {
+                    let Some(scanned) = self.scanned_device(address).await else {
+                        continue;
+                    };
+                    let known = self.seen.insert(address, scanned.name.clone()).is_some();
+                    return Some(match known {
+                        false => ScanEvent::Found(scanned),
+                        true => ScanEvent::Updated(scanned),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Scan for rudelblinken devices and report them as they appear, change and disappear
+///
+/// The stream does not end on its own. Every device that matches `name_filter` and `rudel` is reported as [ScanEvent::Found] once, followed by an [ScanEvent::Updated] for every advertisement or change of its properties.
+pub async fn scan_stream<F: Fn(&str) -> bool>(
+    name_filter: F,
+    // Only report devices that advertise this Rudel id
+    rudel: Option<u16>,
     // Power cycle the adapter to make discovery more reliable
     // TODO: Find a better fix
     powercycle_adapter: bool,
-    f: &dyn Fn(bluer::Device, AbortHandle) -> Fut,
-) -> bluer::Result<()>
-where
-    Err: std::fmt::Debug,
-    Fut: Future<Output = Result<Outcome, Err>>,
-{
+) -> bluer::Result<impl Stream<Item = ScanEvent>> {
     let session = bluer::Session::new().await?;
     let adapter = session.default_adapter().await?;
 
@@ -113,51 +207,96 @@ where
 
     // Starts a discovery session
     // Monitor would be way more appropriate here, but that requires the user to enable experimental features in their bluetoothd
-    let discover = adapter.discover_devices().await?;
-    pin_mut!(discover);
+    // Devices are reported again when their properties change, which includes the RSSI of every advertisement
+    let events = adapter.discover_devices_with_changes().await?;
+    let state = ScanState {
+        adapter,
+        events: Box::pin(events),
+        seen: HashMap::new(),
+        name_filter,
+        rudel,
+    };
+    return Ok(futures::stream::unfold(state, |mut state| async move {
+        let event = state.next_event().await?;
+        return Some((event, state));
+    }));
+}
+
+/// Scan for rudelblinken devices and call `f` for every device that is found
+///
+/// The scan stops when no new device was found for `duration` or when `max_devices` devices were processed.
+pub async fn scan_for<Fut, Err>(
+    duration: Duration,
+    // Just give a big number if you dont want a limit
+    max_devices: u32,
+    name_filter: impl Fn(&str) -> bool,
+    // Only process devices that advertise this Rudel id
+    rudel: Option<u16>,
+    // Power cycle the adapter to make discovery more reliable
+    // TODO: Find a better fix
+    powercycle_adapter: bool,
+    f: &dyn Fn(bluer::Device, AbortHandle) -> Fut,
+) -> bluer::Result<()>
+where
+    Err: std::fmt::Debug,
+    Fut: Future<Output = Result<Outcome, Err>>,
+{
+    let events = scan_stream(name_filter, rudel, powercycle_adapter).await?;
+    let found = events.filter_map(|event| async move {
+        match event {
+            ScanEvent::Found(scanned) => Some(scanned.device),
+            _ => None,
+        }
+    });
+    pin_mut!(found);
     let (abort_handle, abort_registration) = AbortHandle::new_pair();
-    let stream = Abortable::new(discover, abort_registration);
+    let stream = Abortable::new(found, abort_registration);
     let mut stream = stream.timeout(duration);
     let mut programmed_devices = 0;
-    while let Some(evt) = stream.next().await {
-        let Ok(evt) = evt else {
+    while let Some(device) = stream.next().await {
+        let Ok(device) = device else {
             break;
         };
-        match evt {
-            bluer::AdapterEvent::PropertyChanged(a) => {
-                log::debug!("Adapter property changed: {:?}", a);
-            }
-            bluer::AdapterEvent::DeviceRemoved(addr) => {
-                log::debug!("Device removed: {:?}", addr);
+        let result = f(device, abort_handle.clone()).await;
+        if let Err(error) = result {
+            let string_error = format!("{:?}", error);
+            if !string_error.contains("TargetDoesNotLookLikeAnUploadServiceProvider") {
+                log::error!("Failed processing device with {:?}", string_error);
             }
-            bluer::AdapterEvent::DeviceAdded(addr) => {
-                let device = adapter.device(addr)?;
-                let Some(name) = device.name().await? else {
-                    continue;
-                };
-                if !name_filter(&name) {
-                    continue;
-                }
-
-                let result = f(device, abort_handle.clone()).await;
-                if let Err(error) = result {
-                    let string_error = format!("{:?}", error);
-                    if !string_error.contains("TargetDoesNotLookLikeAnUploadServiceProvider") {
-                        log::error!("Failed processing device with {:?}", string_error);
-                    }
-                    continue;
-                }
-                if let Ok(Outcome::Processed) = result {
-                    programmed_devices += 1;
-                    if programmed_devices >= max_devices {
-                        // log::info!("Done after programming {} devices", max_devices);
-                        abort_handle.abort();
-                        break;
-                    }
-                }
+            continue;
+        }
+        if let Ok(Outcome::Processed) = result {
+            programmed_devices += 1;
+            if programmed_devices >= max_devices {
+                // log::info!("Done after programming {} devices", max_devices);
+                abort_handle.abort();
+                break;
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_advertised_rudel_id() {
+        let service_data = HashMap::from([
+            (Uuid::from_u16(0x180F), vec![42]),
+            (Uuid::from_u16(RUDEL_SERVICE), vec![0x34, 0x12]),
+        ]);
+        assert_eq!(parse_rudel_id(&service_data), Some(0x1234));
+    }
+
+    #[test]
+    fn devices_without_a_rudel_have_no_rudel_id() {
+        let service_data = HashMap::from([(Uuid::from_u16(0x180F), vec![42])]);
+        assert_eq!(parse_rudel_id(&service_data), None);
+
+        let truncated = HashMap::from([(Uuid::from_u16(RUDEL_SERVICE), vec![0x34])]);
+        assert_eq!(parse_rudel_id(&truncated), None);
+    }
+}
@@ -1,15 +1,17 @@
 //! Test wasm files on an emulated rudelblinken device.
 mod emulated_host;
+mod replay;
+mod simulation;
 use clap::Args;
 use emulated_host::{EmulatedHost, HostEvent};
-use std::{
-    ffi::OsStr,
-    path::PathBuf,
-    time::{Duration, Instant},
-};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use replay::{parse_trace, Replay, ReplayError, TracedAdvertisement};
+use rudelblinken_runtime::{fuel::FuelMeter, linker::YieldTermination};
+pub use simulation::Simulation;
+use std::{ffi::OsStr, path::PathBuf, time::Duration};
 use thiserror::Error;
 use tokio::{
-    fs::{create_dir_all, read, read_dir, remove_file},
+    fs::{create_dir_all, read, read_dir, read_to_string, remove_file},
     net::UnixDatagram,
     time::interval,
 };
@@ -25,6 +27,16 @@ pub enum EmulatorError {
     NameTooLong(),
     #[error("The name can only contain [-_a-zA-Z0-9]")]
     InvalidCharacters(),
+    #[error("At least one node is required")]
+    NoNodes(),
+    #[error("The packet loss needs to be between 0.0 and 1.0")]
+    InvalidPacketLoss(),
+    #[error("The replay speed needs to be greater than 0.0")]
+    InvalidReplaySpeed(),
+    #[error("Advertisements can only be replayed to a single node")]
+    ReplayNeedsSingleNode(),
+    #[error(transparent)]
+    ReplayError(#[from] ReplayError),
     #[error(transparent)]
     RuntimeError(#[from] rudelblinken_runtime::Error),
 }
@@ -34,9 +46,41 @@ pub struct EmulateCommand {
     /// WASM file to run
     file: PathBuf,
 
-    /// Name of the instance
+    /// Name of the instance. With multiple nodes, this is used as a prefix for their names
     #[arg(short, long)]
     name: Option<String>,
+
+    /// Number of devices to emulate. The devices share a simulated BLE medium, so they receive each others advertisements
+    #[arg(long, default_value_t = 1)]
+    pub nodes: usize,
+
+    /// Seed for the addresses, the random numbers and the packet loss of the emulated devices
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Probability that an advertisement does not reach another node, between 0.0 and 1.0. Only used with multiple nodes
+    #[arg(long, default_value_t = 0.0)]
+    packet_loss: f64,
+
+    /// Mean delay in milliseconds until an advertisement reaches another node. Only used with multiple nodes
+    #[arg(long, default_value_t = 0)]
+    delay_ms: u64,
+
+    /// Maximum deviation from the mean delay in milliseconds. Only used with multiple nodes
+    #[arg(long, default_value_t = 0)]
+    jitter_ms: u64,
+
+    /// Log how much fuel the program consumes, split by the host function it calls next
+    #[arg(long)]
+    profile: bool,
+
+    /// Deliver the advertisements from a trace file to the program at their recorded times. Every line is a JSON object like `{"at_micros": 1500000, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "0a1b2c"}`. Only used with a single node
+    #[arg(long)]
+    replay: Option<PathBuf>,
+
+    /// Speed factor for replaying a trace. 2.0 replays it twice as fast
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
 }
 
 pub struct Emulator {
@@ -45,13 +89,23 @@ pub struct Emulator {
     address: [u8; 6],
     socket: UnixDatagram,
     socket_dir: PathBuf,
+    seed: u64,
+    profile: bool,
+    /// Advertisements to replay and the replay speed
+    replay: Option<(Vec<TracedAdvertisement>, f64)>,
+}
+
+/// Create the random number generator for a command
+fn seeded_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
 }
 
 /// Generate a random 6 byte mac address
-fn random_mac() -> [u8; 6] {
+fn random_mac(rng: &mut impl Rng) -> [u8; 6] {
     use rand::distributions::Standard;
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
     rng.sample(Standard)
 }
 
@@ -63,6 +117,23 @@ fn mac_to_name(mac: &[u8; 6]) -> String {
     )
 }
 
+/// Check that a name could also be used by a real device
+fn validate_name(name: &str) -> Result<(), EmulatorError> {
+    if name.as_bytes().len() < 3 {
+        return Err(EmulatorError::NameTooShort());
+    }
+    if name.as_bytes().len() > 16 {
+        return Err(EmulatorError::NameTooLong());
+    }
+    if !name
+        .chars()
+        .all(|c| char::is_ascii_alphanumeric(&c) || c == '-' || c == '_')
+    {
+        return Err(EmulatorError::InvalidCharacters());
+    }
+    Ok(())
+}
+
 #[repr(packed)]
 #[derive(IntoBytes, FromBytes, Clone, Copy, KnownLayout, Immutable)]
 pub struct Advertisement {
@@ -94,25 +165,27 @@ impl Emulator {
         log::debug!("Emulating WASM file: {:?}", command.file);
         let wasm = read(&command.file).await?;
 
-        let mac: [u8; 6] = random_mac();
+        let mut rng = seeded_rng(command.seed);
+        let mac: [u8; 6] = random_mac(&mut rng);
 
         let name = match command.name {
             Some(name) => name,
             None => mac_to_name(&mac),
         };
-        if name.as_bytes().len() < 3 {
-            return Err(EmulatorError::NameTooShort());
-        }
-        if name.as_bytes().len() > 16 {
-            return Err(EmulatorError::NameTooLong());
-        }
         log::debug!("Using name: {}", name);
-        if !name
-            .chars()
-            .all(|c| char::is_ascii_alphanumeric(&c) || c == '-' || c == '_')
-        {
-            return Err(EmulatorError::InvalidCharacters());
-        }
+        validate_name(&name)?;
+
+        let replay = match &command.replay {
+            Some(path) => {
+                if !(command.speed > 0.0) {
+                    return Err(EmulatorError::InvalidReplaySpeed());
+                }
+                let trace = parse_trace(&read_to_string(path).await?)?;
+                log::debug!("Replaying {} advertisements", trace.len());
+                Some((trace, command.speed))
+            }
+            None => None,
+        };
 
         let tempdir = std::env::temp_dir().join("rudelblinken/emulator");
         create_dir_all(&tempdir).await?;
@@ -128,6 +201,9 @@ impl Emulator {
             address: mac,
             socket: my_socket,
             socket_dir: tempdir,
+            seed: rng.gen(),
+            profile: command.profile,
+            replay,
         })
     }
 
@@ -166,13 +242,28 @@ impl Emulator {
     }
 
     pub async fn emulate(&self) -> Result<(), EmulatorError> {
-        let (sender, mut receiver, host) = EmulatedHost::new(self.address, self.name.clone());
+        let (sender, mut receiver, mut host) =
+            EmulatedHost::new(self.address, self.name.clone(), self.seed);
+        host.fuel_meter = self.profile.then(FuelMeter::new);
+        if let Some((trace, speed)) = self.replay.clone() {
+            host.clock = Replay::clock();
+            host.replay = Some(Replay::new(trace, speed));
+        }
+        let clock = host.clock.clone();
         let mut instance = rudelblinken_runtime::linker::setup(&self.wasm, host)?;
-        let start_time = Instant::now();
         let mut advertisment_data: Vec<u8> = Vec::new();
 
+        let name = self.name.clone();
         std::thread::spawn(move || {
-            instance.run().unwrap();
+            match instance.run() {
+                Ok(()) => {}
+                // The emulator stopped the program
+                Err(error) if error.downcast_ref::<YieldTermination>().is_some() => {}
+                Err(error) => log::error!("[{}] The program failed: {}", name, error),
+            }
+            if let Some(fuel_meter) = instance.fuel_meter() {
+                log::info!("[{}] Fuel: {}", name, fuel_meter);
+            }
         });
 
         let mut advertisement_interval = interval(Duration::from_millis(150));
@@ -208,7 +299,8 @@ impl Emulator {
                                 company: received_advertisement.company,
                                 data: received_advertisement.data,
                                 data_length: received_advertisement.data_length,
-                                received_at: start_time.elapsed().as_micros() as u64,
+                                received_at: clock.now(),
+                                rssi: rudelblinken_runtime::host::UNKNOWN_RSSI,
                             };
 
                             sender
@@ -227,6 +319,8 @@ impl Emulator {
                         emulated_host::WasmEvent::SetAdvertismentData(data) => {
                             advertisment_data = data;
                         },
+                        // The LED brightness is only shown when emulating multiple devices
+                        emulated_host::WasmEvent::SetLeds { .. } | emulated_host::WasmEvent::SetRgb { .. } | emulated_host::WasmEvent::SetRgbRange { .. } => {},
                     }
                 }
                 _val = timer_event => {
@@ -1,19 +1,41 @@
+use super::replay::Replay;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rudelblinken_runtime::{
+    emulated_host::Clock,
+    fuel::FuelMeter,
     host::{
-        Advertisement, AdvertisementSettings, AmbientLightType, Host, LedColor, LedInfo, LogLevel,
-        VibrationSensorType, VoltageSensorType,
+        Advertisement, AdvertisementSettings, AmbientLightType, BrightnessCurve, Host, KvError,
+        LedColor, LedInfo, LogLevel, VibrationSensorType, VoltageSensorType, YieldStatus,
     },
-    linker::linker::WrappedCaller,
+    linker::{linker::WrappedCaller, YieldTermination},
 };
 use std::{
+    collections::HashMap,
     thread,
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::{channel, error::TryRecvError, Receiver, Sender};
+
+/// How often the fuel consumption is logged when profiling
+const FUEL_REPORT_INTERVAL: Duration = Duration::from_secs(5);
 
 pub enum WasmEvent {
     SetAdvertismentSettings(AdvertisementSettings),
     SetAdvertismentData(Vec<u8>),
+    SetLeds {
+        first_id: u16,
+        lux: Vec<u16>,
+    },
+    SetRgb {
+        color: LedColor,
+        lux: u32,
+    },
+    SetRgbRange {
+        first_id: u16,
+        count: u16,
+        color: LedColor,
+        lux: u32,
+    },
 }
 
 pub enum HostEvent {
@@ -21,74 +43,161 @@ pub enum HostEvent {
 }
 
 pub struct EmulatedHost {
-    pub start_time: Instant,
+    /// Time source of the guest. Replace it with [Replay::clock] when replaying a trace
+    pub clock: Clock,
+    /// Advertisements that are delivered at their recorded times on the virtual clock
+    pub replay: Option<Replay>,
     pub host_events: Receiver<HostEvent>,
+    /// An event that interrupted a sleep. It is handled at the next yield
+    pending_event: Option<HostEvent>,
     pub wasm_events: Sender<WasmEvent>,
     // TODO: Actually use this
     #[allow(dead_code)]
     pub address: [u8; 6],
-    // TODO: Actually use this
-    #[allow(dead_code)]
+    /// Prefixed to the log messages of the guest, so multiple emulated devices can be told apart
     pub name: String,
+    /// The key-value store of the guest. It only lives as long as the emulator
+    pub kv_store: HashMap<String, Vec<u8>>,
+    /// Source for `get_random`. Seeded, so emulator runs can be reproduced
+    pub rng: StdRng,
+    /// Records the fuel consumption of the guest when profiling
+    pub fuel_meter: Option<FuelMeter>,
+    last_fuel_report: Instant,
+    /// Set by the guest and applied to the lux before the LED events are sent
+    brightness_curve: BrightnessCurve,
 }
 
 impl EmulatedHost {
-    pub fn new(address: [u8; 6], name: String) -> (Sender<HostEvent>, Receiver<WasmEvent>, Self) {
+    pub fn new(
+        address: [u8; 6],
+        name: String,
+        seed: u64,
+    ) -> (Sender<HostEvent>, Receiver<WasmEvent>, Self) {
         let (host_sender, host_receiver) = channel::<HostEvent>(20);
         let (wasm_sender, wasm_receiver) = channel::<WasmEvent>(20);
         return (
             host_sender,
             wasm_receiver,
             EmulatedHost {
-                start_time: Instant::now(),
+                clock: Clock::new(),
+                replay: None,
                 host_events: host_receiver,
+                pending_event: None,
                 wasm_events: wasm_sender,
                 address,
                 name,
+                kv_store: HashMap::new(),
+                rng: StdRng::seed_from_u64(seed),
+                fuel_meter: None,
+                last_fuel_report: Instant::now(),
+                brightness_curve: BrightnessCurve::Linear,
             },
         );
     }
+
+    /// Log the fuel consumption of the guest, if it is profiled
+    pub fn report_fuel(&mut self) {
+        if let Some(fuel_meter) = &self.fuel_meter {
+            log::info!("[{}] Fuel: {}", self.name, fuel_meter);
+        }
+        self.last_fuel_report = Instant::now();
+    }
 }
 
 impl Host for EmulatedHost {
     fn yield_now(
         caller: &mut WrappedCaller<'_, Self>,
         micros: u64,
-    ) -> Result<u32, rudelblinken_runtime::Error> {
-        let end_time = Instant::now()
-            .checked_add(Duration::from_micros(micros))
-            .unwrap();
+    ) -> Result<YieldStatus, rudelblinken_runtime::Error> {
+        let end_time = caller.data().clock.now().saturating_add(micros);
+        let mut status = YieldStatus::Idle;
         loop {
-            while let Ok(event) = caller.data_mut().host_events.try_recv() {
+            loop {
+                let event = match caller.data_mut().pending_event.take() {
+                    Some(event) => Ok(event),
+                    None => caller.data_mut().host_events.try_recv(),
+                };
+                let event = match event {
+                    Ok(event) => event,
+                    Err(TryRecvError::Empty) => break,
+                    // The emulator stopped this node
+                    Err(TryRecvError::Disconnected) => {
+                        return Err(rudelblinken_runtime::Error::host(YieldTermination));
+                    }
+                };
                 match event {
                     HostEvent::AdvertisementReceived(advertisement) => {
                         caller.on_advertisement(advertisement)?;
                     }
                 }
+                status = YieldStatus::CallbacksDelivered;
             }
-            if end_time <= Instant::now() {
+            let host = caller.data_mut();
+            let now = host.clock.now();
+            if end_time <= now {
                 break;
             }
-            thread::sleep(Duration::from_millis(1));
+            match &mut host.replay {
+                Some(replay) => {
+                    host.pending_event = replay
+                        .advance(&host.clock, end_time - now)
+                        .map(HostEvent::AdvertisementReceived);
+                }
+                None => thread::sleep(Duration::from_millis(1)),
+            }
         }
         caller.inner().set_fuel(999_999).unwrap();
-        return Ok(999_999);
+        if caller.data().last_fuel_report.elapsed() >= FUEL_REPORT_INTERVAL {
+            caller.data_mut().report_fuel();
+        }
+        return Ok(status);
     }
 
     fn sleep(
-        _caller: &mut WrappedCaller<'_, Self>,
+        caller: &mut WrappedCaller<'_, Self>,
         micros: u64,
     ) -> Result<(), rudelblinken_runtime::Error> {
-        std::thread::sleep(Duration::from_micros(micros));
+        // None if the sleep would end after the last representable instant. Then only an event ends it
+        let end_time = Instant::now().checked_add(Duration::from_micros(micros));
+        // The guest did not yield since the last sleep was interrupted
+        if caller.data().pending_event.is_some() {
+            return Ok(());
+        }
+        let host = caller.data_mut();
+        if let Some(replay) = &mut host.replay {
+            host.pending_event = replay
+                .advance(&host.clock, micros)
+                .map(HostEvent::AdvertisementReceived);
+            return Ok(());
+        }
+        loop {
+            let now = Instant::now();
+            if end_time.is_some_and(|end_time| end_time <= now) {
+                break;
+            }
+            // Return early, so the guest can handle the event at its next yield
+            if let Ok(event) = caller.data_mut().host_events.try_recv() {
+                caller.data_mut().pending_event = Some(event);
+                break;
+            }
+            let remaining = end_time.map_or(Duration::MAX, |end_time| end_time - now);
+            thread::sleep(remaining.min(Duration::from_millis(1)));
+        }
         return Ok(());
     }
 
     fn time(caller: &mut WrappedCaller<'_, Self>) -> Result<u64, rudelblinken_runtime::Error> {
-        return Ok(caller.data().start_time.elapsed().as_micros() as u64);
+        return Ok(caller.data().clock.now());
+    }
+
+    fn get_random(
+        caller: &mut WrappedCaller<'_, Self>,
+    ) -> Result<u64, rudelblinken_runtime::Error> {
+        return Ok(caller.data_mut().rng.gen());
     }
 
     fn log(
-        _caller: &mut WrappedCaller<'_, Self>,
+        caller: &mut WrappedCaller<'_, Self>,
         level: LogLevel,
         message: &str,
     ) -> Result<(), rudelblinken_runtime::Error> {
@@ -100,7 +209,8 @@ impl Host for EmulatedHost {
                 LogLevel::Debug => log::Level::Debug,
                 LogLevel::Trace => log::Level::Trace,
             },
-            "{}",
+            "[{}] {}",
+            caller.data().name,
             message
         );
         return Ok(());
@@ -118,19 +228,71 @@ impl Host for EmulatedHost {
         return Ok(vec![]);
     }
 
+    fn kv_get(
+        caller: &mut WrappedCaller<'_, Self>,
+        key: &str,
+    ) -> Result<Option<Vec<u8>>, rudelblinken_runtime::Error> {
+        return Ok(caller.data().kv_store.get(key).cloned());
+    }
+
+    fn kv_set(
+        caller: &mut WrappedCaller<'_, Self>,
+        key: &str,
+        value: &[u8],
+    ) -> Result<Result<(), KvError>, rudelblinken_runtime::Error> {
+        caller
+            .data_mut()
+            .kv_store
+            .insert(key.to_string(), value.to_vec());
+        return Ok(Ok(()));
+    }
+
     fn set_leds(
-        _caller: &mut WrappedCaller<'_, Self>,
-        _first_id: u16,
-        _lux: &[u16],
+        caller: &mut WrappedCaller<'_, Self>,
+        first_id: u16,
+        lux: &[u16],
     ) -> Result<u32, rudelblinken_runtime::Error> {
+        caller
+            .data_mut()
+            .wasm_events
+            .blocking_send(WasmEvent::SetLeds {
+                first_id,
+                lux: lux.into(),
+            })
+            .map_err(|error| rudelblinken_runtime::Error::new(error.to_string()))?;
         Ok(0)
     }
 
     fn set_rgb(
-        _caller: &mut WrappedCaller<'_, Self>,
-        _color: &LedColor,
-        _lux: u32,
+        caller: &mut WrappedCaller<'_, Self>,
+        color: &LedColor,
+        lux: u32,
     ) -> Result<u32, rudelblinken_runtime::Error> {
+        caller
+            .data_mut()
+            .wasm_events
+            .blocking_send(WasmEvent::SetRgb { color: *color, lux })
+            .map_err(|error| rudelblinken_runtime::Error::new(error.to_string()))?;
+        Ok(0)
+    }
+
+    fn set_rgb_range(
+        caller: &mut WrappedCaller<'_, Self>,
+        first_id: u16,
+        count: u16,
+        color: &LedColor,
+        lux: u32,
+    ) -> Result<u32, rudelblinken_runtime::Error> {
+        caller
+            .data_mut()
+            .wasm_events
+            .blocking_send(WasmEvent::SetRgbRange {
+                first_id,
+                count,
+                color: *color,
+                lux,
+            })
+            .map_err(|error| rudelblinken_runtime::Error::new(error.to_string()))?;
         Ok(0)
     }
 
@@ -140,6 +302,18 @@ impl Host for EmulatedHost {
         return Ok(500);
     }
 
+    fn brightness_curve(caller: &mut WrappedCaller<'_, Self>) -> BrightnessCurve {
+        return caller.data().brightness_curve;
+    }
+
+    fn set_brightness_curve(
+        caller: &mut WrappedCaller<'_, Self>,
+        curve: BrightnessCurve,
+    ) -> Result<(), rudelblinken_runtime::Error> {
+        caller.data_mut().brightness_curve = curve;
+        return Ok(());
+    }
+
     fn get_led_info(
         _caller: &mut WrappedCaller<'_, Self>,
         _id: u16,
@@ -174,6 +348,14 @@ impl Host for EmulatedHost {
         return Ok(0);
     }
 
+    fn set_vibration_threshold(
+        _caller: &mut WrappedCaller<'_, Self>,
+        _threshold: u32,
+    ) -> Result<(), rudelblinken_runtime::Error> {
+        // There is no vibration sensor, so the threshold is never crossed
+        return Ok(());
+    }
+
     fn configure_advertisement(
         caller: &mut WrappedCaller<'_, Self>,
         settings: AdvertisementSettings,
@@ -198,6 +380,14 @@ impl Host for EmulatedHost {
         Ok(0)
     }
 
+    fn set_advertisement_tx_power(
+        _caller: &mut WrappedCaller<'_, Self>,
+        tx_power: i8,
+    ) -> Result<i8, rudelblinken_runtime::Error> {
+        // The emulated radio has no range, so the transmit power does not change anything
+        Ok(tx_power)
+    }
+
     fn get_voltage_sensor_type(
         _context: &mut WrappedCaller<'_, Self>,
     ) -> Result<VoltageSensorType, rudelblinken_runtime::Error> {
@@ -209,4 +399,8 @@ impl Host for EmulatedHost {
     ) -> Result<u32, rudelblinken_runtime::Error> {
         Ok(0)
     }
+
+    fn fuel_meter(&mut self) -> Option<&mut FuelMeter> {
+        return self.fuel_meter.as_mut();
+    }
 }
@@ -0,0 +1,293 @@
+//! Replay a trace of recorded advertisements into an emulated device
+//!
+//! A trace is a JSON lines file. Every line is one received advertisement:
+//!
+//! ```json
+//! {"at_micros": 1500000, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "0000ca7ea20102"}
+//! ```
+//!
+//! - `at_micros`: When the advertisement was received, in microseconds since the start of the trace
+//! - `mac`: The address of the sender
+//! - `data_hex`: The manufacturer data of the advertisement as a hex string. At most 32 bytes
+//! - `rssi`: The signal strength in dBm. Optional, the advertisement has an unknown RSSI without it
+//!
+//! The lines need to be sorted by `at_micros`. Empty lines are ignored.
+use rudelblinken_runtime::{
+    emulated_host::Clock,
+    host::{Advertisement, UNKNOWN_RSSI},
+};
+use serde::Deserialize;
+use std::{collections::VecDeque, thread, time::Duration};
+use thiserror::Error;
+
+/// Manufacturer data is limited to 32 bytes
+const MAX_DATA_LENGTH: usize = 32;
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("Line {line} of the trace is not a valid trace entry: {source}")]
+    InvalidEntry {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("Line {line} of the trace does not contain a mac address like AA:BB:CC:DD:EE:FF")]
+    InvalidMac { line: usize },
+    #[error("Line {line} of the trace does not contain the data as a hex string")]
+    InvalidData { line: usize },
+    #[error("Line {line} of the trace contains more than {max} bytes of data", max = MAX_DATA_LENGTH)]
+    DataTooLong { line: usize },
+    #[error("Line {line} of the trace was received before the line above it")]
+    NotSorted { line: usize },
+}
+
+/// One line of a trace file
+#[derive(Deserialize)]
+struct TraceEntry {
+    at_micros: u64,
+    mac: String,
+    data_hex: String,
+    #[serde(default)]
+    rssi: Option<i8>,
+}
+
+/// An advertisement from a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TracedAdvertisement {
+    /// When the advertisement was received, relative to the start of the trace
+    pub at: Duration,
+    pub address: [u8; 6],
+    pub data: Vec<u8>,
+    pub rssi: i8,
+}
+
+/// Parse a mac address like `AA:BB:CC:DD:EE:FF`
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut address = [0u8; 6];
+    let mut parts = mac.split(':');
+    for byte in address.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    return Some(address);
+}
+
+/// Parse a hex string like `0a1b2c`
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    return (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            hex.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect();
+}
+
+/// Parse a trace in the JSON lines format described in the [module documentation](self)
+pub fn parse_trace(trace: &str) -> Result<Vec<TracedAdvertisement>, ReplayError> {
+    let mut advertisements: Vec<TracedAdvertisement> = Vec::new();
+    for (index, text) in trace.lines().enumerate() {
+        let line = index + 1;
+        if text.trim().is_empty() {
+            continue;
+        }
+        let entry: TraceEntry = serde_json::from_str(text)
+            .map_err(|source| ReplayError::InvalidEntry { line, source })?;
+        let address = parse_mac(&entry.mac).ok_or(ReplayError::InvalidMac { line })?;
+        let data = parse_hex(&entry.data_hex).ok_or(ReplayError::InvalidData { line })?;
+        if data.len() > MAX_DATA_LENGTH {
+            return Err(ReplayError::DataTooLong { line });
+        }
+        let at = Duration::from_micros(entry.at_micros);
+        if advertisements
+            .last()
+            .is_some_and(|previous| previous.at > at)
+        {
+            return Err(ReplayError::NotSorted { line });
+        }
+        advertisements.push(TracedAdvertisement {
+            at,
+            address,
+            data,
+            rssi: entry.rssi.unwrap_or(UNKNOWN_RSSI),
+        });
+    }
+    return Ok(advertisements);
+}
+
+/// Delivers the advertisements of a trace at their recorded times
+///
+/// The emulated device runs on a virtual clock that starts at the beginning of the trace, so every advertisement is received at exactly its recorded time and a replay can be reproduced. The virtual clock is only paced to the wall clock, divided by `speed`, so the program can be watched.
+#[derive(Debug)]
+pub struct Replay {
+    trace: VecDeque<TracedAdvertisement>,
+    speed: f64,
+}
+
+impl Replay {
+    /// `speed` needs to be greater than 0.0. A speed of 2.0 replays the trace twice as fast
+    pub fn new(trace: Vec<TracedAdvertisement>, speed: f64) -> Self {
+        return Replay {
+            trace: trace.into(),
+            speed,
+        };
+    }
+
+    /// A virtual clock that starts at the beginning of the trace
+    pub fn clock() -> Clock {
+        return Clock::new_virtual();
+    }
+
+    /// Advance `clock` by `micros`, but stop at the next advertisement of the trace and return it
+    pub fn advance(&mut self, clock: &Clock, micros: u64) -> Option<Advertisement> {
+        let now = clock.now();
+        let end = now.saturating_add(micros);
+        let next_at = self
+            .trace
+            .front()
+            .map(|traced| (traced.at.as_micros() as u64).max(now))
+            .filter(|at| *at <= end);
+        let target = next_at.unwrap_or(end);
+        thread::sleep(Duration::from_micros(target - now).div_f64(self.speed));
+        clock.advance(target - now);
+        if next_at.is_none() {
+            return None;
+        }
+
+        let traced = self.trace.pop_front()?;
+        if self.trace.is_empty() {
+            log::info!("Replayed the entire trace");
+        }
+        let mut data = [0u8; MAX_DATA_LENGTH];
+        data[..traced.data.len()].copy_from_slice(&traced.data);
+        let mut address = [0u8; 8];
+        address[..6].copy_from_slice(&traced.address);
+        return Some(Advertisement {
+            company: 0,
+            address,
+            data,
+            data_length: traced.data.len() as u8,
+            received_at: target,
+            rssi: traced.rssi,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_trace() {
+        let trace = r#"{"at_micros": 0, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "0a1b"}
+
+{"at_micros": 1500000, "mac": "01:02:03:04:05:06", "data_hex": "", "rssi": -60}
+"#;
+        let advertisements = parse_trace(trace).unwrap();
+        assert_eq!(
+            advertisements,
+            vec![
+                TracedAdvertisement {
+                    at: Duration::ZERO,
+                    address: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+                    data: vec![0x0a, 0x1b],
+                    rssi: UNKNOWN_RSSI,
+                },
+                TracedAdvertisement {
+                    at: Duration::from_millis(1500),
+                    address: [1, 2, 3, 4, 5, 6],
+                    data: vec![],
+                    rssi: -60,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let invalid_mac = r#"{"at_micros": 0, "mac": "AA:BB:CC:DD:EE", "data_hex": ""}"#;
+        assert!(matches!(
+            parse_trace(invalid_mac),
+            Err(ReplayError::InvalidMac { line: 1 })
+        ));
+
+        let invalid_data = r#"{"at_micros": 0, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "abc"}"#;
+        assert!(matches!(
+            parse_trace(invalid_data),
+            Err(ReplayError::InvalidData { line: 1 })
+        ));
+
+        let not_sorted = r#"{"at_micros": 10, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": ""}
+{"at_micros": 5, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": ""}"#;
+        assert!(matches!(
+            parse_trace(not_sorted),
+            Err(ReplayError::NotSorted { line: 2 })
+        ));
+    }
+
+    #[test]
+    fn delivers_advertisements_at_their_recorded_times() {
+        let trace = parse_trace(
+            r#"{"at_micros": 1000000, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "2a"}
+{"at_micros": 3000000, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "2b"}
+{"at_micros": 3000000, "mac": "01:02:03:04:05:06", "data_hex": "2c"}"#,
+        )
+        .unwrap();
+        let mut replay = Replay::new(trace, 1000.0);
+        let clock = Replay::clock();
+
+        // Nothing is due yet
+        assert_eq!(replay.advance(&clock, 500_000), None);
+        assert_eq!(clock.now(), 500_000);
+
+        // A long sleep stops at the next advertisement
+        let first = replay.advance(&clock, 10_000_000).unwrap();
+        assert_eq!(first.data[0], 0x2a);
+        assert_eq!(first.data_length, 1);
+        assert_eq!(first.received_at, 1_000_000);
+        assert_eq!(clock.now(), 1_000_000);
+
+        let second = replay.advance(&clock, 10_000_000).unwrap();
+        assert_eq!(second.data[0], 0x2b);
+        assert_eq!(second.received_at, 3_000_000);
+
+        // Advertisements at the same time are delivered without advancing the clock
+        let third = replay.advance(&clock, 10_000_000).unwrap();
+        assert_eq!(third.data[0], 0x2c);
+        assert_eq!(third.address[..6], [1, 2, 3, 4, 5, 6]);
+        assert_eq!(third.received_at, 3_000_000);
+
+        assert_eq!(replay.advance(&clock, 1_000), None);
+        assert_eq!(clock.now(), 3_001_000);
+    }
+
+    #[test]
+    fn replays_are_reproducible() {
+        let trace = parse_trace(
+            r#"{"at_micros": 1234, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "01"}
+{"at_micros": 56789, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "02"}"#,
+        )
+        .unwrap();
+        let run = || {
+            let mut replay = Replay::new(trace.clone(), 1000.0);
+            let clock = Replay::clock();
+            let mut received = Vec::new();
+            for _ in 0..100 {
+                if let Some(advertisement) = replay.advance(&clock, 700) {
+                    received.push((clock.now(), advertisement.received_at));
+                }
+            }
+            received
+        };
+        assert_eq!(run(), vec![(1234, 1234), (56789, 56789)]);
+        assert_eq!(run(), run());
+    }
+}
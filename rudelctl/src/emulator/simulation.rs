@@ -0,0 +1,269 @@
+//! Emulate multiple devices in one process
+//!
+//! All nodes share a simulated BLE medium. Every advertisement a node sends is routed to all other nodes, where it may be lost or delayed.
+use super::{
+    emulated_host::{EmulatedHost, HostEvent, WasmEvent},
+    mac_to_name, random_mac, seeded_rng, validate_name, EmulateCommand, EmulatorError,
+};
+use rand::Rng;
+use router::{LinkSettings, Router};
+use rudelblinken_runtime::{
+    fuel::FuelMeter,
+    host::{Advertisement, UNKNOWN_RSSI},
+    linker::YieldTermination,
+};
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::{
+    fs::read,
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, sleep},
+};
+
+mod router;
+
+/// How often the LED brightness of all nodes is logged
+const STATUS_INTERVAL: Duration = Duration::from_millis(500);
+/// How many advertisements can be in flight before slow nodes start missing them
+const MEDIUM_CAPACITY: usize = 256;
+
+/// Mixed into `--seed` to seed the stream of the router seeds
+const ROUTER_SEED_SALT: u64 = 0x726f_7574_6572;
+
+/// An advertisement on the simulated medium
+#[derive(Clone, Copy)]
+struct Transmission {
+    /// Index of the node that sent the advertisement
+    sender: usize,
+    advertisement: Advertisement,
+}
+
+struct Node {
+    name: String,
+    address: [u8; 6],
+    seed: u64,
+    /// Seed for the router of the advertisements to this node
+    router_seed: u64,
+}
+
+pub struct Simulation {
+    wasm: Vec<u8>,
+    nodes: Vec<Node>,
+    link_settings: LinkSettings,
+    profile: bool,
+    /// The current LED brightness of every node
+    brightness: Mutex<Vec<u32>>,
+}
+
+impl Simulation {
+    pub async fn new(command: EmulateCommand) -> Result<Self, EmulatorError> {
+        log::debug!("Emulating WASM file: {:?}", command.file);
+        let wasm = read(&command.file).await?;
+        if command.nodes == 0 {
+            return Err(EmulatorError::NoNodes());
+        }
+        if !(0.0..=1.0).contains(&command.packet_loss) {
+            return Err(EmulatorError::InvalidPacketLoss());
+        }
+        if command.replay.is_some() {
+            return Err(EmulatorError::ReplayNeedsSingleNode());
+        }
+        let link_settings = LinkSettings {
+            packet_loss: command.packet_loss,
+            delay: Duration::from_millis(command.delay_ms),
+            jitter: Duration::from_millis(command.jitter_ms),
+        };
+
+        let mut rng = seeded_rng(command.seed);
+        // The routers draw from their own stream, so a --seed gives the nodes the same addresses and seeds as before the routers existed
+        let mut router_rng = seeded_rng(command.seed.map(|seed| seed ^ ROUTER_SEED_SALT));
+        let nodes = (0..command.nodes)
+            .map(|index| {
+                let address = random_mac(&mut rng);
+                let name = match &command.name {
+                    Some(prefix) => format!("{}-{}", prefix, index),
+                    None => mac_to_name(&address),
+                };
+                validate_name(&name)?;
+                log::info!("Node {} is {}", index, name);
+                Ok(Node {
+                    name,
+                    address,
+                    seed: rng.gen(),
+                    router_seed: router_rng.gen(),
+                })
+            })
+            .collect::<Result<Vec<_>, EmulatorError>>()?;
+
+        Ok(Self {
+            wasm,
+            link_settings,
+            profile: command.profile,
+            brightness: Mutex::new(vec![0; nodes.len()]),
+            nodes,
+        })
+    }
+
+    pub async fn run(&self) -> Result<(), EmulatorError> {
+        let (medium, _) = broadcast::channel::<Transmission>(MEDIUM_CAPACITY);
+        let start_time = Instant::now();
+
+        let nodes = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| self.run_node(index, node, &medium, start_time))
+            .collect::<Vec<_>>();
+
+        tokio::select! {
+            result = futures::future::try_join_all(nodes) => {
+                result?;
+            }
+            _ = self.log_status() => {}
+        }
+        Ok(())
+    }
+
+    /// Periodically log the LED brightness of all nodes
+    async fn log_status(&self) {
+        let mut status_interval = interval(STATUS_INTERVAL);
+        loop {
+            status_interval.tick().await;
+            let brightness = self.brightness.lock().unwrap();
+            let status = brightness
+                .iter()
+                .map(|lux| format!("{:>5}", lux))
+                .collect::<Vec<_>>()
+                .join(" ");
+            log::info!("Brightness: {}", status);
+        }
+    }
+
+    /// Run a single node until its program exits
+    async fn run_node(
+        &self,
+        index: usize,
+        node: &Node,
+        medium: &broadcast::Sender<Transmission>,
+        start_time: Instant,
+    ) -> Result<(), EmulatorError> {
+        let mut medium_receiver = medium.subscribe();
+        let mut router = Router::new(self.link_settings, node.router_seed);
+        let (sender, mut receiver, mut host) =
+            EmulatedHost::new(node.address, node.name.clone(), node.seed);
+        host.fuel_meter = self.profile.then(FuelMeter::new);
+        let mut instance = rudelblinken_runtime::linker::setup(&self.wasm, host)?;
+
+        let name = node.name.clone();
+        std::thread::spawn(move || {
+            match instance.run() {
+                Ok(()) => {}
+                // The simulation stopped the node
+                Err(error) if error.downcast_ref::<YieldTermination>().is_some() => {}
+                Err(error) => log::error!("[{}] The program failed: {}", name, error),
+            }
+            if let Some(fuel_meter) = instance.fuel_meter() {
+                log::info!("[{}] Fuel: {}", name, fuel_meter);
+            }
+        });
+
+        let mut advertisment_data: Vec<u8> = Vec::new();
+        let mut leds: Vec<u16> = Vec::new();
+        let mut advertisement_interval = interval(Duration::from_millis(150));
+
+        loop {
+            tokio::select! {
+                transmission = medium_receiver.recv() => {
+                    let transmission = match transmission {
+                        Ok(transmission) => transmission,
+                        Err(RecvError::Lagged(missed)) => {
+                            log::warn!("[{}] Missed {} advertisements", node.name, missed);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    if transmission.sender == index {
+                        continue;
+                    }
+                    let Some(delay) = router.route() else {
+                        continue;
+                    };
+                    if sender.is_closed() {
+                        // The program stopped
+                        break;
+                    }
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        sleep(delay).await;
+                        let advertisement = Advertisement {
+                            received_at: start_time.elapsed().as_micros() as u64,
+                            ..transmission.advertisement
+                        };
+                        let _ = sender.send(HostEvent::AdvertisementReceived(advertisement)).await;
+                    });
+                }
+                event = receiver.recv() => {
+                    let Some(event) = event else {
+                        break;
+                    };
+                    match event {
+                        WasmEvent::SetAdvertismentSettings(settings) => {
+                            advertisement_interval = interval(Duration::from_millis(settings.max_interval as u64));
+                        }
+                        WasmEvent::SetAdvertismentData(data) => {
+                            advertisment_data = data;
+                        }
+                        WasmEvent::SetLeds { first_id, lux } => {
+                            let end = first_id as usize + lux.len();
+                            if leds.len() < end {
+                                leds.resize(end, 0);
+                            }
+                            leds[first_id as usize..end].copy_from_slice(&lux);
+                            let brightness = leds.iter().copied().max().unwrap_or(0);
+                            self.brightness.lock().unwrap()[index] = brightness as u32;
+                        }
+                        WasmEvent::SetRgb { lux, .. } => {
+                            self.brightness.lock().unwrap()[index] = lux;
+                        }
+                        WasmEvent::SetRgbRange { first_id, count, lux, .. } => {
+                            let end = first_id as usize + count as usize;
+                            if leds.len() < end {
+                                leds.resize(end, 0);
+                            }
+                            leds[first_id as usize..end].fill(lux as u16);
+                            let brightness = leds.iter().copied().max().unwrap_or(0);
+                            self.brightness.lock().unwrap()[index] = brightness as u32;
+                        }
+                    }
+                }
+                _ = advertisement_interval.tick() => {
+                    let mut data = [0u8; 32];
+                    let data_length = std::cmp::min(32, advertisment_data.len());
+                    data[0..data_length].copy_from_slice(&advertisment_data[0..data_length]);
+                    let address = node.address;
+                    let advertisement = Advertisement {
+                        company: 0u16,
+                        address: [
+                            address[0], address[1], address[2], address[3], address[4],
+                            address[5], 0, 0,
+                        ],
+                        data,
+                        data_length: data_length as u8,
+                        received_at: 0,
+                        rssi: UNKNOWN_RSSI,
+                    };
+                    // Sending only fails if no node is listening anymore
+                    let _ = medium.send(Transmission {
+                        sender: index,
+                        advertisement,
+                    });
+                }
+            }
+        }
+
+        log::info!("[{}] Stopped", node.name);
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+//! Decide whether and when an advertisement reaches a node
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::Duration;
+
+/// Properties of the simulated BLE medium
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkSettings {
+    /// Probability that an advertisement is lost, between 0.0 and 1.0
+    pub packet_loss: f64,
+    /// Mean time between sending and receiving an advertisement
+    pub delay: Duration,
+    /// Maximum deviation from the mean delay. The actual delay is uniformly distributed
+    pub jitter: Duration,
+}
+
+/// Routes the advertisements to a single node
+pub struct Router {
+    settings: LinkSettings,
+    rng: StdRng,
+}
+
+impl Router {
+    pub fn new(settings: LinkSettings, seed: u64) -> Self {
+        Self {
+            settings,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Decide the fate of an advertisement
+    ///
+    /// Returns `None` if the advertisement is lost, or the time after which it should be delivered
+    pub fn route(&mut self) -> Option<Duration> {
+        if self.rng.gen_bool(self.settings.packet_loss) {
+            return None;
+        }
+        let jitter = self.settings.jitter.as_micros() as i64;
+        let offset = if jitter == 0 {
+            0
+        } else {
+            self.rng.gen_range(-jitter..=jitter)
+        };
+        return Some(offset_delay(self.settings.delay, offset));
+    }
+}
+
+/// Shift `delay` by `offset` microseconds. An advertisement can not arrive before it was sent, so the result is at least zero
+fn offset_delay(delay: Duration, offset: i64) -> Duration {
+    let delay = (delay.as_micros() as i64).saturating_add(offset).max(0);
+    return Duration::from_micros(delay as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loss_rate_converges_to_the_configured_value() {
+        let mut router = Router::new(
+            LinkSettings {
+                packet_loss: 0.3,
+                ..Default::default()
+            },
+            42,
+        );
+        let packets = 100_000;
+        let lost = (0..packets).filter(|_| router.route().is_none()).count();
+        let loss_rate = lost as f64 / packets as f64;
+        assert!(
+            (loss_rate - 0.3).abs() < 0.01,
+            "loss rate was {}",
+            loss_rate
+        );
+    }
+
+    #[test]
+    fn delay_stays_within_the_jitter() {
+        let mut router = Router::new(
+            LinkSettings {
+                packet_loss: 0.0,
+                delay: Duration::from_millis(20),
+                jitter: Duration::from_millis(5),
+            },
+            42,
+        );
+        for _ in 0..1000 {
+            let delay = router.route().unwrap();
+            assert!(delay >= Duration::from_millis(15));
+            assert!(delay <= Duration::from_millis(25));
+        }
+    }
+
+    #[test]
+    fn delay_is_never_negative() {
+        assert_eq!(
+            offset_delay(Duration::from_millis(1), -10_000),
+            Duration::ZERO
+        );
+        assert_eq!(
+            offset_delay(Duration::from_millis(1), -1_000),
+            Duration::ZERO
+        );
+        assert_eq!(
+            offset_delay(Duration::from_millis(1), -999),
+            Duration::from_micros(1)
+        );
+        assert_eq!(offset_delay(Duration::ZERO, i64::MIN), Duration::ZERO);
+
+        // 9001 of the 20001 possible offsets are at or below -1ms
+        let mut router = Router::new(
+            LinkSettings {
+                packet_loss: 0.0,
+                delay: Duration::from_millis(1),
+                jitter: Duration::from_millis(10),
+            },
+            42,
+        );
+        let clamped = (0..1000)
+            .filter(|_| router.route().unwrap() == Duration::ZERO)
+            .count();
+        assert!(
+            (350..550).contains(&clamped),
+            "{} delays were clamped",
+            clamped
+        );
+    }
+
+    #[test]
+    fn same_seed_gives_the_same_routes() {
+        let settings = LinkSettings {
+            packet_loss: 0.5,
+            delay: Duration::from_millis(20),
+            jitter: Duration::from_millis(5),
+        };
+        let mut first = Router::new(settings, 7);
+        let mut second = Router::new(settings, 7);
+        for _ in 0..100 {
+            assert_eq!(first.route(), second.route());
+        }
+    }
+}
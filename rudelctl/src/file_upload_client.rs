@@ -1,16 +1,21 @@
 //! Connects to our Bluetooth GATT service and exercises the characteristic.
-use crate::GLOBAL_LOGGER;
+use crate::{update_key::sign_image, GLOBAL_LOGGER};
 use async_recursion::async_recursion;
 use bluer::{
-    gatt::remote::{Characteristic, CharacteristicWriteRequest},
+    gatt::remote::{Characteristic, CharacteristicWriteRequest, Service},
     Device, UuidExt,
 };
+use clap::Args;
+use ed25519_dalek::SigningKey;
 use futures::{lock::Mutex, StreamExt};
 use helpers::{
     connect_to_device, find_characteristic, find_service, FindCharacteristicError, FindServiceError,
 };
 use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use log_lines::{parse_level, LogLineBuffer};
 use rand::{distributions::Alphanumeric, Rng};
+use resume::{can_resume, parse_upload_status};
+use start::{start_upload, Backoff};
 use std::{
     fmt::Write,
     ops::Div,
@@ -24,10 +29,13 @@ use tokio::{
     time::sleep,
 };
 use tokio_util::sync::CancellationToken;
-use upload_request::UploadRequest;
+use upload_request::{compress, compressed_upload_hash, file_hash, UploadRequest};
 use uuid::Uuid;
 use zerocopy::IntoBytes;
 mod helpers;
+mod log_lines;
+mod resume;
+mod start;
 mod upload_request;
 
 const FILE_UPLOAD_SERVICE: u16 = 0x9160;
@@ -42,9 +50,49 @@ const FILE_UPLOAD_SERVICE_LAST_ERROR: u16 = 0x9164;
 // Read to get the hash of the current upload.
 const FILE_UPLOAD_SERVICE_CURRENT_HASH: u16 = 0x9166;
 
+// The firmware update service uses the same protocol as the file upload service, but writes the upload to the next OTA partition
+const FIRMWARE_UPDATE_SERVICE: u16 = 0x9170;
+const FIRMWARE_UPDATE_SERVICE_DATA: u16 = 0x9171;
+const FIRMWARE_UPDATE_SERVICE_START_UPDATE: u16 = 0x9172;
+const FIRMWARE_UPDATE_SERVICE_UPDATE_PROGRESS: u16 = 0x9173;
+const FIRMWARE_UPDATE_SERVICE_LAST_ERROR: u16 = 0x9174;
+const FIRMWARE_UPDATE_SERVICE_CURRENT_HASH: u16 = 0x9176;
+
+/// The first byte of every ESP-IDF app image
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+
 const CAT_MANAGEMENT_SERVICE: u16 = 0x7992;
 const CAT_MANAGEMENT_SERVICE_PROGRAM_HASH: u16 = 0x7893;
 const CAT_MANAGEMENT_SERVICE_NAME: u16 = 0x7894;
+// Read or write the configuration that is passed to the WASM guest
+const CAT_MANAGEMENT_SERVICE_WASM_GUEST_CONFIG: u16 = 0x7896;
+// Read the free space on the filesystem of the target
+const CAT_MANAGEMENT_SERVICE_FREE_SPACE: u16 = 0x7897;
+// Read or write the Rudel the target belongs to
+const CAT_MANAGEMENT_SERVICE_RUDEL_ID: u16 = 0x7898;
+// Delete all files on the target
+const CAT_MANAGEMENT_SERVICE_ERASE_FILESYSTEM: u16 = 0x7899;
+/// The target only erases its filesystem if this is written, so it is not erased by accident
+const ERASE_FILESYSTEM_CONFIRMATION: &[u8] = b"erase";
+// Read or write the advertising interval and transmit power of the target
+const CAT_MANAGEMENT_SERVICE_ADVERTISING_SETTINGS: u16 = 0x789A;
+// Read the uptime, heap usage, boot count and last reset reason of the target
+const CAT_MANAGEMENT_SERVICE_HEALTH: u16 = 0x789B;
+
+/// How often to check whether the target started the program after a run
+const RUN_CONFIRMATION_ATTEMPTS: usize = 20;
+/// Delay between two checks whether the target started the program
+const RUN_CONFIRMATION_DELAY: Duration = Duration::from_millis(500);
+
+/// The maximum length of the guest configuration. This is the maximum length of a BLE attribute value.
+pub const MAX_WASM_GUEST_CONFIG_LENGTH: usize = 512;
+/// The shortest name a device accepts
+pub const MIN_NAME_LENGTH: usize = 3;
+/// The longest name a device accepts
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// The firmware advertises the battery level as service data of the standard battery service
+const BATTERY_SERVICE: u16 = 0x180F;
 
 const SERIAL_LOGGING_TIO_SERVICE: Uuid = uuid::uuid!("6E400001-B5A3-F393-E0A9-E50E24DCCA9E");
 const SERIAL_LOGGING_TIO_CHAR_RX: Uuid = uuid::uuid!("6E400002-B5A3-F393-E0A9-E50E24DCCA9E"); // Write no response
@@ -68,29 +116,341 @@ pub enum UpdateTargetError {
     UploadError(bluer::Error),
     #[error("The update target seemingly ignored our upload request")]
     UploadRequestIgnored,
+    #[error("The update target started a different upload. Maybe someone else is uploading to it")]
+    CompetingUpload,
     #[error("We lost connection to the target device and failed to reconnect")]
     ReconnectFailed,
     #[error("The upload status did not contain the current progress")]
     FailedToParseUploadStatus,
+    #[error("The config is {0} bytes long, but can be at most {max} bytes", max = MAX_WASM_GUEST_CONFIG_LENGTH)]
+    ConfigTooLong(usize),
+    #[error("The name is {0} bytes long, but must be between {min} and {max} bytes", min = MIN_NAME_LENGTH, max = MAX_NAME_LENGTH)]
+    InvalidNameLength(usize),
+    #[error("The target does not support firmware updates over BLE")]
+    FirmwareUpdateNotSupported,
+    #[error("The file does not look like an ESP-IDF app image")]
+    InvalidFirmwareImage,
+    #[error("The target did not start the program. Maybe it failed to load")]
+    ProgramNotStarted,
+    #[error("The target firmware is too old to support Rudels")]
+    RudelNotSupported,
+    #[error("The target firmware is too old to erase its filesystem over BLE")]
+    EraseFilesystemNotSupported,
+    #[error("The target firmware is too old to configure its advertising")]
+    AdvertisingSettingsNotSupported,
+    #[error("The target reported advertising settings that could not be parsed")]
+    FailedToParseAdvertisingSettings,
+    #[error(transparent)]
+    InvalidAdvertisingSettings(#[from] InvalidAdvertisingSettings),
 }
 
-pub struct FileUploadClient {
-    data_characteristic: Characteristic,
-    start_upload_characteristic: Characteristic,
-    missing_chunks_characteristic: Characteristic,
+/// Shortest advertising interval allowed by the BLE spec in units of 0.625 ms (20 ms)
+pub const MIN_ADVERTISING_INTERVAL: u16 = 0x0020;
+/// Longest advertising interval allowed by the BLE spec in units of 0.625 ms (10.24 s)
+pub const MAX_ADVERTISING_INTERVAL: u16 = 0x4000;
+/// Transmit powers in dBm that the target supports
+pub const SUPPORTED_TX_POWERS: [i8; 8] = [-12, -9, -6, -3, 0, 3, 6, 9];
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidAdvertisingSettings {
+    #[error("The advertising interval needs to be between 20 ms and 10240 ms")]
+    IntervalOutOfRange,
+    #[error("The minimum advertising interval is longer than the maximum interval")]
+    MinIntervalAboveMaxInterval,
+    #[error("The transmit power needs to be one of {:?} dBm", SUPPORTED_TX_POWERS)]
+    UnsupportedTxPower,
+}
+
+/// How often and how loud a target advertises
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AdvertisingSettings {
+    /// Minimum advertising interval in units of 0.625 ms
+    pub min_interval: u16,
+    /// Maximum advertising interval in units of 0.625 ms
+    pub max_interval: u16,
+    /// Transmit power in dBm
+    pub tx_power: i8,
+}
+
+impl AdvertisingSettings {
+    /// Check that the target accepts the settings
+    pub fn validate(&self) -> Result<(), InvalidAdvertisingSettings> {
+        if self.min_interval < MIN_ADVERTISING_INTERVAL
+            || self.max_interval > MAX_ADVERTISING_INTERVAL
+        {
+            return Err(InvalidAdvertisingSettings::IntervalOutOfRange);
+        }
+        if self.min_interval > self.max_interval {
+            return Err(InvalidAdvertisingSettings::MinIntervalAboveMaxInterval);
+        }
+        if !SUPPORTED_TX_POWERS.contains(&self.tx_power) {
+            return Err(InvalidAdvertisingSettings::UnsupportedTxPower);
+        }
+        return Ok(());
+    }
+
+    /// Convert milliseconds to an advertising interval, rounding down
+    pub fn interval_from_millis(millis: u32) -> u16 {
+        return (millis.saturating_mul(8) / 5).min(u16::MAX as u32) as u16;
+    }
+
+    /// Convert an advertising interval to milliseconds
+    pub fn interval_to_millis(interval: u16) -> f32 {
+        return interval as f32 * 0.625;
+    }
+
+    /// Parse the settings as reported by the target
+    ///
+    /// The layout is the minimum and maximum interval as little endian `u16` followed by the transmit power as `i8`.
+    fn decode(encoded: &[u8]) -> Option<Self> {
+        let encoded: &[u8; 5] = encoded.try_into().ok()?;
+        return Some(AdvertisingSettings {
+            min_interval: u16::from_le_bytes([encoded[0], encoded[1]]),
+            max_interval: u16::from_le_bytes([encoded[2], encoded[3]]),
+            tx_power: encoded[4] as i8,
+        });
+    }
+
+    /// Encode the settings in the layout read by [AdvertisingSettings::decode]
+    fn encode(&self) -> [u8; 5] {
+        let min_interval = self.min_interval.to_le_bytes();
+        let max_interval = self.max_interval.to_le_bytes();
+        return [
+            min_interval[0],
+            min_interval[1],
+            max_interval[0],
+            max_interval[1],
+            self.tx_power as u8,
+        ];
+    }
+}
+
+/// What a target reports about itself
+#[derive(Debug, Clone)]
+pub struct TargetStatus {
+    /// The configured name of the target
+    pub name: String,
+    /// Hash of the running program. `None` if the target runs its built-in default program
+    pub running_program: Option<[u8; 32]>,
+    /// Free space on the filesystem in bytes. `None` if the target firmware is too old to report it
+    pub free_space: Option<u32>,
+    /// `None` if the target firmware is too old to report its health
+    pub health: Option<Health>,
+}
+
+/// Result of comparing a local program with the one running on a target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramVerification {
+    /// Hash of the local program
+    pub expected: [u8; 32],
+    /// Hash of the running program. `None` if the target runs its built-in default program
+    pub running: Option<[u8; 32]>,
+}
+
+impl ProgramVerification {
+    /// Whether the target runs the local program
+    pub fn passed(&self) -> bool {
+        return self.running == Some(self.expected);
+    }
+}
+
+/// Uptime, heap usage and reboots of a target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// Time since the target booted
+    pub uptime: Duration,
+    /// Free heap in bytes
+    pub free_heap: u32,
+    /// Largest block that can be allocated on the heap in bytes
+    pub largest_free_block: u32,
+    /// How often the target booted
+    pub boot_count: u32,
+    /// Why the target was reset the last time, as ESP-IDF `esp_reset_reason_t`
+    pub reset_reason: u8,
+}
+
+impl Health {
+    /// Parse the health report of the target
+    ///
+    /// The layout is the uptime in microseconds as `u64`, the free heap, the largest free heap block and the boot count as `u32` and the reset reason as `u8`. All numbers are little endian.
+    fn decode(encoded: &[u8]) -> Option<Self> {
+        let encoded: &[u8; 21] = encoded.get(0..21)?.try_into().ok()?;
+        let u32_at =
+            |index: usize| u32::from_le_bytes(encoded[index..index + 4].try_into().unwrap());
+        return Some(Health {
+            uptime: Duration::from_micros(u64::from_le_bytes(encoded[0..8].try_into().unwrap())),
+            free_heap: u32_at(8),
+            largest_free_block: u32_at(12),
+            boot_count: u32_at(16),
+            reset_reason: encoded[20],
+        });
+    }
+
+    /// A short description of the reason of the last reset
+    pub fn reset_reason_name(&self) -> &'static str {
+        return match self.reset_reason {
+            1 => "power on",
+            2 => "external pin",
+            3 => "software",
+            4 => "panic",
+            5 => "interrupt watchdog",
+            6 => "task watchdog",
+            7 => "other watchdog",
+            8 => "deep sleep",
+            9 => "brownout",
+            10 => "sdio",
+            11 => "usb",
+            12 => "jtag",
+            13 => "efuse error",
+            14 => "power glitch",
+            15 => "cpu lockup",
+            _ => "unknown",
+        };
+    }
+}
+
+/// Tuning parameters for uploads
+///
+/// The defaults work well for most adapters. Flaky BLE stacks may need more conservative settings.
+#[derive(Args, Debug, Clone)]
+pub struct UploadSettings {
+    /// Number of chunks sent before the first progress check. The number adapts during the upload; higher values speed up the start of an upload but may cause timeouts on slow adapters
+    #[arg(long, default_value = "2")]
+    pub initial_chunks: usize,
+
+    /// How often to reconnect after the connection was lost before giving up. Higher values make uploads more persistent but failures take longer to report
+    #[arg(long, default_value = "10")]
+    pub max_reconnects: usize,
+
+    /// Number of bytes subtracted from the MTU to get the chunk size. Lower values send more data per chunk, but some adapters drop chunks that are too close to the MTU
+    #[arg(long, default_value = "28")]
+    pub mtu_overhead: u16,
+
+    /// Milliseconds to wait after reconnecting before continuing the upload. Some adapters need longer until the connection is usable
+    #[arg(long, default_value = "2000")]
+    pub reconnect_delay_ms: u64,
+
+    /// Milliseconds to wait before retrying a failed transfer with fewer chunks
+    #[arg(long, default_value = "3000")]
+    pub retry_delay_ms: u64,
+
+    /// How often to resend the upload request if the target does not start the upload
+    #[arg(long, default_value = "10")]
+    pub start_retries: usize,
+
+    /// Milliseconds to wait before resending the upload request. The delay doubles with every retry and gets some random jitter, so multiple uploaders do not keep colliding
+    #[arg(long, default_value = "500")]
+    pub start_retry_delay_ms: u64,
+
+    /// Compress files before uploading them. Needs a firmware that supports compressed uploads. Files that do not get smaller and firmware updates are sent uncompressed
+    #[arg(long)]
+    pub compress: bool,
+}
+
+impl Default for UploadSettings {
+    fn default() -> Self {
+        Self {
+            initial_chunks: 2,
+            max_reconnects: 10,
+            mtu_overhead: 28,
+            reconnect_delay_ms: 2000,
+            retry_delay_ms: 3000,
+            start_retries: 10,
+            start_retry_delay_ms: 500,
+            compress: false,
+        }
+    }
+}
+
+/// Why [FileUploadClient::attach_logger] returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStreamEnd {
+    /// The device stopped sending logs while still being connected
+    Closed,
+    /// The connection to the device was lost
+    Disconnected,
+    /// The device did not send any logs for the idle timeout
+    Idle,
+}
+
+/// Input that gets forwarded to the device by [FileUploadClient::attach_logger]
+pub type LogInput = Mutex<tokio::sync::mpsc::Receiver<Vec<u8>>>;
+
+/// Read stdin in the background
+///
+/// The reading is independent of a connection, so no input is lost while reconnecting.
+pub fn read_stdin() -> LogInput {
+    let (sender, receiver) = tokio::sync::mpsc::channel(20);
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 200];
+        while let Ok(length) = stdin().read(&mut buffer).await {
+            if length == 0 {
+                break;
+            }
+            if sender.send(buffer[0..length].to_vec()).await.is_err() {
+                break;
+            }
+        }
+    });
+    Mutex::new(receiver)
+}
+
+/// The characteristics of a service that accepts uploads
+///
+/// Both the file upload service and the firmware update service use this layout.
+pub struct UploadCharacteristics {
+    data: Characteristic,
+    start_upload: Characteristic,
+    missing_chunks: Characteristic,
     // TODO: Use this
     #[allow(dead_code)]
-    last_error_characteristic: Characteristic,
-    current_hash_characteristic: Characteristic,
+    last_error: Characteristic,
+    current_hash: Characteristic,
+}
+
+impl UploadCharacteristics {
+    async fn from_service(
+        service: &Service,
+        data: u16,
+        start_upload: u16,
+        missing_chunks: u16,
+        last_error: u16,
+        current_hash: u16,
+    ) -> Result<Self, UpdateTargetError> {
+        return Ok(UploadCharacteristics {
+            data: find_characteristic(service, uuid::Uuid::from_u16(data)).await?,
+            start_upload: find_characteristic(service, uuid::Uuid::from_u16(start_upload)).await?,
+            missing_chunks: find_characteristic(service, uuid::Uuid::from_u16(missing_chunks))
+                .await?,
+            last_error: find_characteristic(service, uuid::Uuid::from_u16(last_error)).await?,
+            current_hash: find_characteristic(service, uuid::Uuid::from_u16(current_hash)).await?,
+        });
+    }
+}
+
+pub struct FileUploadClient {
+    file_upload: UploadCharacteristics,
+    /// `None` if the target firmware is too old to update itself
+    firmware_update: Option<UploadCharacteristics>,
 
     log_tx_characteristic: Characteristic,
     log_rx_characteristic: Characteristic,
 
     program_hash_characteristic: Characteristic,
-    // TODO: Use this
-    #[allow(dead_code)]
     name_characteristic: Characteristic,
+    wasm_guest_config_characteristic: Characteristic,
+    /// `None` if the target firmware is too old to report its free space
+    free_space_characteristic: Option<Characteristic>,
+    /// `None` if the target firmware is too old to support Rudels
+    rudel_id_characteristic: Option<Characteristic>,
+    /// `None` if the target firmware is too old to erase its filesystem
+    erase_filesystem_characteristic: Option<Characteristic>,
+    /// `None` if the target firmware is too old to configure its advertising
+    advertising_settings_characteristic: Option<Characteristic>,
+    /// `None` if the target firmware is too old to report its health
+    health_characteristic: Option<Characteristic>,
     device: Device,
+    upload_settings: UploadSettings,
 }
 
 impl FileUploadClient {
@@ -107,6 +467,15 @@ impl FileUploadClient {
         let rssi = device.rssi().await?;
         return Ok((name, rssi));
     }
+
+    /// Get the battery level in percent from the advertisement of a device
+    ///
+    /// Older firmware versions and devices without space left in their advertisement do not advertise their battery level
+    pub async fn advertised_battery_level(device: &Device) -> Option<u8> {
+        let service_data = device.service_data().await.ok().flatten()?;
+        let level = service_data.get(&uuid::Uuid::from_u16(BATTERY_SERVICE))?;
+        return level.first().copied();
+    }
     pub async fn new_from_peripheral(
         device: &Device,
     ) -> Result<FileUploadClient, UpdateTargetError> {
@@ -122,32 +491,33 @@ impl FileUploadClient {
         let update_service =
             find_service(&device, uuid::Uuid::from_u16(FILE_UPLOAD_SERVICE)).await?;
 
-        let data_characteristic = find_characteristic(
-            &update_service,
-            uuid::Uuid::from_u16(FILE_UPLOAD_SERVICE_DATA),
-        )
-        .await?;
-        let start_upload_characteristic = find_characteristic(
-            &update_service,
-            uuid::Uuid::from_u16(FILE_UPLOAD_SERVICE_START_UPLOAD),
-        )
-        .await?;
-        let missing_chunks_characteristic = find_characteristic(
-            &update_service,
-            uuid::Uuid::from_u16(FILE_UPLOAD_SERVICE_UPLOAD_PROGRESS),
-        )
-        .await?;
-        let last_error_characteristic = find_characteristic(
+        let file_upload = UploadCharacteristics::from_service(
             &update_service,
-            uuid::Uuid::from_u16(FILE_UPLOAD_SERVICE_LAST_ERROR),
-        )
-        .await?;
-        let current_hash_characteristic = find_characteristic(
-            &update_service,
-            uuid::Uuid::from_u16(FILE_UPLOAD_SERVICE_CURRENT_HASH),
+            FILE_UPLOAD_SERVICE_DATA,
+            FILE_UPLOAD_SERVICE_START_UPLOAD,
+            FILE_UPLOAD_SERVICE_UPLOAD_PROGRESS,
+            FILE_UPLOAD_SERVICE_LAST_ERROR,
+            FILE_UPLOAD_SERVICE_CURRENT_HASH,
         )
         .await?;
 
+        let firmware_update =
+            match find_service(&device, uuid::Uuid::from_u16(FIRMWARE_UPDATE_SERVICE)).await {
+                Ok(firmware_update_service) => Some(
+                    UploadCharacteristics::from_service(
+                        &firmware_update_service,
+                        FIRMWARE_UPDATE_SERVICE_DATA,
+                        FIRMWARE_UPDATE_SERVICE_START_UPDATE,
+                        FIRMWARE_UPDATE_SERVICE_UPDATE_PROGRESS,
+                        FIRMWARE_UPDATE_SERVICE_LAST_ERROR,
+                        FIRMWARE_UPDATE_SERVICE_CURRENT_HASH,
+                    )
+                    .await?,
+                ),
+                Err(FindServiceError::NoUpdateService) => None,
+                Err(error) => return Err(error.into()),
+            };
+
         let cat_management_service =
             find_service(&device, uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE)).await?;
 
@@ -161,6 +531,61 @@ impl FileUploadClient {
             uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_PROGRAM_HASH),
         )
         .await?;
+        let wasm_guest_config_characteristic = find_characteristic(
+            &cat_management_service,
+            uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_WASM_GUEST_CONFIG),
+        )
+        .await?;
+        let free_space_characteristic = match find_characteristic(
+            &cat_management_service,
+            uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_FREE_SPACE),
+        )
+        .await
+        {
+            Ok(characteristic) => Some(characteristic),
+            Err(FindCharacteristicError::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+        let rudel_id_characteristic = match find_characteristic(
+            &cat_management_service,
+            uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_RUDEL_ID),
+        )
+        .await
+        {
+            Ok(characteristic) => Some(characteristic),
+            Err(FindCharacteristicError::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+        let erase_filesystem_characteristic = match find_characteristic(
+            &cat_management_service,
+            uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_ERASE_FILESYSTEM),
+        )
+        .await
+        {
+            Ok(characteristic) => Some(characteristic),
+            Err(FindCharacteristicError::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+        let advertising_settings_characteristic = match find_characteristic(
+            &cat_management_service,
+            uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_ADVERTISING_SETTINGS),
+        )
+        .await
+        {
+            Ok(characteristic) => Some(characteristic),
+            Err(FindCharacteristicError::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
+        let health_characteristic = match find_characteristic(
+            &cat_management_service,
+            uuid::Uuid::from_u16(CAT_MANAGEMENT_SERVICE_HEALTH),
+        )
+        .await
+        {
+            Ok(characteristic) => Some(characteristic),
+            Err(FindCharacteristicError::NotFound) => None,
+            Err(error) => return Err(error.into()),
+        };
 
         let logging_service = find_service(&device, SERIAL_LOGGING_TIO_SERVICE).await?;
         let log_tx_characteristic =
@@ -171,26 +596,36 @@ impl FileUploadClient {
         log::debug!("{:.04} Serviced", start.elapsed().as_secs_f64());
 
         return Ok(FileUploadClient {
-            data_characteristic,
-            start_upload_characteristic,
-            missing_chunks_characteristic,
-            last_error_characteristic,
+            file_upload,
+            firmware_update,
             name_characteristic,
             program_hash_characteristic,
-            current_hash_characteristic,
+            wasm_guest_config_characteristic,
+            free_space_characteristic,
+            rudel_id_characteristic,
+            erase_filesystem_characteristic,
+            advertising_settings_characteristic,
+            health_characteristic,
             log_tx_characteristic,
             log_rx_characteristic,
             device: device.clone(),
+            upload_settings: UploadSettings::default(),
         });
     }
 
-    pub async fn run_program(&self, data: &[u8]) -> Result<(), UpdateTargetError> {
+    /// Use different upload settings for this client
+    pub fn with_upload_settings(mut self, upload_settings: UploadSettings) -> Self {
+        self.upload_settings = upload_settings;
+        self
+    }
+
+    pub async fn run_program(&self, data: &[u8], force: bool) -> Result<(), UpdateTargetError> {
         let file_name: Vec<u8> = rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(10)
             .collect();
         let file_name = String::from_utf8(file_name).unwrap();
-        let program_hash = self.upload_file(data, file_name).await?;
+        let program_hash = self.upload_file(data, file_name, force).await?;
         log::debug!("Uploaded file.");
         self.program_hash_characteristic
             .write_ext(
@@ -204,22 +639,278 @@ impl FileUploadClient {
             )
             .await?;
         log::debug!("Wrote program hash.");
+
+        // The target reports the running program, so we can wait until it restarted with the new one
+        for _ in 0..RUN_CONFIRMATION_ATTEMPTS {
+            if self.read_running_program().await? == Some(program_hash) {
+                log::debug!("Target is running the new program.");
+                return Ok(());
+            }
+            sleep(RUN_CONFIRMATION_DELAY).await;
+        }
+        return Err(UpdateTargetError::ProgramNotStarted);
+    }
+
+    /// Read the hash of the program that is running on the target
+    ///
+    /// Returns `None` if the target runs its built-in default program. Older firmware versions report the program that was set last instead.
+    pub async fn read_running_program(&self) -> Result<Option<[u8; 32]>, UpdateTargetError> {
+        let hash = self.program_hash_characteristic.read().await?;
+        let Ok(hash): Result<[u8; 32], _> = hash.try_into() else {
+            return Ok(None);
+        };
+        if hash == [0u8; 32] {
+            return Ok(None);
+        }
+        return Ok(Some(hash));
+    }
+
+    /// Check whether the target runs the program `data`
+    ///
+    /// The target checks the hash of every uploaded file, so a matching hash means that the program arrived intact. The firmware can not send files back, so the content itself is not compared.
+    pub async fn verify_program(
+        &self,
+        data: &[u8],
+    ) -> Result<ProgramVerification, UpdateTargetError> {
+        return Ok(ProgramVerification {
+            expected: file_hash(data),
+            running: self.read_running_program().await?,
+        });
+    }
+
+    /// Read the name, the running program, the free space and the health of the target
+    pub async fn get_status(&self) -> Result<TargetStatus, UpdateTargetError> {
+        let name = String::from_utf8_lossy(&self.name_characteristic.read().await?).into_owned();
+        let running_program = self.read_running_program().await?;
+        let free_space = match &self.free_space_characteristic {
+            Some(characteristic) => {
+                let bytes = characteristic.read().await?;
+                bytes
+                    .get(0..4)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            None => None,
+        };
+        let health = match &self.health_characteristic {
+            Some(characteristic) => Health::decode(&characteristic.read().await?),
+            None => None,
+        };
+        return Ok(TargetStatus {
+            name,
+            running_program,
+            free_space,
+            health,
+        });
+    }
+
+    /// Read the configuration that is passed to the WASM guest
+    pub async fn get_config(&self) -> Result<Vec<u8>, UpdateTargetError> {
+        return Ok(self.wasm_guest_config_characteristic.read().await?);
+    }
+
+    /// Replace the configuration that is passed to the WASM guest
+    pub async fn set_config(&self, config: &[u8]) -> Result<(), UpdateTargetError> {
+        if config.len() > MAX_WASM_GUEST_CONFIG_LENGTH {
+            return Err(UpdateTargetError::ConfigTooLong(config.len()));
+        }
+        self.wasm_guest_config_characteristic
+            .write_ext(
+                config,
+                &CharacteristicWriteRequest {
+                    offset: 0,
+                    op_type: bluer::gatt::WriteOp::Reliable,
+                    prepare_authorize: false,
+                    _non_exhaustive: (),
+                },
+            )
+            .await?;
         return Ok(());
     }
 
-    #[async_recursion(?Send)]
+    /// Give the target a new name
+    ///
+    /// The name is stored on the target and survives reboots.
+    pub async fn set_name(&self, name: &str) -> Result<(), UpdateTargetError> {
+        if name.len() < MIN_NAME_LENGTH || name.len() > MAX_NAME_LENGTH {
+            return Err(UpdateTargetError::InvalidNameLength(name.len()));
+        }
+        self.name_characteristic
+            .write_ext(
+                name.as_bytes(),
+                &CharacteristicWriteRequest {
+                    offset: 0,
+                    op_type: bluer::gatt::WriteOp::Reliable,
+                    prepare_authorize: false,
+                    _non_exhaustive: (),
+                },
+            )
+            .await?;
+        return Ok(());
+    }
+
+    /// Assign the target to a Rudel or remove it from its Rudel
+    ///
+    /// The Rudel id is stored on the target and survives reboots.
+    pub async fn set_rudel(&self, id: Option<u16>) -> Result<(), UpdateTargetError> {
+        let Some(rudel_id_characteristic) = &self.rudel_id_characteristic else {
+            return Err(UpdateTargetError::RudelNotSupported);
+        };
+        let value = id.map(u16::to_le_bytes);
+        rudel_id_characteristic
+            .write_ext(
+                value.as_ref().map_or(&[][..], |value| &value[..]),
+                &CharacteristicWriteRequest {
+                    offset: 0,
+                    op_type: bluer::gatt::WriteOp::Reliable,
+                    prepare_authorize: false,
+                    _non_exhaustive: (),
+                },
+            )
+            .await?;
+        return Ok(());
+    }
+
+    /// Delete all files on the target
+    ///
+    /// The running program is deleted once it is stopped.
+    pub async fn erase_filesystem(&self) -> Result<(), UpdateTargetError> {
+        let Some(erase_filesystem_characteristic) = &self.erase_filesystem_characteristic else {
+            return Err(UpdateTargetError::EraseFilesystemNotSupported);
+        };
+        erase_filesystem_characteristic
+            .write_ext(
+                ERASE_FILESYSTEM_CONFIRMATION,
+                &CharacteristicWriteRequest {
+                    offset: 0,
+                    op_type: bluer::gatt::WriteOp::Reliable,
+                    prepare_authorize: false,
+                    _non_exhaustive: (),
+                },
+            )
+            .await?;
+        return Ok(());
+    }
+
+    /// Read how often and how loud the target advertises
+    pub async fn get_advertising_settings(&self) -> Result<AdvertisingSettings, UpdateTargetError> {
+        let Some(advertising_settings_characteristic) = &self.advertising_settings_characteristic
+        else {
+            return Err(UpdateTargetError::AdvertisingSettingsNotSupported);
+        };
+        let encoded = advertising_settings_characteristic.read().await?;
+        return AdvertisingSettings::decode(&encoded)
+            .ok_or(UpdateTargetError::FailedToParseAdvertisingSettings);
+    }
+
+    /// Change how often and how loud the target advertises. `None` restores the defaults of the target
+    ///
+    /// The settings are stored on the target, survive reboots and are applied immediately.
+    pub async fn set_advertising_settings(
+        &self,
+        settings: Option<AdvertisingSettings>,
+    ) -> Result<(), UpdateTargetError> {
+        let Some(advertising_settings_characteristic) = &self.advertising_settings_characteristic
+        else {
+            return Err(UpdateTargetError::AdvertisingSettingsNotSupported);
+        };
+        if let Some(settings) = &settings {
+            settings.validate()?;
+        }
+        let value = settings.map(|settings| settings.encode());
+        advertising_settings_characteristic
+            .write_ext(
+                value.as_ref().map_or(&[][..], |value| &value[..]),
+                &CharacteristicWriteRequest {
+                    offset: 0,
+                    op_type: bluer::gatt::WriteOp::Reliable,
+                    prepare_authorize: false,
+                    _non_exhaustive: (),
+                },
+            )
+            .await?;
+        return Ok(());
+    }
+
+    /// Upload a file to the target and return its hash
+    ///
+    /// If the target is still receiving the same file from an earlier, interrupted upload, only the missing chunks are sent. Set `force` to always start a new upload.
     pub async fn upload_file(
         &self,
         data: &[u8],
         file_name: String,
+        force: bool,
+    ) -> Result<[u8; 32], UpdateTargetError> {
+        return self
+            .upload_to(
+                &self.file_upload,
+                data,
+                file_name,
+                force,
+                self.upload_settings.compress,
+            )
+            .await;
+    }
+
+    /// Replace the firmware of the target with the given ESP-IDF app image
+    ///
+    /// The image is signed with `signing_key`. The target verifies the image and the signature and reboots into it after the upload. If the new firmware fails to boot, the bootloader rolls back to the old one.
+    pub async fn update_firmware(
+        &self,
+        image: &[u8],
+        signing_key: &SigningKey,
+        force: bool,
+    ) -> Result<(), UpdateTargetError> {
+        let Some(firmware_update) = &self.firmware_update else {
+            return Err(UpdateTargetError::FirmwareUpdateNotSupported);
+        };
+        if image.first() != Some(&ESP_IMAGE_MAGIC) {
+            return Err(UpdateTargetError::InvalidFirmwareImage);
+        }
+        let update = sign_image(image, signing_key);
+        self.upload_to(firmware_update, &update, "firmware".into(), force, false)
+            .await?;
+        log::info!("Firmware uploaded. The target will now reboot into the new firmware.");
+        return Ok(());
+    }
+
+    /// Upload data to one of the upload services of the target and return its hash
+    ///
+    /// Checksum files are always uploaded to the file upload service.
+    #[async_recursion(?Send)]
+    async fn upload_to(
+        &self,
+        target: &UploadCharacteristics,
+        data: &[u8],
+        file_name: String,
+        force: bool,
+        compress_data: bool,
     ) -> Result<[u8; 32], UpdateTargetError> {
         log::debug!("Preparing data for upload...");
+        let hash = file_hash(data);
+
+        let compressed = if compress_data {
+            let compressed = compress(data);
+            log::info!(
+                "Compressed {:.2}kB to {:.2}kB ({:.0}%)",
+                data.len() as f32 / 1024.0,
+                compressed.len() as f32 / 1024.0,
+                compressed.len() as f32 / data.len().max(1) as f32 * 100.0
+            );
+            (compressed.len() < data.len()).then_some(compressed)
+        } else {
+            None
+        };
+        // The data that is actually sent
+        let payload = compressed.as_deref().unwrap_or(data);
 
         // -2 for the length
-        // -28 was found to be good by empirical methods
-        let chunk_size: u16 = (self.data_characteristic.mtu().await? as u16) - 28 - 2;
+        // The default overhead of 28 was found to be good by empirical methods
+        let chunk_size: u16 = (target.data.mtu().await? as u16)
+            .saturating_sub(self.upload_settings.mtu_overhead)
+            .saturating_sub(2)
+            .max(1);
         log::debug!("Using a chunk size of {}", chunk_size);
-        let chunks: Vec<Vec<u8>> = data
+        let chunks: Vec<Vec<u8>> = payload
             .chunks(chunk_size as usize)
             .enumerate()
             .map(|(index, data)| {
@@ -230,54 +921,61 @@ impl FileUploadClient {
             })
             .collect();
 
+        if !force {
+            let upload_hash = match compressed {
+                Some(_) => compressed_upload_hash(&hash),
+                None => hash,
+            };
+            // The chunk size is derived from the MTU, so it matches the interrupted upload as long as the connection parameters did not change
+            if can_resume(target, &upload_hash).await? {
+                log::info!("Target is already receiving this file. Resuming upload...");
+                self.upload_chunks(target, chunks).await?;
+                log::debug!("Uploaded file {:?}", hash);
+                return Ok(hash);
+            }
+        }
+
         // TODO: Fix the name story on both sides.
         // file_name[0..9].copy_from_slice(&"test.wasm".as_bytes());
 
-        let upload_request = UploadRequest::new(&file_name, data, chunk_size, async |data| {
-            self.upload_file(data, "checksums.temp".into()).await
-        })
-        .await?;
+        let upload_checksums =
+            async |data: &[u8]| self.upload_file(data, "checksums.temp".into(), force).await;
+        let upload_request = match &compressed {
+            Some(compressed) => {
+                UploadRequest::new_compressed(
+                    &file_name,
+                    data,
+                    compressed,
+                    chunk_size,
+                    upload_checksums,
+                )
+                .await?
+            }
+            None => UploadRequest::new(&file_name, data, chunk_size, upload_checksums).await?,
+        };
 
-        self.start_upload(&upload_request).await?;
-        self.upload_chunks(chunks).await?;
+        log::debug!("Sending file information...");
+        let backoff = Backoff {
+            retries: self.upload_settings.start_retries,
+            base_delay: Duration::from_millis(self.upload_settings.start_retry_delay_ms),
+        };
+        start_upload(
+            target,
+            upload_request.as_bytes(),
+            &upload_request.upload_hash(),
+            &backoff,
+        )
+        .await?;
+        self.upload_chunks(target, chunks).await?;
         log::debug!("Uploaded file {:?}", upload_request.hash);
         return Ok(upload_request.hash);
     }
 
-    async fn start_upload(&self, upload_request: &UploadRequest) -> Result<(), UpdateTargetError> {
-        let upload_request_bytes = upload_request.as_bytes();
-        log::debug!("Sending file information...");
-
-        self.start_upload_characteristic
-            .write(&upload_request_bytes)
-            .await?;
-
-        const MAX_RETRIES: usize = 10;
-        let mut retries_left = MAX_RETRIES;
-        loop {
-            let current_target_hash = self.current_hash_characteristic.read().await?;
-            if current_target_hash == upload_request.hash {
-                break;
-            }
-
-            if retries_left == 0 {
-                return Err(UpdateTargetError::UploadRequestIgnored);
-            }
-            log::debug!(
-                "Target did not process our upload request. Retry {}/{}...",
-                MAX_RETRIES - retries_left,
-                MAX_RETRIES
-            );
-            retries_left -= 1;
-            self.start_upload_characteristic
-                .write(&upload_request_bytes)
-                .await?;
-            sleep(Duration::from_secs(1)).await;
-        }
-        Ok(())
-    }
-
-    async fn upload_chunks(&self, chunks: Vec<Vec<u8>>) -> Result<(), UpdateTargetError> {
+    async fn upload_chunks(
+        &self,
+        target: &UploadCharacteristics,
+        chunks: Vec<Vec<u8>>,
+    ) -> Result<(), UpdateTargetError> {
         // Chunk size without the index
         let chunk_size = chunks.first().map_or(0, |chunk| chunk.len() - 2);
         // Total size without the indexes
@@ -294,13 +992,13 @@ impl FileUploadClient {
 
         // The number of chunks we send between checking for missing chunks
         // The read after the write will wait until this number of chunks is written. If we send too many chunks at once, we get timeouts
-        let mut simultaneous_chunks = 2usize;
+        let mut simultaneous_chunks = std::cmp::max(1, self.upload_settings.initial_chunks);
         // The smallest number of chunks, where we had a bad transfer
         let mut min_bad_chunks = 1000usize;
         // let mut max_good_chunks = 1;
         // How many times we will reconnect to the device
-        let total_reconnects = 10usize;
-        let mut reconnects_left = 10usize;
+        let total_reconnects = self.upload_settings.max_reconnects;
+        let mut reconnects_left = total_reconnects;
         let mut estimated_speed = Duration::from_secs(1);
         let mut measurement_valid = false;
         let mut last_transfer_start = std::time::Instant::now();
@@ -308,7 +1006,7 @@ impl FileUploadClient {
         let mut cancel_auto_increment = CancellationToken::new();
         loop {
             // Reading a property will wait until the writes are done
-            let upload_status = match self.missing_chunks_characteristic.read().await {
+            let upload_status = match target.missing_chunks.read().await {
                 Ok(upload_status) => upload_status,
                 Err(error) => {
                     measurement_valid = false;
@@ -341,7 +1039,10 @@ impl FileUploadClient {
                         ));
                         drop(progress_bar);
                         let _ = self.device.connect().await;
-                        sleep(Duration::from_secs(2)).await;
+                        sleep(Duration::from_millis(
+                            self.upload_settings.reconnect_delay_ms,
+                        ))
+                        .await;
                         reconnects_left -= 1;
                         continue;
                     }
@@ -364,7 +1065,7 @@ impl FileUploadClient {
                     }
                     drop(progress_bar);
 
-                    sleep(Duration::from_secs(3)).await;
+                    sleep(Duration::from_millis(self.upload_settings.retry_delay_ms)).await;
 
                     simultaneous_chunks = new_simultaneous_chunks;
                     continue;
@@ -384,11 +1085,7 @@ impl FileUploadClient {
                 );
             }
 
-            let upload_status = upload_status
-                .into_iter()
-                .array_chunks::<2>()
-                .map(|chunk_id_bytes| u16::from_le_bytes(chunk_id_bytes))
-                .collect::<Vec<u16>>();
+            let upload_status = parse_upload_status(upload_status);
             if upload_status.len() <= 1 {
                 break;
             }
@@ -434,7 +1131,7 @@ impl FileUploadClient {
             measurement_valid = true;
 
             // Upload at most 10 chunks at a time, because we may get timeouts otherwise
-            let mut write_io = self.data_characteristic.write_io().await?;
+            let mut write_io = target.data.write_io().await?;
             for chunk_id in missing_chunks.iter().take(number_of_chunks) {
                 write_io.send(&chunks[*chunk_id as usize]).await.unwrap();
             }
@@ -448,35 +1145,57 @@ impl FileUploadClient {
         Ok(())
     }
 
-    pub async fn attach_logger(&self) -> Result<(), UpdateTargetError> {
+    /// Print the logs of the target and forward the input from [read_stdin] to it
+    ///
+    /// Lines with a level that is more verbose than `max_level` are not printed. Lines without a recognizable level are always printed.
+    ///
+    /// Returns once the log stream ends or no logs were received for `idle_timeout`. The input is only borrowed, so it can be reused for the next connection.
+    pub async fn attach_logger(
+        &self,
+        max_level: log::LevelFilter,
+        idle_timeout: Option<Duration>,
+        input: &LogInput,
+    ) -> Result<LogStreamEnd, UpdateTargetError> {
         let name = self.device.name().await.ok().flatten().unwrap();
         log::info!(target: "rudelctl", "Connected to {}", name);
 
         let log_receiver = self.log_tx_characteristic.notify();
         let mut log_receiver = pin!(log_receiver.await?);
         let printer = async {
-            while let Some(chunk) = log_receiver.next().await {
-                let Ok(chunk) = std::str::from_utf8(chunk.as_ref()) else {
-                    log::warn!("Received log message contains invalid UTF-8. Not printing it.");
-                    // TODO: Handle unicode characters split across multiple messages
-                    continue;
+            let mut line_buffer = LogLineBuffer::default();
+            loop {
+                let chunk = match idle_timeout {
+                    Some(idle_timeout) => {
+                        match tokio::time::timeout(idle_timeout, log_receiver.next()).await {
+                            Ok(chunk) => chunk,
+                            Err(_) => return LogStreamEnd::Idle,
+                        }
+                    }
+                    None => log_receiver.next().await,
                 };
-                print!("{}", chunk);
+                let Some(chunk) = chunk else {
+                    break;
+                };
+                for line in line_buffer.push(chunk.as_ref()) {
+                    if parse_level(&line).is_some_and(|level| level > max_level) {
+                        continue;
+                    }
+                    print!("{}", line);
+                }
             }
-            return Result::<(), UpdateTargetError>::Ok(());
+            return LogStreamEnd::Closed;
         };
 
         let reader = async {
-            let mut buffer = [0u8; 200];
-            while let Ok(length) = stdin().read(&mut buffer).await {
-                let result = self.log_rx_characteristic.write(&buffer[0..length]).await;
-                if result.is_err() {
-                    log::error!("Failed to send input to client");
-                    // TODO: Implement retry mechanism
-                    return result;
+            let mut input = input.lock().await;
+            while let Some(data) = input.recv().await {
+                if let Err(error) = self.log_rx_characteristic.write(&data).await {
+                    log::error!("Failed to send input to client: {}", error);
+                    return LogStreamEnd::Disconnected;
                 }
             }
-            return Ok(());
+            // stdin was closed, but we can still print the logs
+            return std::future::pending().await;
         };
 
         let checker = async {
@@ -484,16 +1203,98 @@ impl FileUploadClient {
                 tokio::time::sleep(Duration::from_millis(300)).await;
                 if !self.device.is_connected().await? {
                     log::info!(target: "rudelctl", "Disconnected from {}", name);
-                    return Result::<(), UpdateTargetError>::Ok(());
+                    return Result::<LogStreamEnd, UpdateTargetError>::Ok(
+                        LogStreamEnd::Disconnected,
+                    );
                 }
             }
         };
 
-        tokio::select! {
-            _ = printer => {}
-            _ = reader => {}
-            _ = checker => {}
-        }
-        Ok(())
+        let stream_end = tokio::select! {
+            stream_end = printer => stream_end,
+            stream_end = reader => stream_end,
+            stream_end = checker => stream_end?,
+        };
+        Ok(stream_end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_reports_are_parsed() {
+        let mut report = Vec::new();
+        report.extend_from_slice(&90_000_000u64.to_le_bytes());
+        report.extend_from_slice(&120_000u32.to_le_bytes());
+        report.extend_from_slice(&64_000u32.to_le_bytes());
+        report.extend_from_slice(&7u32.to_le_bytes());
+        report.push(4);
+
+        let health = Health::decode(&report).unwrap();
+        assert_eq!(health.uptime, Duration::from_secs(90));
+        assert_eq!(health.free_heap, 120_000);
+        assert_eq!(health.largest_free_block, 64_000);
+        assert_eq!(health.boot_count, 7);
+        assert_eq!(health.reset_reason_name(), "panic");
+        assert_eq!(Health::decode(&report[..20]), None);
+    }
+
+    #[test]
+    fn programs_only_pass_verification_if_the_hashes_match() {
+        let program = b"\0asm fancy program";
+        let expected = file_hash(program);
+        assert_eq!(expected, *blake3::hash(program).as_bytes());
+
+        let verification = |running| ProgramVerification { expected, running };
+        assert!(verification(Some(expected)).passed());
+        assert!(!verification(Some(file_hash(b"\0asm other program"))).passed());
+        assert!(!verification(None).passed());
+    }
+
+    #[test]
+    fn advertising_intervals_are_converted_from_milliseconds() {
+        assert_eq!(AdvertisingSettings::interval_from_millis(20), 0x0020);
+        assert_eq!(AdvertisingSettings::interval_from_millis(10240), 0x4000);
+        assert_eq!(AdvertisingSettings::interval_to_millis(160), 100.0);
+    }
+
+    #[test]
+    fn advertising_settings_are_validated() {
+        let settings = AdvertisingSettings {
+            min_interval: 100,
+            max_interval: 150,
+            tx_power: 3,
+        };
+        assert_eq!(settings.validate(), Ok(()));
+        assert_eq!(
+            AdvertisingSettings::decode(&settings.encode()),
+            Some(settings)
+        );
+        assert_eq!(
+            AdvertisingSettings {
+                min_interval: 0x10,
+                ..settings
+            }
+            .validate(),
+            Err(InvalidAdvertisingSettings::IntervalOutOfRange)
+        );
+        assert_eq!(
+            AdvertisingSettings {
+                min_interval: 200,
+                ..settings
+            }
+            .validate(),
+            Err(InvalidAdvertisingSettings::MinIntervalAboveMaxInterval)
+        );
+        assert_eq!(
+            AdvertisingSettings {
+                tx_power: 4,
+                ..settings
+            }
+            .validate(),
+            Err(InvalidAdvertisingSettings::UnsupportedTxPower)
+        );
     }
 }
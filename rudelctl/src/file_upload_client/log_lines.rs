@@ -0,0 +1,142 @@
+//! Reassemble log lines from the chunks received over the logging characteristic
+use log::Level;
+
+/// Lines longer than this are split, so a missing newline does not make us buffer forever
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Collects log chunks until a complete line is available
+///
+/// Chunks are split at arbitrary byte positions, so a multi-byte UTF-8 character may be spread across multiple chunks. Lines are only decoded once they are complete.
+#[derive(Default)]
+pub struct LogLineBuffer {
+    pending: Vec<u8>,
+}
+
+impl LogLineBuffer {
+    /// Add a received chunk and return all lines that are now complete. The lines include their trailing newline.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.pending.extend_from_slice(chunk);
+        let mut lines = Vec::new();
+        while let Some(position) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=position).collect();
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+        if self.pending.len() > MAX_LINE_LENGTH {
+            // Only split at a character boundary, an incomplete character at the end stays in the buffer
+            let valid_length = match std::str::from_utf8(&self.pending) {
+                Ok(_) => self.pending.len(),
+                Err(error) if error.error_len().is_none() => error.valid_up_to(),
+                Err(_) => self.pending.len(),
+            };
+            let line: Vec<u8> = self.pending.drain(..valid_length).collect();
+            lines.push(String::from_utf8_lossy(&line).into_owned());
+        }
+        return lines;
+    }
+}
+
+/// Remove ANSI escape sequences like color codes
+fn strip_ansi_codes(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(character) = chars.next() {
+        if character != '\x1b' {
+            result.push(character);
+            continue;
+        }
+        // Skip until the end of the escape sequence
+        for character in chars.by_ref() {
+            if character.is_ascii_alphabetic() {
+                break;
+            }
+        }
+    }
+    return result;
+}
+
+/// Try to find the level of a log line
+///
+/// Understands the format of `tracing_subscriber` (`INFO target: message`, optionally after a timestamp) and of ESP-IDF (`I (123) tag: message`). Returns `None` if the line has no recognizable level.
+pub fn parse_level(line: &str) -> Option<Level> {
+    let line = strip_ansi_codes(line);
+    let mut words = line.split_whitespace().take(3).peekable();
+
+    // ESP-IDF prefixes its messages with a single letter followed by the timestamp in parentheses
+    if let Some(first) = words.peek() {
+        let esp_idf_level = match *first {
+            "E" => Some(Level::Error),
+            "W" => Some(Level::Warn),
+            "I" => Some(Level::Info),
+            "D" => Some(Level::Debug),
+            "V" => Some(Level::Trace),
+            _ => None,
+        };
+        if esp_idf_level.is_some() && line.split_whitespace().nth(1)?.starts_with('(') {
+            return esp_idf_level;
+        }
+    }
+
+    return words.find_map(|word| match word {
+        "ERROR" => Some(Level::Error),
+        "WARN" => Some(Level::Warn),
+        "INFO" => Some(Level::Info),
+        "DEBUG" => Some(Level::Debug),
+        "TRACE" => Some(Level::Trace),
+        _ => None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassembles_lines_split_across_chunks() {
+        let mut buffer = LogLineBuffer::default();
+        assert!(buffer.push(b"INFO foo: hel").is_empty());
+        assert_eq!(buffer.push(b"lo\nINFO b"), vec!["INFO foo: hello\n"]);
+        assert_eq!(buffer.push(b"ar\n"), vec!["INFO bar\n"]);
+    }
+
+    #[test]
+    fn handles_characters_split_across_chunks() {
+        let mut buffer = LogLineBuffer::default();
+        let message = "miau 🐈\n".as_bytes();
+        let (first, second) = message.split_at(7);
+        assert!(buffer.push(first).is_empty());
+        assert_eq!(buffer.push(second), vec!["miau 🐈\n"]);
+    }
+
+    #[test]
+    fn a_four_byte_character_is_printed_once_wherever_it_is_split() {
+        let message = "🐈\n".as_bytes();
+        for split in 1..4 {
+            let mut buffer = LogLineBuffer::default();
+            let (first, second) = message.split_at(split);
+            assert!(buffer.push(first).is_empty());
+            assert_eq!(buffer.push(second), vec!["🐈\n"]);
+            assert!(buffer.pending.is_empty());
+        }
+    }
+
+    #[test]
+    fn parses_tracing_levels() {
+        assert_eq!(
+            parse_level("\x1b[2m2024-01-01T00:00:00Z\x1b[0m \x1b[33m WARN\x1b[0m file-upload: x"),
+            Some(Level::Warn)
+        );
+        assert_eq!(parse_level("DEBUG wasm: hi"), Some(Level::Debug));
+    }
+
+    #[test]
+    fn parses_esp_idf_levels() {
+        assert_eq!(parse_level("E (1234) wifi: broken"), Some(Level::Error));
+        assert_eq!(parse_level("I (1) boot: ok"), Some(Level::Info));
+    }
+
+    #[test]
+    fn lines_without_level_are_not_parsed() {
+        assert_eq!(parse_level("just some output"), None);
+        assert_eq!(parse_level(""), None);
+    }
+}
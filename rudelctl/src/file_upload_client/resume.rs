@@ -0,0 +1,115 @@
+//! Detect interrupted uploads that can be continued without sending a new upload request
+use super::UploadCharacteristics;
+
+/// The parts of the file upload service that are needed to decide whether an upload can be resumed
+pub trait UploadStatusSource {
+    /// Read the hash of the upload that is currently in progress on the target
+    ///
+    /// The target reports all zeroes if there is no upload in progress
+    async fn read_current_hash(&self) -> Result<Vec<u8>, bluer::Error>;
+    /// Read the number of received chunks followed by the IDs of some missing chunks
+    async fn read_upload_status(&self) -> Result<Vec<u8>, bluer::Error>;
+}
+
+impl UploadStatusSource for UploadCharacteristics {
+    async fn read_current_hash(&self) -> Result<Vec<u8>, bluer::Error> {
+        self.current_hash.read().await
+    }
+    async fn read_upload_status(&self) -> Result<Vec<u8>, bluer::Error> {
+        self.missing_chunks.read().await
+    }
+}
+
+/// Parse the upload status into a list of u16. The first entry is the number of received chunks, all other entries are IDs of missing chunks.
+pub fn parse_upload_status(upload_status: Vec<u8>) -> Vec<u16> {
+    upload_status
+        .into_iter()
+        .array_chunks::<2>()
+        .map(|chunk_id_bytes| u16::from_le_bytes(chunk_id_bytes))
+        .collect()
+}
+
+/// Check if the target is currently receiving a file with the given hash and is still missing some chunks.
+///
+/// If this returns true, the missing chunks can be sent directly without starting a new upload.
+pub async fn can_resume(
+    target: &impl UploadStatusSource,
+    hash: &[u8; 32],
+) -> Result<bool, bluer::Error> {
+    let current_hash = target.read_current_hash().await?;
+    if current_hash != hash {
+        return Ok(false);
+    }
+    let upload_status = parse_upload_status(target.read_upload_status().await?);
+    return Ok(upload_status.len() > 1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockCharacteristics {
+        current_hash: Vec<u8>,
+        upload_status: Vec<u8>,
+    }
+
+    impl UploadStatusSource for MockCharacteristics {
+        async fn read_current_hash(&self) -> Result<Vec<u8>, bluer::Error> {
+            Ok(self.current_hash.clone())
+        }
+        async fn read_upload_status(&self) -> Result<Vec<u8>, bluer::Error> {
+            Ok(self.upload_status.clone())
+        }
+    }
+
+    fn upload_status(transferred: u16, missing: &[u16]) -> Vec<u8> {
+        std::iter::once(transferred)
+            .chain(missing.iter().copied())
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn resumes_partial_upload_of_the_same_file() {
+        let target = MockCharacteristics {
+            current_hash: vec![7; 32],
+            upload_status: upload_status(3, &[3, 4, 5]),
+        };
+        assert!(can_resume(&target, &[7; 32]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_a_different_file() {
+        let target = MockCharacteristics {
+            current_hash: vec![7; 32],
+            upload_status: upload_status(3, &[3, 4, 5]),
+        };
+        assert!(!can_resume(&target, &[8; 32]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_when_no_upload_is_active() {
+        let target = MockCharacteristics {
+            current_hash: vec![0; 32],
+            upload_status: upload_status(0, &[]),
+        };
+        assert!(!can_resume(&target, &[7; 32]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn does_not_resume_when_nothing_is_missing() {
+        let target = MockCharacteristics {
+            current_hash: vec![7; 32],
+            upload_status: upload_status(6, &[]),
+        };
+        assert!(!can_resume(&target, &[7; 32]).await.unwrap());
+    }
+
+    #[test]
+    fn parses_upload_status() {
+        assert_eq!(
+            parse_upload_status(upload_status(2, &[2, 9])),
+            vec![2, 2, 9]
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Send an upload request and wait until the target accepted it
+use super::{resume::UploadStatusSource, UpdateTargetError, UploadCharacteristics};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// The upper limit for the delay between two upload requests
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+
+/// The parts of the file upload service that are needed to start an upload
+pub trait UploadStarter: UploadStatusSource {
+    /// Write an upload request. The target starts a new upload with the hash in the request
+    async fn write_upload_request(&self, request: &[u8]) -> Result<(), bluer::Error>;
+}
+
+impl UploadStarter for UploadCharacteristics {
+    async fn write_upload_request(&self, request: &[u8]) -> Result<(), bluer::Error> {
+        self.start_upload.write(request).await
+    }
+}
+
+/// How often and how fast upload requests are repeated
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Number of times the request is sent again
+    pub retries: usize,
+    /// Delay before the first retry. It doubles with every retry
+    pub base_delay: Duration,
+}
+
+impl Backoff {
+    /// Delay before the retry with the given number, starting at 0
+    ///
+    /// The delay doubles with every retry up to [MAX_RETRY_DELAY]. Up to half of it is subtracted at random, so uploaders that collided once do not collide again.
+    pub fn delay(&self, retry: usize, rng: &mut impl Rng) -> Duration {
+        let max_millis = MAX_RETRY_DELAY.as_millis() as u64;
+        let millis = (self.base_delay.as_millis() as u64)
+            .saturating_mul(1 << retry.min(16))
+            .min(max_millis);
+        let jitter = rng.gen_range(0..=millis / 2);
+        return Duration::from_millis(millis - jitter);
+    }
+}
+
+/// Send the upload request until the target reports its hash as the current upload
+///
+/// Fails with [UpdateTargetError::CompetingUpload] if the target switched to a different upload in the meantime and with [UpdateTargetError::UploadRequestIgnored] if it kept the upload it had before.
+pub async fn start_upload(
+    target: &impl UploadStarter,
+    request: &[u8],
+    hash: &[u8; 32],
+    backoff: &Backoff,
+) -> Result<(), UpdateTargetError> {
+    let previous_hash = target.read_current_hash().await?;
+    target.write_upload_request(request).await?;
+
+    let mut retry = 0;
+    loop {
+        let current_hash = target.read_current_hash().await?;
+        if current_hash == hash {
+            return Ok(());
+        }
+
+        if retry == backoff.retries {
+            if current_hash != previous_hash {
+                return Err(UpdateTargetError::CompetingUpload);
+            }
+            return Err(UpdateTargetError::UploadRequestIgnored);
+        }
+        let delay = backoff.delay(retry, &mut rand::thread_rng());
+        retry += 1;
+        log::debug!(
+            "Target did not process our upload request. Retry {}/{} in {:?}...",
+            retry,
+            backoff.retries,
+            delay
+        );
+        sleep(delay).await;
+        target.write_upload_request(request).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::sync::Mutex;
+
+    /// Reports the hashes in order. The last one is repeated
+    struct MockCharacteristics {
+        hashes: Mutex<Vec<Vec<u8>>>,
+        requests: Mutex<usize>,
+    }
+
+    impl MockCharacteristics {
+        fn new(hashes: &[[u8; 32]]) -> Self {
+            Self {
+                hashes: Mutex::new(hashes.iter().rev().map(|hash| hash.to_vec()).collect()),
+                requests: Mutex::new(0),
+            }
+        }
+
+        fn requests(&self) -> usize {
+            *self.requests.lock().unwrap()
+        }
+    }
+
+    impl UploadStatusSource for MockCharacteristics {
+        async fn read_current_hash(&self) -> Result<Vec<u8>, bluer::Error> {
+            let mut hashes = self.hashes.lock().unwrap();
+            if hashes.len() > 1 {
+                return Ok(hashes.pop().unwrap());
+            }
+            Ok(hashes[0].clone())
+        }
+        async fn read_upload_status(&self) -> Result<Vec<u8>, bluer::Error> {
+            Ok(Vec::new())
+        }
+    }
+
+    impl UploadStarter for MockCharacteristics {
+        async fn write_upload_request(&self, _request: &[u8]) -> Result<(), bluer::Error> {
+            *self.requests.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    const NO_DELAY: Backoff = Backoff {
+        retries: 3,
+        base_delay: Duration::ZERO,
+    };
+
+    #[tokio::test]
+    async fn starts_once_the_target_reports_our_hash() {
+        let target = MockCharacteristics::new(&[[0; 32], [0; 32], [0; 32], [7; 32]]);
+        start_upload(&target, &[], &[7; 32], &NO_DELAY)
+            .await
+            .unwrap();
+        assert_eq!(target.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn an_unchanged_hash_means_the_request_was_ignored() {
+        let target = MockCharacteristics::new(&[[1; 32]]);
+        let result = start_upload(&target, &[], &[7; 32], &NO_DELAY).await;
+        assert!(matches!(
+            result,
+            Err(UpdateTargetError::UploadRequestIgnored)
+        ));
+        assert_eq!(target.requests(), 4);
+    }
+
+    #[tokio::test]
+    async fn a_different_hash_means_a_competing_upload() {
+        let target = MockCharacteristics::new(&[[1; 32], [1; 32], [2; 32]]);
+        let result = start_upload(&target, &[], &[7; 32], &NO_DELAY).await;
+        assert!(matches!(result, Err(UpdateTargetError::CompetingUpload)));
+    }
+
+    #[test]
+    fn delay_grows_exponentially_with_jitter() {
+        let backoff = Backoff {
+            retries: 10,
+            base_delay: Duration::from_millis(500),
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        for (retry, full_delay) in [
+            (0, 500),
+            (1, 1000),
+            (2, 2000),
+            (3, 4000),
+            (4, 8000),
+            (9, 8000),
+        ] {
+            let delay = backoff.delay(retry, &mut rng);
+            assert!(delay <= Duration::from_millis(full_delay));
+            assert!(delay >= Duration::from_millis(full_delay / 2));
+        }
+    }
+}
@@ -2,6 +2,33 @@ use zerocopy::{Immutable, IntoBytes, KnownLayout, TryFromBytes};
 
 use super::UpdateTargetError;
 
+/// Flag of an upload whose data is compressed
+///
+/// The data is the length of the decompressed file as little endian `u32`, followed by the file as a raw deflate stream. See [compress].
+pub const COMPRESSED: u16 = 1;
+
+/// The hash the target uses to identify a file
+pub fn file_hash(data: &[u8]) -> [u8; 32] {
+    return blake3::hash(data).into();
+}
+
+/// The hash the target reports while receiving a compressed upload of the file with `hash`
+///
+/// This keeps a compressed upload from being resumed with the chunks of an uncompressed upload of the same file and the other way round.
+pub fn compressed_upload_hash(hash: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(b"rudelblinken compressed upload");
+    hasher.update(hash);
+    return hasher.finalize().into();
+}
+
+/// Compress a file for a [COMPRESSED] upload
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut compressed = (data.len() as u32).to_le_bytes().to_vec();
+    compressed.extend(miniz_oxide::deflate::compress_to_vec(data, 9));
+    return compressed;
+}
+
 // TODO: Implement better debug printing
 #[derive(Debug, Clone, TryFromBytes, IntoBytes, Immutable, KnownLayout, PartialEq, PartialOrd)]
 #[repr(C)]
@@ -18,8 +45,8 @@ pub struct UploadRequest {
     pub file_name: [u8; 16],
     /// Size of a single chunk
     pub chunk_size: u16,
-    /// Unused padding. Reserved for future use
-    pub _padding: u16,
+    /// Flags like [COMPRESSED]. Firmwares without support for a flag ignore it
+    pub flags: u16,
 }
 
 impl UploadRequest {
@@ -29,6 +56,7 @@ impl UploadRequest {
         checksums: [u8; 32],
         file_name: [u8; 16],
         chunk_size: u16,
+        flags: u16,
     ) -> Self {
         Self {
             file_size,
@@ -36,7 +64,7 @@ impl UploadRequest {
             checksums,
             file_name,
             chunk_size,
-            _padding: 0,
+            flags,
         }
     }
 
@@ -46,12 +74,58 @@ impl UploadRequest {
         chunk_size: u16,
         upload_checksums: impl async Fn(&[u8]) -> Result<[u8; 32], UpdateTargetError>,
     ) -> Result<Self, UpdateTargetError> {
-        let mut hasher = blake3::Hasher::new();
-        hasher.update(&data);
-        // TODO: I am sure there is a better way to convert this into an array but I didnt find it after 10 minutes.
-        let mut hash: [u8; 32] = [0; 32];
-        hash.copy_from_slice(hasher.finalize().as_bytes());
+        return Self::with_payload(
+            file_name,
+            file_hash(data),
+            data,
+            0,
+            chunk_size,
+            upload_checksums,
+        )
+        .await;
+    }
+
+    /// A request to upload `data`, which was compressed to `compressed` with [compress]
+    pub async fn new_compressed(
+        file_name: &str,
+        data: &[u8],
+        compressed: &[u8],
+        chunk_size: u16,
+        upload_checksums: impl async Fn(&[u8]) -> Result<[u8; 32], UpdateTargetError>,
+    ) -> Result<Self, UpdateTargetError> {
+        return Self::with_payload(
+            file_name,
+            file_hash(data),
+            compressed,
+            COMPRESSED,
+            chunk_size,
+            upload_checksums,
+        )
+        .await;
+    }
 
+    /// Whether the data is compressed, see [COMPRESSED]
+    pub fn is_compressed(&self) -> bool {
+        return self.flags & COMPRESSED != 0;
+    }
+
+    /// The hash the target reports while receiving this upload
+    pub fn upload_hash(&self) -> [u8; 32] {
+        if self.is_compressed() {
+            return compressed_upload_hash(&self.hash);
+        }
+        return self.hash;
+    }
+
+    /// A request for the file with `hash`, which is sent as `data`
+    async fn with_payload(
+        file_name: &str,
+        hash: [u8; 32],
+        data: &[u8],
+        flags: u16,
+        chunk_size: u16,
+        upload_checksums: impl async Fn(&[u8]) -> Result<[u8; 32], UpdateTargetError>,
+    ) -> Result<Self, UpdateTargetError> {
         // -2 for the length
         // -28 was found to be good by empirical methods
 
@@ -83,6 +157,70 @@ impl UploadRequest {
             checksums,
             file_name_array,
             chunk_size,
+            flags,
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn no_checksums_file(_: &[u8]) -> Result<[u8; 32], UpdateTargetError> {
+        panic!("The file has too few chunks for a checksums file");
+    }
+
+    #[test]
+    fn compressed_data_can_be_decompressed() {
+        let data = b"blink blink blink blink blink blink".repeat(20);
+        let compressed = compress(&data);
+        assert!(compressed.len() < data.len());
+        assert_eq!(compressed[0..4], (data.len() as u32).to_le_bytes());
+        assert_eq!(
+            miniz_oxide::inflate::decompress_to_vec(&compressed[4..]).unwrap(),
+            data
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_requests_describe_the_compressed_data() {
+        let data = b"blink blink blink blink blink blink".repeat(20);
+        let compressed = compress(&data);
+        let request =
+            UploadRequest::new_compressed("test", &data, &compressed, 64, no_checksums_file)
+                .await
+                .unwrap();
+
+        assert!(request.is_compressed());
+        assert_eq!(request.file_size, compressed.len() as u32);
+        assert_eq!(request.hash, file_hash(&data));
+        assert_eq!(
+            request.upload_hash(),
+            compressed_upload_hash(&file_hash(&data))
+        );
+        assert_ne!(request.upload_hash(), request.hash);
+        let crc8 = crc::Crc::<u8>::new(&crc::CRC_8_LTE);
+        assert_eq!(
+            request.checksums[0],
+            crc8.checksum(compressed.chunks(64).next().unwrap())
+        );
+
+        let parsed = UploadRequest::try_ref_from_bytes(request.as_bytes()).unwrap();
+        assert_eq!(parsed, &request);
+        assert!(parsed.is_compressed());
+    }
+
+    #[tokio::test]
+    async fn uncompressed_requests_are_identified_by_the_file_hash() {
+        let data = b"blink".repeat(20);
+        let request = UploadRequest::new("test", &data, 64, no_checksums_file)
+            .await
+            .unwrap();
+
+        assert!(!request.is_compressed());
+        assert_eq!(request.file_size, data.len() as u32);
+        assert_eq!(request.upload_hash(), file_hash(&data));
+        let parsed = UploadRequest::try_ref_from_bytes(request.as_bytes()).unwrap();
+        assert_eq!(parsed.flags, 0);
+    }
+}
@@ -0,0 +1,90 @@
+//! Inspect a dump of the filesystem of a device on the host
+//!
+//! Dump the `storage` partition of a device that is connected via USB with `espflash read-flash 0x300000 0x100000 storage.bin`. `rudelctl directory storage.bin` then prints the files in it as JSON:
+//!
+//! ```json
+//! [
+//!   {"name": "program", "length": 20480, "start_block": 12, "block_count": 6, "age": 0, "priority": 1, "important": false}
+//! ]
+//! ```
+//!
+//! The dump is copied into memory before it is mounted, so the file is never modified.
+use rudelblinken_filesystem::{
+    storage::{simulated::Esp32C3SimulatedStorage, Storage},
+    FileEntry, Filesystem,
+};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum FilesystemImageError {
+    #[error("Failed to read the dump: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The dump is {got} bytes long, but the storage partition has {expected} bytes")]
+    WrongSize { expected: u32, got: usize },
+    #[error("Failed to load the dump: {0}")]
+    Load(String),
+}
+
+/// List the files in the dump of a storage partition at `path`
+pub fn read_directory(path: &Path) -> Result<Vec<FileEntry>, FilesystemImageError> {
+    let image = std::fs::read(path)?;
+    return directory_of_image(&image);
+}
+
+/// List the files in the dump of a storage partition
+fn directory_of_image(image: &[u8]) -> Result<Vec<FileEntry>, FilesystemImageError> {
+    if image.len() != Esp32C3SimulatedStorage::SIZE as usize {
+        return Err(FilesystemImageError::WrongSize {
+            expected: Esp32C3SimulatedStorage::SIZE,
+            got: image.len(),
+        });
+    }
+    let storage = Esp32C3SimulatedStorage::new();
+    let block_size = Esp32C3SimulatedStorage::BLOCK_SIZE;
+    for (block, data) in image.chunks(block_size as usize).enumerate() {
+        storage
+            .write(block as u32 * block_size, data)
+            .map_err(|error| FilesystemImageError::Load(error.to_string()))?;
+    }
+    // The filesystem needs a storage that is never dropped. rudelctl exits after printing the directory
+    let storage: &'static Esp32C3SimulatedStorage = Box::leak(Box::new(storage));
+    let filesystem = Filesystem::new(storage);
+    return Ok(filesystem.dump_directory());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_directory_of_a_dump_lists_its_files() {
+        let storage: &'static Esp32C3SimulatedStorage =
+            Box::leak(Box::new(Esp32C3SimulatedStorage::new()));
+        let mut filesystem = Filesystem::new(storage);
+        let content = [7u8; 5000];
+        let hash: [u8; 32] = blake3::hash(&content).into();
+        filesystem.write_file("program", &content, &hash).unwrap();
+        filesystem
+            .write_important_file("config", &[1, 2, 3], &[0u8; 32])
+            .unwrap();
+
+        let block_size = Esp32C3SimulatedStorage::BLOCK_SIZE;
+        let image: Vec<u8> = (0..Esp32C3SimulatedStorage::BLOCKS)
+            .flat_map(|block| storage.read(block * block_size, block_size).unwrap())
+            .copied()
+            .collect();
+        let directory = directory_of_image(&image).unwrap();
+        assert_eq!(directory, filesystem.dump_directory());
+        assert_eq!(directory.len(), 2);
+    }
+
+    #[test]
+    fn dumps_of_another_size_are_rejected() {
+        let result = directory_of_image(&[0xff; 4096]);
+        assert!(matches!(
+            result,
+            Err(FilesystemImageError::WrongSize { got: 4096, .. })
+        ));
+    }
+}
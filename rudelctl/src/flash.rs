@@ -8,10 +8,38 @@ use espflash::{
     },
     elf::{ElfFirmwareImage, RomSegment},
 };
+use serialport::{SerialPortType, UsbPortInfo};
 use thiserror::Error;
 
+/// Name of the partition that selects the OTA partition to boot
+///
+/// It is always erased, so the bootloader boots the freshly flashed firmware instead of one installed by an earlier OTA update.
+const OTA_DATA_PARTITION: &str = "otadata";
+
+/// USB vendor and product ids of the serial interfaces found on ESP32-C3 boards
+const KNOWN_USB_DEVICES: &[(u16, u16)] = &[
+    // Builtin USB serial/JTAG controller
+    (0x303a, 0x1001),
+    // CP210x
+    (0x10c4, 0xea60),
+    // CH340
+    (0x1a86, 0x7523),
+    // CH9102
+    (0x1a86, 0x55d4),
+];
+
+/// Name of the partition that contains the filesystem
+const FILESYSTEM_PARTITION: &str = "storage";
+
 #[derive(Error, Debug)]
-pub enum FlashError {}
+pub enum FlashError {
+    #[error("Failed to list the serial ports")]
+    FailedToListPorts(#[from] serialport::Error),
+    #[error("No rudelblinken board found. Use --port to select the serial port")]
+    NoPortFound,
+    #[error("Found multiple boards on {}. Use --port to select one of them", .0.join(", "))]
+    MultiplePortsFound(Vec<String>),
+}
 
 #[derive(Args, Debug)]
 pub struct FlashCommand {
@@ -24,6 +52,47 @@ pub struct FlashCommand {
     /// Flash the default program
     #[clap(short, long, default_value = "true")]
     default_program: bool,
+    /// Erase the filesystem, so the device starts without any uploaded files
+    #[clap(long, default_value = "false")]
+    erase_filesystem: bool,
+    /// Serial port of the board. Detected automatically if there is only one board connected
+    #[clap(short, long)]
+    port: Option<String>,
+    /// Baud rate for flashing
+    #[clap(short, long)]
+    baud: Option<u32>,
+}
+
+/// Find the serial port of the only connected board
+fn detect_port() -> Result<String, FlashError> {
+    let usb_ports = serialport::available_ports()?
+        .into_iter()
+        .filter_map(|port| match port.port_type {
+            SerialPortType::UsbPort(UsbPortInfo { vid, pid, .. }) => {
+                Some((port.port_name, (vid, pid)))
+            }
+            _ => None,
+        })
+        .collect();
+    return select_port(usb_ports);
+}
+
+/// Select the port of the only board from the USB serial ports and their vendor and product ids
+///
+/// Ports of known boards are preferred. If there are none, any USB serial port is used, because there are boards with other USB serial converters.
+fn select_port(usb_ports: Vec<(String, (u16, u16))>) -> Result<String, FlashError> {
+    let (known, unknown): (Vec<_>, Vec<_>) = usb_ports
+        .into_iter()
+        .partition(|(_, ids)| KNOWN_USB_DEVICES.contains(ids));
+    let candidates: Vec<String> = if known.is_empty() { unknown } else { known }
+        .into_iter()
+        .map(|(port, _)| port)
+        .collect();
+    match candidates.as_slice() {
+        [] => Err(FlashError::NoPortFound),
+        [port] => Ok(port.clone()),
+        _ => Err(FlashError::MultiplePortsFound(candidates)),
+    }
 }
 
 /// Wraps espflash to flash the rudelblinken firmware.
@@ -32,14 +101,24 @@ pub struct Flasher {
     /// Flash a special test firmware instead of the normal firmware.
     board_test_firmware: bool,
     flash_default_program: bool,
+    erase_filesystem: bool,
+    port: String,
+    baud: Option<u32>,
 }
 
 impl Flasher {
     pub async fn new(command: FlashCommand) -> Result<Self, FlashError> {
+        let port = match command.port {
+            Some(port) => port,
+            None => detect_port()?,
+        };
         Ok(Flasher {
             monitor: command.monitor,
             board_test_firmware: command.test,
             flash_default_program: command.default_program,
+            erase_filesystem: command.erase_filesystem,
+            port,
+            baud: command.baud,
         })
     }
 
@@ -74,11 +153,16 @@ impl Flasher {
             #[clap(short = 'S', long, global = true, action)]
             skip_update_check: bool,
         }
-        let mock_args = vec!["espflash", "flash"];
+        let baud = self.baud.map(|baud| baud.to_string());
+        let mut mock_args = vec!["espflash", "flash", "--port", &self.port];
+        if let Some(baud) = &baud {
+            mock_args.extend(["--baud", baud]);
+        }
         let mut mock_cli = MockCli::parse_from(mock_args);
         mock_cli.skip_update_check = true;
         let Commands::Flash(args) = mock_cli.subcommand;
 
+        log::info!("Flashing the board on {}", self.port);
         let config = Config::load().unwrap();
         let mut flasher = connect(
             &args.connect_args,
@@ -128,15 +212,18 @@ impl Flasher {
                 esp_idf_part::PartitionTable::try_from(Vec::from(partition_table_bytes)).ok();
             flash_data.bootloader = Some(Vec::from(bootloader_bytes));
 
-            if args.flash_args.erase_parts.is_some() || args.flash_args.erase_data_parts.is_some() {
-                erase_partitions(
-                    &mut flasher,
-                    flash_data.partition_table.clone(),
-                    args.flash_args.erase_parts,
-                    args.flash_args.erase_data_parts,
-                )
-                .unwrap();
+            let mut erase_parts = args.flash_args.erase_parts.unwrap_or_default();
+            erase_parts.push(OTA_DATA_PARTITION.to_string());
+            if self.erase_filesystem {
+                erase_parts.push(FILESYSTEM_PARTITION.to_string());
             }
+            erase_partitions(
+                &mut flasher,
+                flash_data.partition_table.clone(),
+                Some(erase_parts),
+                args.flash_args.erase_data_parts,
+            )
+            .unwrap();
 
             let prog_seg = if self.flash_default_program {
                 let prog_part = flash_data
@@ -212,3 +299,33 @@ impl Flasher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_boards_are_preferred() {
+        let ports = vec![
+            ("/dev/ttyUSB0".to_string(), (0x0403, 0x6001)),
+            ("/dev/ttyACM0".to_string(), (0x303a, 0x1001)),
+        ];
+        assert_eq!(select_port(ports).unwrap(), "/dev/ttyACM0");
+    }
+
+    #[test]
+    fn any_usb_serial_port_is_used_without_a_known_board() {
+        let ports = vec![("/dev/ttyUSB0".to_string(), (0x0403, 0x6001))];
+        assert_eq!(select_port(ports).unwrap(), "/dev/ttyUSB0");
+
+        let ports = vec![
+            ("/dev/ttyUSB0".to_string(), (0x0403, 0x6001)),
+            ("/dev/ttyUSB1".to_string(), (0x0403, 0x6015)),
+        ];
+        assert!(matches!(
+            select_port(ports),
+            Err(FlashError::MultiplePortsFound(ports)) if ports.len() == 2
+        ));
+        assert!(matches!(select_port(vec![]), Err(FlashError::NoPortFound)));
+    }
+}
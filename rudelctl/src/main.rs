@@ -10,17 +10,53 @@
 //! Usage: rudelctl <COMMAND>
 //!
 //! Commands:
-//! upload   Upload a file
-//! run      Run a WASM binary
-//! scan     Scan for cats
-//! emulate  Emulate a rudelblinken device
-//! flash    Flash a built-in copy of the rudelblinken firmware via USB
-//! help     Print this message or the help of the given subcommand(s)
+//! upload           Upload a file
+//! run              Run a WASM binary
+//! verify           Check that a device runs exactly this WASM binary
+//! scan             Scan for cats
+//! watch            Show cats as they appear, change and disappear until interrupted
+//! status           Show the name, the running program, the free space and the health of a device
+//! log              Attach to the logs of a device
+//! config           Read or change the configuration that is passed to the program on a device
+//! rename           Give a device a new name
+//! assign-rudel     Assign a device to a Rudel, so it can be selected with --rudel
+//! advertising      Show or change how often and how loud a device advertises
+//! erase-filesystem Delete all files on a device [aliases: format]
+//! update-firmware  Update the firmware of a device over BLE [aliases: ota]
+//! emulate          Emulate a rudelblinken device
+//! flash            Flash a built-in copy of the rudelblinken firmware via USB
+//! help             Print this message or the help of the given subcommand(s)
 //!
 //! Options:
 //! -h, --help     Print help
 //! ```
 //!
+//! ## Replaying advertisements
+//!
+//! To reproduce how a device reacted to the advertisements around it, replay them into the emulator with `rudelctl emulate --replay trace.jsonl program.wasm`. The trace is a JSON lines file with one received advertisement per line:
+//!
+//! ```json
+//! {"at_micros": 1500000, "mac": "AA:BB:CC:DD:EE:FF", "data_hex": "0000ca7ea20102"}
+//! ```
+//!
+//! `at_micros` is the time the advertisement was received in microseconds since the start of the trace, `mac` is the address of the sender and `data_hex` is the manufacturer data as a hex string (at most 32 bytes). An optional `rssi` sets the signal strength in dBm. The lines need to be sorted by time.
+//!
+//! The program runs on a virtual clock that starts with the trace, so every advertisement is received at exactly its recorded time and a replay behaves the same every time. Use `--speed` to run the virtual clock faster or slower than the wall clock. `rudelctl` can not capture traces yet, so they have to be converted from another tool, for example `btmon`.
+//!
+//! ## Partition layout
+//!
+//! The firmware has two OTA partitions, so it can be updated over BLE with `rudelctl update-firmware`. `rudelctl flash` writes the firmware to the first one and erases the OTA data partition, so the device boots the flashed firmware even if it was updated over BLE before.
+//!
+//! Older firmwares used a single `factory` partition and a 24 KiB NVS partition. The NVS partition now has 16 KiB, because the OTA data partition took its last 8 KiB, and the `default_program` partition moved from `0x2f8000` to `0x2f0000`. A device with the old layout can not be updated over BLE, it has to be reflashed via USB once with `rudelctl flash`, which also writes the new partition table. The settings in the first 16 KiB of the NVS partition are kept. If they do not fit, the firmware erases the NVS partition on the first boot and the device loses its name, its config and its Rudel, so note them down before reflashing. To start with a clean NVS partition, flash with `--erase-parts nvs`. `rudelctl flash` also writes the default program to its new location. After flashing with `cargo run` instead, the default program has to be written again.
+//!
+//! ## Uploading to many devices
+//!
+//! To deploy a program to many devices, run `rudelctl upload --devices 50 --report report.json program.wasm`. After the scan, `rudelctl` prints a table with the result of every device and exits with an error if an upload failed. `--report` also writes the results as JSON, with the mac, the name, the number of bytes sent, the duration, the throughput and the error of every device. Add `--compress` to send the programs compressed, which makes uploads of large programs faster. This needs a firmware that supports compressed uploads.
+//!
+//! ## Color palettes
+//!
+//! Programs can read a palette of colors from their config with `rudelblinken_sdk::config::palette()`, so you can change the colors of a device without recompiling the program. Set the palette with `rudelctl config set-palette ff0000 00ff00 0000ff`. This replaces the entire config with the number of colors followed by the red, green and blue byte of every color.
+//!
 //! ## Updating the integrated rudelblinken firmware binary
 //!
 //! `rudelctl` contains a built-in rudelblinken firmware binary. To update the binary, run the `update-firmware.sh`` script in the root of this crate. This will build the firmware and copy the binary to the `firmware` directory. You need to have the entire repository checked out to run the script, because it will look for firmware sources in an adjacent directory.
@@ -31,17 +67,32 @@
 mod bluetooth;
 mod emulator;
 mod file_upload_client;
+mod filesystem_image;
 mod flash;
+mod update_key;
+mod upload_report;
 use bluer::Device;
-use bluetooth::{scan_for, Outcome};
+use bluetooth::{scan_for, scan_stream, Outcome, ScanEvent};
 use clap::{Parser, Subcommand};
-use emulator::{EmulateCommand, Emulator};
-use file_upload_client::{FileUploadClient, UpdateTargetError};
+use emulator::{EmulateCommand, Emulator, Simulation};
+use file_upload_client::{
+    read_stdin, AdvertisingSettings, FileUploadClient, LogStreamEnd, ProgramVerification,
+    UpdateTargetError, UploadSettings, MAX_NAME_LENGTH, MAX_WASM_GUEST_CONFIG_LENGTH,
+    MIN_NAME_LENGTH,
+};
 use flash::Flasher;
+use futures::StreamExt;
 use futures_time::time::Duration;
 use indicatif::MultiProgress;
 use indicatif_log_bridge::LogWrapper;
-use std::{path::PathBuf, sync::LazyLock, time::Instant, u32};
+use std::{
+    cell::{Cell, RefCell},
+    path::PathBuf,
+    sync::LazyLock,
+    time::Instant,
+    u32,
+};
+use upload_report::{UploadReport, UploadResult};
 
 /// Rudelblinken cli utility
 #[derive(Parser, Debug)]
@@ -50,6 +101,9 @@ struct Cli {
     /// Only process cats that have this name
     #[arg(short, long, global = true)]
     name: Option<String>,
+    /// Only process cats that belong to this Rudel
+    #[arg(long, global = true)]
+    rudel: Option<u16>,
     #[command(subcommand)]
     command: Commands,
     /// Powercycle the bluetooth adapter before doing anything
@@ -69,6 +123,17 @@ enum Commands {
         #[arg(short, long, default_value = "1")]
         devices: u32,
 
+        /// Always start a new upload, even if a device is still receiving the same file from an interrupted upload
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        upload_settings: UploadSettings,
+
+        /// Write the result of every device as JSON to this file
+        #[arg(long)]
+        report: Option<PathBuf>,
+
         /// WASM file that will get flashed to the devices
         file: PathBuf,
     },
@@ -82,23 +147,261 @@ enum Commands {
         #[arg(short, long, default_value = "1")]
         devices: u32,
 
+        /// Always start a new upload, even if a device is still receiving the same file from an interrupted upload
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        upload_settings: UploadSettings,
+
         /// WASM file that will get flashed to the devices
         file: PathBuf,
     },
+    /// Check that a device runs exactly this WASM binary
+    ///
+    /// Compares the hash of the file with the hash of the program that is running on the device. Exits with an error unless the requested number of devices was found and every one of them runs this file.
+    ///
+    /// Only the running program is checked. A file that was uploaded with `upload` but not started with `run` fails the check.
+    Verify {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        /// Maximum number of devices to check
+        #[arg(short, long, default_value = "1")]
+        devices: u32,
+
+        /// WASM file that should be running on the devices
+        file: PathBuf,
+    },
     /// Scan for cats
     Scan {
         /// Stop scanning after this many seconds
         #[arg(short, long, default_value = "10")]
         timeout: f32,
     },
+    /// Show cats as they appear, change and disappear until interrupted
+    Watch,
+    /// Show the name, the running program, the free space and the health of a device
+    Status {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        /// Maximum number of devices to query
+        #[arg(short, long, default_value = "1")]
+        devices: u32,
+
+        /// Print one comma separated line per device instead of one line per value
+        #[arg(long)]
+        table: bool,
+    },
     /// Attach to the logs of a device
-    Log {},
+    ///
+    /// Reconnects when the device goes away and keeps scanning until it is back, until interrupted. Use --once or --idle-timeout to exit instead.
+    Log {
+        /// Only print log lines up to this level (error, warn, info, debug, trace). Lines without a level are always printed
+        #[arg(short, long, default_value = "trace")]
+        level: log::LevelFilter,
+
+        /// Exit after this many seconds without log output or without finding a device
+        #[arg(long, value_parser = parse_seconds)]
+        idle_timeout: Option<Duration>,
+
+        /// Exit once the first device disconnects or closes its log stream instead of reconnecting
+        #[arg(long)]
+        once: bool,
+    },
+    /// Read or change the configuration that is passed to the program on a device
+    Config {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Give a device a new name
+    Rename {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        /// The new name. Must be between 3 and 16 bytes long
+        new_name: String,
+    },
+    /// Assign a device to a Rudel, so it can be selected with --rudel
+    AssignRudel {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        /// The new Rudel id. Omit it to remove the device from its Rudel
+        id: Option<u16>,
+    },
+    /// Show or change how often and how loud a device advertises
+    ///
+    /// Without options the current settings are printed. Omitted options keep their current value. The settings survive reboots.
+    Advertising {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        /// Minimum time between two advertisements in milliseconds (20 to 10240)
+        #[arg(long)]
+        min_interval: Option<u32>,
+
+        /// Maximum time between two advertisements in milliseconds (20 to 10240)
+        #[arg(long)]
+        max_interval: Option<u32>,
+
+        /// Transmit power in dBm. One of -12, -9, -6, -3, 0, 3, 6 and 9
+        #[arg(long, allow_negative_numbers = true)]
+        tx_power: Option<i8>,
+
+        /// Restore the default settings of the device
+        #[arg(long, conflicts_with_all = ["min_interval", "max_interval", "tx_power"])]
+        reset: bool,
+    },
+    /// Delete all files on a device
+    ///
+    /// Use `flash --erase-filesystem` for devices that are connected via USB.
+    #[command(visible_alias = "format")]
+    EraseFilesystem {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+    },
+    /// Update the firmware of a device over BLE
+    ///
+    /// The device only accepts the update if it is signed with the key the device firmware was built for.
+    #[command(visible_alias = "ota")]
+    UpdateFirmware {
+        /// Stop scanning after this many seconds
+        #[arg(short, long, default_value = "3")]
+        timeout: f32,
+
+        /// File with the key that signs the update, as created by `generate-update-key`
+        #[arg(long)]
+        signing_key: PathBuf,
+
+        /// Always start a new upload, even if the device is still receiving the same image from an interrupted update
+        #[arg(long)]
+        force: bool,
+
+        #[command(flatten)]
+        upload_settings: UploadSettings,
+
+        /// ESP-IDF app image (not the ELF file) with the new firmware
+        image: PathBuf,
+    },
+    /// Create a key for signing firmware updates
+    ///
+    /// Prints the public key. Build the firmware with it in `RUDELBLINKEN_UPDATE_KEY`, so devices accept updates signed with the new key.
+    GenerateUpdateKey {
+        /// File to store the secret signing key in. Must not exist yet
+        key: PathBuf,
+    },
+    /// Print the files in a dump of the storage partition of a device as JSON
+    ///
+    /// Dump the partition of a device that is connected via USB with `espflash read-flash 0x300000 0x100000 storage.bin`. The dump is only read, never modified.
+    Directory {
+        /// Dump of the storage partition
+        image: PathBuf,
+    },
     /// Emulate a rudelblinken device
     Emulate(EmulateCommand),
     /// Flash a built-in copy of the rudelblinken firmware via USB
     Flash(flash::FlashCommand),
 }
 
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the current config as hex
+    Get,
+    /// Replace the config
+    Set {
+        /// The new config. Either a path to a file with the raw config or the bytes as a hex string like `0a1b2c`
+        value: String,
+    },
+    /// Replace the config with a palette of colors
+    SetPalette {
+        /// The colors as hex strings like `ff8000`
+        #[arg(required = true)]
+        colors: Vec<String>,
+    },
+}
+
+/// Format a file hash as hex
+fn format_hash(hash: &[u8; 32]) -> String {
+    return hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+/// Explain why a device does not run the expected program
+fn describe_mismatch(verification: &ProgramVerification) -> String {
+    let running = match &verification.running {
+        Some(hash) => format_hash(hash),
+        None => "the default program".to_string(),
+    };
+    return format!(
+        "expected {} but the device runs {}",
+        format_hash(&verification.expected),
+        running
+    );
+}
+
+/// Encode colors like `ff8000` as a palette config
+///
+/// The layout is the one read by `rudelblinken_sdk::config::palette`: the number of colors, followed by the red, green and blue byte of every color.
+fn encode_palette(colors: &[String]) -> Result<Vec<u8>, String> {
+    let count = u8::try_from(colors.len())
+        .map_err(|_| format!("A palette can have at most {} colors", u8::MAX))?;
+    let mut config = vec![count];
+    for color in colors {
+        let hex = color.trim().trim_start_matches('#');
+        let rgb = u32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| hex.len() == 6)
+            .ok_or_else(|| format!("{} is not a color like ff8000", color))?;
+        config.extend_from_slice(&rgb.to_be_bytes()[1..]);
+    }
+    return Ok(config);
+}
+
+/// Parse a non-negative number of seconds
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    let seconds: f32 = value
+        .parse()
+        .map_err(|_| format!("{} is not a number of seconds", value))?;
+    return Duration::try_from_secs_f32(seconds)
+        .map_err(|_| format!("{} is not a valid number of seconds", value));
+}
+
+/// Parse a config value from a file or a hex string
+fn parse_config_value(value: &str) -> Result<Vec<u8>, String> {
+    let path = std::path::Path::new(value);
+    if path.is_file() {
+        return std::fs::read(path).map_err(|error| format!("Failed to read {}: {}", value, error));
+    }
+    let hex = value.trim().trim_start_matches("0x");
+    if hex.len() % 2 != 0 {
+        return Err(format!("{} is neither a file nor a hex string", value));
+    }
+    return (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            hex.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(|| format!("{} is neither a file nor a hex string", value))
+        })
+        .collect();
+}
+
+/// Delay before reconnecting after the log stream ended
+const LOG_RECONNECT_MIN_BACKOFF: std::time::Duration = std::time::Duration::from_millis(500);
+/// Upper limit for the delay between failed reconnects
+const LOG_RECONNECT_MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(30);
+
 pub static GLOBAL_LOGGER: LazyLock<MultiProgress> = LazyLock::new(|| {
     let logger =
         env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
@@ -136,31 +439,33 @@ async fn main() -> bluer::Result<()> {
         Commands::Upload {
             timeout,
             devices,
+            force,
+            upload_settings,
+            report,
             file,
         } => {
             let file_content = tokio::fs::read(file)
                 .await
                 .expect("Failed to read the WASM file");
+            let results = RefCell::new(UploadReport::default());
 
             scan_for(
                 Duration::from_millis((timeout * 1000.0) as u64),
                 devices,
                 name_filter,
+                cli.rudel,
                 cli.powercycle,
                 &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
                     let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
                     else {
                         return Ok(Outcome::Ignored);
                     };
+                    let update_target = update_target.with_upload_settings(upload_settings.clone());
                     if devices == 1 {
                         abort.abort();
                     }
-                    let target_name = device
-                        .name()
-                        .await
-                        .ok()
-                        .flatten()
-                        .unwrap_or(device.address().to_string());
+                    let mac = device.address().to_string();
+                    let target_name = device.name().await.ok().flatten().unwrap_or(mac.clone());
                     log::info!("Connected to {}", target_name);
 
                     let data = &file_content;
@@ -169,14 +474,19 @@ async fn main() -> bluer::Result<()> {
                     log::info!(
                         "Sending {:.2}kB to {}",
                         data.len() as f32 / 1024.0,
-                        device
-                            .name()
-                            .await
-                            .ok()
-                            .flatten()
-                            .unwrap_or(device.address().to_string())
+                        target_name
                     );
-                    update_target.upload_file(&data, "test.txt".into()).await?;
+                    if let Err(error) = update_target
+                        .upload_file(&data, "test.txt".into(), force)
+                        .await
+                    {
+                        results.borrow_mut().devices.push(UploadResult::failed(
+                            mac,
+                            target_name,
+                            error.to_string(),
+                        ));
+                        return Err(error);
+                    }
                     let duration = now.elapsed();
                     log::info!(
                         "Sending {:.2}kB took {} millis ({:.3}kB/s)",
@@ -184,15 +494,34 @@ async fn main() -> bluer::Result<()> {
                         duration.as_millis(),
                         (data.len() as f64 / duration.as_millis() as f64)
                     );
+                    results.borrow_mut().devices.push(UploadResult::succeeded(
+                        mac,
+                        target_name,
+                        data.len(),
+                        duration,
+                    ));
                     return Ok(Outcome::Processed);
                 },
             )
             .await
             .unwrap();
+
+            let results = results.into_inner();
+            println!("{}", results.summary_table());
+            if let Some(report) = report {
+                results
+                    .write(&report)
+                    .expect("Failed to write the upload report");
+            }
+            if results.has_failures() {
+                std::process::exit(1);
+            }
         }
         Commands::Run {
             timeout,
             devices,
+            force,
+            upload_settings,
             file,
         } => {
             let file_content = tokio::fs::read(file)
@@ -203,49 +532,458 @@ async fn main() -> bluer::Result<()> {
                 Duration::from_millis((timeout * 1000.0) as u64),
                 devices,
                 name_filter,
+                cli.rudel,
                 cli.powercycle,
                 &async |device: Device, _| -> Result<Outcome, UpdateTargetError> {
                     let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
                     else {
                         return Ok(Outcome::Ignored);
                     };
+                    let update_target = update_target.with_upload_settings(upload_settings.clone());
 
                     let data = &file_content;
 
-                    update_target.run_program(&data).await?;
+                    update_target.run_program(&data, force).await?;
                     return Ok(Outcome::Processed);
                 },
             )
             .await
             .unwrap();
         }
-        Commands::Log {} => loop {
+        Commands::Verify {
+            timeout,
+            devices,
+            file,
+        } => {
+            let file_content = tokio::fs::read(file)
+                .await
+                .expect("Failed to read the WASM file");
+            let passed = Cell::new(0u32);
+
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                devices,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    if devices == 1 {
+                        abort.abort();
+                    }
+
+                    let name = match update_target.get_status().await {
+                        Ok(status) => status.name,
+                        Err(_) => device.address().to_string(),
+                    };
+                    let verification = match update_target.verify_program(&file_content).await {
+                        Ok(verification) => verification,
+                        Err(error) => {
+                            println!("{}: FAIL, {}", name, error);
+                            return Err(error);
+                        }
+                    };
+                    if verification.passed() {
+                        passed.set(passed.get() + 1);
+                        println!(
+                            "{}: pass, running {}",
+                            name,
+                            format_hash(&verification.expected)
+                        );
+                    } else {
+                        println!("{}: FAIL, {}", name, describe_mismatch(&verification));
+                    }
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+
+            // Devices that were not found or could not be checked count as failures
+            if passed.get() < devices {
+                eprintln!("{} of {} devices passed", passed.get(), devices);
+                std::process::exit(1);
+            }
+        }
+        Commands::Log {
+            level,
+            idle_timeout,
+            once,
+        } => {
+            let input = read_stdin();
+            let mut backoff = LOG_RECONNECT_MIN_BACKOFF;
+            loop {
+                let stream_end = Cell::new(None);
+                scan_for(
+                    idle_timeout.unwrap_or(Duration::from_secs(9999999999 as u64)),
+                    1,
+                    name_filter,
+                    cli.rudel,
+                    cli.powercycle,
+                    &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                        let Ok(update_target) =
+                            FileUploadClient::new_from_peripheral(&device).await
+                        else {
+                            return Ok(Outcome::Ignored);
+                        };
+                        // Stop scanning once we found a valid target
+                        abort.abort();
+
+                        stream_end.set(Some(
+                            update_target
+                                .attach_logger(level, idle_timeout, &input)
+                                .await?,
+                        ));
+                        return Ok(Outcome::Processed);
+                    },
+                )
+                .await
+                .unwrap();
+
+                match stream_end.get() {
+                    Some(LogStreamEnd::Idle) => {
+                        log::info!(
+                            "No logs received for {:.1}s",
+                            idle_timeout.unwrap_or_default().as_secs_f32()
+                        );
+                        break;
+                    }
+                    Some(_) if once => {
+                        log::info!("The device stopped sending logs");
+                        break;
+                    }
+                    None if idle_timeout.is_some() => {
+                        log::info!("No device found");
+                        break;
+                    }
+                    Some(LogStreamEnd::Closed) => {
+                        log::info!("The device closed the log stream. Reconnecting…");
+                        backoff = LOG_RECONNECT_MIN_BACKOFF;
+                    }
+                    Some(LogStreamEnd::Disconnected) => {
+                        log::info!("Lost the connection to the device. Reconnecting…");
+                        backoff = LOG_RECONNECT_MIN_BACKOFF;
+                    }
+                    None => {
+                        log::info!(
+                            "Failed to attach to a device. Reconnecting in {:.1}s…",
+                            backoff.as_secs_f32()
+                        );
+                    }
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, LOG_RECONNECT_MAX_BACKOFF);
+            }
+        }
+        Commands::Config { timeout, action } => {
+            let new_config = match &action {
+                ConfigAction::Get => None,
+                ConfigAction::Set { value } => Some(parse_config_value(value)),
+                ConfigAction::SetPalette { colors } => Some(encode_palette(colors)),
+            };
+            let new_config = match new_config {
+                None => None,
+                Some(config) => {
+                    let config = config.unwrap_or_else(|error| {
+                        log::error!("{}", error);
+                        std::process::exit(1);
+                    });
+                    if config.len() > MAX_WASM_GUEST_CONFIG_LENGTH {
+                        log::error!(
+                            "The config is {} bytes long, but can be at most {} bytes",
+                            config.len(),
+                            MAX_WASM_GUEST_CONFIG_LENGTH
+                        );
+                        std::process::exit(1);
+                    }
+                    Some(config)
+                }
+            };
+
             scan_for(
-                Duration::from_secs(9999999999 as u64),
+                Duration::from_millis((timeout * 1000.0) as u64),
                 1,
                 name_filter,
+                cli.rudel,
                 cli.powercycle,
                 &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
                     let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
                     else {
                         return Ok(Outcome::Ignored);
                     };
-                    // Stop scanning once we found a valid target
                     abort.abort();
 
-                    update_target.attach_logger().await?;
+                    match &new_config {
+                        None => {
+                            let config = update_target.get_config().await?;
+                            let hex: String =
+                                config.iter().map(|byte| format!("{:02x}", byte)).collect();
+                            println!("{}", hex);
+                        }
+                        Some(config) => {
+                            update_target.set_config(config).await?;
+                            log::info!("Updated the config ({} bytes)", config.len());
+                        }
+                    }
                     return Ok(Outcome::Processed);
                 },
             )
             .await
             .unwrap();
-        },
+        }
+        Commands::Status {
+            timeout,
+            devices,
+            table,
+        } => {
+            if table {
+                println!("name, program, free space, uptime, free heap, largest free block, boots, last reset");
+            }
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                devices,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    if devices == 1 {
+                        abort.abort();
+                    }
+
+                    let status = update_target.get_status().await?;
+                    let program = match status.running_program {
+                        Some(hash) => format_hash(&hash),
+                        None => "default".to_string(),
+                    };
+                    let free_space = match status.free_space {
+                        Some(free_space) => format!("{:.2}kB", free_space as f32 / 1024.0),
+                        None => "unknown".to_string(),
+                    };
+                    if table {
+                        let health = match &status.health {
+                            Some(health) => format!(
+                                "{}s, {}, {}, {}, {}",
+                                health.uptime.as_secs(),
+                                health.free_heap,
+                                health.largest_free_block,
+                                health.boot_count,
+                                health.reset_reason_name()
+                            ),
+                            None => ", , , , ".to_string(),
+                        };
+                        println!("{}, {}, {}, {}", status.name, program, free_space, health);
+                        return Ok(Outcome::Processed);
+                    }
+
+                    println!("name: {}", status.name);
+                    println!("program: {}", program);
+                    println!("free space: {}", free_space);
+                    if let Some(health) = &status.health {
+                        println!("uptime: {}s", health.uptime.as_secs());
+                        println!(
+                            "heap: {:.2}kB free, largest free block {:.2}kB",
+                            health.free_heap as f32 / 1024.0,
+                            health.largest_free_block as f32 / 1024.0
+                        );
+                        println!("boots: {}", health.boot_count);
+                        println!("last reset: {}", health.reset_reason_name());
+                    }
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+        }
+        Commands::Rename { timeout, new_name } => {
+            if new_name.len() < MIN_NAME_LENGTH || new_name.len() > MAX_NAME_LENGTH {
+                log::error!(
+                    "The name is {} bytes long, but must be between {} and {} bytes",
+                    new_name.len(),
+                    MIN_NAME_LENGTH,
+                    MAX_NAME_LENGTH
+                );
+                std::process::exit(1);
+            }
+
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                1,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    abort.abort();
+
+                    update_target.set_name(&new_name).await?;
+                    log::info!("Renamed the device to {}", new_name);
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+        }
+        Commands::AssignRudel { timeout, id } => {
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                1,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    abort.abort();
+
+                    update_target.set_rudel(id).await?;
+                    match id {
+                        Some(id) => log::info!("Assigned the device to Rudel {}", id),
+                        None => log::info!("Removed the device from its Rudel"),
+                    }
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+        }
+        Commands::Advertising {
+            timeout,
+            min_interval,
+            max_interval,
+            tx_power,
+            reset,
+        } => {
+            let change = min_interval.is_some() || max_interval.is_some() || tx_power.is_some();
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                1,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    abort.abort();
+
+                    if reset {
+                        update_target.set_advertising_settings(None).await?;
+                        log::info!("Restored the default advertising settings");
+                        return Ok(Outcome::Processed);
+                    }
+
+                    let mut settings = update_target.get_advertising_settings().await?;
+                    if change {
+                        if let Some(min_interval) = min_interval {
+                            settings.min_interval =
+                                AdvertisingSettings::interval_from_millis(min_interval);
+                        }
+                        if let Some(max_interval) = max_interval {
+                            settings.max_interval =
+                                AdvertisingSettings::interval_from_millis(max_interval);
+                        }
+                        if let Some(tx_power) = tx_power {
+                            settings.tx_power = tx_power;
+                        }
+                        update_target
+                            .set_advertising_settings(Some(settings))
+                            .await?;
+                        log::info!("Updated the advertising settings");
+                    }
+                    println!(
+                        "interval: {} ms to {} ms",
+                        AdvertisingSettings::interval_to_millis(settings.min_interval),
+                        AdvertisingSettings::interval_to_millis(settings.max_interval)
+                    );
+                    println!("tx power: {} dBm", settings.tx_power);
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+        }
+        Commands::EraseFilesystem { timeout } => {
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                1,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    abort.abort();
+
+                    update_target.erase_filesystem().await?;
+                    log::info!(
+                        "Erased the filesystem. The running program is deleted once it is stopped"
+                    );
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+        }
+        Commands::UpdateFirmware {
+            timeout,
+            signing_key,
+            force,
+            upload_settings,
+            image,
+        } => {
+            let image_content = tokio::fs::read(image)
+                .await
+                .expect("Failed to read the firmware image");
+            let signing_key = update_key::load(&signing_key).unwrap_or_else(|error| {
+                log::error!("{}", error);
+                std::process::exit(1);
+            });
+
+            scan_for(
+                Duration::from_millis((timeout * 1000.0) as u64),
+                1,
+                name_filter,
+                cli.rudel,
+                cli.powercycle,
+                &async |device: Device, abort| -> Result<Outcome, UpdateTargetError> {
+                    let Ok(update_target) = FileUploadClient::new_from_peripheral(&device).await
+                    else {
+                        return Ok(Outcome::Ignored);
+                    };
+                    let update_target = update_target.with_upload_settings(upload_settings.clone());
+                    abort.abort();
+
+                    update_target
+                        .update_firmware(&image_content, &signing_key, force)
+                        .await?;
+                    return Ok(Outcome::Processed);
+                },
+            )
+            .await
+            .unwrap();
+        }
         Commands::Scan { timeout } => {
-            println!("name, mac, rssi");
+            println!("name, mac, rssi, battery");
             scan_for(
                 Duration::from_millis((timeout * 1000.0) as u64),
                 u32::MAX,
                 name_filter,
+                cli.rudel,
                 cli.powercycle,
                 &async |device: Device, _| -> Result<Outcome, UpdateTargetError> {
                     let address = device.address();
@@ -254,7 +992,11 @@ async fn main() -> bluer::Result<()> {
                     let Some(rssi) = rssi else {
                         return Ok(Outcome::Ignored);
                     };
-                    println!("{}, {}, {}", name, address, rssi);
+                    let battery = match FileUploadClient::advertised_battery_level(&device).await {
+                        Some(level) => format!("{}%", level),
+                        None => String::new(),
+                    };
+                    println!("{}, {}, {}, {}", name, address, rssi, battery);
                     //device.disconnect().await.unwrap();
                     return Ok(Outcome::Processed);
                 },
@@ -262,6 +1004,56 @@ async fn main() -> bluer::Result<()> {
             .await
             .unwrap();
         }
+        Commands::GenerateUpdateKey { key } => {
+            let public_key = update_key::generate(&key).unwrap_or_else(|error| {
+                log::error!("{}", error);
+                std::process::exit(1);
+            });
+            println!("{}", public_key);
+        }
+        Commands::Directory { image } => {
+            let directory = filesystem_image::read_directory(&image).unwrap_or_else(|error| {
+                log::error!("{}", error);
+                std::process::exit(1);
+            });
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&directory)
+                    .expect("Failed to serialize the directory")
+            );
+        }
+        Commands::Watch => {
+            println!("event, name, mac, rssi");
+            let events = scan_stream(name_filter, cli.rudel, cli.powercycle)
+                .await
+                .unwrap();
+            futures::pin_mut!(events);
+            while let Some(event) = events.next().await {
+                let (event, scanned) = match event {
+                    ScanEvent::Found(scanned) => ("found", scanned),
+                    ScanEvent::Updated(scanned) => ("updated", scanned),
+                    ScanEvent::Lost { address, name } => {
+                        println!("lost, {}, {}, ", name, address);
+                        continue;
+                    }
+                };
+                let rssi = scanned
+                    .rssi
+                    .map(|rssi| rssi.to_string())
+                    .unwrap_or_default();
+                println!(
+                    "{}, {}, {}, {}",
+                    event,
+                    scanned.name,
+                    scanned.device.address(),
+                    rssi
+                );
+            }
+        }
+        Commands::Emulate(emulate_command) if emulate_command.nodes != 1 => {
+            let simulation = Simulation::new(emulate_command).await.unwrap();
+            simulation.run().await.unwrap();
+        }
         Commands::Emulate(emulate_command) => {
             let emulator = Emulator::new(emulate_command).await.unwrap();
             emulator.emulate().await.unwrap();
@@ -0,0 +1,111 @@
+//! Sign firmware updates
+//!
+//! Devices only accept firmware updates over BLE that are signed with the key they were built for. The signing key is stored as 64 hex digits in a file. Build the firmware with the matching public key in `RUDELBLINKEN_UPDATE_KEY`:
+//!
+//! ```sh
+//! rudelctl generate-update-key update-key.hex
+//! RUDELBLINKEN_UPDATE_KEY=<printed public key> cargo build --release
+//! rudelctl update-firmware --signing-key update-key.hex firmware.bin
+//! ```
+use ed25519_dalek::{Signer, SigningKey, SECRET_KEY_LENGTH};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum UpdateKeyError {
+    #[error("Failed to access the signing key: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("The signing key needs to be {} hex digits", SECRET_KEY_LENGTH * 2)]
+    InvalidKey,
+}
+
+/// Format bytes as hex
+fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+/// Create a new signing key in `path` and return its public key as hex
+///
+/// Fails if the file already exists, so a key can not be lost by accident.
+pub fn generate(path: &Path) -> Result<String, UpdateKeyError> {
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    std::io::Write::write_all(&mut file, to_hex(key.as_bytes()).as_bytes())?;
+    return Ok(to_hex(key.verifying_key().as_bytes()));
+}
+
+/// Load the signing key in `path`
+pub fn load(path: &Path) -> Result<SigningKey, UpdateKeyError> {
+    let hex = std::fs::read_to_string(path)?;
+    let hex = hex.trim();
+    if hex.len() != SECRET_KEY_LENGTH * 2 {
+        return Err(UpdateKeyError::InvalidKey);
+    }
+    let mut key = [0u8; SECRET_KEY_LENGTH];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16)
+            .map_err(|_| UpdateKeyError::InvalidKey)?;
+    }
+    return Ok(SigningKey::from_bytes(&key));
+}
+
+/// The firmware update for `image`: the image followed by the signature of its blake3 hash
+pub fn sign_image(image: &[u8], key: &SigningKey) -> Vec<u8> {
+    let hash = blake3::hash(image);
+    let signature = key.sign(hash.as_bytes());
+    return [image, &signature.to_bytes()].concat();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::VerifyingKey;
+
+    /// Check an update like the firmware does. Returns the image without the signature
+    fn verify_update<'a>(update: &'a [u8], key: &VerifyingKey) -> Option<&'a [u8]> {
+        let signature_start = update.len().checked_sub(ed25519_dalek::SIGNATURE_LENGTH)?;
+        let (image, signature) = update.split_at(signature_start);
+        let signature = ed25519_dalek::Signature::from_slice(signature).ok()?;
+        key.verify_strict(blake3::hash(image).as_bytes(), &signature)
+            .ok()?;
+        return Some(image);
+    }
+
+    #[test]
+    fn a_generated_key_signs_updates_for_its_public_key() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("key.hex");
+        let public_key = generate(&path).unwrap();
+        let key = load(&path).unwrap();
+        assert_eq!(to_hex(key.verifying_key().as_bytes()), public_key);
+
+        let update = sign_image(&[0xE9, 1, 2, 3], &key);
+        assert_eq!(
+            verify_update(&update, &key.verifying_key()),
+            Some([0xE9, 1, 2, 3].as_slice())
+        );
+    }
+
+    #[test]
+    fn updates_with_another_key_or_content_are_rejected() {
+        let key = SigningKey::from_bytes(&[1; SECRET_KEY_LENGTH]);
+        let other_key = SigningKey::from_bytes(&[2; SECRET_KEY_LENGTH]);
+        let mut update = sign_image(&[0xE9, 1, 2, 3], &key);
+        assert_eq!(verify_update(&update, &other_key.verifying_key()), None);
+        update[1] = 7;
+        assert_eq!(verify_update(&update, &key.verifying_key()), None);
+    }
+
+    #[test]
+    fn existing_keys_are_not_overwritten() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("key.hex");
+        generate(&path).unwrap();
+        let key = std::fs::read(&path).unwrap();
+        assert!(generate(&path).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), key);
+    }
+}
@@ -0,0 +1,181 @@
+//! Collect the results of uploading a program to many devices
+//!
+//! `rudelctl upload` prints a summary table after all devices were processed. With `--report <path>` it also writes the results as JSON, so scripts can check which devices of a deployment failed:
+//!
+//! ```json
+//! {
+//!   "devices": [
+//!     {"mac": "AA:BB:CC:DD:EE:FF", "name": "cat", "bytes_sent": 20480, "duration_millis": 4000, "throughput_bytes_per_second": 5120.0, "error": null}
+//!   ]
+//! }
+//! ```
+//!
+//! `error` describes why the upload failed. `bytes_sent`, `duration_millis` and `throughput_bytes_per_second` are 0 for failed uploads.
+use serde::Serialize;
+use std::{path::Path, time::Duration};
+
+/// The result of uploading a program to one device
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UploadResult {
+    /// Address of the device
+    pub mac: String,
+    /// Name of the device
+    pub name: String,
+    pub bytes_sent: usize,
+    pub duration_millis: u128,
+    pub throughput_bytes_per_second: f64,
+    /// Why the upload failed. `None` if it succeeded
+    pub error: Option<String>,
+}
+
+impl UploadResult {
+    /// A successful upload of `bytes_sent` bytes that took `duration`
+    pub fn succeeded(mac: String, name: String, bytes_sent: usize, duration: Duration) -> Self {
+        let seconds = duration.as_secs_f64();
+        let throughput_bytes_per_second = if seconds > 0.0 {
+            bytes_sent as f64 / seconds
+        } else {
+            0.0
+        };
+        return UploadResult {
+            mac,
+            name,
+            bytes_sent,
+            duration_millis: duration.as_millis(),
+            throughput_bytes_per_second,
+            error: None,
+        };
+    }
+
+    /// A failed upload
+    pub fn failed(mac: String, name: String, error: String) -> Self {
+        return UploadResult {
+            mac,
+            name,
+            bytes_sent: 0,
+            duration_millis: 0,
+            throughput_bytes_per_second: 0.0,
+            error: Some(error),
+        };
+    }
+}
+
+/// The results of an upload to all devices
+#[derive(Serialize, Debug, Clone, PartialEq, Default)]
+pub struct UploadReport {
+    pub devices: Vec<UploadResult>,
+}
+
+impl UploadReport {
+    /// Whether the upload failed on at least one device
+    pub fn has_failures(&self) -> bool {
+        return self.devices.iter().any(|result| result.error.is_some());
+    }
+
+    /// A table with one line per device
+    pub fn summary_table(&self) -> String {
+        let name_width = self
+            .devices
+            .iter()
+            .map(|result| result.name.len())
+            .chain(std::iter::once("NAME".len()))
+            .max()
+            .unwrap_or_default();
+        let mut table = format!(
+            "{:<17}  {:<name_width$}  {:>9}  {:>8}  {:>9}  RESULT\n",
+            "MAC", "NAME", "SIZE", "TIME", "SPEED"
+        );
+        for result in &self.devices {
+            let outcome = match &result.error {
+                Some(error) => format!("FAIL, {}", error),
+                None => "ok".to_string(),
+            };
+            table += &format!(
+                "{:<17}  {:<name_width$}  {:>7.2}kB  {:>7.1}s  {:>5.2}kB/s  {}\n",
+                result.mac,
+                result.name,
+                result.bytes_sent as f64 / 1024.0,
+                result.duration_millis as f64 / 1000.0,
+                result.throughput_bytes_per_second / 1024.0,
+                outcome
+            );
+        }
+        let failed = self
+            .devices
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count();
+        table += &format!(
+            "{} of {} uploads succeeded",
+            self.devices.len() - failed,
+            self.devices.len()
+        );
+        return table;
+    }
+
+    /// Write the report as JSON
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        return std::fs::write(path, json);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> UploadReport {
+        return UploadReport {
+            devices: vec![
+                UploadResult::succeeded(
+                    "AA:BB:CC:DD:EE:FF".into(),
+                    "cat".into(),
+                    20480,
+                    Duration::from_secs(4),
+                ),
+                UploadResult::failed(
+                    "01:02:03:04:05:06".into(),
+                    "kitten".into(),
+                    "Timed out".into(),
+                ),
+            ],
+        };
+    }
+
+    #[test]
+    fn the_report_contains_every_device_as_json() {
+        let json: serde_json::Value = serde_json::to_value(report()).unwrap();
+        assert_eq!(
+            json["devices"][0],
+            serde_json::json!({
+                "mac": "AA:BB:CC:DD:EE:FF",
+                "name": "cat",
+                "bytes_sent": 20480,
+                "duration_millis": 4000,
+                "throughput_bytes_per_second": 5120.0,
+                "error": null,
+            })
+        );
+        assert_eq!(json["devices"][1]["error"], "Timed out");
+    }
+
+    #[test]
+    fn the_summary_lists_every_device_and_counts_the_failures() {
+        let report = report();
+        assert!(report.has_failures());
+
+        let table = report.summary_table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].starts_with("AA:BB:CC:DD:EE:FF  cat "));
+        assert!(lines[1].ends_with("ok"));
+        assert!(lines[2].ends_with("FAIL, Timed out"));
+        assert_eq!(lines[3], "1 of 2 uploads succeeded");
+    }
+
+    #[test]
+    fn instant_uploads_have_no_throughput() {
+        let result = UploadResult::succeeded("mac".into(), "name".into(), 10, Duration::ZERO);
+        assert_eq!(result.throughput_bytes_per_second, 0.0);
+    }
+}
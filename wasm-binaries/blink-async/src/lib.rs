@@ -0,0 +1,28 @@
+//! The `blink` program, written as an async main function
+use rudelblinken_sdk::{executor::Timer, log, set_rgb, Advertisement, LedColor, LogLevel};
+
+#[rudelblinken_sdk_macro::main(async)]
+pub async fn main() {
+    let mut timer = Timer::new(500_000);
+    let mut on = false;
+    loop {
+        on = !on;
+        log(
+            LogLevel::Info,
+            &format!("Turning LED {}", if on { "on" } else { "off" }),
+        );
+
+        timer.tick().await;
+        set_rgb(
+            LedColor {
+                red: 0xff,
+                green: 0xff,
+                blue: 0xff,
+            },
+            if on { 255 } else { 0 },
+        );
+    }
+}
+
+#[rudelblinken_sdk_macro::on_advertisement]
+fn on_advertisement(_advertisement: Advertisement) {}
@@ -1,88 +1,48 @@
 use rudelblinken_sdk::{
-    export,
+    brightness, export,
     exports::{self},
-    get_ambient_light, set_advertisement_data, set_leds, time, yield_now, Advertisement, BleGuest,
-    Guest,
+    sensor::Sensor,
+    set_advertisement_data, set_leds,
+    sync::SharedState,
+    time, yield_with_status, Advertisement, AdvertisementData, AdvertisementDataExt, BleGuest,
+    Guest, YieldStatus,
 };
-use std::sync::{LazyLock, Mutex};
-use talc::{ClaimOnOom, Span, Talc, Talck};
-
-const HEAP_SIZE: usize = 36624;
-static mut HEAP: [u8; HEAP_SIZE] = [0u8; HEAP_SIZE];
-
-#[global_allocator]
-static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> =
-    Talc::new(unsafe { ClaimOnOom::new(Span::from_array((&raw const HEAP).cast_mut())) }).lock();
-
-const SINE_TABLE: [u8; 256] = [
-    0x80, 0x83, 0x86, 0x89, 0x8C, 0x90, 0x93, 0x96, 0x99, 0x9C, 0x9F, 0xA2, 0xA5, 0xA8, 0xAB, 0xAE,
-    0xB1, 0xB3, 0xB6, 0xB9, 0xBC, 0xBF, 0xC1, 0xC4, 0xC7, 0xC9, 0xCC, 0xCE, 0xD1, 0xD3, 0xD5, 0xD8,
-    0xDA, 0xDC, 0xDE, 0xE0, 0xE2, 0xE4, 0xE6, 0xE8, 0xEA, 0xEB, 0xED, 0xEF, 0xF0, 0xF1, 0xF3, 0xF4,
-    0xF5, 0xF6, 0xF8, 0xF9, 0xFA, 0xFA, 0xFB, 0xFC, 0xFD, 0xFD, 0xFE, 0xFE, 0xFE, 0xFF, 0xFF, 0xFF,
-    0xFF, 0xFF, 0xFF, 0xFF, 0xFE, 0xFE, 0xFE, 0xFD, 0xFD, 0xFC, 0xFB, 0xFA, 0xFA, 0xF9, 0xF8, 0xF6,
-    0xF5, 0xF4, 0xF3, 0xF1, 0xF0, 0xEF, 0xED, 0xEB, 0xEA, 0xE8, 0xE6, 0xE4, 0xE2, 0xE0, 0xDE, 0xDC,
-    0xDA, 0xD8, 0xD5, 0xD3, 0xD1, 0xCE, 0xCC, 0xC9, 0xC7, 0xC4, 0xC1, 0xBF, 0xBC, 0xB9, 0xB6, 0xB3,
-    0xB1, 0xAE, 0xAB, 0xA8, 0xA5, 0xA2, 0x9F, 0x9C, 0x99, 0x96, 0x93, 0x90, 0x8C, 0x89, 0x86, 0x83,
-    0x80, 0x7D, 0x7A, 0x77, 0x74, 0x70, 0x6D, 0x6A, 0x67, 0x64, 0x61, 0x5E, 0x5B, 0x58, 0x55, 0x52,
-    0x4F, 0x4D, 0x4A, 0x47, 0x44, 0x41, 0x3F, 0x3C, 0x39, 0x37, 0x34, 0x32, 0x2F, 0x2D, 0x2B, 0x28,
-    0x26, 0x24, 0x22, 0x20, 0x1E, 0x1C, 0x1A, 0x18, 0x16, 0x15, 0x13, 0x11, 0x10, 0x0F, 0x0D, 0x0C,
-    0x0B, 0x0A, 0x08, 0x07, 0x06, 0x06, 0x05, 0x04, 0x03, 0x03, 0x02, 0x02, 0x02, 0x01, 0x01, 0x01,
-    0x01, 0x01, 0x01, 0x01, 0x02, 0x02, 0x02, 0x03, 0x03, 0x04, 0x05, 0x06, 0x06, 0x07, 0x08, 0x0A,
-    0x0B, 0x0C, 0x0D, 0x0F, 0x10, 0x11, 0x13, 0x15, 0x16, 0x18, 0x1A, 0x1C, 0x1E, 0x20, 0x22, 0x24,
-    0x26, 0x28, 0x2B, 0x2D, 0x2F, 0x32, 0x34, 0x37, 0x39, 0x3C, 0x3F, 0x41, 0x44, 0x47, 0x4A, 0x4D,
-    0x4F, 0x52, 0x55, 0x58, 0x5B, 0x5E, 0x61, 0x64, 0x67, 0x6A, 0x6D, 0x70, 0x74, 0x77, 0x7A,
-    0x7D,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    // 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0, 0xa0,
-];
-
-fn calc_bright(progress: u16) -> u32 {
-    // This whole function is really hacky and should be replaced
-    //
-    // All *2 multipliers are hacked on, to make it a bit brighter
-    let fraction = (progress / 256) as u8;
-    // log(LogLevel::Error, format!("Progress: {}", progress).as_str());
-    // log(LogLevel::Error, format!("Fraction: {}", fraction).as_str());
-    // Related to PWM frequency
-    const MAX_VALUE: u32 = 2500;
-    // relative brightness to use in bright ambient conditions (>= MAX_AMBIENT); 0-255
-    const MAX_BRIGHTNESS_MULTIPLIER: u32 = (0.8 * MAX_VALUE as f32) as u32;
-    const MIN_BRIGHTNESS_MULTIPLIER: u32 = (0.2 * MAX_VALUE as f32) as u32;
-
-    const BRIGHTNESS_MULTIPLIER_RANGE: u32 = MAX_BRIGHTNESS_MULTIPLIER - MIN_BRIGHTNESS_MULTIPLIER;
-    let ambient_reading = get_ambient_light();
-    let ambient_multiplier = ((ambient_reading * BRIGHTNESS_MULTIPLIER_RANGE as u32) * 2 / 2500)
-        + MIN_BRIGHTNESS_MULTIPLIER as u32;
-
-    // map fraction to sine wave and apply ambient light multiplier
-    let brightness: u64 = (SINE_TABLE[fraction as usize] as u64 * ambient_multiplier as u64) / 255;
-
-    let adjusted_brightness = (brightness * brightness) / MAX_VALUE as u64;
-
-    adjusted_brightness as u32
+use std::sync::LazyLock;
+
+// The tests run natively with the system allocator
+#[cfg(target_arch = "wasm32")]
+mod allocator {
+    use talc::{ClaimOnOom, Span, Talc, Talck};
+
+    const HEAP_SIZE: usize = 36624;
+    static mut HEAP: [u8; HEAP_SIZE] = [0u8; HEAP_SIZE];
+
+    #[global_allocator]
+    static ALLOCATOR: Talck<spin::Mutex<()>, ClaimOnOom> =
+        Talc::new(unsafe { ClaimOnOom::new(Span::from_array((&raw const HEAP).cast_mut())) })
+            .lock();
+}
+
+// The brightness curve was tuned for this maximum. The maximum reported by the host is not used,
+// because the firmware reports its PWM resolution and the emulator reports 0
+const MAX_LUX: u16 = 2500;
+
+fn calc_bright(progress: u16) -> u16 {
+    let fraction = brightness::phase_to_fraction(progress);
+    let ambient = Sensor::ambient().unwrap_or(brightness::MIN_AMBIENT);
+    brightness::ambient_adjusted_lux(ambient, fraction, MAX_LUX)
 }
 
 // How much nudges are attenuated
 const NUDGE_ATTENUATION: i32 = 50;
 // A cycle is 65536 steps. Adjust this to change the speed of a cycle
 const US_PER_STEP: u64 = 20;
-// Mark peers as outdated if they are older than this
-const MAX_PING_AGE: u64 = 1_000_000 * 5; // 10 seconds
-                                         // Delay between nudges
+// Company identifier in the advertisements
+const COMPANY_ID: u16 = 0x0000;
+// Forget peers that were not heard from for this long
+const MAX_PING_AGE: u64 = 1_000_000 * 5; // 5 seconds
+
+// Delay between nudges
 const NUDGE_DELAY: u64 = 200_000; // 200ms
 
 #[derive(Debug, Clone)]
@@ -95,6 +55,17 @@ struct ReceivedPing {
     offset: i16,
 }
 
+/// A progress received from another device
+#[derive(Debug, Clone)]
+struct Nudge {
+    /// When the advertisement was received
+    received_at: u64,
+    /// Progress of the other device
+    progress: u16,
+    /// Address of the other device
+    source_address: u64,
+}
+
 #[derive(Debug, Clone)]
 struct CycleState {
     /// Progress in the cycle, 0-65536
@@ -127,13 +98,13 @@ impl CycleState {
         self.progress.wrapping_add(steps)
     }
 
-    /// This function gets called when a nudge is received
-    /// from another device. It is called with the timestamp
-    ///
-    /// received_at: when the ping was received
-    /// progress: progress of the other device
-    /// source_address: address of the other device
-    fn register_nudge(&mut self, received_at: u64, progress: u16, source_address: u64) {
+    /// This function gets called for every nudge received from another device
+    fn register_nudge(&mut self, nudge: Nudge) {
+        let Nudge {
+            received_at,
+            progress,
+            source_address,
+        } = nudge;
         let progress_at_receive = self.progress_at(received_at);
         let offset = progress.wrapping_sub(progress_at_receive) as i16;
 
@@ -157,11 +128,14 @@ impl CycleState {
     }
 
     /// This function gets called every tick
-    fn update_progress(&mut self) {
-        let now = time();
+    fn update_progress(&mut self, now: u64) {
         let step_duration = now - self.update_time;
         self.update_time = now;
 
+        // Peers that went silent should not influence the progress anymore
+        self.peers
+            .retain(|peer| peer.received_at > now.saturating_sub(MAX_PING_AGE));
+
         // Apply nudges to progress based on received offsets
         let since_last_nudge = now - self.nudge_time;
         if since_last_nudge > NUDGE_DELAY {
@@ -170,7 +144,6 @@ impl CycleState {
             let average_offset = self
                 .peers
                 .iter()
-                .filter(|peer| peer.received_at > (now.saturating_sub(MAX_PING_AGE)))
                 .map(|peer| peer.offset as i32)
                 .sum::<i32>();
 
@@ -184,32 +157,39 @@ impl CycleState {
     }
 }
 
-static CYCLE_STATE: LazyLock<Mutex<CycleState>> = LazyLock::new(|| Mutex::new(CycleState::new()));
+// Nudges received between two ticks. Enough for every peer to advertise a few times
+const MAX_PENDING_NUDGES: usize = 64;
+
+static CYCLE_STATE: LazyLock<SharedState<CycleState, Nudge>> =
+    LazyLock::new(|| SharedState::new(CycleState::new(), MAX_PENDING_NUDGES));
+
+// Longest yield while no advertisements arrive. The LED still fades smoothly at this rate
+const MAX_IDLE_YIELD: u64 = 1000;
 
 /// Advance a tick, updating the cycle state and setting the advertisement data
 ///
+/// Yields for `yield_micros` first. That is doubled while nothing is received and reset once advertisements arrive, so the program does not busy-loop while alone.
+///
 /// Returns the progress of the cycle state
-fn tick() -> u16 {
-    let progress = loop {
-        yield_now(1);
-
-        let Ok(mut state) = CYCLE_STATE.try_lock() else {
-            continue;
-        };
-        state.update_progress();
-        break state.progress;
+fn tick(yield_micros: &mut u64) -> u16 {
+    *yield_micros = match yield_with_status(*yield_micros) {
+        YieldStatus::Idle => (*yield_micros * 2).min(MAX_IDLE_YIELD),
+        YieldStatus::CallbacksDelivered => 1,
     };
+    // Apply the nudges received while yielding before advancing the progress
+    let progress = CYCLE_STATE.update(CycleState::register_nudge, |state| {
+        state.update_progress(time());
+        state.progress
+    });
 
     let progress_bytes = progress.to_le_bytes();
-    set_advertisement_data(&vec![
-        0x00,
-        0x00,
-        0xca,
-        0x7e,
-        0xa2,
-        progress_bytes[0],
-        progress_bytes[1],
-    ]);
+    let data = AdvertisementData::manufacturer(
+        COMPANY_ID,
+        &[0xca, 0x7e, 0xa2, progress_bytes[0], progress_bytes[1]],
+    )
+    .unwrap();
+    // The advertisement is updated on every tick, so there is no need to handle a failed update
+    let _ = set_advertisement_data(&data);
     progress
 }
 
@@ -226,35 +206,31 @@ impl Guest for Test {
         //     LogLevel::Error,
         //     format!("Brightness table: {:?}", table).as_str(),
         // );
+        let mut yield_micros = 1;
         loop {
-            let progress = tick();
+            let progress = tick(&mut yield_micros);
 
             // TODO: Add high-level API for setting led
-            set_leds(0, &[calc_bright(progress) as u16]);
+            set_leds(0, &[calc_bright(progress)]);
         }
     }
 }
 
 impl BleGuest for Test {
     fn on_advertisement(advertisement: Advertisement) {
-        let data = unsafe {
-            std::mem::transmute::<[u32; 8], [u8; 32]>(
-                advertisement.data.try_into().unwrap_unchecked(),
-            )
-        };
-        let slice = &data[0..(advertisement.data_length as usize)];
-        let [0xca, 0x7e, 0xa2, other_progress_0, other_progress_1] = slice else {
+        let Some([0xca, 0x7e, 0xa2, other_progress_0, other_progress_1]) =
+            advertisement.manufacturer(COMPANY_ID)
+        else {
             return;
         };
         let other_progress = u16::from_le_bytes([*other_progress_0, *other_progress_1]);
 
-        if let Ok(mut state) = CYCLE_STATE.try_lock() {
-            state.register_nudge(
-                advertisement.received_at,
-                other_progress,
-                advertisement.address,
-            );
-        }
+        // The nudge is applied on the next tick
+        CYCLE_STATE.push(Nudge {
+            received_at: advertisement.received_at,
+            progress: other_progress,
+            source_address: advertisement.address,
+        });
     }
 }
 
@@ -263,3 +239,55 @@ impl BleGuest for Test {
 fn main() {}
 
 export! {Test}
+
+// Run with your host target, e.g. `cargo test -p reference-sync-v1 --target x86_64-unknown-linux-gnu`, as the workspace builds for wasm32 by default
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_at(now: u64) -> CycleState {
+        return CycleState {
+            progress: 0,
+            update_time: now,
+            nudge_time: now,
+            peers: Vec::new(),
+        };
+    }
+
+    #[test]
+    fn a_silent_peer_stops_influencing_the_progress() {
+        let start = 1_000_000;
+        let mut state = state_at(start);
+        state.register_nudge(Nudge {
+            received_at: start,
+            progress: 1000,
+            source_address: 1,
+        });
+
+        // The peer is ahead, so it pulls the progress forward
+        let now = start + NUDGE_DELAY + 1;
+        let without_nudge = ((now - start) / US_PER_STEP) as u16;
+        state.update_progress(now);
+        assert_eq!(
+            state.progress,
+            without_nudge + (1000 / NUDGE_ATTENUATION) as u16
+        );
+
+        // A peer that is still heard from, without an offset
+        state.register_nudge(Nudge {
+            received_at: state.update_time,
+            progress: state.progress,
+            source_address: 2,
+        });
+
+        // The first peer was not heard from for too long
+        let now = start + MAX_PING_AGE + 1;
+        let without_nudge = state
+            .progress
+            .wrapping_add(((now - state.update_time) / US_PER_STEP) as u16);
+        state.update_progress(now);
+        assert_eq!(state.progress, without_nudge);
+        assert_eq!(state.peers.len(), 1);
+        assert_eq!(state.peers[0].address, 2);
+    }
+}